@@ -5,7 +5,19 @@ use derive_more::{Binary, Display, LowerHex, Octal, UpperHex};
 
 /// 論理アドレス。
 #[derive(
-    Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Binary, Display, LowerHex, Octal, UpperHex,
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Binary,
+    Display,
+    LowerHex,
+    Octal,
+    UpperHex,
 )]
 pub struct Address(u16);
 
@@ -89,7 +101,19 @@ impl From<Address> for usize {
 
 /// ゼロページアドレス。
 #[derive(
-    Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Binary, Display, LowerHex, Octal, UpperHex,
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Binary,
+    Display,
+    LowerHex,
+    Octal,
+    UpperHex,
 )]
 pub struct ZpAddress(u8);
 
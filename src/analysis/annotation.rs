@@ -0,0 +1,49 @@
+//! 利用者による注釈の適用。
+//!
+//! CDL による解析の直後に実行され、その結果を上書きする:
+//!
+//! * データとして指定されたアドレス範囲は `NotCode` とする。
+//! * コードとして指定されたアドレスは `Code` とし、エントリポイントラベルを振る。
+//! * 名前の付いたアドレスにはラベルを振り、その名前を設定する。
+//!
+//! データ型の指定はマニフェストにより構造体の配列に変換済みなので、ここでは扱わない。
+
+use crate::address::Address;
+use crate::assembly::{Label, Labels};
+use crate::input::Input;
+
+use super::{Analysis, AnalysisKind};
+
+pub(super) fn analyze(analysis: &mut Analysis, labels: &mut Labels, input: &Input) {
+    let annotations = input.annotations();
+
+    for &range in annotations.data_ranges() {
+        analysis[range].fill(AnalysisKind::NotCode);
+    }
+
+    for addr in annotations.code_entries() {
+        // ロードされていないアドレスは無視する。
+        if input.memory().find_bank_id(addr).is_none() {
+            continue;
+        }
+        analysis[addr] = AnalysisKind::Code;
+        labels.set(addr, Label::new(true));
+    }
+
+    for (addr, name) in annotations.labels() {
+        labels.set(addr, Label::new(false));
+        labels.set_name(addr, name);
+    }
+}
+
+/// 逆アセンブル対象バンク内のコメントたちを返す。
+pub(super) fn collect_comments(input: &Input) -> Vec<(Address, String)> {
+    let bank = input.target_bank();
+
+    input
+        .annotations()
+        .comments()
+        .filter(|&(addr, _)| bank.contains_addr(addr))
+        .map(|(addr, comment)| (addr, comment.to_owned()))
+        .collect()
+}
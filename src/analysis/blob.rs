@@ -0,0 +1,157 @@
+//! `.incbin` 対象データの判定。
+//!
+//! 逆アセンブル対象バンク内で連続する `Statement::Byte` のうち、バイト数が `incbin_threshold` 以上のものを
+//! `Statement::Blob` にまとめる。ただしアドレス範囲ごとの指定があればそれに従う。
+//!
+//! 途中にラベルがあっても区切らない (出力時に `:=` で定義される)。
+
+use crate::assembly::Statement;
+use crate::config::AnalysisConfig;
+use crate::input::Input;
+
+pub(super) fn analyze(
+    stmts: Vec<Statement>,
+    input: &Input,
+    config: &AnalysisConfig,
+) -> Vec<Statement> {
+    let mut res = Vec::<Statement>::with_capacity(stmts.len());
+
+    // 連続する Byte を、アドレス範囲ごとの指定が変わる位置で区切ったもの。
+    let mut run = Vec::<u8>::new();
+    let mut run_override: Option<bool> = None;
+
+    macro_rules! flush {
+        () => {{
+            let incbin = run_override.unwrap_or_else(|| {
+                config.incbin_threshold() != 0 && run.len() >= config.incbin_threshold()
+            });
+            if incbin && !run.is_empty() {
+                res.push(Statement::Blob(std::mem::take(&mut run)));
+            } else {
+                res.extend(run.drain(..).map(Statement::Byte));
+            }
+        }};
+    }
+
+    let mut addr = input.target_bank().addr();
+    for stmt in stmts {
+        let stmt_len = stmt.len();
+
+        match stmt {
+            Statement::Byte(b) => {
+                let incbin_override = input.incbin_override(addr);
+                if incbin_override != run_override {
+                    flush!();
+                }
+                run_override = incbin_override;
+                run.push(b);
+            }
+            stmt => {
+                flush!();
+                res.push(stmt);
+            }
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt_len) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+    flush!();
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::{Address, AddressRange};
+    use crate::bank::Bank;
+    use crate::config::OutputConfig;
+    use crate::output::assembly_lines;
+
+    use super::super::test_util::{input_builder, make_bank_body, make_input};
+    use super::*;
+
+    fn bytes(bs: &[u8]) -> Vec<Statement> {
+        bs.iter().copied().map(Statement::Byte).collect()
+    }
+
+    #[test]
+    fn test_threshold() {
+        let input = make_input(&[(0xC000, &[0x60])]);
+        // 3 バイトの並びは閾値未満、4 バイトの並びは閾値以上。
+        let mut stmts = bytes(&[1, 2, 3]);
+        stmts.push(Statement::Dpcm(vec![0; 1]));
+        stmts.extend(bytes(&[4, 5, 6, 7]));
+
+        let config: AnalysisConfig = toml::from_str("incbin_threshold = 4").unwrap();
+        let mut expected = bytes(&[1, 2, 3]);
+        expected.push(Statement::Dpcm(vec![0; 1]));
+        expected.push(Statement::Blob(vec![4, 5, 6, 7]));
+        assert_eq!(analyze(stmts.clone(), &input, &config), expected);
+
+        // 閾値 0 なら .incbin を使わない。
+        assert_eq!(
+            analyze(stmts.clone(), &input, &AnalysisConfig::default()),
+            stmts
+        );
+    }
+
+    #[test]
+    fn test_override() {
+        // $C001-$C002 は閾値によらず .incbin とし、$C004-$C005 は .incbin としない。
+        let bank = Bank::new(
+            Address::new(0xC000),
+            make_bank_body(&[(0xC000, &[0x60])]),
+            true,
+        );
+        let range =
+            |min: u16, max: u16| AddressRange::from_min_max(Address::new(min), Address::new(max));
+        let input = input_builder(vec![bank])
+            .incbin_range(range(0xC001, 0xC002), true)
+            .incbin_range(range(0xC004, 0xC005), false)
+            .build()
+            .unwrap();
+
+        let config: AnalysisConfig = toml::from_str("incbin_threshold = 2").unwrap();
+        let mut expected = bytes(&[0]);
+        expected.push(Statement::Blob(vec![1, 2]));
+        expected.extend(bytes(&[3, 4, 5]));
+        assert_eq!(
+            analyze(bytes(&[0, 1, 2, 3, 4, 5]), &input, &config),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_output() {
+        #[rustfmt::skip]
+        let prog = [
+            0xAD, 0x10, 0xC1, // $C000: lda $C110
+            0x4C, 0x03, 0xC0, // $C003: jmp $C003
+        ];
+        let input = make_input(&[(0xC000, &prog)]);
+        let config: AnalysisConfig = toml::from_str("incbin_threshold = 16").unwrap();
+        let asm = super::super::analyze(&input, &config);
+
+        // $C006 から割り込みベクタまでの全体が 1 つの .incbin となり、途中のラベルは := で定義される。
+        let lines: Vec<String> = assembly_lines(&asm, &OutputConfig::default())
+            .unwrap()
+            .into_iter()
+            .filter(|&(addr, _)| addr == Address::new(0xC006))
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "",
+                "L_C006:",
+                "L_C110 := L_C006 + 266",
+                "L_FFFA := L_C006 + 16372",
+                "L_FFFC := L_C006 + 16374",
+                "L_FFFE := L_C006 + 16376",
+                r#"        .incbin "PRG.bin", $0006, $3FFA"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,579 @@
+//! 定数伝播。
+//!
+//! 命令列について A/X/Y レジスタおよび C/Z/N/V フラグの定数伝播を行う。
+//! DMC のサンプルアドレス/サンプル長レジスタへの書き込みも追跡する。
+//! また、マッパーが指定されていればマッパーレジスタへの書き込みを認識して、
+//! 各アドレスの時点で $8000-$FFFF の各 8KB 領域にどのバンクが割り当てられているかを推定する。
+//!
+//! 直前の命令からの流入に加え、前方からの分岐/jmp abs による流入も状態を伝播させる。
+//! 分岐の結果に応じてフラグを絞り込み、常に成立しない(あるいは常に成立する)分岐の辺は伝播させない。
+//! 後方からの分岐/jmp、jmp/jsr の飛び先、jsr の戻り先、割り込みなど、
+//! それ以外から制御が移りうるアドレスでは全ての状態を不明とする。
+//!
+//! `NotCode` でない全ての命令を直前の命令の候補とする。実際のコード上では直前の命令は 1 つしかないので、
+//! 候補が複数ある場合は `Code` であるものを、次いでより長く連続している命令列に属するものを優先する。
+//! (誤った命令境界から読んだ命令列は短く、すぐに正しい命令列に合流するため)
+//! 優先度が同じ候補が複数あれば、それらの結果が一致する値のみを確定させる。
+//!
+//! 間接アドレッシングによる書き込みは RAM に対するものとみなし、マッパーレジスタへの書き込みとしては扱わない。
+
+use crate::address::{Address, ArrayByAddress};
+use crate::assembly::BankSwitch;
+use crate::input::Input;
+use crate::mapper::Mapper;
+use crate::memory::OpSuccResolved;
+use crate::op::{Op, Operand};
+use crate::regs::RegState;
+
+use super::{Analysis, AnalysisKind};
+
+/// 論理アドレス空間全体の定数伝播結果。各アドレスの命令を実行する直前の状態を保持する。
+#[derive(Debug, Default)]
+pub struct Constants(ArrayByAddress<State>);
+
+/// $8000, $A000, $C000, $E000 から始まる各 8KB 領域に割り当てられているバンク番号。
+/// 16KB 単位で切り替えるマッパーの場合、隣接する 2 領域が同じ 16KB バンク番号を持つ。
+type Slots = [Option<u8>; 4];
+
+impl Constants {
+    /// `site` 上の命令を実行する直前のレジスタ状態を返す。
+    pub fn regs(&self, site: Address) -> RegState {
+        self.0[site].regs
+    }
+
+    /// `site` 上の分岐命令 `op` が、直前の命令列から推定されるフラグにより常に分岐するかどうかを返す。
+    pub fn is_always_taken(&self, site: Address, op: Op) -> bool {
+        self.regs(site).branch_taken(op) == Some(true)
+    }
+
+    /// `site` 上の命令 `op` が `Code` であり、かつ常に分岐する分岐命令かどうかを返す。
+    /// (`collect_always_taken_branches()` が列挙する命令の判定基準)
+    pub fn is_always_taken_code(&self, analysis: &Analysis, site: Address, op: Op) -> bool {
+        analysis[site] == AnalysisKind::Code && self.is_always_taken(site, op)
+    }
+
+    /// `site` 上の命令を実行する時点で、`dst` を含む領域に割り当てられていると推定されるバンク番号を返す。
+    pub fn mapped_bank_number(&self, site: Address, dst: Address) -> Option<usize> {
+        let slot = usize::from(dst.get().checked_sub(0x8000)? >> 13);
+        self.0[site].slots[slot].map(usize::from)
+    }
+
+    /// `site` 上の命令から参照される `dst` が、ロードされているバンクのうちどれを指すかを返す。
+    /// 実際に参照されるバンクがロードされていない、あるいは確定できない場合、`None` を返す。
+    ///
+    /// 参照先が固定バンクならば常に確定できる。
+    /// さもなくば、バンク切り替えにより参照先のバンク番号が判明していればそれが逆アセンブル対象バンクかどうかで、
+    /// 判明していなければ参照元と同一バンクかどうかで判定する。
+    pub fn resolve_bank_id(&self, input: &Input, site: Address, dst: Address) -> Option<usize> {
+        let memory = input.memory();
+
+        let dst_bank_id = memory.find_bank_id(dst)?;
+        if memory.banks()[dst_bank_id].is_fixed() {
+            return Some(dst_bank_id);
+        }
+
+        let ok = match self.mapped_bank_number(site, dst) {
+            Some(number) => input.target_bank_number() == Some(number),
+            None => memory.find_bank_id(site) == Some(dst_bank_id),
+        };
+
+        ok.then_some(dst_bank_id)
+    }
+}
+
+pub(super) fn analyze(analysis: &Analysis, input: &Input) -> Constants {
+    let mut consts = Constants::default();
+
+    let memory = input.memory();
+    let mapper = input.mapper();
+
+    // 直前の命令および前方からの分岐/jmp abs 以外から制御が移りうるアドレスを求める。
+    let mut joins = ArrayByAddress::<bool>::default();
+    for vector in [0xFFFA, 0xFFFC, 0xFFFE] {
+        if let Some((dst, _)) = memory.fetch_addr(Address::new(vector)) {
+            joins[dst] = true;
+        }
+    }
+    for addr in Address::all() {
+        if analysis[addr] == AnalysisKind::NotCode {
+            continue;
+        }
+        let Ok((op, _)) = memory.fetch_op(addr) else {
+            continue;
+        };
+
+        match memory.resolve_op_succ(addr, op.succ()) {
+            OpSuccResolved::Normal(_) | OpSuccResolved::Kil => {}
+            OpSuccResolved::Branch { taken, .. } | OpSuccResolved::JmpAbs(taken) => {
+                if taken <= addr {
+                    joins[taken] = true;
+                }
+            }
+            OpSuccResolved::Jsr(dst) => {
+                joins[dst] = true;
+                joins[addr.wrapping_add_unsigned(op.len())] = true;
+            }
+            OpSuccResolved::Brk(dst) | OpSuccResolved::JmpInd(dst) => {
+                if let Some(dst) = dst {
+                    joins[dst] = true;
+                }
+            }
+            OpSuccResolved::Rti | OpSuccResolved::Rts => {}
+        }
+    }
+
+    // 状態の伝播は常にアドレスが増える方向に行うので、アドレスの昇順に 1 回走査すれば全ての状態が求まる。
+    //
+    // 直前の命令からの流入については、各アドレスについて (優先度, 状態) を保持する。
+    // 優先度は (`Code` かどうか, 連続する命令数)。
+    // 前方からの分岐/jmp abs による流入については、全ての流入元の状態を合流させたものを保持する。
+    let mut falls = ArrayByAddress::<Option<((bool, usize), State)>>::default();
+    let mut jumps = ArrayByAddress::<Option<State>>::default();
+    for addr in Address::all() {
+        if analysis[addr] == AnalysisKind::NotCode {
+            continue;
+        }
+
+        let (run, state) = if joins[addr] {
+            (0, State::default())
+        } else {
+            match (falls[addr], jumps[addr]) {
+                (Some(((_, run), fall)), Some(jump)) => (run, fall.meet(jump)),
+                (Some(((_, run), fall)), None) => (run, fall),
+                (None, Some(jump)) => (0, jump),
+                (None, None) => (0, State::default()),
+            }
+        };
+        consts.0[addr] = state;
+
+        let Ok((op, _)) = memory.fetch_op(addr) else {
+            continue;
+        };
+
+        let prio = (analysis[addr] == AnalysisKind::Code, run + 1);
+        let next_state = state.step(mapper, op);
+
+        macro_rules! fall {
+            ($next:expr, $state:expr) => {{
+                let (next, state) = ($next, $state);
+                if next > addr {
+                    falls[next] = Some(match falls[next] {
+                        Some((prio_other, other)) if prio_other > prio => (prio_other, other),
+                        Some((prio_other, other)) if prio_other == prio => {
+                            (prio, other.meet(state))
+                        }
+                        _ => (prio, state),
+                    });
+                }
+            }};
+        }
+        macro_rules! jump {
+            ($dst:expr, $state:expr) => {{
+                let (dst, state) = ($dst, $state);
+                if dst > addr {
+                    jumps[dst] = Some(match jumps[dst] {
+                        Some(other) => other.meet(state),
+                        None => state,
+                    });
+                }
+            }};
+        }
+
+        match memory.resolve_op_succ(addr, op.succ()) {
+            OpSuccResolved::Normal(next) => fall!(next, next_state),
+            OpSuccResolved::Branch { taken, not_taken } => {
+                let taken_known = state.regs.branch_taken(op);
+                if taken_known != Some(false) {
+                    jump!(taken, next_state.refine_branch(op, true));
+                }
+                if taken_known != Some(true) {
+                    fall!(not_taken, next_state.refine_branch(op, false));
+                }
+            }
+            OpSuccResolved::JmpAbs(dst) => jump!(dst, next_state),
+            _ => {}
+        }
+    }
+
+    consts
+}
+
+/// 逆アセンブル対象バンク内の `Code` である jsr, jmp 命令のうち、
+/// 固定バンク外への行き先のバンク番号が推定できたものを列挙する。
+pub(super) fn collect_bank_switches(
+    consts: &Constants,
+    analysis: &Analysis,
+    input: &Input,
+) -> Vec<BankSwitch> {
+    let memory = input.memory();
+
+    input
+        .target_bank()
+        .addr_range()
+        .into_iter()
+        .filter(|&site| analysis[site] == AnalysisKind::Code)
+        .filter_map(|site| {
+            let (op, _) = memory.fetch_op(site).ok()?;
+            let dst = match memory.resolve_op_succ(site, op.succ()) {
+                OpSuccResolved::Jsr(dst) | OpSuccResolved::JmpAbs(dst) => dst,
+                OpSuccResolved::JmpInd(dst) => dst?,
+                _ => return None,
+            };
+            let fixed = memory
+                .find_bank_id(dst)
+                .is_some_and(|id| memory.banks()[id].is_fixed());
+            if fixed {
+                return None;
+            }
+            let number = consts.mapped_bank_number(site, dst)?;
+            Some(BankSwitch::new(site, dst, number))
+        })
+        .collect()
+}
+
+/// 逆アセンブル対象バンク内の `Code` である分岐命令のうち、常に分岐するものを列挙する。
+pub(super) fn collect_always_taken_branches(
+    consts: &Constants,
+    analysis: &Analysis,
+    input: &Input,
+) -> Vec<Address> {
+    let memory = input.memory();
+
+    input
+        .target_bank()
+        .addr_range()
+        .into_iter()
+        .filter(|&site| {
+            memory
+                .fetch_op(site)
+                .is_ok_and(|(op, _)| consts.is_always_taken_code(analysis, site, op))
+        })
+        .collect()
+}
+
+/// `Code` である DMC レジスタ ($4012, $4013) への書き込みのうち、書き込み後に両レジスタの値が判明しているものから、
+/// 逆アセンブル対象バンク内の DPCM サンプルを (開始アドレス, バイト数) の形で列挙する。
+pub(super) fn collect_dpcm_samples(
+    consts: &Constants,
+    analysis: &Analysis,
+    input: &Input,
+) -> Vec<(Address, usize)> {
+    let memory = input.memory();
+
+    let mut samples: Vec<_> = Address::all()
+        .filter(|&site| analysis[site] == AnalysisKind::Code)
+        .filter_map(|site| {
+            let (op, _) = memory.fetch_op(site).ok()?;
+            if !matches!(op.operand(), Operand::Abs(abs) if matches!(abs.get(), 0x4012 | 0x4013)) {
+                return None;
+            }
+
+            let state = consts.0[site].step(input.mapper(), op);
+            let [Some(addr), Some(len)] = state.dmc else {
+                return None;
+            };
+            let start = Address::new(0xC000 + 64 * u16::from(addr));
+            let len = 16 * usize::from(len) + 1;
+
+            (consts.resolve_bank_id(input, site, start) == Some(input.target_bank_id()))
+                .then_some((start, len))
+        })
+        .collect();
+
+    samples.sort_unstable();
+    samples.dedup();
+
+    samples
+}
+
+/// ある命令を実行する直前の状態。`None` は値が不明であることを表す。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct State {
+    regs: RegState,
+    slots: Slots,
+    /// MMC1 のシフトレジスタの (書き込み回数, 値)。
+    mmc1_shift: Option<(u8, u8)>,
+    /// MMC3 のバンク選択レジスタ ($8000) の値。
+    mmc3_select: Option<u8>,
+    /// DMC のサンプルアドレス ($4012), サンプル長 ($4013) レジスタの値。
+    dmc: [Option<u8>; 2],
+}
+
+/// デフォルト値は何もわかっていない状態。
+///
+/// ただし、MMC1 のシフトレジスタは空であると仮定する。
+/// (通常、シリアル書き込みは 1 つのルーチン内で 5 回連続して行われるため)
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            regs: RegState::default(),
+            slots: [None; 4],
+            mmc1_shift: Some((0, 0)),
+            mmc3_select: None,
+            dmc: [None; 2],
+        }
+    }
+}
+
+impl State {
+    /// 2 つの状態のうち、共通する値のみを残したものを返す。
+    fn meet(self, other: Self) -> Self {
+        fn meet<T: Eq>(lhs: Option<T>, rhs: Option<T>) -> Option<T> {
+            if lhs == rhs {
+                lhs
+            } else {
+                None
+            }
+        }
+
+        Self {
+            regs: self.regs.meet(other.regs),
+            slots: std::array::from_fn(|i| meet(self.slots[i], other.slots[i])),
+            mmc1_shift: meet(self.mmc1_shift, other.mmc1_shift),
+            mmc3_select: meet(self.mmc3_select, other.mmc3_select),
+            dmc: std::array::from_fn(|i| meet(self.dmc[i], other.dmc[i])),
+        }
+    }
+
+    /// 命令を 1 つ実行した後の状態を返す。
+    fn step(mut self, mapper: Option<Mapper>, op: Op) -> Self {
+        self.step_write(mapper, op);
+        self.regs = self.regs.step(op);
+        self
+    }
+
+    /// 分岐命令の結果に応じてフラグを絞り込んだ状態を返す。
+    fn refine_branch(mut self, op: Op, taken: bool) -> Self {
+        self.regs = self.regs.refine_branch(op, taken);
+        self
+    }
+
+    /// 命令による DMC レジスタおよび $8000-$FFFF への書き込みを処理する。
+    fn step_write(&mut self, mapper: Option<Mapper>, op: Op) {
+        if !op.is_write() {
+            return;
+        }
+
+        let (base, index) = match op.operand() {
+            Operand::Abs(abs) => (abs, Some(0)),
+            Operand::AbsX(abs) => (abs, self.regs.x()),
+            Operand::AbsY(abs) => (abs, self.regs.y()),
+            _ => return,
+        };
+
+        // インデックスが不明で、かつ DMC レジスタや $8000 以降に届きうる場合、どのレジスタに書き込んだかわからない。
+        let Some(index) = index else {
+            let reach = usize::from(base)..=usize::from(base) + 0xFF;
+            if reach.contains(&0x4012) || reach.contains(&0x4013) {
+                self.dmc = [None; 2];
+            }
+            if mapper.is_some() && *reach.end() >= 0x8000 {
+                self.slots = [None; 4];
+                self.mmc1_shift = None;
+                self.mmc3_select = None;
+            }
+            return;
+        };
+
+        let addr = base.wrapping_add_unsigned(usize::from(index));
+        if !matches!(addr.get(), 0x4012 | 0x4013) && addr.get() < 0x8000 {
+            return;
+        }
+
+        let value = match op {
+            Op::StaAbs(_) | Op::StaAbsX(_) | Op::StaAbsY(_) => self.regs.a(),
+            Op::StxAbs(_) => self.regs.x(),
+            Op::StyAbs(_) => self.regs.y(),
+            Op::SaxAbs(_) => self.regs.a().zip(self.regs.x()).map(|(a, x)| a & x),
+            _ => None,
+        };
+
+        match addr.get() {
+            0x4012 => self.dmc[0] = value,
+            0x4013 => self.dmc[1] = value,
+            _ => {
+                if let Some(mapper) = mapper {
+                    self.write_mapper(mapper, addr, value);
+                }
+            }
+        }
+    }
+
+    fn write_mapper(&mut self, mapper: Mapper, addr: Address, value: Option<u8>) {
+        match mapper {
+            Mapper::UxRom => {
+                self.slots[0] = value;
+                self.slots[1] = value;
+            }
+            Mapper::Mmc1 => {
+                // bit7 が立っていればシフトレジスタをリセットする。
+                // 5 回目の書き込みでアドレスに応じたレジスタに値が設定される。
+                // PRG バンクレジスタ ($E000-$FFFF) 以外は無視する。
+                let is_prg = addr.get() >= 0xE000;
+                match (value, self.mmc1_shift) {
+                    (Some(value), _) if value & 0x80 != 0 => self.mmc1_shift = Some((0, 0)),
+                    (Some(value), Some((count, shift))) => {
+                        let shift = shift | ((value & 1) << count);
+                        if count == 4 {
+                            if is_prg {
+                                self.slots[0] = Some(shift & 0x0F);
+                                self.slots[1] = Some(shift & 0x0F);
+                            }
+                            self.mmc1_shift = Some((0, 0));
+                        } else {
+                            self.mmc1_shift = Some((count + 1, shift));
+                        }
+                    }
+                    _ => {
+                        if is_prg {
+                            self.slots[0] = None;
+                            self.slots[1] = None;
+                        }
+                        self.mmc1_shift = None;
+                    }
+                }
+            }
+            Mapper::Mmc3 => {
+                // $A000 以降のレジスタはバンク切り替えに関係しない。
+                if addr.get() >= 0xA000 {
+                    return;
+                }
+                if addr.get() & 1 == 0 {
+                    self.mmc3_select = value;
+                    return;
+                }
+                let value = value.map(|value| value & 0x3F);
+                match self.mmc3_select {
+                    // R6 は PRG モードに応じて $8000 または $C000 を切り替える。
+                    Some(select) if select & 7 == 6 => {
+                        let slot = if select & 0x40 == 0 { 0 } else { 2 };
+                        self.slots[slot] = value;
+                    }
+                    Some(select) if select & 7 == 7 => self.slots[1] = value,
+                    Some(_) => {}
+                    None => {
+                        self.slots[0] = None;
+                        self.slots[1] = None;
+                        self.slots[2] = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AnalysisConfig;
+
+    use super::super::pass::BuiltinPass;
+    use super::super::test_util::{make_switchable_input, run_passes};
+    use super::*;
+
+    /// 固定バンクの `prog` (末尾で `jsr $8000` する) を解析し、
+    /// jsr 命令の時点で $8000 に割り当てられているバンク番号と、参照先のバンクを返す。
+    fn resolve_call(
+        mapper: Mapper,
+        number: usize,
+        len: usize,
+        prog: &[u8],
+    ) -> (Option<usize>, Option<usize>) {
+        let mut prog = prog.to_vec();
+        let site = Address::new(0xC000 + prog.len() as u16);
+        prog.extend([0x20, 0x00, 0x80]); // jsr $8000
+        prog.extend([0x4C, 0x00, 0xC0]); // jmp $C000
+        let input = make_switchable_input(
+            mapper,
+            number,
+            len,
+            &[(0x8000, &[0x60])],
+            &[(0xC000, &prog)],
+        );
+        let config = AnalysisConfig::default();
+
+        let cx = run_passes(
+            &input,
+            &config,
+            &[
+                &BuiltinPass::Interrupt,
+                &BuiltinPass::Op,
+                &BuiltinPass::Flow,
+            ],
+        );
+        assert_eq!(cx.analysis()[site], AnalysisKind::Code);
+        let consts = analyze(cx.analysis(), &input);
+
+        let dst = Address::new(0x8000);
+        (
+            consts.mapped_bank_number(site, dst),
+            consts.resolve_bank_id(&input, site, dst),
+        )
+    }
+
+    #[test]
+    fn test_uxrom() {
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x02,       // lda #2
+            0x8D, 0x00, 0x80, // sta $8000
+        ];
+        assert_eq!(
+            resolve_call(Mapper::UxRom, 2, 0x4000, &prog),
+            (Some(2), Some(0))
+        );
+        // 別のバンクが割り当てられていれば、逆アセンブル対象バンクを指さない。
+        assert_eq!(
+            resolve_call(Mapper::UxRom, 3, 0x4000, &prog),
+            (Some(2), None)
+        );
+        // 書き込みがなければバンク番号は不明で、参照元と別のバンクなので確定できない。
+        assert_eq!(resolve_call(Mapper::UxRom, 2, 0x4000, &[]), (None, None));
+    }
+
+    #[test]
+    fn test_mmc1() {
+        // 5 回のシリアル書き込みで PRG バンクレジスタ ($E000) に 5 (%00101) を設定する。
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x05,       // lda #5
+            0x8D, 0x00, 0xE0, // sta $E000
+            0x4A,             // lsr a
+            0x8D, 0x00, 0xE0, // sta $E000
+            0x4A,             // lsr a
+            0x8D, 0x00, 0xE0, // sta $E000
+            0x4A,             // lsr a
+            0x8D, 0x00, 0xE0, // sta $E000
+            0x4A,             // lsr a
+            0x8D, 0x00, 0xE0, // sta $E000
+        ];
+        assert_eq!(
+            resolve_call(Mapper::Mmc1, 5, 0x4000, &prog),
+            (Some(5), Some(0))
+        );
+        // 4 回だけではレジスタは設定されない。
+        assert_eq!(
+            resolve_call(Mapper::Mmc1, 5, 0x4000, &prog[..prog.len() - 4]),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn test_mmc3() {
+        // R6 ($8000 の 8KB) に 9 を設定する。
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x06,       // lda #6
+            0x8D, 0x00, 0x80, // sta $8000
+            0xA9, 0x09,       // lda #9
+            0x8D, 0x01, 0x80, // sta $8001
+        ];
+        assert_eq!(
+            resolve_call(Mapper::Mmc3, 9, 0x2000, &prog),
+            (Some(9), Some(0))
+        );
+        // R7 は $A000 の 8KB を切り替えるので、$8000 には影響しない。
+        let mut r7 = prog;
+        r7[1] = 0x07;
+        assert_eq!(resolve_call(Mapper::Mmc3, 9, 0x2000, &r7), (None, None));
+    }
+}
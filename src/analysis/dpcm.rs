@@ -0,0 +1,301 @@
+//! DPCM サンプル検出。
+//!
+//! 逆アセンブル対象バンク内で、CDL で PCM データとされている範囲、
+//! および定数伝播により判明した DMC レジスタ ($4012, $4013) への書き込みが指す範囲を DPCM サンプルとする。
+//! 重なり合うサンプルは 1 つにまとめる。
+//!
+//! サンプルは `NotCode` とし、先頭にラベルを振る。
+//! サンプルがコードや構造体の配列、分割ポインタテーブルと重なる場合は警告を出し、サンプルとしては扱わない。
+//!
+//! サンプル内の `Statement::Byte` は `Statement::Dpcm` にまとめる。
+
+use log::warn;
+
+use crate::address::{Address, AddressRange};
+use crate::assembly::{Label, Labels, Statement};
+use crate::input::Input;
+use crate::layout::SplitTable;
+
+use super::constant::{self, Constants};
+use super::{Analysis, AnalysisKind};
+
+/// DPCM サンプルの範囲たちを求める (開始アドレスの昇順)。
+pub(super) fn analyze(
+    analysis: &mut Analysis,
+    labels: &mut Labels,
+    input: &Input,
+    consts: &Constants,
+    split_tables: &[SplitTable],
+) -> Vec<AddressRange> {
+    let bank = input.target_bank();
+    let cdl = input.cdl();
+
+    // (開始アドレス, 終端アドレス (これは含まない)) の形で列挙する。
+    let mut samples: Vec<(usize, usize)> = constant::collect_dpcm_samples(consts, analysis, input)
+        .into_iter()
+        .map(|(start, len)| (usize::from(start), usize::from(start) + len))
+        .collect();
+
+    let mut addr = bank.addr_range().into_iter().peekable();
+    while let Some(start) = addr.next() {
+        if !cdl.is_pcm_data(start) {
+            continue;
+        }
+        let mut end = usize::from(start) + 1;
+        while addr.next_if(|&addr| cdl.is_pcm_data(addr)).is_some() {
+            end += 1;
+        }
+        samples.push((usize::from(start), end));
+    }
+
+    // バンク内に切り詰め、重なり合うサンプルをまとめる。
+    let bank_end = usize::from(bank.addr_range().max()) + 1;
+    samples.sort_unstable();
+    let mut merged = Vec::<(usize, usize)>::new();
+    for (start, end) in samples {
+        let end = end.min(bank_end);
+        match merged.last_mut() {
+            Some((_, end_pre)) if start < *end_pre => *end_pre = (*end_pre).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let mut res = Vec::<AddressRange>::with_capacity(merged.len());
+    for (start, end) in merged {
+        let range = AddressRange::from_start_len(
+            Address::new(start as u16),
+            (end - start).try_into().unwrap(),
+        );
+
+        let code = range
+            .into_iter()
+            .any(|addr| analysis[addr] == AnalysisKind::Code);
+        let overlapped = input
+            .struct_arrays()
+            .iter()
+            .any(|array| array.addr_range().intersects(range))
+            || split_tables.iter().any(|table| {
+                table.lo_range().intersects(range) || table.hi_range().intersects(range)
+            });
+        if code || overlapped {
+            warn!(
+                "DPCM sample {:#06X}-{:#06X} overlaps code or other data",
+                range.min(),
+                range.max()
+            );
+            continue;
+        }
+
+        analysis[range].fill(AnalysisKind::NotCode);
+        labels.set(range.min(), Label::new(false));
+        res.push(range);
+    }
+
+    res
+}
+
+/// DPCM サンプルの範囲内の `Statement::Byte` を `Statement::Dpcm` にまとめる。
+///
+/// サンプルの範囲が `Statement::Byte` だけで埋まっていなければ、そのサンプルはまとめない。
+pub(super) fn merge_statements(
+    stmts: Vec<Statement>,
+    input: &Input,
+    samples: &[AddressRange],
+) -> Vec<Statement> {
+    let mut res = Vec::<Statement>::with_capacity(stmts.len());
+    let mut samples = samples.iter().peekable();
+    // 現在のサンプル内の Byte たち。サンプルの末尾に達したらサンプル全体を 1 つの文にまとめる。
+    let mut pending = Vec::<Statement>::new();
+
+    let mut addr = input.target_bank().addr();
+    for stmt in stmts {
+        let stmt_len = stmt.len();
+
+        // 終了したサンプルを読み飛ばす。
+        while samples.next_if(|range| range.max() < addr).is_some() {}
+
+        match stmt {
+            Statement::Byte(_)
+                if samples.peek().is_some_and(|range| {
+                    range.contains_addr(addr) && (addr == range.min() || !pending.is_empty())
+                }) =>
+            {
+                pending.push(stmt);
+                if samples.peek().unwrap().max() == addr {
+                    samples.next();
+                    let buf = pending.drain(..).flat_map(|stmt| stmt.to_bytes()).collect();
+                    res.push(Statement::Dpcm(buf));
+                }
+            }
+            stmt => {
+                if samples
+                    .peek()
+                    .is_some_and(|range| range.contains_addr(addr))
+                {
+                    samples.next();
+                }
+                res.append(&mut pending);
+                res.push(stmt);
+            }
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt_len) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+    res.append(&mut pending);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AnalysisConfig, OutputConfig};
+    use crate::output::assembly_lines;
+
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+
+    use crate::bank::Bank;
+    use crate::layout::{FieldType, StructArray, StructField, StructType};
+
+    use super::super::test_util::{input_builder, make_bank_body, make_input};
+    use super::*;
+
+    /// `lda #addr; sta $4012; lda #len; sta $4013` の後に無限ループするプログラム。
+    #[rustfmt::skip]
+    fn make_prog(addr: u8, len: u8) -> [u8; 13] {
+        [
+            0xA9, addr,       // $C000: lda #addr
+            0x8D, 0x12, 0x40, // $C002: sta $4012
+            0xA9, len,        // $C005: lda #len
+            0x8D, 0x13, 0x40, // $C007: sta $4013
+            0x4C, 0x0A, 0xC0, // $C00A: jmp $C00A
+        ]
+    }
+
+    #[test]
+    fn test_dpcm_from_registers() {
+        // $C000 + 64 * 4 = $C100 から 16 * 1 + 1 = 17 バイト。
+        let prog = make_prog(0x04, 0x01);
+        let sample = [0x55; 17];
+        let input = make_input(&[(0xC000, &prog), (0xC100, &sample)]);
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        let mut addr = asm.bank_addr();
+        let mut dpcms = vec![];
+        for stmt in asm.statements() {
+            if let Statement::Dpcm(ref buf) = *stmt {
+                dpcms.push((addr, buf.clone()));
+            }
+            addr = addr.wrapping_add_unsigned(stmt.len());
+        }
+        assert_eq!(dpcms, [(Address::new(0xC100), sample.to_vec())]);
+        assert!(asm.labels().get(Address::new(0xC100)).is_some());
+
+        // 64 バイト境界上にあることを検査する。
+        let lines = assembly_lines(&asm, &OutputConfig::default()).unwrap();
+        let assert_line = lines
+            .iter()
+            .position(|(_, line)| line.trim_start().starts_with(".assert"))
+            .unwrap();
+        assert_eq!(lines[assert_line].0, Address::new(0xC100));
+        assert!(lines[assert_line + 1..]
+            .iter()
+            .take_while(|(addr, _)| *addr == Address::new(0xC100))
+            .any(|(_, line)| line.contains(".byte")));
+    }
+
+    #[test]
+    fn test_dpcm_overlapping_code() {
+        // サンプル ($C000 から 17 バイト) がコードと重なるので、サンプルとしては扱わない (警告を出す)。
+        let prog = make_prog(0x00, 0x01);
+        let input = make_input(&[(0xC000, &prog)]);
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        assert!(!asm
+            .statements()
+            .iter()
+            .any(|stmt| matches!(stmt, Statement::Dpcm(_))));
+        assert!(asm
+            .statements()
+            .iter()
+            .take(5)
+            .all(|stmt| matches!(stmt, Statement::Op(_))));
+    }
+
+    #[test]
+    fn test_dpcm_overlapping_struct_array() {
+        // サンプル ($C100 から 17 バイト) が構造体の配列 ($C100 から 4 バイト × 16) と重なるので、
+        // サンプルとしては扱わない (配列の要素の途中でサンプルが終わり、文の配列が壊れるのを防ぐ)。
+        let prog = make_prog(0x04, 0x01);
+        let body = make_bank_body(&[(0xC000, &prog), (0xC100, &[0x55; 64])]);
+        let ty = StructType::new(
+            "Entry",
+            [
+                StructField::new("x", FieldType::Byte),
+                StructField::new("y", FieldType::Byte),
+                StructField::new("w", FieldType::Word),
+            ],
+        )
+        .unwrap();
+        let array = StructArray::new(
+            Address::new(0xC100),
+            Arc::new(ty),
+            NonZeroUsize::new(16).unwrap(),
+        )
+        .unwrap();
+        let input = input_builder(vec![Bank::new(Address::new(0xC000), body, true)])
+            .struct_array(array)
+            .build()
+            .unwrap();
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        assert!(!asm
+            .statements()
+            .iter()
+            .any(|stmt| matches!(stmt, Statement::Dpcm(_))));
+        assert_eq!(
+            asm.statements()
+                .iter()
+                .filter(|stmt| matches!(stmt, Statement::Record(_)))
+                .count(),
+            16
+        );
+    }
+
+    #[test]
+    fn test_merge_statements() {
+        let input = make_input(&[(0xC000, &[0x60])]);
+        let bytes = |bs: &[u8]| bs.iter().copied().map(Statement::Byte).collect::<Vec<_>>();
+        let range =
+            |min: u16, max: u16| AddressRange::from_min_max(Address::new(min), Address::new(max));
+
+        // Byte だけで埋まったサンプルはまとめる。
+        let stmts = bytes(&[0, 1, 2, 3]);
+        let mut expected = bytes(&[0]);
+        expected.push(Statement::Dpcm(vec![1, 2]));
+        expected.extend(bytes(&[3]));
+        assert_eq!(
+            merge_statements(stmts, &input, &[range(0xC001, 0xC002)]),
+            expected
+        );
+
+        // 途中に Byte 以外の文があるサンプルはまとめず、集めた Byte もそのまま出力する。
+        let mut stmts = bytes(&[0, 1]);
+        stmts.push(Statement::Blob(vec![2, 3]));
+        stmts.extend(bytes(&[4, 5]));
+        assert_eq!(
+            merge_statements(stmts.clone(), &input, &[range(0xC001, 0xC004)]),
+            stmts
+        );
+
+        // 文の配列の末尾で終わらないサンプルの Byte も失わない。
+        let stmts = bytes(&[0, 1, 2]);
+        assert_eq!(
+            merge_statements(stmts.clone(), &input, &[range(0xC001, 0xC005)]),
+            stmts
+        );
+    }
+}
@@ -2,6 +2,13 @@
 //!
 //! `NotCode` にしか到達しない制御フローを `NotCode` とする。
 //! また、`Code` から一意に辿れる制御フローを `Code` とする。
+//!
+//! jsr 命令については、サブルーチン解析により飛び先のルーチンが正常に戻ると判定された場合
+//! (`ReturnKind::Returns`) のみ直後のアドレスへの制御フローを考える。
+//! 戻る経路と判定できない経路の双方を持つルーチン (`ReturnKind::MayReturn`) は、
+//! 直後のアドレスが実行されるとは限らないので対象外とする。
+//!
+//! 分岐命令については、定数伝播により分岐結果が判明している場合、実際に辿る側の制御フローのみを考える。
 
 use arrayvec::ArrayVec;
 
@@ -9,15 +16,22 @@ use crate::address::Address;
 use crate::input::Input;
 use crate::memory::OpSuccResolved;
 
+use super::constant::Constants;
+use super::subroutine::Subroutines;
 use super::{Analysis, AnalysisKind};
 
-pub(super) fn analyze(analysis: &mut Analysis, input: &Input) {
-    analyze_notcode(analysis, input);
-    analyze_code(analysis, input);
+pub(super) fn analyze(
+    analysis: &mut Analysis,
+    input: &Input,
+    subs: &Subroutines,
+    consts: &Constants,
+) {
+    analyze_notcode(analysis, input, subs, consts);
+    analyze_code(analysis, input, subs, consts);
 }
 
 /// `NotCode` にしか到達しない制御フローを `NotCode` とする。
-fn analyze_notcode(analysis: &mut Analysis, input: &Input) {
+fn analyze_notcode(analysis: &mut Analysis, input: &Input, subs: &Subroutines, consts: &Constants) {
     // 以下のような制御フローグラフ G(V, E) を考える:
     //
     // * V は全ての論理アドレス、および特別な頂点 C からなる。
@@ -36,13 +50,18 @@ fn analyze_notcode(analysis: &mut Analysis, input: &Input) {
     //
     // 「後続アドレスが具体的に確定できる」ためには、以下のいずれかの条件を満たさねばならない:
     //
-    // * 命令とその後続アドレスがともに同一バンク上にある。
-    // * 後続アドレスが非固定バンク上にない。
+    // * 後続アドレスが固定バンク上にあるか、どのバンク上にもない。
+    // * 後続アドレスが非固定バンク上にあり、バンク切り替え解析によりそれが実際に参照されるバンクだと判明している。
+    // * 後続アドレスが非固定バンク上にあり、バンク切り替えが判明しておらず、命令と同一バンク上にある。
     //
     // このルールはバンク切り替えを考慮したもの。
     // (後続アドレスが別の非固定バンク上にある場合、実際に参照されるバンクを確定できない)
+    //
+    // 飛び先のルーチンが正常に戻る jsr 命令は、飛び先と直後のアドレスの双方を実行する。
+    // よって、直後のアドレスが NotCode となった時点で jsr 命令自体も NotCode としてよい。
+    // この関係は G とは別のグラフ(の逆グラフ) R で表す。
 
-    let (graph_t, mut in_degs) = make_transpose_graph(analysis, input);
+    let (graph_t, mut in_degs, graph_r) = make_transpose_graph(analysis, input, subs, consts);
 
     // 入次数 0 の頂点を全てスタックに入れる。
     // 仮想頂点 C を直接扱うことはないので、型は Address にしてしまう。
@@ -52,17 +71,32 @@ fn analyze_notcode(analysis: &mut Analysis, input: &Input) {
 
     // スタックが空になるまで前述の操作を繰り返す。
     // この操作により仮想頂点 C が入次数 0 になることはないから、常に Address 型を使ってよい。
+    //
+    // R により NotCode とされた頂点も同様にスタックに入れる。
+    // 同じ頂点を 2 回スタックに入れないよう、Unknown である場合のみ NotCode とする。
+    macro_rules! set_notcode {
+        ($dst:expr) => {{
+            let dst = Address::new(u16::try_from($dst).unwrap());
+            if analysis[dst] == AnalysisKind::Unknown {
+                analysis[dst] = AnalysisKind::NotCode;
+                stack.push(dst);
+            }
+        }};
+    }
+
     while let Some(addr) = stack.pop() {
         assert_eq!(analysis[addr], AnalysisKind::NotCode);
 
         for &dst in &graph_t[usize::from(addr)] {
             in_degs[dst] -= 1;
             if in_degs[dst] == 0 {
-                let dst = Address::new(u16::try_from(dst).unwrap());
-                analysis[dst] = AnalysisKind::NotCode;
-                stack.push(dst);
+                set_notcode!(dst);
             }
         }
+
+        for &dst in &graph_r[usize::from(addr)] {
+            set_notcode!(dst);
+        }
     }
 }
 
@@ -72,12 +106,19 @@ type Graph = Box<[Vec<usize>; 0x10001]>;
 /// グラフの各頂点の入次数を表す配列。
 type InDegs = Box<[usize; 0x10001]>;
 
-/// 制御フローグラフの逆グラフ T, およびその入次数配列を作る。
-fn make_transpose_graph(analysis: &Analysis, input: &Input) -> (Graph, InDegs) {
+/// 制御フローグラフの逆グラフ T, およびその入次数配列、
+/// そして jsr 命令の直後のアドレスから jsr 命令への辺からなるグラフ R を作る。
+fn make_transpose_graph(
+    analysis: &Analysis,
+    input: &Input,
+    subs: &Subroutines,
+    consts: &Constants,
+) -> (Graph, InDegs, Graph) {
     const VERTEX_C: usize = 0x10000;
 
     let mut graph_t: Graph = vec![vec![]; 0x10001].try_into().unwrap();
     let mut in_degs: InDegs = vec![0; 0x10001].try_into().unwrap();
+    let mut graph_r: Graph = vec![vec![]; 0x10001].try_into().unwrap();
 
     // 辺 src -> dst を逆向きにした辺を追加する。
     macro_rules! add_edge {
@@ -90,13 +131,16 @@ fn make_transpose_graph(analysis: &Analysis, input: &Input) -> (Graph, InDegs) {
     for src in Address::all() {
         match analysis[src] {
             AnalysisKind::Unknown => {
-                if let Some(succ_addrs) = get_succ_addrs(input, src) {
-                    for succ_addr in succ_addrs {
+                if let Some(succ_addrs) = get_succ_addrs(input, subs, consts, src) {
+                    for succ_addr in succ_addrs.alts {
                         match succ_addr {
                             SuccAddr::Somewhere => add_edge!(usize::from(src), VERTEX_C),
                             SuccAddr::Addr(dst) => add_edge!(usize::from(src), usize::from(dst)),
                         }
                     }
+                    if let Some(SuccAddr::Addr(ret)) = succ_addrs.ret {
+                        graph_r[usize::from(ret)].push(usize::from(src));
+                    }
                 } else {
                     add_edge!(usize::from(src), usize::from(src));
                 }
@@ -109,11 +153,11 @@ fn make_transpose_graph(analysis: &Analysis, input: &Input) -> (Graph, InDegs) {
     // 頂点 C に自己ループを持たせる。
     add_edge!(VERTEX_C, VERTEX_C);
 
-    (graph_t, in_degs)
+    (graph_t, in_degs, graph_r)
 }
 
 /// `Code` から一意に辿れる制御フローを `Code` とする。
-fn analyze_code(analysis: &mut Analysis, input: &Input) {
+fn analyze_code(analysis: &mut Analysis, input: &Input, subs: &Subroutines, consts: &Constants) {
     let mut visited: Box<[bool; 0x10000]> = vec![false; 0x10000].try_into().unwrap();
 
     macro_rules! visit {
@@ -128,34 +172,63 @@ fn analyze_code(analysis: &mut Analysis, input: &Input) {
     }
 
     for addr in Address::all() {
-        if !visit!(addr) {
+        if analysis[addr] != AnalysisKind::Code {
             continue;
         }
-        if analysis[addr] != AnalysisKind::Code {
+        if !visit!(addr) {
             continue;
         }
 
-        let mut src = addr;
-        while let Some(dst) = get_unique_succ_addr(analysis, input, src) {
-            if !visit!(dst) {
-                break;
+        // jsr 命令の場合は飛び先と戻り先の 2 つを辿りうるので、スタックを用いて探索する。
+        let mut stack = vec![addr];
+        while let Some(src) = stack.pop() {
+            for dst in get_certain_succ_addrs(analysis, input, subs, consts, src) {
+                if !visit!(dst) {
+                    continue;
+                }
+                analysis[dst] = AnalysisKind::Code;
+                stack.push(dst);
             }
-            analysis[dst] = AnalysisKind::Code;
-            src = dst;
         }
     }
 }
 
-/// `Code` であるアドレス `addr` から一意に辿れる具体的に確定可能な後続アドレスがあればそれを返す。
-fn get_unique_succ_addr(analysis: &Analysis, input: &Input, addr: Address) -> Option<Address> {
-    // get_succ_addrs() の結果が具体的に確定可能なアドレスのみであり、
-    // かつ NotCode なアドレスを除いた結果がちょうど 1 つだけならそれを返す。
-
+/// `Code` であるアドレス `addr` から確実に辿れる具体的に確定可能な後続アドレスたちを返す。
+fn get_certain_succ_addrs(
+    analysis: &Analysis,
+    input: &Input,
+    subs: &Subroutines,
+    consts: &Constants,
+    addr: Address,
+) -> ArrayVec<Address, 2> {
     assert_eq!(analysis[addr], AnalysisKind::Code);
 
-    let addrs: ArrayVec<Address, 2> = get_succ_addrs(input, addr)?
-        .into_iter()
-        .map(|succ_addr| match succ_addr {
+    let mut res = ArrayVec::<Address, 2>::new();
+
+    let Some(succ_addrs) = get_succ_addrs(input, subs, consts, addr) else {
+        return res;
+    };
+
+    if let Some(dst) = get_unique_succ_addr(analysis, &succ_addrs.alts) {
+        res.push(dst);
+    }
+
+    // 飛び先のルーチンが正常に戻る jsr 命令の場合、戻り先も確実に辿れる。
+    if let Some(SuccAddr::Addr(ret)) = succ_addrs.ret {
+        if analysis[ret] != AnalysisKind::NotCode && !res.contains(&ret) {
+            res.push(ret);
+        }
+    }
+
+    res
+}
+
+/// 後続アドレス候補たちが全て具体的に確定可能であり、
+/// かつ `NotCode` なアドレスを除いた結果がちょうど 1 つだけならそれを返す。
+fn get_unique_succ_addr(analysis: &Analysis, alts: &[SuccAddr]) -> Option<Address> {
+    let addrs: ArrayVec<Address, 2> = alts
+        .iter()
+        .map(|succ_addr| match *succ_addr {
             SuccAddr::Somewhere => None,
             SuccAddr::Addr(dst) => Some(dst),
         })
@@ -178,20 +251,31 @@ enum SuccAddr {
     Addr(Address),
 }
 
-type SuccAddrs = ArrayVec<SuccAddr, 2>;
+/// あるアドレス上の命令から生じる全ての後続アドレス。
+#[derive(Debug)]
+struct SuccAddrs {
+    /// いずれか 1 つに制御が移る後続アドレスたち(最大 2 つ、重複なし)。
+    alts: ArrayVec<SuccAddr, 2>,
+    /// jsr 命令で、かつ飛び先のルーチンが正常に戻る場合、その戻り先。
+    ret: Option<SuccAddr>,
+}
 
-/// 指定したアドレス上の命令を取得し、そこから生じる全ての後続アドレス(最大 2 つ、重複なし)を返す。
+/// 指定したアドレス上の命令を取得し、そこから生じる全ての後続アドレスを返す。
 /// 命令が取得できない場合、`None` を返す。
-fn get_succ_addrs(input: &Input, addr: Address) -> Option<SuccAddrs> {
+fn get_succ_addrs(
+    input: &Input,
+    subs: &Subroutines,
+    consts: &Constants,
+    addr: Address,
+) -> Option<SuccAddrs> {
     let memory = input.memory();
-    let (op, bank_id) = memory.fetch_op(addr).ok()?;
+    let (op, _) = memory.fetch_op(addr).ok()?;
 
     let determine = |dst: Address| {
-        // 命令とその後続アドレスがともに同一バンク上にあるか、
-        // 後続アドレスが非固定バンク上になければ確定可能。
-        let ok = memory.find_bank_id(dst).map_or(true, |dst_bank_id| {
-            bank_id == dst_bank_id || !memory.banks()[dst_bank_id].is_fixed()
-        });
+        // 後続アドレスにバンクがロードされていないか、
+        // ロードされているバンクのうちどれを指すか確定できれば確定可能。
+        let ok = memory.find_bank_id(dst).is_none()
+            || consts.resolve_bank_id(input, addr, dst).is_some();
         if ok {
             SuccAddr::Addr(dst)
         } else {
@@ -199,7 +283,8 @@ fn get_succ_addrs(input: &Input, addr: Address) -> Option<SuccAddrs> {
         }
     };
 
-    let mut res = SuccAddrs::new();
+    let mut res = ArrayVec::<SuccAddr, 2>::new();
+    let mut ret = None;
 
     // ここでは行き先が別バンクになるケースも許す。
     // (そのようなケースのうち Unknown なものについては命令単位の解析で排除済み)
@@ -211,17 +296,117 @@ fn get_succ_addrs(input: &Input, addr: Address) -> Option<SuccAddrs> {
             // 分岐時/非分岐時の行き先が同じケースがあることに注意。
             let taken = determine(taken);
             let not_taken = determine(not_taken);
-            res.push(taken);
-            if taken != not_taken {
-                res.push(not_taken);
+            match consts.regs(addr).branch_taken(op) {
+                Some(true) => res.push(taken),
+                Some(false) => res.push(not_taken),
+                None => {
+                    res.push(taken);
+                    if taken != not_taken {
+                        res.push(not_taken);
+                    }
+                }
+            }
+        }
+        OpSuccResolved::Jsr(dst) => {
+            res.push(determine(dst));
+            if subs.returns(dst) {
+                ret = Some(determine(addr.wrapping_add_unsigned(op.len())));
             }
         }
-        OpSuccResolved::Jsr(dst) => res.push(determine(dst)),
         OpSuccResolved::Rti => res.push(SuccAddr::Somewhere),
         OpSuccResolved::Rts => res.push(SuccAddr::Somewhere),
         OpSuccResolved::JmpAbs(dst) => res.push(determine(dst)),
         OpSuccResolved::JmpInd(dst) => res.push(dst.map_or(SuccAddr::Somewhere, determine)),
     }
 
-    Some(res)
+    Some(SuccAddrs { alts: res, ret })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AnalysisConfig;
+
+    use super::super::pass::BuiltinPass;
+    use super::super::test_util::{make_cross_bank_input, make_input, run_passes};
+    use super::*;
+
+    /// $C000 から `routine` を jsr で呼ぶプログラムについて制御フロー解析までを行い、
+    /// jsr 命令の直後 ($C003) の解析結果を返す。
+    fn analyze_fall_through(routine: &[u8]) -> AnalysisKind {
+        #[rustfmt::skip]
+        let prog = [
+            0x20, 0x00, 0xC1, // $C000: jsr $C100
+            0xA9, 0x00,       // $C003: lda #0
+            0x4C, 0x05, 0xC0, // $C005: jmp $C005
+        ];
+        let input = make_input(&[(0xC000, &prog), (0xC100, routine)]);
+        let config = AnalysisConfig::default();
+
+        let cx = run_passes(
+            &input,
+            &config,
+            &[
+                &BuiltinPass::Interrupt,
+                &BuiltinPass::Op,
+                &BuiltinPass::Subroutine,
+                &BuiltinPass::Flow,
+            ],
+        );
+        assert_eq!(cx.analysis()[Address::new(0xC100)], AnalysisKind::Code);
+
+        cx.analysis()[Address::new(0xC003)]
+    }
+
+    #[test]
+    fn test_jsr_fall_through() {
+        // 正常に戻るなら直後は Code。
+        assert_eq!(analyze_fall_through(&[0x60]), AnalysisKind::Code);
+
+        // 決して戻らない (jmp $C100) なら Code としない。
+        assert_ne!(
+            analyze_fall_through(&[0x4C, 0x00, 0xC1]),
+            AnalysisKind::Code
+        );
+
+        // リターンアドレスを捨てる (pla; pla; rts) なら Code としない。
+        assert_ne!(
+            analyze_fall_through(&[0x68, 0x68, 0x60]),
+            AnalysisKind::Code
+        );
+
+        // 間接ジャンプする経路があり、戻るとは限らないなら Code としない。
+        #[rustfmt::skip]
+        let may_return = [
+            0xA5, 0x00,       // $C100: lda $00
+            0xF0, 0x03,       // $C102: beq $C107
+            0x6C, 0x02, 0x00, // $C104: jmp ($0002)
+            0x60,             // $C107: rts
+        ];
+        assert_ne!(analyze_fall_through(&may_return), AnalysisKind::Code);
+    }
+
+    #[test]
+    fn test_cross_bank_flow() {
+        let input = make_cross_bank_input();
+        let config = AnalysisConfig::default();
+
+        let cx = run_passes(
+            &input,
+            &config,
+            &[
+                &BuiltinPass::Interrupt,
+                &BuiltinPass::Op,
+                &BuiltinPass::Flow,
+            ],
+        );
+        let at = |addr: u16| cx.analysis()[Address::new(addr)];
+
+        // バンク切り替えにより逆アセンブル対象バンクを指すと判明した呼び出しは辿る。
+        assert_eq!(at(0xC008), AnalysisKind::Code);
+        assert_eq!(at(0x8000), AnalysisKind::Code);
+        // 非固定バンクから固定バンクへの移動は常に辿る。
+        assert_eq!(at(0xC020), AnalysisKind::Code);
+        // バンク切り替えが不明な固定バンクからの呼び出しは、どのバンクを指すかわからないので辿らない。
+        assert_ne!(at(0x8010), AnalysisKind::Code);
+    }
 }
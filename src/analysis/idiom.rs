@@ -0,0 +1,384 @@
+//! NES の定番処理 (イディオム) の認識。
+//!
+//! ハードウェアレジスタへのアクセスから以下の処理を認識する:
+//!
+//! * VBlank 待ち: `$2002` の読み取りと、その読み取りへ戻る `bpl` によるループ。
+//! * OAM DMA: `$4014` への書き込み。定数伝播で書き込む値が判明すれば、そのページを `oam_buffer` とする。
+//! * コントローラ読み取り: `$4016` への書き込み (ストローブ) と、ループ内での (またはボタン数以上繰り返される)
+//!   `$4016`/`$4017` の読み取り。読み取りの直後にローテート/シフトされる RAM を `joy1_state`/`joy2_state` とする。
+//! * VRAM 転送: `$2006` への書き込みと、RAM からインデックス付きで読み取った値の `$2007` への書き込み。
+//!   読み取り元を `vram_buffer` とする。
+//!
+//! 命令の直前/直後の命令としては、アドレスが連続し直接流れ込むもの (fall-through) のみを見る。
+//! 分岐先として合流する経路は考慮しない。
+//!
+//! ルーチンに含まれるイディオムが 1 種類だけならば、そのルーチンにも名前を付ける
+//! (複数の処理を行うルーチンや割り込みハンドラの名前は付けない)。
+//! 既に名前の付いたアドレス、および既に使われている名前は上書きしない。
+
+use std::collections::HashSet;
+
+use crate::address::Address;
+use crate::assembly::{Label, Labels, Routine};
+use crate::input::Input;
+use crate::op::{Op, Operand};
+
+use super::constant::Constants;
+
+/// イディオムの種類。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Idiom {
+    WaitVBlank,
+    OamDma,
+    ReadJoypad,
+    UploadVram,
+}
+
+impl Idiom {
+    /// イディオムを含むルーチンに付ける名前。
+    fn routine_name(self) -> &'static str {
+        match self {
+            Self::WaitVBlank => "WaitVBlank",
+            Self::OamDma => "OamDma",
+            Self::ReadJoypad => "ReadJoypad",
+            Self::UploadVram => "UploadVram",
+        }
+    }
+}
+
+/// 読み取りの直後の RAM を操作する命令を探す範囲 (命令数)。
+const FOLLOWING_OPS: usize = 4;
+
+/// コントローラのボタン数。ループなしで読み取る場合、この回数以上の読み取りを要求する。
+const JOYPAD_BUTTONS: usize = 8;
+
+pub(super) fn analyze(
+    labels: &mut Labels,
+    input: &Input,
+    consts: &Constants,
+    routines: &[Routine],
+) {
+    let memory = input.memory();
+
+    let mut used_names: HashSet<String> = Address::all()
+        .filter_map(|addr| labels.get(addr).and_then(Label::name))
+        .map(str::to_owned)
+        .collect();
+
+    let handlers: Vec<Address> = [0xFFFA, 0xFFFC, 0xFFFE]
+        .into_iter()
+        .filter_map(|ptr| memory.fetch_addr(Address::new(ptr)))
+        .map(|(dst, _)| dst)
+        .collect();
+
+    for routine in routines {
+        let ops: Vec<(Address, Op)> = routine
+            .op_addrs()
+            .iter()
+            .filter_map(|&addr| memory.fetch_op(addr).ok().map(|(op, _)| (addr, op)))
+            .collect();
+
+        let mut idioms = Vec::<Idiom>::new();
+        let mut buffers = Vec::<(Address, &str)>::new();
+        let mut add_idiom = |idiom: Idiom| {
+            if !idioms.contains(&idiom) {
+                idioms.push(idiom);
+            }
+        };
+
+        let strobes_joypad = ops
+            .iter()
+            .any(|&(_, op)| op.is_write() && base_addr(op) == Some(Address::new(0x4016)));
+        let sets_vram_addr = ops
+            .iter()
+            .any(|&(_, op)| op.is_write() && base_addr(op) == Some(Address::new(0x2006)));
+        let read_count = |port: u16| {
+            ops.iter()
+                .filter(|&&(_, op)| op.is_read() && base_addr(op) == Some(Address::new(port)))
+                .count()
+        };
+
+        for (i, &(addr, op)) in ops.iter().enumerate() {
+            let prev = i
+                .checked_sub(1)
+                .map(|j| ops[j])
+                .filter(|&prev| falls_into(prev, addr))
+                .map(|(_, prev)| prev);
+            let following = following_ops(&ops, i);
+
+            let Some(base) = base_addr(op) else {
+                continue;
+            };
+
+            match (base.get(), op.is_read(), op.is_write()) {
+                (0x2002, true, _) => {
+                    let loops = following.first().is_some_and(|&(branch_addr, branch)| {
+                        matches!(branch, Op::Bpl(_)) && branch_dst(branch_addr, branch) == addr
+                    });
+                    if loops {
+                        add_idiom(Idiom::WaitVBlank);
+                    }
+                }
+                (0x4014, _, true) => {
+                    add_idiom(Idiom::OamDma);
+                    let page = stored_value(consts, addr, op, prev);
+                    if let Some(buf) = page.map(|page| Address::new(u16::from(page) << 8)) {
+                        if is_ram(input, buf) {
+                            buffers.push((buf, "oam_buffer"));
+                        }
+                    }
+                }
+                (port @ (0x4016 | 0x4017), true, _)
+                    if strobes_joypad
+                        && (in_loop(&ops, addr) || read_count(port) >= JOYPAD_BUTTONS) =>
+                {
+                    add_idiom(Idiom::ReadJoypad);
+                    let state = following
+                        .iter()
+                        .filter(|&&(_, op)| op.is_read() && op.is_write())
+                        .find_map(|&(_, op)| base_addr(op).filter(|&dst| is_ram(input, dst)));
+                    if let Some(state) = state {
+                        let name = if port == 0x4016 {
+                            "joy1_state"
+                        } else {
+                            "joy2_state"
+                        };
+                        buffers.push((state, name));
+                    }
+                }
+                (0x2007, _, true) if sets_vram_addr => {
+                    // 直前の命令でインデックス付きで RAM から読み取った値を書き込むなら転送とみなす。
+                    let src = prev
+                        .filter(|&src| src.is_read() && is_indexed(src))
+                        .and_then(base_addr)
+                        .filter(|&src| is_ram(input, src));
+                    if let Some(src) = src {
+                        add_idiom(Idiom::UploadVram);
+                        buffers.push((src, "vram_buffer"));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (addr, name) in buffers {
+            set_name(labels, &mut used_names, addr, name, false);
+        }
+
+        if let [idiom] = idioms[..] {
+            if !handlers.contains(&routine.entry()) {
+                set_name(
+                    labels,
+                    &mut used_names,
+                    routine.entry(),
+                    idiom.routine_name(),
+                    true,
+                );
+            }
+        }
+    }
+}
+
+/// 指定したアドレスに名前が付いておらず、かつ名前が未使用ならばラベルを振って名前を付ける。
+fn set_name(
+    labels: &mut Labels,
+    used_names: &mut HashSet<String>,
+    addr: Address,
+    name: &str,
+    entrypoint: bool,
+) {
+    if used_names.contains(name) || labels.get(addr).is_some_and(|label| label.name().is_some()) {
+        return;
+    }
+
+    labels.set(addr, Label::new(entrypoint));
+    labels.set_name(addr, name);
+    used_names.insert(name.to_owned());
+}
+
+/// `prev` の命令から `addr` の命令へ直接流れ込む (アドレスが連続し、`prev` がコード終端でない) かどうかを返す。
+fn falls_into((prev_addr, prev): (Address, Op), addr: Address) -> bool {
+    !matches!(prev, Op::Rti | Op::Rts | Op::JmpAbs(_) | Op::JmpInd(_))
+        && prev_addr.checked_add_unsigned(prev.len()) == Some(addr)
+}
+
+/// `ops[i]` から直接流れ込む後続の命令たち (最大 `FOLLOWING_OPS` 個) を返す。
+fn following_ops(ops: &[(Address, Op)], i: usize) -> &[(Address, Op)] {
+    let len = ops[i..]
+        .windows(2)
+        .take(FOLLOWING_OPS)
+        .take_while(|w| falls_into(w[0], w[1].0))
+        .count();
+    &ops[i + 1..][..len]
+}
+
+/// `addr` の命令が、ルーチン内の後方分岐によるループに含まれるかどうかを返す。
+fn in_loop(ops: &[(Address, Op)], addr: Address) -> bool {
+    ops.iter().any(|&(branch_addr, branch)| {
+        matches!(branch.operand(), Operand::Rel(_))
+            && branch_addr >= addr
+            && branch_dst(branch_addr, branch) <= addr
+    })
+}
+
+/// 命令のオペランドが指すアドレス (インデックス付きのものはベースアドレス) を返す。
+/// 間接アドレッシングの場合はポインタのアドレスではなく `None` を返す。
+fn base_addr(op: Op) -> Option<Address> {
+    match op.operand() {
+        Operand::Zp(zp) | Operand::ZpX(zp) | Operand::ZpY(zp) => Some(Address::from(zp)),
+        Operand::Abs(abs) | Operand::AbsX(abs) | Operand::AbsY(abs) => Some(abs),
+        _ => None,
+    }
+}
+
+fn is_indexed(op: Op) -> bool {
+    matches!(
+        op.operand(),
+        Operand::ZpX(_) | Operand::ZpY(_) | Operand::AbsX(_) | Operand::AbsY(_)
+    )
+}
+
+fn branch_dst(addr: Address, op: Op) -> Address {
+    let Operand::Rel(rel) = op.operand() else {
+        unreachable!("branch_dst() requires a branch op");
+    };
+    addr.wrapping_add_unsigned(2_usize).wrapping_add_signed(rel)
+}
+
+/// ストア命令が書き込む値を求める。
+///
+/// 直前の命令が同じレジスタへの即値ロードならその値を、さもなくば定数伝播の結果を返す。
+fn stored_value(consts: &Constants, site: Address, op: Op, prev: Option<Op>) -> Option<u8> {
+    let regs = consts.regs(site);
+
+    match (op, prev) {
+        (Op::StaAbs(_), Some(Op::LdaImm(value)))
+        | (Op::StxAbs(_), Some(Op::LdxImm(value)))
+        | (Op::StyAbs(_), Some(Op::LdyImm(value))) => Some(value),
+        (Op::StaAbs(_), _) => regs.a(),
+        (Op::StxAbs(_), _) => regs.x(),
+        (Op::StyAbs(_), _) => regs.y(),
+        _ => None,
+    }
+}
+
+/// 内部 RAM ($0000-$07FF とそのミラー) または PRG-RAM ($6000-$7FFF) のアドレスかどうかを返す。
+/// (ROM がロードされているアドレスは除く)
+fn is_ram(input: &Input, addr: Address) -> bool {
+    matches!(addr.get(), 0x0000..=0x1FFF | 0x6000..=0x7FFF)
+        && input.memory().find_bank_id(addr).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembly::Assembly;
+    use crate::config::AnalysisConfig;
+
+    use super::super::test_util::make_input;
+    use super::*;
+
+    fn analyze_chunks(chunks: &[(u16, &[u8])]) -> Assembly {
+        let input = make_input(chunks);
+        let config: AnalysisConfig = toml::from_str("builtin_signatures = false").unwrap();
+        super::super::analyze(&input, &config)
+    }
+
+    fn name_at(asm: &Assembly, addr: u16) -> Option<&str> {
+        asm.labels().get(Address::new(addr)).and_then(Label::name)
+    }
+
+    /// コントローラのストローブ。
+    #[rustfmt::skip]
+    const STROBE: [u8; 10] = [
+        0xA9, 0x01,       // lda #1
+        0x8D, 0x16, 0x40, // sta $4016
+        0xA9, 0x00,       // lda #0
+        0x8D, 0x16, 0x40, // sta $4016
+    ];
+
+    #[test]
+    fn test_idioms() {
+        #[rustfmt::skip]
+        let main = [
+            0x20, 0x20, 0xC0, // $C000: jsr $C020
+            0x20, 0x30, 0xC0, // $C003: jsr $C030
+            0x20, 0x50, 0xC0, // $C006: jsr $C050
+            0x4C, 0x09, 0xC0, // $C009: jmp $C009
+        ];
+        #[rustfmt::skip]
+        let wait_vblank = [
+            0xAD, 0x02, 0x20, // $C020: lda $2002
+            0x10, 0xFB,       // $C023: bpl $C020
+            0x60,             // $C025: rts
+        ];
+        #[rustfmt::skip]
+        let read_joypad = [
+            0xA2, 0x01,       // $C030: ldx #1
+            0x8E, 0x16, 0x40, // $C032: stx $4016
+            0xCA,             // $C035: dex
+            0x8E, 0x16, 0x40, // $C036: stx $4016
+            0xA2, 0x08,       // $C039: ldx #8
+            0xAD, 0x16, 0x40, // $C03B: lda $4016
+            0x29, 0x03,       // $C03E: and #3
+            0xC9, 0x01,       // $C040: cmp #1
+            0x26, 0x20,       // $C042: rol $20
+            0xCA,             // $C044: dex
+            0xD0, 0xF4,       // $C045: bne $C03B
+            0x60,             // $C047: rts
+        ];
+        #[rustfmt::skip]
+        let oam_dma = [
+            0xA9, 0x00,       // $C050: lda #0
+            0x8D, 0x03, 0x20, // $C052: sta $2003
+            0xA9, 0x02,       // $C055: lda #2
+            0x8D, 0x14, 0x40, // $C057: sta $4014
+            0x60,             // $C05A: rts
+        ];
+        let asm = analyze_chunks(&[
+            (0xC000, &main),
+            (0xC020, &wait_vblank),
+            (0xC030, &read_joypad),
+            (0xC050, &oam_dma),
+        ]);
+
+        assert_eq!(name_at(&asm, 0xC020), Some("WaitVBlank"));
+        assert_eq!(name_at(&asm, 0xC030), Some("ReadJoypad"));
+        assert_eq!(name_at(&asm, 0x0020), Some("joy1_state"));
+        assert_eq!(name_at(&asm, 0xC050), Some("OamDma"));
+        assert_eq!(name_at(&asm, 0x0200), Some("oam_buffer"));
+        // 割り込みハンドラ (ここでは RESET) には名前を付けない。
+        assert_eq!(name_at(&asm, 0xC000), None);
+    }
+
+    #[test]
+    fn test_joypad_requires_repeated_reads() {
+        // ストローブ後に $4016 を 1 回読むだけではコントローラ読み取りとみなさない。
+        let mut single = STROBE.to_vec();
+        #[rustfmt::skip]
+        single.extend([
+            0xAD, 0x16, 0x40, // lda $4016
+            0x4A,             // lsr
+            0x26, 0x20,       // rol $20
+            0x60,             // rts
+        ]);
+        let main = [0x20, 0x10, 0xC0, 0x4C, 0x03, 0xC0]; // $C000: jsr $C010; jmp $C003
+        let asm = analyze_chunks(&[(0xC000, &main), (0xC010, &single)]);
+        assert_eq!(name_at(&asm, 0xC010), None);
+        assert_eq!(name_at(&asm, 0x0020), None);
+
+        // ループなしでもボタン数分読むならコントローラ読み取りとみなす。
+        let mut unrolled = STROBE.to_vec();
+        for _ in 0..JOYPAD_BUTTONS {
+            #[rustfmt::skip]
+            unrolled.extend([
+                0xAD, 0x16, 0x40, // lda $4016
+                0x4A,             // lsr
+                0x26, 0x20,       // rol $20
+            ]);
+        }
+        unrolled.push(0x60); // rts
+        let asm = analyze_chunks(&[(0xC000, &main), (0xC010, &unrolled)]);
+        assert_eq!(name_at(&asm, 0xC010), Some("ReadJoypad"));
+        assert_eq!(name_at(&asm, 0x0020), Some("joy1_state"));
+    }
+}
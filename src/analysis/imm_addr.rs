@@ -0,0 +1,187 @@
+//! 即値によるアドレス指定の認識。
+//!
+//! 逆アセンブル対象バンク内のコードから、以下のような命令列を探す:
+//!
+//! ```text
+//! lda #lo     ; ldx, ldy でもよい (ゼロページの連続する 2 バイトへ書き込むこと。上位バイトが先でもよい)
+//! sta ptr
+//! lda #hi
+//! sta ptr+1
+//!
+//! lda #hi     ; RTS トリック (スタックに積んだアドレス + 1 へ rts で飛ぶ)
+//! pha
+//! lda #lo
+//! pha
+//! ```
+//!
+//! 即値が表すアドレスが実際に参照されるバンクとしてロードされていれば、そこにラベルを振り、
+//! 出力時に即値を `#<label`, `#>label` の形で表せるようにする。
+
+use crate::address::Address;
+use crate::assembly::{ImmAddr, Label, Labels};
+use crate::input::Input;
+use crate::op::Op;
+
+use super::constant::Constants;
+use super::{Analysis, AnalysisKind};
+
+pub(super) fn analyze(
+    analysis: &Analysis,
+    labels: &mut Labels,
+    input: &Input,
+    consts: &Constants,
+) -> Vec<ImmAddr> {
+    let memory = input.memory();
+    let bank = input.target_bank();
+
+    // 連続する 4 命令を (アドレス, 命令) の形で取得する。全てコードでなければならない。
+    let fetch_ops = |site: Address| -> Option<[(Address, Op); 4]> {
+        let mut ops = Vec::<(Address, Op)>::with_capacity(4);
+        let mut addr = site;
+        for _ in 0..4 {
+            if !bank.contains_addr(addr) || analysis[addr] != AnalysisKind::Code {
+                return None;
+            }
+            let (op, _) = memory.fetch_op(addr).ok()?;
+            ops.push((addr, op));
+            addr = addr.checked_add_unsigned(op.len())?;
+        }
+        ops.try_into().ok()
+    };
+
+    let mut res = Vec::<ImmAddr>::new();
+
+    for site in bank.addr_range() {
+        let Some(ops) = fetch_ops(site) else {
+            continue;
+        };
+        let [(site0, op0), (_, op1), (site2, op2), (_, op3)] = ops;
+
+        // (下位バイトの命令アドレス, 下位バイト, 上位バイトの命令アドレス, 上位バイト, オフセット)
+        let found = if let (Some((0, hi)), Op::Pha, Some((0, lo)), Op::Pha) =
+            (load_imm(op0), op1, load_imm(op2), op3)
+        {
+            Some((site2, lo, site0, hi, -1))
+        } else {
+            match (load_imm(op0), store_zp(op1), load_imm(op2), store_zp(op3)) {
+                (Some((reg0, v0)), Some((reg1, p1)), Some((reg2, v2)), Some((reg3, p3)))
+                    if reg0 == reg1 && reg2 == reg3 =>
+                {
+                    if p1.checked_add(1) == Some(p3) {
+                        Some((site0, v0, site2, v2, 0))
+                    } else if p3.checked_add(1) == Some(p1) {
+                        Some((site2, v2, site0, v0, 0))
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            }
+        };
+        let Some((site_lo, lo, site_hi, hi, offset)) = found else {
+            continue;
+        };
+
+        let value = Address::new(u16::from_le_bytes([lo, hi]));
+        let dst = value.wrapping_add_signed(-isize::from(offset));
+        if consts.resolve_bank_id(input, site, dst).is_none() {
+            continue;
+        }
+
+        // 既に他の命令列の一部と判明している命令は対象外とする。
+        if res
+            .iter()
+            .any(|imm_addr| [site_lo, site_hi].contains(&imm_addr.site()))
+        {
+            continue;
+        }
+
+        labels.set(dst, Label::new(false));
+        res.push(ImmAddr::new(site_lo, dst, offset, false));
+        res.push(ImmAddr::new(site_hi, dst, offset, true));
+    }
+
+    res
+}
+
+/// 即値ロード命令なら (レジスタ番号 (A: 0, X: 1, Y: 2), 即値) を返す。
+fn load_imm(op: Op) -> Option<(u8, u8)> {
+    match op {
+        Op::LdaImm(imm) => Some((0, imm)),
+        Op::LdxImm(imm) => Some((1, imm)),
+        Op::LdyImm(imm) => Some((2, imm)),
+        _ => None,
+    }
+}
+
+/// ゼロページへのストア命令なら (レジスタ番号, 書き込み先) を返す。
+fn store_zp(op: Op) -> Option<(u8, u8)> {
+    match op {
+        Op::StaZp(zp) => Some((0, zp.get())),
+        Op::StxZp(zp) => Some((1, zp.get())),
+        Op::StyZp(zp) => Some((2, zp.get())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AnalysisConfig, OutputConfig};
+    use crate::output::assembly_lines;
+
+    use super::super::test_util::make_input;
+    use super::*;
+
+    #[test]
+    fn test_imm_addr() {
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x34,       // $C000: lda #$34
+            0x85, 0x10,       // $C002: sta $10
+            0xA9, 0xC1,       // $C004: lda #$C1
+            0x85, 0x11,       // $C006: sta $11
+            0xA9, 0xC1,       // $C008: lda #$C1      ; RTS トリック ($C134 - 1 を積む)
+            0x48,             // $C00A: pha
+            0xA9, 0x33,       // $C00B: lda #$33
+            0x48,             // $C00D: pha
+            0xA9, 0x40,       // $C00E: lda #$40      ; 連続しない書き込みは対象外
+            0x85, 0x12,       // $C010: sta $12
+            0xEA,             // $C012: nop
+            0xA9, 0xC1,       // $C013: lda #$C1
+            0x85, 0x13,       // $C015: sta $13
+            0x4C, 0x17, 0xC0, // $C017: jmp $C017
+        ];
+        let input = make_input(&[(0xC000, &prog)]);
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        let dst = Address::new(0xC134);
+        assert_eq!(
+            asm.imm_addrs(),
+            [
+                ImmAddr::new(Address::new(0xC000), dst, 0, false),
+                ImmAddr::new(Address::new(0xC004), dst, 0, true),
+                ImmAddr::new(Address::new(0xC008), dst, -1, true),
+                ImmAddr::new(Address::new(0xC00B), dst, -1, false),
+            ]
+        );
+        // 参照元が 1 ルーチンのみなのでローカルラベルとなる。
+        assert!(asm.labels().get(dst).is_some_and(Label::is_local));
+        assert!(asm.labels().get(Address::new(0xC140)).is_none());
+
+        let lines = assembly_lines(&asm, &OutputConfig::default()).unwrap();
+        let line_at = |addr: u16| {
+            lines
+                .iter()
+                .filter(|&&(a, _)| a == Address::new(addr))
+                .map(|(_, line)| line.as_str())
+                .next_back()
+                .unwrap()
+        };
+        assert_eq!(line_at(0xC000), "        lda     #<@L_C134");
+        assert_eq!(line_at(0xC004), "        lda     #>@L_C134");
+        assert_eq!(line_at(0xC008), "        lda     #>(@L_C134-1)");
+        assert_eq!(line_at(0xC00B), "        lda     #<(@L_C134-1)");
+        assert_eq!(line_at(0xC00E), "        lda     #$40");
+        assert_eq!(line_at(0xC013), "        lda     #$C1");
+    }
+}
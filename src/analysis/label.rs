@@ -2,96 +2,138 @@
 //!
 //! コードと確定したアドレスについて命令を取得し、必要に応じて命令の参照先にラベルを振る。
 //!
-//! 逆アセンブル対象バンク内の命令は必ず調べ、参照先にバンクがロードされていればラベルを振る。
+//! 逆アセンブル対象バンク内の命令は、参照先が実際に参照されるバンクとしてロードされていればラベルを振る。
 //!
-//! 外部バンクの命令は、逆アセンブル対象が固定バンクの場合のみ調べる。
-//! (バンク切り替えを考慮したルール。参照先が逆アセンブル対象バンク内の場合のみラベルを振る)
+//! 外部バンクの命令は、参照先が逆アセンブル対象バンク内であり、かつ実際にそれが参照される場合のみラベルを振る。
+//! (バンク切り替えを考慮したルール。逆アセンブル対象が非固定バンクの場合、
+//! 固定バンクからの参照はバンク切り替え解析により参照先のバンクが判明しているもののみ対象となる)
 
 use crate::address::Address;
 use crate::assembly::{Label, Labels};
 use crate::input::Input;
 use crate::op::{Op, Operand};
 
+use super::constant::Constants;
 use super::{Analysis, AnalysisKind};
 
-pub(super) fn analyze(analysis: &Analysis, labels: &mut Labels, input: &Input) {
+pub(super) fn analyze(analysis: &Analysis, labels: &mut Labels, input: &Input, consts: &Constants) {
     let memory = input.memory();
-    let target_bank = input.target_bank();
     let target_bank_id = input.target_bank_id();
 
-    // target_bank が固定バンクなら全アドレスを、さもなくば target_bank の範囲内のみを調べる。
-    let addrs = if target_bank.is_fixed() {
-        Address::all()
-    } else {
-        target_bank.addr_range().into_iter()
-    };
-
-    for addr in addrs {
+    for addr in Address::all() {
         if analysis[addr] != AnalysisKind::Code {
             continue;
         }
 
         if let Ok((op, bank_id)) = memory.fetch_op(addr) {
             let from_target = bank_id == target_bank_id;
-            set_needed_labels(labels, input, addr, op, from_target);
+            set_needed_labels(labels, input, consts, addr, op, from_target);
         }
     }
 }
 
-fn set_needed_labels(labels: &mut Labels, input: &Input, addr: Address, op: Op, from_target: bool) {
+fn set_needed_labels(
+    labels: &mut Labels,
+    input: &Input,
+    consts: &Constants,
+    addr: Address,
+    op: Op,
+    from_target: bool,
+) {
     let memory = input.memory();
 
+    macro_rules! set_label {
+        ($dst:expr, $entrypoint:expr) => {{
+            set_label(labels, input, consts, addr, from_target, $dst, $entrypoint);
+        }};
+    }
+
     // jsr, jmp ind の飛び先は(追跡できるなら)エントリポイントラベルとする。
     // それ以外については通常ラベルとする。
     match op {
-        Op::Jsr(dst) => set_label(labels, input, from_target, dst, true),
+        Op::Jsr(dst) => set_label!(dst, true),
         Op::JmpInd(ptr) => {
             if let Some((dst, _)) = memory.fetch_addr(ptr) {
-                set_label(labels, input, from_target, dst, true);
+                set_label!(dst, true);
             }
         }
         _ => match op.operand() {
             Operand::Zp(zp) | Operand::ZpX(zp) | Operand::ZpY(zp) => {
-                set_label(labels, input, from_target, Address::from(zp), false);
+                set_label!(Address::from(zp), false);
             }
             Operand::Abs(abs) | Operand::AbsX(abs) | Operand::AbsY(abs) => {
-                set_label(labels, input, from_target, abs, false);
+                set_label!(abs, false);
             }
-            Operand::IndX(zp) => set_label(labels, input, from_target, Address::from(zp), false),
+            Operand::IndX(zp) => set_label!(Address::from(zp), false),
             Operand::IndY(zp) => {
                 let ptr = Address::from(zp);
-                set_label(labels, input, from_target, ptr, false);
+                set_label!(ptr, false);
                 if let Some((dst, _)) = memory.fetch_addr(ptr) {
-                    set_label(labels, input, from_target, dst, false);
+                    set_label!(dst, false);
                 }
             }
             Operand::Rel(rel) => {
                 let dst = addr.wrapping_add_unsigned(2_usize).wrapping_add_signed(rel);
-                set_label(labels, input, from_target, dst, false);
+                set_label!(dst, false);
             }
             _ => {}
         },
     }
 }
 
-/// 参照元が逆アセンブル対象バンクの場合、参照先にバンクがロードされていればラベルを振る。
-/// 参照元が外部バンクの場合、参照先が逆アセンブル対象バンクならラベルを振る。
+/// 参照元が逆アセンブル対象バンクの場合、参照先が実際に参照されるバンクとしてロードされていればラベルを振る。
+/// 参照元が外部バンクの場合、参照先が実際に参照される逆アセンブル対象バンクならラベルを振る。
 fn set_label(
     labels: &mut Labels,
     input: &Input,
+    consts: &Constants,
+    src: Address,
     from_target: bool,
     dst: Address,
     entrypoint: bool,
 ) {
-    let dst_bank_id = input.memory().find_bank_id(dst);
+    let dst_bank_id = consts.resolve_bank_id(input, src, dst);
 
     let cond = if from_target {
         dst_bank_id.is_some()
     } else {
-        dst_bank_id.map_or(false, |dst_bank_id| dst_bank_id == input.target_bank_id())
+        dst_bank_id == Some(input.target_bank_id())
     };
 
     if cond {
         labels.set(dst, Label::new(entrypoint));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AnalysisConfig;
+
+    use super::super::pass::BuiltinPass;
+    use super::super::test_util::{make_cross_bank_input, run_passes};
+    use super::*;
+
+    #[test]
+    fn test_cross_bank_labels() {
+        let input = make_cross_bank_input();
+        let config = AnalysisConfig::default();
+
+        let cx = run_passes(
+            &input,
+            &config,
+            &[
+                &BuiltinPass::Interrupt,
+                &BuiltinPass::Op,
+                &BuiltinPass::Flow,
+                &BuiltinPass::Label,
+            ],
+        );
+        let label = |addr: u16| cx.labels().get(Address::new(addr));
+
+        // 固定バンクからの参照は、逆アセンブル対象バンクを指すと判明している場合のみラベルを振る。
+        assert!(label(0x8000).is_some_and(Label::is_entrypoint));
+        assert!(label(0x8010).is_none());
+        // 逆アセンブル対象バンクから固定バンクへの参照にはラベルを振る。
+        assert!(label(0xC020).is_some_and(|label| !label.is_entrypoint()));
+    }
+}
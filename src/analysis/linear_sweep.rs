@@ -3,6 +3,7 @@
 //! 逆アセンブル対象バンクを先頭からなめて解析し、`Statement` 配列に変換する。
 //! (原始的な逆アセンブラと同じ要領)
 //! 必要に応じてラベル振りも行う(コード/データ境界など)。
+//! 定数伝播により常に分岐すると判明している分岐命令もコード終端として扱う。
 
 use crate::address::Address;
 use crate::assembly::{Label, Labels, Statement};
@@ -10,17 +11,20 @@ use crate::input::Input;
 use crate::memory::FetchOpError;
 use crate::op::Op;
 
+use super::constant::Constants;
 use super::{Analysis, AnalysisKind};
 
 pub(super) fn analyze(
     analysis: &mut Analysis,
     labels: &mut Labels,
     input: &Input,
+    consts: &Constants,
 ) -> Vec<Statement> {
     let bank = input.target_bank();
 
     let mut addr = bank.addr();
     let mut stmts = Vec::<Statement>::new();
+    let mut addr_pre = addr;
 
     loop {
         let stmt = get_stmt(analysis, input, addr);
@@ -30,7 +34,7 @@ pub(super) fn analyze(
         // 逆アセンブル対象バンク内であることは確定していることに注意。
         if stmts
             .last()
-            .map_or(true, |stmt_pre| needs_label(stmt_pre, &stmt))
+            .is_none_or(|stmt_pre| needs_label(analysis, consts, addr_pre, stmt_pre, &stmt))
         {
             labels.set(addr, Label::new(false));
         }
@@ -45,6 +49,7 @@ pub(super) fn analyze(
             break;
         }
 
+        addr_pre = addr;
         addr = addr_nxt;
     }
 
@@ -82,8 +87,14 @@ fn get_stmt(analysis: &mut Analysis, input: &Input, addr: Address) -> Statement
     }
 }
 
-/// 直前の文と現在の文が与えられたとき、現在の文にラベルを振るべきかどうかを返す。
-fn needs_label(stmt_pre: &Statement, stmt: &Statement) -> bool {
+/// 直前の文(とそのアドレス)と現在の文が与えられたとき、現在の文にラベルを振るべきかどうかを返す。
+fn needs_label(
+    analysis: &Analysis,
+    consts: &Constants,
+    addr_pre: Address,
+    stmt_pre: &Statement,
+    stmt: &Statement,
+) -> bool {
     // コード/データ境界ならラベルを振る。
     if matches!(
         (stmt_pre, stmt),
@@ -98,13 +109,45 @@ fn needs_label(stmt_pre: &Statement, stmt: &Statement) -> bool {
         return true;
     }
 
-    // 直前の文がコード終端 (rti, rts, jmp) ならラベルを振る。
+    // 直前の文がコード終端 (rti, rts, jmp, 常に分岐する分岐命令) ならラベルを振る。
     if matches!(
         stmt_pre,
         Statement::Op(Op::Rti | Op::Rts | Op::JmpAbs(_) | Op::JmpInd(_))
     ) {
         return true;
     }
+    // 出力時の空行と同じく、Code である分岐命令のみを対象とする。
+    if let Statement::Op(op) = *stmt_pre {
+        if consts.is_always_taken_code(analysis, addr_pre, op) {
+            return true;
+        }
+    }
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AnalysisConfig;
+
+    use super::super::test_util::make_input;
+    use super::*;
+
+    #[test]
+    fn test_always_taken_branch() {
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x00,       // $C000: lda #0
+            0xF0, 0x02,       // $C002: beq $C006
+            0x01, 0x02,       // $C004: (データだが命令として読める)
+            0x4C, 0x06, 0xC0, // $C006: jmp $C006
+        ];
+        let input = make_input(&[(0xC000, &prog)]);
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        assert_eq!(asm.always_taken_branches(), [Address::new(0xC002)]);
+        // 常に分岐する分岐命令の直後にはラベルを振る。
+        assert!(asm.labels().get(Address::new(0xC004)).is_some());
+        assert!(asm.labels().get(Address::new(0xC002)).is_none());
+    }
+}
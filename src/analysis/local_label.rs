@@ -0,0 +1,162 @@
+//! ローカルラベル判定。
+//!
+//! 逆アセンブル対象バンク内のラベルのうち、同一スコープ内からしか参照されないものをローカルラベル
+//! (ca65 の cheap local label) とする。
+//! スコープとは、隣接する 2 つの非ローカルラベルに挟まれた範囲のこと。
+//!
+//! 以下のラベルは常に非ローカルラベルとする:
+//!
+//! * エントリポイントラベル
+//! * 名前の付いたラベル
+//! * バンク先頭のラベル
+//! * 文の途中にあるラベル、および文の途中にラベルを持つ文のラベル (`:=` による定義で参照されるため)
+
+use crate::address::Address;
+use crate::assembly::{ImmAddr, Label, Labels, Statement};
+use crate::input::Input;
+use crate::layout::FieldType;
+use crate::op::Operand;
+
+pub(super) fn analyze(
+    labels: &mut Labels,
+    input: &Input,
+    stmts: &[Statement],
+    imm_addrs: &[ImmAddr],
+) {
+    let bank = input.target_bank();
+
+    // 各文の (アドレス, 参照先アドレス) を列挙し、ラベル候補を決める。
+    let mut refs = Vec::<(Address, Address)>::new();
+    let mut candidates = Vec::<Address>::new();
+    let mut addr = bank.addr();
+    for stmt in stmts {
+        let mid_label =
+            (1..stmt.len().get()).any(|i| labels.get(addr.wrapping_add_unsigned(i)).is_some());
+
+        // 文の途中にラベルがあれば、出力時に文の先頭にもラベルが振られるので、スコープの区切りとなる。
+        if mid_label {
+            labels.set(addr, Label::new(false));
+        }
+
+        let is_candidate = addr != bank.addr()
+            && !mid_label
+            && labels
+                .get(addr)
+                .is_some_and(|label| !label.is_entrypoint() && label.name().is_none());
+        if is_candidate {
+            candidates.push(addr);
+        }
+
+        match *stmt {
+            Statement::Op(op) => {
+                let dst = match op.operand() {
+                    Operand::Abs(abs)
+                    | Operand::AbsX(abs)
+                    | Operand::AbsY(abs)
+                    | Operand::Ind(abs) => Some(abs),
+                    Operand::Rel(rel) => {
+                        Some(addr.wrapping_add_unsigned(2_usize).wrapping_add_signed(rel))
+                    }
+                    _ => None,
+                };
+                if let Some(dst) = dst.filter(|&dst| bank.contains_addr(dst)) {
+                    refs.push((addr, dst));
+                }
+            }
+            // 構造体のポインタ型フィールド、分割ポインタテーブルの要素も参照とみなす。
+            Statement::Record(ref record) => {
+                let dsts = record
+                    .fields()
+                    .filter(|(_, field, _)| field.ty() == FieldType::Ptr)
+                    .map(|(_, _, value)| Address::new(value))
+                    .filter(|&dst| bank.contains_addr(dst));
+                refs.extend(dsts.map(|dst| (addr, dst)));
+            }
+            Statement::LoBytes(ref dsts) | Statement::HiBytes(ref dsts) => {
+                let dsts = dsts.iter().copied().filter(|&dst| bank.contains_addr(dst));
+                refs.extend(dsts.map(|dst| (addr, dst)));
+            }
+            _ => {}
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt.len()) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+
+    // 即値によるアドレス指定も参照とみなす。
+    refs.extend(
+        imm_addrs
+            .iter()
+            .filter(|imm_addr| bank.contains_addr(imm_addr.dst()))
+            .map(|imm_addr| (imm_addr.site(), imm_addr.dst())),
+    );
+
+    // まず候補を全てローカルラベルとし、スコープ外から参照されるものを非ローカルラベルに戻す。
+    // 非ローカルラベルが増えるとスコープが細かくなるので、変化がなくなるまで繰り返す。
+    for &addr in &candidates {
+        labels.set_local(addr, true);
+    }
+
+    loop {
+        let scopes = make_scopes(labels, input);
+        let scope_of = |addr: Address| scopes[usize::from(addr) - usize::from(bank.addr())];
+
+        let mut changed = false;
+        for &(src, dst) in &refs {
+            let local = labels.get(dst).is_some_and(|label| label.is_local());
+            if local && scope_of(src) != scope_of(dst) {
+                labels.set_local(dst, false);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// 逆アセンブル対象バンク内の各アドレスについて、それが属するスコープの先頭
+/// (直前の非ローカルラベルのアドレス) を求める。
+fn make_scopes(labels: &Labels, input: &Input) -> Vec<Address> {
+    let bank = input.target_bank();
+
+    let mut scopes = Vec::<Address>::with_capacity(bank.len().get());
+    let mut scope = bank.addr();
+    for addr in bank.addr_range() {
+        if labels.get(addr).is_some_and(|label| !label.is_local()) {
+            scope = addr;
+        }
+        scopes.push(scope);
+    }
+
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AnalysisConfig;
+
+    use super::super::test_util::make_shared_tail_input;
+    use super::*;
+
+    #[test]
+    fn test_local_labels() {
+        let input = make_shared_tail_input();
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        let is_local = |addr: u16| asm.labels().get(Address::new(addr)).unwrap().is_local();
+
+        // エントリポイントは常に非ローカル。
+        assert!(!is_local(0xC000));
+        assert!(!is_local(0xC010));
+        assert!(!is_local(0xC020));
+        // 同じルーチン内からのみ参照されるラベルはローカル。
+        assert!(is_local(0xC006));
+        assert!(is_local(0xC012));
+        // 別のルーチンから参照されるラベルは非ローカルのまま。
+        assert!(!is_local(0xC015));
+    }
+}
@@ -1,53 +1,58 @@
+mod annotation;
+mod blob;
 mod cdl;
+mod constant;
+mod dpcm;
 mod flow;
+mod idiom;
+mod imm_addr;
 mod interrupt;
 mod label;
 mod linear_sweep;
+mod local_label;
 mod op;
+mod pass;
 mod permission;
+mod pipeline;
+mod record;
+mod routine;
+mod signature;
+mod split_table;
+mod subroutine;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod text;
 
 use crate::address::ArrayByAddress;
-use crate::assembly::{Assembly, AssemblyBuilder, Labels};
+use crate::assembly::Assembly;
 use crate::config::AnalysisConfig;
 use crate::input::Input;
 
+pub use self::constant::Constants;
+pub use self::pass::*;
+pub use self::pipeline::*;
+pub use self::subroutine::{ReturnKind, Subroutines};
+
 /// 各種解析を行い、コード/非コードの識別とラベル振りを行い、`Assembly` を返す。
+///
+/// デフォルトのパイプライン (組み込みパスのみ) を使う。パスを差し替えるには `PipelineBuilder` を使う。
 pub fn analyze(input: &Input, config: &AnalysisConfig) -> Assembly {
-    let mut analysis = Analysis::default();
-    let mut labels = Labels::default();
-
-    self::cdl::analyze(&mut analysis, &mut labels, input);
-    self::permission::analyze(&mut analysis, input);
-    self::interrupt::analyze(&mut analysis, &mut labels, input, config);
-    self::op::analyze(&mut analysis, input, config);
-    self::flow::analyze(&mut analysis, input);
-    let stmts = self::linear_sweep::analyze(&mut analysis, &mut labels, input);
-    self::label::analyze(&analysis, &mut labels, input);
-
-    let asm = AssemblyBuilder::new()
-        .bank_addr_range(input.target_bank().addr_range())
-        .bank_name(input.target_bank_name())
-        .statements(stmts)
-        .labels(labels)
-        .build()
-        .expect("AssemblyBuilder::build() should success");
-
-    asm
+    Pipeline::default()
+        .run(input, config)
+        .expect("default pipeline should success")
 }
 
 /// 論理アドレス空間全体の解析結果。
-type Analysis = ArrayByAddress<AnalysisKind>;
+pub type Analysis = ArrayByAddress<AnalysisKind>;
 
 /// ある論理アドレスに対する解析結果。
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum AnalysisKind {
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AnalysisKind {
+    /// 未確定。
+    #[default]
     Unknown,
+    /// 命令の先頭。
     Code,
+    /// 命令の先頭ではない。
     NotCode,
 }
-
-impl Default for AnalysisKind {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
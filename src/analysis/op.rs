@@ -94,23 +94,17 @@ fn operand_is_wrapping_ptr(op: Op) -> bool {
 /// 命令が有効な後続アドレス(実行後のプログラムカウンタとしてありうる値)を持つかどうかを返す。
 fn op_has_valid_succ(memory: &Memory, addr: Address, op: Op, bank_id: usize) -> bool {
     // 後続アドレスが元のアドレスと同一バンクに属するかどうかを返す。
-    let is_same_bank = |addr_succ: Address| {
-        memory
-            .find_bank_id(addr_succ)
-            .map_or(false, |bank_id_succ| bank_id_succ == bank_id)
-    };
+    let is_same_bank = |addr_succ: Address| memory.find_bank_id(addr_succ) == Some(bank_id);
 
     // アドレス空間内で wrap したり、元のバンク外に出るのは基本的に許さない。
     // 例外として brk, jsr, rti, rts, jmp abs, jmp ind のみ元のバンク外に出ることを許す。
     match op.succ() {
-        OpSucc::Normal(offset) => addr
-            .checked_add_unsigned(offset)
-            .map_or(false, is_same_bank),
+        OpSucc::Normal(offset) => addr.checked_add_unsigned(offset).is_some_and(is_same_bank),
         OpSucc::Brk => true,
         OpSucc::Kil => true,
         // 分岐時の行き先がアドレス空間内で wrap したり、元のバンク外に出るのは許さない。
         // 無条件分岐の可能性があるので、非分岐時の行き先は問わない。
-        OpSucc::Branch(rel) => addr.checked_add_signed(rel).map_or(false, is_same_bank),
+        OpSucc::Branch(rel) => addr.checked_add_signed(rel).is_some_and(is_same_bank),
         OpSucc::Jsr(_) => true,
         OpSucc::Rti => true,
         OpSucc::Rts => true,
@@ -121,12 +115,12 @@ fn op_has_valid_succ(memory: &Memory, addr: Address, op: Op, bank_id: usize) ->
 
 /// 命令がメモリを読み取り、かつそのアドレス候補が全て読み取り不可かどうかを返す。
 fn op_reads_unreadable_addr(perms: &Permissions, op: Op) -> bool {
-    op_read_candidates(op).map_or(false, |mut it| it.all(|addr| !perms[addr].is_readable()))
+    op_read_candidates(op).is_some_and(|mut it| it.all(|addr| !perms[addr].is_readable()))
 }
 
 /// 命令がメモリに書き込み、かつそのアドレス候補が全て書き込み不可かどうかを返す。
 fn op_writes_unwritable_addr(perms: &Permissions, op: Op) -> bool {
-    op_write_candidates(op).map_or(false, |mut it| it.all(|addr| !perms[addr].is_writable()))
+    op_write_candidates(op).is_some_and(|mut it| it.all(|addr| !perms[addr].is_writable()))
 }
 
 /// 命令がメモリを読み取る場合、そのアドレス候補を全て列挙する。
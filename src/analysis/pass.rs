@@ -0,0 +1,423 @@
+//! 解析パス。
+//!
+//! 各パスは `AnalysisContext` 上の解析結果 (`Analysis`)、ラベル、文の配列などを読み書きする。
+//! 組み込みのパスは `BuiltinPass` として提供され、利用者はゲーム固有のヒューリスティクスなどを
+//! `AnalysisPass` を実装して `PipelineBuilder` で挿入できる。
+
+use anyhow::bail;
+
+use crate::address::AddressRange;
+use crate::assembly::{Assembly, AssemblyBuilder, ImmAddr, Labels, Routine, Statement};
+use crate::config::AnalysisConfig;
+use crate::input::Input;
+use crate::layout::{SplitTable, StructArray};
+
+use super::constant::Constants;
+use super::subroutine::Subroutines;
+use super::Analysis;
+
+/// 解析パス。
+pub trait AnalysisPass {
+    /// パス名。パイプライン内でパスを指定するのに使われる。
+    fn name(&self) -> &str;
+
+    /// 解析を行い、結果をコンテキストに書き込む。
+    fn run(&self, cx: &mut AnalysisContext<'_>);
+}
+
+/// 解析パスたちが共有する状態。
+///
+/// 定数伝播およびサブルーチン解析の結果 (`consts()`, `subroutines()`) は、それらのパスが実行されていなければ
+/// 必要になった時点の `Analysis` から求められる。`Analysis` が変更されると (組み込みのパスによるものを含む)
+/// 破棄され、次に必要になった時点で求め直される。
+pub struct AnalysisContext<'a> {
+    input: &'a Input,
+    config: &'a AnalysisConfig,
+    analysis: Analysis,
+    labels: Labels,
+    consts: Option<Constants>,
+    subs: Option<Subroutines>,
+    struct_arrays: Vec<StructArray>,
+    split_tables: Vec<SplitTable>,
+    dpcm_samples: Vec<AddressRange>,
+    stmts: Option<Vec<Statement>>,
+    imm_addrs: Vec<ImmAddr>,
+    routines: Vec<Routine>,
+}
+
+impl<'a> AnalysisContext<'a> {
+    pub(super) fn new(input: &'a Input, config: &'a AnalysisConfig) -> Self {
+        Self {
+            input,
+            config,
+            analysis: Analysis::default(),
+            labels: Labels::default(),
+            consts: None,
+            subs: None,
+            struct_arrays: vec![],
+            split_tables: vec![],
+            dpcm_samples: vec![],
+            stmts: None,
+            imm_addrs: vec![],
+            routines: vec![],
+        }
+    }
+
+    pub fn input(&self) -> &'a Input {
+        self.input
+    }
+
+    pub fn config(&self) -> &'a AnalysisConfig {
+        self.config
+    }
+
+    /// 論理アドレス空間全体のコード/非コードの解析結果を返す。
+    pub fn analysis(&self) -> &Analysis {
+        &self.analysis
+    }
+
+    /// 論理アドレス空間全体のコード/非コードの解析結果を返す。
+    ///
+    /// 定数伝播およびサブルーチン解析の結果は破棄され、次に必要になった時点で求め直される。
+    pub fn analysis_mut(&mut self) -> &mut Analysis {
+        self.invalidate_consts();
+        &mut self.analysis
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    pub fn labels_mut(&mut self) -> &mut Labels {
+        &mut self.labels
+    }
+
+    /// 逆アセンブル対象バンクの文の配列を返す。linear sweep 解析の前は `None` を返す。
+    pub fn statements(&self) -> Option<&[Statement]> {
+        self.stmts.as_deref()
+    }
+
+    /// 逆アセンブル対象バンクの文の配列を返す。linear sweep 解析の前は `None` を返す。
+    ///
+    /// 文の長さの合計はバンクサイズと一致していなければならない。
+    pub fn statements_mut(&mut self) -> Option<&mut Vec<Statement>> {
+        self.stmts.as_mut()
+    }
+
+    /// 逆アセンブル対象バンク内のルーチンたちを返す。ルーチン範囲の解析の前は空。
+    pub fn routines(&self) -> &[Routine] {
+        &self.routines
+    }
+
+    /// 定数伝播の結果を返す。求められていなければ現在の `Analysis` から求める。
+    pub fn consts(&mut self) -> &Constants {
+        self.ensure_consts();
+        self.consts.as_ref().unwrap()
+    }
+
+    /// サブルーチン解析の結果を返す。求められていなければ現在の `Analysis` から求める。
+    pub fn subroutines(&mut self) -> &Subroutines {
+        self.ensure_subs();
+        self.subs.as_ref().unwrap()
+    }
+
+    /// 定数伝播およびサブルーチン解析の結果を破棄する。`Analysis` を変更したら呼ばなければならない。
+    fn invalidate_consts(&mut self) {
+        self.consts = None;
+        self.subs = None;
+    }
+
+    fn ensure_consts(&mut self) {
+        if self.consts.is_none() {
+            self.consts = Some(super::constant::analyze(&self.analysis, self.input));
+        }
+    }
+
+    fn ensure_subs(&mut self) {
+        self.ensure_consts();
+        if self.subs.is_none() {
+            let consts = self.consts.as_ref().unwrap();
+            self.subs = Some(super::subroutine::analyze(
+                &self.analysis,
+                self.input,
+                consts,
+            ));
+        }
+    }
+
+    /// 解析結果から `Assembly` を作る。
+    pub(super) fn into_assembly(mut self) -> anyhow::Result<Assembly> {
+        self.ensure_subs();
+
+        let Some(stmts) = self.stmts else {
+            bail!("no statements (the pipeline must contain a linear sweep pass)");
+        };
+        let input = self.input;
+        let consts = self.consts.as_ref().unwrap();
+        let subs = self.subs.as_ref().unwrap();
+
+        let bank_switches = super::constant::collect_bank_switches(consts, &self.analysis, input);
+        let always_taken_branches =
+            super::constant::collect_always_taken_branches(consts, &self.analysis, input);
+        let no_return_calls =
+            super::subroutine::collect_no_return_calls(subs, &self.analysis, input);
+        let comments = super::annotation::collect_comments(input);
+
+        AssemblyBuilder::new()
+            .bank_addr_range(input.target_bank().addr_range())
+            .bank_name(input.target_bank_name())
+            .statements(stmts)
+            .labels(self.labels)
+            .routines(self.routines)
+            .bank_switches(bank_switches)
+            .always_taken_branches(always_taken_branches)
+            .no_return_calls(no_return_calls)
+            .imm_addrs(self.imm_addrs)
+            .comments(comments)
+            .build()
+    }
+}
+
+/// 組み込みの解析パス。`BuiltinPass::ALL` の順に実行するのがデフォルトのパイプライン
+/// (`Op` から `Dpcm` までは `Analysis` が変化しなくなるまで繰り返す)。
+///
+/// 文の配列を扱うパス (`Label` 以降の多く) は、linear sweep 解析の前に実行すると何もしない。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BuiltinPass {
+    /// CDL による解析。
+    Cdl,
+    /// 利用者による注釈の適用。
+    Annotation,
+    /// 構造体の配列の解析。
+    Record,
+    /// パーミッションによる解析。
+    Permission,
+    /// 割り込みベクタによる解析。
+    Interrupt,
+    /// 命令の妥当性の解析。
+    Op,
+    /// 定数伝播 (の再計算)。
+    Constant,
+    /// サブルーチン解析 (の再計算)。
+    Subroutine,
+    /// 制御フロー解析。
+    Flow,
+    /// 分割ポインタテーブル検出。
+    SplitTable,
+    /// DPCM サンプル検出。
+    Dpcm,
+    /// linear sweep 解析。文の配列を作る。
+    LinearSweep,
+    /// ラベル振り。
+    Label,
+    /// シグネチャによる既知のルーチンの認識。
+    Signature,
+    /// 即値によるアドレス指定の検出。
+    ImmAddr,
+    /// DPCM サンプル、構造体の配列、分割ポインタテーブルの文へのまとめ。
+    Merge,
+    /// テキスト検出。
+    Text,
+    /// `.incbin` で出力するデータの検出。
+    Blob,
+    /// ルーチン範囲の解析。
+    Routine,
+    /// NES の定番処理 (イディオム) の認識。
+    Idiom,
+    /// ローカルラベル判定。
+    LocalLabel,
+}
+
+impl BuiltinPass {
+    /// 全ての組み込みパス (デフォルトの実行順)。
+    pub const ALL: [Self; 21] = [
+        Self::Cdl,
+        Self::Annotation,
+        Self::Record,
+        Self::Permission,
+        Self::Interrupt,
+        Self::Op,
+        Self::Constant,
+        Self::Subroutine,
+        Self::Flow,
+        Self::SplitTable,
+        Self::Dpcm,
+        Self::LinearSweep,
+        Self::Label,
+        Self::Signature,
+        Self::ImmAddr,
+        Self::Merge,
+        Self::Text,
+        Self::Blob,
+        Self::Routine,
+        Self::Idiom,
+        Self::LocalLabel,
+    ];
+}
+
+impl AnalysisPass for BuiltinPass {
+    fn name(&self) -> &str {
+        match self {
+            Self::Cdl => "cdl",
+            Self::Annotation => "annotation",
+            Self::Record => "record",
+            Self::Permission => "permission",
+            Self::Interrupt => "interrupt",
+            Self::Op => "op",
+            Self::Constant => "constant",
+            Self::Subroutine => "subroutine",
+            Self::Flow => "flow",
+            Self::SplitTable => "split_table",
+            Self::Dpcm => "dpcm",
+            Self::LinearSweep => "linear_sweep",
+            Self::Label => "label",
+            Self::Signature => "signature",
+            Self::ImmAddr => "imm_addr",
+            Self::Merge => "merge",
+            Self::Text => "text",
+            Self::Blob => "blob",
+            Self::Routine => "routine",
+            Self::Idiom => "idiom",
+            Self::LocalLabel => "local_label",
+        }
+    }
+
+    fn run(&self, cx: &mut AnalysisContext<'_>) {
+        // `Analysis` を変更したパスの後は、`analysis_mut()` と同様に定数伝播およびサブルーチン解析の結果を破棄する。
+        let before = self.mutates_analysis().then(|| cx.analysis.clone());
+        self.run_pass(cx);
+        if before.is_some_and(|before| before != cx.analysis) {
+            cx.invalidate_consts();
+        }
+    }
+}
+
+impl BuiltinPass {
+    /// `Analysis` を変更しうるパスかどうかを返す。
+    fn mutates_analysis(self) -> bool {
+        matches!(
+            self,
+            Self::Cdl
+                | Self::Annotation
+                | Self::Record
+                | Self::Permission
+                | Self::Interrupt
+                | Self::Op
+                | Self::Flow
+                | Self::SplitTable
+                | Self::Dpcm
+                | Self::LinearSweep
+        )
+    }
+
+    fn run_pass(self, cx: &mut AnalysisContext<'_>) {
+        let input = cx.input;
+        let config = cx.config;
+
+        match self {
+            Self::Cdl => super::cdl::analyze(&mut cx.analysis, &mut cx.labels, input),
+            Self::Annotation => {
+                super::annotation::analyze(&mut cx.analysis, &mut cx.labels, input);
+            }
+            Self::Record => {
+                cx.struct_arrays = super::record::analyze(&mut cx.analysis, &mut cx.labels, input);
+            }
+            Self::Permission => super::permission::analyze(&mut cx.analysis, input),
+            Self::Interrupt => {
+                super::interrupt::analyze(&mut cx.analysis, &mut cx.labels, input, config);
+            }
+            Self::Op => super::op::analyze(&mut cx.analysis, input, config),
+            Self::Constant => {
+                cx.consts = None;
+                cx.ensure_consts();
+            }
+            Self::Subroutine => {
+                cx.subs = None;
+                cx.ensure_subs();
+            }
+            Self::Flow => {
+                cx.ensure_subs();
+                let consts = cx.consts.as_ref().unwrap();
+                let subs = cx.subs.as_ref().unwrap();
+                super::flow::analyze(&mut cx.analysis, input, subs, consts);
+            }
+            Self::SplitTable => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                cx.split_tables =
+                    super::split_table::analyze(&mut cx.analysis, &mut cx.labels, input, consts);
+            }
+            Self::Dpcm => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                cx.dpcm_samples = super::dpcm::analyze(
+                    &mut cx.analysis,
+                    &mut cx.labels,
+                    input,
+                    consts,
+                    &cx.split_tables,
+                );
+            }
+            Self::LinearSweep => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                cx.stmts = Some(super::linear_sweep::analyze(
+                    &mut cx.analysis,
+                    &mut cx.labels,
+                    input,
+                    consts,
+                ));
+            }
+            Self::Label => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                super::label::analyze(&cx.analysis, &mut cx.labels, input, consts);
+            }
+            Self::Signature => {
+                if let Some(stmts) = cx.stmts.as_ref() {
+                    super::signature::analyze(&mut cx.labels, input, config, stmts);
+                }
+            }
+            Self::ImmAddr => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                cx.imm_addrs =
+                    super::imm_addr::analyze(&cx.analysis, &mut cx.labels, input, consts);
+            }
+            Self::Merge => {
+                if let Some(stmts) = cx.stmts.take() {
+                    let stmts = super::dpcm::merge_statements(stmts, input, &cx.dpcm_samples);
+                    let stmts = super::record::merge_statements(stmts, input, &cx.struct_arrays);
+                    let stmts =
+                        super::split_table::merge_statements(stmts, input, &cx.split_tables);
+                    cx.stmts = Some(stmts);
+                }
+            }
+            Self::Text => {
+                if let Some(stmts) = cx.stmts.take() {
+                    cx.stmts = Some(super::text::analyze(stmts, &cx.labels, input, config));
+                }
+            }
+            Self::Blob => {
+                if let Some(stmts) = cx.stmts.take() {
+                    cx.stmts = Some(super::blob::analyze(stmts, input, config));
+                }
+            }
+            Self::Routine => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                cx.routines = super::routine::analyze(&cx.analysis, &cx.labels, input, consts);
+            }
+            Self::Idiom => {
+                cx.ensure_consts();
+                let consts = cx.consts.as_ref().unwrap();
+                super::idiom::analyze(&mut cx.labels, input, consts, &cx.routines);
+            }
+            Self::LocalLabel => {
+                if let Some(stmts) = cx.stmts.as_ref() {
+                    super::local_label::analyze(&mut cx.labels, input, stmts, &cx.imm_addrs);
+                }
+            }
+        }
+    }
+}
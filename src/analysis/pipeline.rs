@@ -0,0 +1,651 @@
+//! 解析パイプライン。
+//!
+//! 後のパスが見つけた事実 (制御フロー解析で新たに判明した `Code` など) を前のパスが利用できるよう、
+//! パイプラインの一部の範囲は `Analysis` が変化しなくなるまで繰り返し実行される (不動点反復)。
+//! 繰り返しの最大回数は `AnalysisConfig::max_iterations()` で指定する。
+//! 各回の変化は `info` レベルでログに出力される。
+//!
+//! 繰り返しは `Analysis` の変化のみで判定するので、`Analysis` を変更しないパスは範囲に含めない。
+//! 特にラベル振り (`label`) は `Analysis` を読むだけで繰り返しの判定に寄与せず、また振ったラベルは
+//! 取り除かれないので、linear sweep 解析で全てのアドレスが確定した後に 1 回だけ実行する。
+
+use std::fmt::{Debug, Formatter};
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, ensure};
+use log::{debug, info, warn};
+
+use crate::address::Address;
+use crate::assembly::Assembly;
+use crate::config::AnalysisConfig;
+use crate::input::Input;
+
+use super::pass::{AnalysisContext, AnalysisPass, BuiltinPass};
+use super::{Analysis, AnalysisKind};
+
+/// デフォルトのパイプラインで繰り返し実行する範囲の先頭と末尾のパス名。
+///
+/// linear sweep 解析は残った `Unknown` を全て確定させるので、その手前までとする。
+const DEFAULT_FIXPOINT: (&str, &str) = ("op", "dpcm");
+
+/// 解析パスの列。先頭から順に実行される。
+pub struct Pipeline {
+    passes: Vec<Box<dyn AnalysisPass>>,
+    /// 収束するまで繰り返し実行するパスの範囲。
+    fixpoint: Option<RangeInclusive<usize>>,
+}
+
+impl Default for Pipeline {
+    /// 組み込みパスを `BuiltinPass::ALL` の順に並べ、op から dpcm までを繰り返すパイプラインを返す。
+    fn default() -> Self {
+        PipelineBuilder::new()
+            .build()
+            .expect("default pipeline should be valid")
+    }
+}
+
+impl Debug for Pipeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("passes", &self.pass_names().collect::<Vec<_>>())
+            .field("fixpoint", &self.fixpoint)
+            .finish()
+    }
+}
+
+impl Pipeline {
+    /// パス名たちを実行順に返す。
+    pub fn pass_names(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    /// 全てのパスを実行し、`Assembly` を返す。
+    ///
+    /// 文の配列が作られなかった場合 (linear sweep 解析のパスがない場合) はエラーを返す。
+    pub fn run(&self, input: &Input, config: &AnalysisConfig) -> anyhow::Result<Assembly> {
+        let mut cx = AnalysisContext::new(input, config);
+
+        match self.fixpoint.clone() {
+            Some(range) => {
+                run_passes(&self.passes[..*range.start()], &mut cx);
+                let end = *range.end();
+                run_fixpoint(&self.passes[range], &mut cx);
+                run_passes(&self.passes[end + 1..], &mut cx);
+            }
+            None => run_passes(&self.passes, &mut cx),
+        }
+
+        cx.into_assembly()
+    }
+}
+
+fn run_passes(passes: &[Box<dyn AnalysisPass>], cx: &mut AnalysisContext<'_>) {
+    for pass in passes {
+        pass.run(cx);
+    }
+}
+
+/// `Analysis` が変化しなくなるか最大回数に達するまで、パスたちを繰り返し実行する。
+fn run_fixpoint(passes: &[Box<dyn AnalysisPass>], cx: &mut AnalysisContext<'_>) {
+    let max_iterations = cx.config().max_iterations().get();
+
+    for iteration in 1..=max_iterations {
+        let before = cx.analysis().clone();
+        run_passes(passes, cx);
+
+        let changes = count_changes(&before, cx.analysis());
+        let total: usize = changes.iter().map(|&(_, _, count)| count).sum();
+        if total == 0 {
+            debug!("analysis converged after {iteration} iteration(s)");
+            return;
+        }
+
+        let detail = changes
+            .iter()
+            .map(|(from, to, count)| format!("{from:?} -> {to:?}: {count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!("iteration {iteration}: {total} address(es) changed ({detail})");
+    }
+
+    if max_iterations > 1 {
+        warn!("analysis did not converge in {max_iterations} iterations");
+    }
+}
+
+/// 解析結果の変化を (変化前, 変化後, アドレス数) の形で数える。
+fn count_changes(before: &Analysis, after: &Analysis) -> Vec<(AnalysisKind, AnalysisKind, usize)> {
+    const KINDS: [AnalysisKind; 3] = [
+        AnalysisKind::Unknown,
+        AnalysisKind::Code,
+        AnalysisKind::NotCode,
+    ];
+
+    let mut counts = [[0_usize; 3]; 3];
+    for addr in Address::all() {
+        if before[addr] != after[addr] {
+            counts[before[addr] as usize][after[addr] as usize] += 1;
+        }
+    }
+
+    let mut res = Vec::new();
+    for (from, row) in KINDS.into_iter().zip(counts) {
+        for (to, count) in KINDS.into_iter().zip(row) {
+            if count > 0 {
+                res.push((from, to, count));
+            }
+        }
+    }
+
+    res
+}
+
+/// `Pipeline` のビルダー。組み込みパスのみからなるパイプラインから始め、パスを挿入/削除/移動できる。
+///
+/// 同名のパスが複数ある場合、名前による指定は最初のものを指す。
+/// デフォルトでは op から dpcm までを繰り返すので、それらを取り除く場合は `fixpoint()` で
+/// 範囲を指定し直すか、`no_fixpoint()` で繰り返しを無効にすること。
+pub struct PipelineBuilder {
+    passes: Vec<Box<dyn AnalysisPass>>,
+    fixpoint: Option<(String, String)>,
+    errors: Vec<String>,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        let passes = BuiltinPass::ALL
+            .into_iter()
+            .map(|pass| Box::new(pass) as Box<dyn AnalysisPass>)
+            .collect();
+
+        Self {
+            passes,
+            fixpoint: Some((DEFAULT_FIXPOINT.0.to_owned(), DEFAULT_FIXPOINT.1.to_owned())),
+            errors: vec![],
+        }
+    }
+}
+
+impl Debug for PipelineBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PipelineBuilder")
+            .field(
+                "passes",
+                &self
+                    .passes
+                    .iter()
+                    .map(|pass| pass.name())
+                    .collect::<Vec<_>>(),
+            )
+            .field("fixpoint", &self.fixpoint)
+            .field("errors", &self.errors)
+            .finish()
+    }
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// パスを持たず、繰り返しも行わないパイプラインから始める。
+    pub fn empty() -> Self {
+        Self {
+            passes: vec![],
+            fixpoint: None,
+            errors: vec![],
+        }
+    }
+
+    /// パイプラインを作る。
+    ///
+    /// 存在しない名前のパスが指定されていた場合、繰り返し範囲の先頭が末尾より後にある場合はエラーを返す。
+    pub fn build(mut self) -> anyhow::Result<Pipeline> {
+        let fixpoint = match self.fixpoint.take() {
+            Some((first, last)) => {
+                let (first_i, last_i) = (self.position(&first), self.position(&last));
+                if let (Some(first_i), Some(last_i)) = (first_i, last_i) {
+                    ensure!(
+                        first_i <= last_i,
+                        "PipelineBuilder: fixpoint range '{first}'..='{last}' is reversed"
+                    );
+                    Some(first_i..=last_i)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        if let Some(e) = self.errors.first() {
+            bail!("PipelineBuilder: {e}");
+        }
+
+        Ok(Pipeline {
+            passes: self.passes,
+            fixpoint,
+        })
+    }
+
+    /// 末尾にパスを追加する。
+    pub fn push(mut self, pass: impl AnalysisPass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// 指定した名前のパスの直前にパスを挿入する。
+    pub fn insert_before(mut self, name: &str, pass: impl AnalysisPass + 'static) -> Self {
+        if let Some(i) = self.position(name) {
+            self.passes.insert(i, Box::new(pass));
+        }
+        self
+    }
+
+    /// 指定した名前のパスの直後にパスを挿入する。
+    pub fn insert_after(mut self, name: &str, pass: impl AnalysisPass + 'static) -> Self {
+        if let Some(i) = self.position(name) {
+            self.passes.insert(i + 1, Box::new(pass));
+        }
+        self
+    }
+
+    /// 指定した名前のパスを取り除く。
+    pub fn remove(mut self, name: &str) -> Self {
+        if let Some(i) = self.position(name) {
+            self.passes.remove(i);
+        }
+        self
+    }
+
+    /// 指定した名前のパスを、`target` という名前のパスの直前に移動する。
+    pub fn move_before(mut self, name: &str, target: &str) -> Self {
+        if let Some(pass) = self.take(name) {
+            if let Some(i) = self.position(target) {
+                self.passes.insert(i, pass);
+            }
+        }
+        self
+    }
+
+    /// 指定した名前のパスを、`target` という名前のパスの直後に移動する。
+    pub fn move_after(mut self, name: &str, target: &str) -> Self {
+        if let Some(pass) = self.take(name) {
+            if let Some(i) = self.position(target) {
+                self.passes.insert(i + 1, pass);
+            }
+        }
+        self
+    }
+
+    /// `first` から `last` までのパスを、`Analysis` が変化しなくなるまで繰り返し実行するようにする。
+    /// 範囲はパイプラインを作る時点のパスの並びで決まる。
+    pub fn fixpoint(mut self, first: &str, last: &str) -> Self {
+        self.fixpoint = Some((first.to_owned(), last.to_owned()));
+        self
+    }
+
+    /// 繰り返し実行を行わないようにする。
+    pub fn no_fixpoint(mut self) -> Self {
+        self.fixpoint = None;
+        self
+    }
+
+    fn position(&mut self, name: &str) -> Option<usize> {
+        let i = self.passes.iter().position(|pass| pass.name() == name);
+        if i.is_none() {
+            self.errors.push(format!("pass '{name}' not found"));
+        }
+        i
+    }
+
+    fn take(&mut self, name: &str) -> Option<Box<dyn AnalysisPass>> {
+        self.position(name).map(|i| self.passes.remove(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::assembly::Statement;
+
+    use crate::address::AddressRange;
+    use crate::annotation::Annotations;
+    use crate::bank::Bank;
+
+    use super::super::test_util::{input_builder, make_bank_body, make_input};
+    use super::super::ReturnKind;
+    use super::*;
+
+    /// 何もしないパス。
+    struct Nop(&'static str);
+
+    impl AnalysisPass for Nop {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn run(&self, _cx: &mut AnalysisContext<'_>) {}
+    }
+
+    fn names(builder: PipelineBuilder) -> Vec<String> {
+        builder
+            .build()
+            .unwrap()
+            .pass_names()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn test_builder_order() {
+        let builder = PipelineBuilder::empty()
+            .push(Nop("a"))
+            .push(Nop("b"))
+            .push(Nop("c"))
+            .insert_before("b", Nop("x"))
+            .insert_after("c", Nop("y"))
+            .remove("a")
+            .move_before("y", "x")
+            .move_after("b", "c");
+        assert_eq!(names(builder), ["y", "x", "c", "b"]);
+
+        let builder = PipelineBuilder::new()
+            .remove("blob")
+            .move_after("idiom", "local_label");
+        let expected: Vec<&str> = BuiltinPass::ALL
+            .iter()
+            .map(|pass| pass.name())
+            .filter(|&name| name != "blob" && name != "idiom")
+            .chain(["idiom"])
+            .collect();
+        assert_eq!(names(builder), expected);
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        assert!(PipelineBuilder::new()
+            .remove("nonexistent")
+            .build()
+            .is_err());
+        assert!(PipelineBuilder::new()
+            .insert_after("nonexistent", Nop("x"))
+            .build()
+            .is_err());
+        assert!(PipelineBuilder::new()
+            .fixpoint("op", "nonexistent")
+            .build()
+            .is_err());
+        assert!(PipelineBuilder::new()
+            .fixpoint("dpcm", "op")
+            .build()
+            .is_err());
+        // 繰り返し範囲内のパスを取り除いた場合も、範囲を指定し直さなければエラー。
+        assert!(PipelineBuilder::new().remove("op").build().is_err());
+        assert!(PipelineBuilder::new()
+            .remove("op")
+            .fixpoint("constant", "dpcm")
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_run_without_linear_sweep() {
+        let input = make_input(&[(0xC000, &[0x4C, 0x00, 0xC0])]);
+        let pipeline = PipelineBuilder::new()
+            .remove("linear_sweep")
+            .build()
+            .unwrap();
+        assert!(pipeline.run(&input, &AnalysisConfig::default()).is_err());
+    }
+
+    /// 指定したアドレスを `NotCode` とするパス。
+    struct MarkNotCode(Address);
+
+    impl AnalysisPass for MarkNotCode {
+        fn name(&self) -> &str {
+            "mark_not_code"
+        }
+
+        fn run(&self, cx: &mut AnalysisContext<'_>) {
+            cx.analysis_mut()[self.0] = AnalysisKind::NotCode;
+        }
+    }
+
+    #[test]
+    fn test_custom_pass_analysis() {
+        #[rustfmt::skip]
+        let main = [
+            0x4C, 0x00, 0xC0, // $C000: jmp $C000
+        ];
+        #[rustfmt::skip]
+        let sub = [
+            0xA9, 0x01, // $C010: lda #1 (どこからも参照されないが、linear sweep でコードとなる)
+            0x60,       // $C012: rts
+        ];
+        let input = make_input(&[(0xC000, &main), (0xC010, &sub)]);
+        let config = AnalysisConfig::default();
+
+        let stmt_at = |asm: &Assembly, addr: u16| {
+            let mut cur = asm.bank_addr();
+            asm.statements()
+                .iter()
+                .find(|stmt| {
+                    let found = cur == Address::new(addr);
+                    cur = cur.wrapping_add_unsigned(stmt.len());
+                    found
+                })
+                .cloned()
+        };
+
+        let asm = Pipeline::default().run(&input, &config).unwrap();
+        assert!(matches!(stmt_at(&asm, 0xC010), Some(Statement::Op(_))));
+
+        let asm = PipelineBuilder::new()
+            .insert_after("interrupt", MarkNotCode(Address::new(0xC010)))
+            .build()
+            .unwrap()
+            .run(&input, &config)
+            .unwrap();
+        assert_eq!(stmt_at(&asm, 0xC010), Some(Statement::Byte(0xA9)));
+    }
+
+    /// $C002 時点の A レジスタの値と、$C010 から始まるルーチンの判定結果。
+    type Probed = Option<(Option<u8>, ReturnKind)>;
+
+    /// 定数伝播およびサブルーチン解析の結果を記録するパス。
+    struct Probe(Rc<Cell<Probed>>);
+
+    impl AnalysisPass for Probe {
+        fn name(&self) -> &str {
+            "probe"
+        }
+
+        fn run(&self, cx: &mut AnalysisContext<'_>) {
+            let a = cx.consts().regs(Address::new(0xC002)).a();
+            let kind = cx.subroutines().get(Address::new(0xC010));
+            self.0.set(Some((a, kind)));
+        }
+    }
+
+    #[test]
+    fn test_custom_pass_consts() {
+        #[rustfmt::skip]
+        let main = [
+            0xA9, 0x05,       // $C000: lda #5
+            0x20, 0x10, 0xC0, // $C002: jsr $C010
+            0x4C, 0x05, 0xC0, // $C005: jmp $C005
+        ];
+        let input = make_input(&[(0xC000, &main), (0xC010, &[0x60])]);
+
+        let probed = Rc::new(Cell::new(None));
+        PipelineBuilder::new()
+            .push(Probe(Rc::clone(&probed)))
+            .build()
+            .unwrap()
+            .run(&input, &AnalysisConfig::default())
+            .unwrap();
+        assert_eq!(probed.get(), Some((Some(5), ReturnKind::Returns)));
+    }
+
+    #[test]
+    fn test_builtin_pass_invalidates_consts() {
+        #[rustfmt::skip]
+        let main = [
+            0xA9, 0x05,       // $C000: lda #5
+            0x20, 0x10, 0xC0, // $C002: jsr $C010
+            0x4C, 0x05, 0xC0, // $C005: jmp $C005
+        ];
+        #[rustfmt::skip]
+        let other = [
+            0xA9, 0x06,       // $C020: lda #6
+            0x4C, 0x02, 0xC0, // $C022: jmp $C002
+        ];
+        let body = make_bank_body(&[(0xC000, &main), (0xC010, &[0x60]), (0xC020, &other)]);
+        let mut annotations = Annotations::default();
+        annotations.mark_data(AddressRange::from_min_max(
+            Address::new(0xC020),
+            Address::new(0xC024),
+        ));
+        let input = input_builder(vec![Bank::new(Address::new(0xC000), body, true)])
+            .annotations(annotations)
+            .build()
+            .unwrap();
+        let config = AnalysisConfig::default();
+
+        // 注釈で $C020 がデータと判明する前に求めた結果は、`Analysis` の変更により破棄される。
+        let before = Rc::new(Cell::new(None));
+        let after = Rc::new(Cell::new(None));
+        super::super::test_util::run_passes(
+            &input,
+            &config,
+            &[
+                &Probe(Rc::clone(&before)),
+                &BuiltinPass::Annotation,
+                &Probe(Rc::clone(&after)),
+            ],
+        );
+        assert_eq!(before.get().unwrap().0, None);
+        assert_eq!(after.get().unwrap().0, Some(5));
+    }
+
+    #[test]
+    fn test_count_changes() {
+        let before = Analysis::default();
+        let mut after = Analysis::default();
+        after[Address::new(0xC000)] = AnalysisKind::Code;
+        after[Address::new(0xC001)] = AnalysisKind::NotCode;
+        after[Address::new(0xC003)] = AnalysisKind::Code;
+        assert_eq!(
+            count_changes(&before, &after),
+            [
+                (AnalysisKind::Unknown, AnalysisKind::Code, 2),
+                (AnalysisKind::Unknown, AnalysisKind::NotCode, 1),
+            ]
+        );
+
+        let mut after2 = after.clone();
+        after2[Address::new(0xC003)] = AnalysisKind::NotCode;
+        assert_eq!(
+            count_changes(&after, &after2),
+            [(AnalysisKind::Code, AnalysisKind::NotCode, 1)]
+        );
+        assert!(count_changes(&after, &after).is_empty());
+    }
+
+    /// 実行回数を数え、最初の `CHANGING_RUNS` 回だけ `Analysis` を変更するパス。
+    struct Counter(Rc<Cell<usize>>);
+
+    const CHANGING_RUNS: usize = 3;
+
+    impl AnalysisPass for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn run(&self, cx: &mut AnalysisContext<'_>) {
+            let runs = self.0.get() + 1;
+            self.0.set(runs);
+            if runs <= CHANGING_RUNS {
+                cx.analysis_mut()[Address::new(runs as u16)] = AnalysisKind::NotCode;
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_fixpoint() {
+        let input = make_input(&[(0xC000, &[0x4C, 0x00, 0xC0])]);
+
+        let runs = |config: &AnalysisConfig| {
+            let counter = Rc::new(Cell::new(0));
+            let passes: [Box<dyn AnalysisPass>; 1] = [Box::new(Counter(Rc::clone(&counter)))];
+            let mut cx = AnalysisContext::new(&input, config);
+            run_fixpoint(&passes, &mut cx);
+            counter.get()
+        };
+
+        // 変化しなくなったことを確認するため、変化する回数 + 1 回実行される。
+        assert_eq!(runs(&AnalysisConfig::default()), CHANGING_RUNS + 1);
+        let config: AnalysisConfig = toml::from_str("max_iterations = 2").unwrap();
+        assert_eq!(runs(&config), 2);
+    }
+
+    #[test]
+    fn test_fixpoint_op_after_flow() {
+        // $C010 は制御フロー解析で初めて Code となるので、そのオペランド $C011 (単体では nop) を
+        // NotCode とするのは 2 回目の命令単位の解析となる。
+        #[rustfmt::skip]
+        let main = [
+            0x4C, 0x10, 0xC0, // $C000: jmp $C010
+        ];
+        #[rustfmt::skip]
+        let sub = [
+            0xA9, 0xEA,       // $C010: lda #$EA
+            0xEA,             // $C012: nop
+            0x4C, 0x13, 0xC0, // $C013: jmp $C013
+        ];
+        let input = make_input(&[(0xC000, &main), (0xC010, &sub)]);
+
+        let run = |config: &AnalysisConfig| {
+            let builder = PipelineBuilder::new();
+            let first = builder
+                .passes
+                .iter()
+                .position(|pass| pass.name() == "op")
+                .unwrap();
+            let last = builder
+                .passes
+                .iter()
+                .position(|pass| pass.name() == "dpcm")
+                .unwrap();
+
+            let mut cx = AnalysisContext::new(&input, config);
+            run_passes(&builder.passes[..first], &mut cx);
+            run_fixpoint(&builder.passes[first..=last], &mut cx);
+
+            // 収束していれば、もう 1 回実行しても変化しない。
+            let before = cx.analysis().clone();
+            run_passes(&builder.passes[first..=last], &mut cx);
+            let converged = count_changes(&before, cx.analysis()).is_empty();
+
+            (
+                before[Address::new(0xC010)],
+                before[Address::new(0xC011)],
+                converged,
+            )
+        };
+
+        assert_eq!(
+            run(&AnalysisConfig::default()),
+            (AnalysisKind::Code, AnalysisKind::NotCode, true)
+        );
+
+        // 繰り返さなければ従来通り $C011 は未確定のまま残る。
+        let config: AnalysisConfig = toml::from_str("max_iterations = 1").unwrap();
+        assert_eq!(
+            run(&config),
+            (AnalysisKind::Code, AnalysisKind::Unknown, false)
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! 構造体の配列。
+//!
+//! マニフェストで宣言された構造体の配列を `NotCode` とし、先頭にラベルを振る。
+//! ポインタ型のフィールドの参照先がロードされているバンク内ならば、そこにもラベルを振る。
+//! 配列がコードと重なる場合は警告を出し、配列としては扱わない。
+//!
+//! 配列内の `Statement::Byte` は要素ごとに `Statement::Record` にまとめる。
+
+use log::warn;
+
+use crate::address::Address;
+use crate::assembly::{Label, Labels, Record, Statement};
+use crate::input::Input;
+use crate::layout::{FieldType, StructArray};
+
+use super::{Analysis, AnalysisKind};
+
+/// 有効な構造体の配列たちを返す (開始アドレスの昇順)。
+pub(super) fn analyze(
+    analysis: &mut Analysis,
+    labels: &mut Labels,
+    input: &Input,
+) -> Vec<StructArray> {
+    let memory = input.memory();
+    let bank = input.target_bank();
+
+    let mut res = Vec::<StructArray>::with_capacity(input.struct_arrays().len());
+
+    for array in input.struct_arrays() {
+        let range = array.addr_range();
+
+        if let Some(code) = range
+            .into_iter()
+            .find(|&addr| analysis[addr] == AnalysisKind::Code)
+        {
+            warn!(
+                "struct array '{}' {:#06X}-{:#06X} overlaps code at {:#06X}",
+                array.ty().name(),
+                range.min(),
+                range.max(),
+                code
+            );
+            continue;
+        }
+
+        analysis[range].fill(AnalysisKind::NotCode);
+        labels.set(range.min(), Label::new(false));
+
+        let body = bank.get_bytes(range).unwrap();
+        for (offset, field) in array.ty().field_offsets() {
+            if field.ty() != FieldType::Ptr {
+                continue;
+            }
+            for record in body.chunks_exact(array.ty().len().get()) {
+                let dst = Address::new(u16::from_le_bytes([record[offset], record[offset + 1]]));
+                if memory.find_bank_id(dst).is_some() {
+                    labels.set(dst, Label::new(false));
+                }
+            }
+        }
+
+        res.push(array.clone());
+    }
+
+    res
+}
+
+/// 構造体の配列の範囲内の `Statement::Byte` を要素ごとに `Statement::Record` にまとめる。
+///
+/// 要素の範囲が `Statement::Byte` だけで埋まっていなければ、その要素はまとめない。
+pub(super) fn merge_statements(
+    stmts: Vec<Statement>,
+    input: &Input,
+    arrays: &[StructArray],
+) -> Vec<Statement> {
+    let mut res = Vec::<Statement>::with_capacity(stmts.len());
+    let mut arrays = arrays.iter().peekable();
+    // 現在の要素内の Byte たち。要素の末尾に達したら要素全体を 1 つの文にまとめる。
+    let mut pending = Vec::<Statement>::new();
+
+    let mut addr = input.target_bank().addr();
+    for stmt in stmts {
+        let stmt_len = stmt.len();
+
+        // 終了した配列を読み飛ばす。
+        while arrays
+            .next_if(|array| array.addr_range().max() < addr)
+            .is_some()
+        {}
+
+        // addr が配列内にあれば、要素内でのオフセットを求める。
+        let offset = arrays
+            .peek()
+            .filter(|array| array.addr_range().contains_addr(addr))
+            .map(|array| usize::from(addr.get() - array.addr().get()) % array.ty().len().get());
+
+        match stmt {
+            Statement::Byte(_)
+                if offset.is_some_and(|offset| offset == 0 || !pending.is_empty()) =>
+            {
+                pending.push(stmt);
+                let ty = arrays.peek().unwrap().ty();
+                if pending.len() == ty.len().get() {
+                    let bytes: Vec<u8> =
+                        pending.drain(..).flat_map(|stmt| stmt.to_bytes()).collect();
+                    res.push(Statement::Record(Record::new(ty.clone(), bytes)));
+                }
+            }
+            stmt => {
+                res.append(&mut pending);
+                res.push(stmt);
+            }
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt_len) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+    res.append(&mut pending);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+
+    use crate::bank::Bank;
+    use crate::config::{AnalysisConfig, OutputConfig};
+    use crate::layout::{StructField, StructType};
+    use crate::output::assembly_lines;
+
+    use super::super::test_util::{input_builder, make_bank_body, make_input};
+    use super::*;
+
+    fn make_array(addr: u16, fields: &[(&str, FieldType)], count: usize) -> StructArray {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|&(name, ty)| StructField::new(name, ty))
+            .collect();
+        let ty = StructType::new("Entry", fields).unwrap();
+        StructArray::new(
+            Address::new(addr),
+            Arc::new(ty),
+            NonZeroUsize::new(count).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_records() {
+        #[rustfmt::skip]
+        let prog = [
+            0xAD, 0x00, 0xC1, // $C000: lda $C100
+            0x4C, 0x03, 0xC0, // $C003: jmp $C003
+        ];
+        #[rustfmt::skip]
+        let entries = [
+            0x05, 0x03, 0xC0, // $C100: x = 5, ptr = $C003
+            0x06, 0x20, 0xC0, // $C103: x = 6, ptr = $C020
+        ];
+        #[rustfmt::skip]
+        let words = [
+            0x34, 0x12, // $C110: w = $1234
+        ];
+        let body = make_bank_body(&[(0xC000, &prog), (0xC100, &entries), (0xC110, &words)]);
+        let input = input_builder(vec![Bank::new(Address::new(0xC000), body, true)])
+            .struct_array(make_array(
+                0xC100,
+                &[("x", FieldType::Byte), ("ptr", FieldType::Ptr)],
+                2,
+            ))
+            .struct_array(make_array(0xC110, &[("w", FieldType::Word)], 1))
+            .build()
+            .unwrap();
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        // ポインタの参照先にラベルが振られる。
+        assert!(asm.labels().get(Address::new(0xC020)).is_some());
+
+        let lines: Vec<(u16, String)> = assembly_lines(&asm, &OutputConfig::default())
+            .unwrap()
+            .into_iter()
+            .map(|(addr, line)| (addr.get(), line))
+            .filter(|&(addr, _)| [0xC100, 0xC103, 0xC110].contains(&addr))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (0xC100, "".to_owned()),
+                (0xC100, "@L_C100:".to_owned()),
+                (
+                    0xC100,
+                    "        .byte   $05, <@L_C003, >@L_C003 ; x, ptr".to_owned()
+                ),
+                (
+                    0xC103,
+                    "        .byte   $06, <@L_C020, >@L_C020 ; x, ptr".to_owned()
+                ),
+                (0xC110, "".to_owned()),
+                (0xC110, "@L_C110:".to_owned()),
+                (0xC110, "        .word   $1234 ; w".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_analyze_overlapping_code() {
+        let input = input_builder(vec![Bank::new(
+            Address::new(0xC000),
+            make_bank_body(&[(0xC000, &[0x60])]),
+            true,
+        )])
+        .struct_array(make_array(0xC010, &[("x", FieldType::Byte)], 4))
+        .struct_array(make_array(0xC020, &[("x", FieldType::Byte)], 4))
+        .build()
+        .unwrap();
+
+        let mut analysis = Analysis::default();
+        analysis[Address::new(0xC022)] = AnalysisKind::Code;
+        let mut labels = Labels::default();
+        let arrays = analyze(&mut analysis, &mut labels, &input);
+
+        // コードと重なる配列は無視する。
+        assert_eq!(arrays, [input.struct_arrays()[0].clone()]);
+        let range = arrays[0].addr_range();
+        assert!(analysis[range]
+            .iter()
+            .all(|&kind| kind == AnalysisKind::NotCode));
+        assert!(labels.get(Address::new(0xC010)).is_some());
+        assert!(labels.get(Address::new(0xC020)).is_none());
+        assert_eq!(analysis[Address::new(0xC020)], AnalysisKind::Unknown);
+    }
+
+    #[test]
+    fn test_merge_statements() {
+        let input = make_input(&[(0xC000, &[0x60])]);
+        let array = make_array(0xC001, &[("x", FieldType::Byte), ("y", FieldType::Byte)], 3);
+        let ty = array.ty().clone();
+        let bytes = |bs: &[u8]| bs.iter().copied().map(Statement::Byte).collect::<Vec<_>>();
+
+        // 2 番目の要素の途中 ($C004) に Byte 以外の文があれば、その要素はまとめず Byte のまま残す。
+        // 以降の要素は要素の境界から再びまとめる。
+        let mut stmts = bytes(&[0, 1, 2, 3]);
+        stmts.push(Statement::Dpcm(vec![4]));
+        stmts.extend(bytes(&[5, 6, 7]));
+
+        let mut expected = bytes(&[0]);
+        expected.push(Statement::Record(Record::new(ty.clone(), [1, 2])));
+        expected.extend(bytes(&[3]));
+        expected.push(Statement::Dpcm(vec![4]));
+        expected.push(Statement::Record(Record::new(ty, [5, 6])));
+        expected.extend(bytes(&[7]));
+        assert_eq!(
+            merge_statements(stmts, &input, std::slice::from_ref(&array)),
+            expected
+        );
+
+        // 文の配列の末尾で途切れた要素の Byte も失わない。
+        let stmts = bytes(&[0, 1, 2, 3]);
+        let mut res = merge_statements(stmts, &input, &[array]);
+        assert_eq!(res.pop(), Some(Statement::Byte(3)));
+    }
+}
@@ -0,0 +1,198 @@
+//! ルーチン範囲の解析。
+//!
+//! 逆アセンブル対象バンク内の各エントリポイントについて、
+//! そこから他のエントリポイントを経由せずに到達できる `Code` をそのルーチンの範囲とする。
+//! あわせてルーチン間の呼び出し関係と、ルーチンから制御が出ていく命令を求める。
+
+use std::collections::BTreeMap;
+
+use crate::address::{Address, ArrayByAddress};
+use crate::assembly::{Labels, Routine};
+use crate::input::Input;
+use crate::memory::OpSuccResolved;
+
+use super::constant::Constants;
+use super::{Analysis, AnalysisKind};
+
+pub(super) fn analyze(
+    analysis: &Analysis,
+    labels: &Labels,
+    input: &Input,
+    consts: &Constants,
+) -> Vec<Routine> {
+    let bank = input.target_bank();
+
+    let is_entry = |addr: Address| {
+        bank.contains_addr(addr)
+            && analysis[addr] == AnalysisKind::Code
+            && labels.get(addr).is_some_and(|label| label.is_entrypoint())
+    };
+
+    let entries: Vec<Address> = bank
+        .addr_range()
+        .into_iter()
+        .filter(|&addr| is_entry(addr))
+        .collect();
+
+    // 各エントリポイントについて範囲を求める。
+    let mut extents = BTreeMap::<Address, Extent>::new();
+    for &entry in &entries {
+        extents.insert(
+            entry,
+            trace_routine(analysis, input, consts, &is_entry, entry),
+        );
+    }
+
+    // 呼び出し関係を反転して呼び出し元を求める。
+    let mut callers = BTreeMap::<Address, Vec<Address>>::new();
+    for (&entry, extent) in &extents {
+        for &callee in &extent.callees {
+            if extents.contains_key(&callee) {
+                callers.entry(callee).or_default().push(entry);
+            }
+        }
+    }
+
+    extents
+        .into_iter()
+        .map(|(entry, extent)| {
+            Routine::new(
+                entry,
+                extent.len,
+                extent.op_addrs,
+                callers.remove(&entry).unwrap_or_default(),
+                extent.callees,
+                extent.exits,
+            )
+        })
+        .collect()
+}
+
+/// 1 つのルーチンの範囲。
+#[derive(Debug, Default)]
+struct Extent {
+    len: usize,
+    op_addrs: Vec<Address>,
+    callees: Vec<Address>,
+    exits: Vec<Address>,
+}
+
+/// `entry` から他のエントリポイントを経由せずに到達できる `Code` を辿る。
+fn trace_routine(
+    analysis: &Analysis,
+    input: &Input,
+    consts: &Constants,
+    is_entry: &impl Fn(Address) -> bool,
+    entry: Address,
+) -> Extent {
+    let memory = input.memory();
+    let bank = input.target_bank();
+
+    let mut extent = Extent::default();
+    let mut visited = ArrayByAddress::<bool>::default();
+    let mut stack = vec![entry];
+
+    while let Some(addr) = stack.pop() {
+        if visited[addr] {
+            continue;
+        }
+        visited[addr] = true;
+
+        let Ok((op, _)) = memory.fetch_op(addr) else {
+            continue;
+        };
+        extent.len += op.len().get();
+        extent.op_addrs.push(addr);
+
+        // 後続アドレスがバンク内の Code であり、かつ他のエントリポイントでなければルーチンの一部とする。
+        // 他のエントリポイントへ移る場合、それを呼び出し先とし、現在の命令を出口とする。
+        macro_rules! follow {
+            ($dst:expr) => {{
+                let dst: Address = $dst;
+                if dst != entry && is_entry(dst) {
+                    extent.callees.push(dst);
+                    extent.exits.push(addr);
+                } else if bank.contains_addr(dst) && analysis[dst] == AnalysisKind::Code {
+                    stack.push(dst);
+                }
+            }};
+        }
+
+        match memory.resolve_op_succ(addr, op.succ()) {
+            OpSuccResolved::Normal(dst) => follow!(dst),
+            OpSuccResolved::Branch { taken, not_taken } => {
+                follow!(taken);
+                // 常に分岐する分岐命令の直後はルーチンの一部とはみなさない。
+                if !consts.is_always_taken(addr, op) {
+                    follow!(not_taken);
+                }
+            }
+            OpSuccResolved::Jsr(dst) => {
+                extent.callees.push(dst);
+                // 直後が Code なら呼び出し先から戻ってくるものとみなす。
+                follow!(addr.wrapping_add_unsigned(op.len()));
+            }
+            OpSuccResolved::JmpAbs(dst) => {
+                if bank.contains_addr(dst) {
+                    follow!(dst);
+                } else {
+                    // バンク外のルーチンへの末尾呼び出し。
+                    extent.callees.push(dst);
+                    extent.exits.push(addr);
+                }
+            }
+            OpSuccResolved::Brk(_)
+            | OpSuccResolved::Kil
+            | OpSuccResolved::Rti
+            | OpSuccResolved::Rts
+            | OpSuccResolved::JmpInd(_) => extent.exits.push(addr),
+        }
+    }
+
+    extent
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AnalysisConfig;
+
+    use super::super::test_util::make_shared_tail_input;
+    use super::*;
+
+    #[test]
+    fn test_routines() {
+        let input = make_shared_tail_input();
+        let asm = super::super::analyze(&input, &AnalysisConfig::default());
+
+        let addrs = |addrs: &[u16]| addrs.iter().copied().map(Address::new).collect::<Vec<_>>();
+
+        assert_eq!(
+            asm.routines()
+                .iter()
+                .map(Routine::entry)
+                .collect::<Vec<_>>(),
+            addrs(&[0xC000, 0xC010, 0xC020])
+        );
+
+        let main = asm.find_routine(Address::new(0xC000)).unwrap();
+        assert_eq!(main.len(), 9);
+        assert_eq!(main.op_addrs(), addrs(&[0xC000, 0xC003, 0xC006]));
+        assert!(main.callers().is_empty());
+        assert_eq!(main.callees(), addrs(&[0xC010, 0xC020]));
+        assert!(main.exits().is_empty());
+
+        let sub1 = asm.find_routine(Address::new(0xC010)).unwrap();
+        assert_eq!(sub1.len(), 6);
+        assert_eq!(sub1.op_addrs(), addrs(&[0xC010, 0xC012, 0xC013, 0xC015]));
+        assert_eq!(sub1.callers(), addrs(&[0xC000]));
+        assert_eq!(sub1.exits(), addrs(&[0xC015]));
+
+        // 他のエントリポイントを経由しない末尾 ($C015) は両方のルーチンに含まれる。
+        let sub2 = asm.find_routine(Address::new(0xC020)).unwrap();
+        assert_eq!(sub2.len(), 6);
+        assert_eq!(sub2.op_addrs(), addrs(&[0xC015, 0xC020, 0xC022]));
+        assert_eq!(sub2.callers(), addrs(&[0xC000]));
+        assert!(sub2.callees().is_empty());
+        assert_eq!(sub2.exits(), addrs(&[0xC015]));
+    }
+}
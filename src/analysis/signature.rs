@@ -0,0 +1,244 @@
+//! シグネチャによる既知のルーチンの認識。
+//!
+//! 逆アセンブル対象バンク内の命令列がシグネチャのパターンにマッチしたら、その先頭に
+//! エントリポイントラベルを振ってルーチン名を設定し、シグネチャが指定する命令のオペランドの参照先
+//! (変数やデータテーブル) にも名前を設定する。
+//!
+//! パターンは命令の区切りと一致し、かつ全て命令 (`Statement::Op`) でなければマッチしない。
+//! 既に名前の付いたアドレス、および既に使われている名前は上書きしない
+//! (利用者のシグネチャ、組み込みのシグネチャの順に、先にマッチしたものが優先される)。
+
+use std::collections::HashSet;
+
+use crate::address::Address;
+use crate::assembly::{Label, Labels, Statement};
+use crate::config::AnalysisConfig;
+use crate::input::Input;
+use crate::op::{Op, Operand};
+use crate::signature::{builtin_signatures, Signature};
+
+pub(super) fn analyze(
+    labels: &mut Labels,
+    input: &Input,
+    config: &AnalysisConfig,
+    stmts: &[Statement],
+) {
+    let mut sigs = input.signatures().to_vec();
+    if config.builtin_signatures() {
+        sigs.extend(builtin_signatures());
+    }
+    if sigs.is_empty() {
+        return;
+    }
+
+    // 逆アセンブル対象バンク内の命令を列挙する。
+    let mut ops = Vec::<(Address, Op)>::new();
+    let mut addr = input.target_bank().addr();
+    for stmt in stmts {
+        if let Statement::Op(op) = *stmt {
+            ops.push((addr, op));
+        }
+        addr = addr.wrapping_add_unsigned(stmt.len().get());
+    }
+
+    let mut used_names: HashSet<String> = Address::all()
+        .filter_map(|addr| labels.get(addr).and_then(Label::name))
+        .map(str::to_owned)
+        .collect();
+
+    for i in 0..ops.len() {
+        let (addr, op) = ops[i];
+        if labels.get(addr).is_some_and(|label| label.name().is_some()) {
+            continue;
+        }
+
+        let first_byte = op.to_bytes()[0];
+        for sig in sigs.iter().filter(|sig| sig.first_byte() == first_byte) {
+            if used_names.contains(sig.name()) {
+                continue;
+            }
+            let Some(matched) = match_ops(&ops[i..], sig) else {
+                continue;
+            };
+
+            labels.set(addr, Label::new(true));
+            labels.set_name(addr, sig.name());
+            used_names.insert(sig.name().to_owned());
+
+            for r in sig.refs() {
+                let Some(&(_, op)) = matched
+                    .iter()
+                    .find(|&&(op_addr, _)| usize::from(op_addr) - usize::from(addr) == r.offset())
+                else {
+                    continue;
+                };
+                let Some(dst) = operand_addr(op) else {
+                    continue;
+                };
+                if used_names.contains(r.name())
+                    || labels.get(dst).is_some_and(|label| label.name().is_some())
+                {
+                    continue;
+                }
+                labels.set(dst, Label::new(false));
+                labels.set_name(dst, r.name());
+                used_names.insert(r.name().to_owned());
+            }
+
+            break;
+        }
+    }
+}
+
+/// 連続する命令列の先頭がシグネチャにマッチすれば、マッチした命令たちを返す。
+fn match_ops<'a>(ops: &'a [(Address, Op)], sig: &Signature) -> Option<&'a [(Address, Op)]> {
+    let start = ops[0].0;
+
+    let mut bytes = Vec::<u8>::with_capacity(sig.pattern().len());
+    for (i, &(addr, op)) in ops.iter().enumerate() {
+        if usize::from(addr) != usize::from(start) + bytes.len() {
+            return None;
+        }
+        bytes.extend(op.to_bytes());
+
+        if bytes.len() >= sig.pattern().len() {
+            let matched = bytes.len() == sig.pattern().len() && sig.matches(&bytes);
+            return matched.then_some(&ops[..=i]);
+        }
+    }
+
+    None
+}
+
+/// 命令のオペランドが指すアドレス (インデックス付きのものはベースアドレス) を返す。
+fn operand_addr(op: Op) -> Option<Address> {
+    match op.operand() {
+        Operand::Zp(zp)
+        | Operand::ZpX(zp)
+        | Operand::ZpY(zp)
+        | Operand::IndX(zp)
+        | Operand::IndY(zp) => Some(Address::from(zp)),
+        Operand::Abs(abs) | Operand::AbsX(abs) | Operand::AbsY(abs) | Operand::Ind(abs) => {
+            Some(abs)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::annotation::Annotations;
+    use crate::assembly::Assembly;
+    use crate::bank::Bank;
+
+    use super::super::test_util::{input_builder, make_bank_body};
+    use super::*;
+
+    /// ReadJoypad (zp $F0 に読み取る)。
+    #[rustfmt::skip]
+    const READ_JOYPAD: [u8; 20] = [
+        0xA9, 0x01,       // lda #1
+        0x8D, 0x16, 0x40, // sta $4016
+        0x85, 0xF0,       // sta $F0
+        0x4A,             // lsr
+        0x8D, 0x16, 0x40, // sta $4016
+        0xAD, 0x16, 0x40, // lda $4016
+        0x4A,             // lsr
+        0x26, 0xF0,       // rol $F0
+        0x90, 0xF8,       // bcc -8
+        0x60,             // rts
+    ];
+
+    /// bit $2002 による WaitVBlank。
+    #[rustfmt::skip]
+    const WAIT_VBLANK_BIT: [u8; 6] = [
+        0x2C, 0x02, 0x20, // bit $2002
+        0x10, 0xFB,       // bpl -5
+        0x60,             // rts
+    ];
+
+    /// lda $2002 による WaitVBlank。
+    #[rustfmt::skip]
+    const WAIT_VBLANK_LDA: [u8; 6] = [
+        0xAD, 0x02, 0x20, // lda $2002
+        0x10, 0xFB,       // bpl -5
+        0x60,             // rts
+    ];
+
+    #[rustfmt::skip]
+    const MAIN: [u8; 15] = [
+        0x20, 0x10, 0xC0, // $C000: jsr $C010
+        0x20, 0x30, 0xC0, // $C003: jsr $C030
+        0x20, 0x40, 0xC0, // $C006: jsr $C040
+        0x20, 0x50, 0xC0, // $C009: jsr $C050
+        0x4C, 0x00, 0xC0, // $C00C: jmp $C000
+    ];
+
+    /// WaitVBlank のパターンを命令の途中から含む。
+    #[rustfmt::skip]
+    const MISALIGNED: [u8; 7] = [
+        0xAD, 0x2C, 0x02, // $C050: lda $022C
+        0x20, 0x10, 0xFB, // $C053: jsr $FB10
+        0x60,             // $C056: rts
+    ];
+
+    fn analyze_with(annotations: Annotations) -> Assembly {
+        let body = make_bank_body(&[
+            (0xC000, &MAIN),
+            (0xC010, &READ_JOYPAD),
+            (0xC030, &WAIT_VBLANK_BIT),
+            (0xC040, &WAIT_VBLANK_LDA),
+            (0xC050, &MISALIGNED),
+            (0xFB10, &[0x60]),
+        ]);
+        let input = input_builder(vec![Bank::new(Address::new(0xC000), body, true)])
+            .annotations(annotations)
+            .build()
+            .unwrap();
+
+        super::super::analyze(&input, &AnalysisConfig::default())
+    }
+
+    fn name_at(asm: &Assembly, addr: u16) -> Option<&str> {
+        asm.labels().get(Address::new(addr)).and_then(Label::name)
+    }
+
+    #[test]
+    fn test_builtin_signatures() {
+        let asm = analyze_with(Annotations::default());
+
+        assert_eq!(name_at(&asm, 0xC010), Some("ReadJoypad"));
+        assert_eq!(name_at(&asm, 0x00F0), Some("joy1_state"));
+        assert_eq!(name_at(&asm, 0xC030), Some("WaitVBlank"));
+        // 既に使われている名前は付けない。
+        assert_eq!(name_at(&asm, 0xC040), None);
+        // 命令の区切りと一致しないものはマッチしない。
+        assert!(asm
+            .routines()
+            .iter()
+            .any(|routine| routine.entry() == Address::new(0xC050)));
+        assert_eq!(name_at(&asm, 0xC050), None);
+        assert!(asm.labels().get(Address::new(0xC051)).is_none());
+    }
+
+    #[test]
+    fn test_named_addresses_are_kept() {
+        let mut annotations = Annotations::default();
+        annotations
+            .set_label(Address::new(0xC030), "VSync")
+            .unwrap();
+        annotations.set_label(Address::new(0x00F0), "pad").unwrap();
+        annotations
+            .set_label(Address::new(0xC100), "joy1_state")
+            .unwrap();
+        let asm = analyze_with(annotations);
+
+        // 名前の付いたアドレスは上書きしないので、次にマッチしたものが名前を得る。
+        assert_eq!(name_at(&asm, 0xC030), Some("VSync"));
+        assert_eq!(name_at(&asm, 0xC040), Some("WaitVBlank"));
+        // ルーチン名が付いても、参照先の名前は既存のものを優先する。
+        assert_eq!(name_at(&asm, 0xC010), Some("ReadJoypad"));
+        assert_eq!(name_at(&asm, 0x00F0), Some("pad"));
+        assert_eq!(name_at(&asm, 0xC100), Some("joy1_state"));
+    }
+}
@@ -0,0 +1,259 @@
+//! 分割ポインタテーブル。
+//!
+//! マニフェストで宣言されたもの、およびコード中の以下のような命令列から検出したものを対象とする:
+//!
+//! ```text
+//! lda lo,x    ; lo,y でもよい (2 つの lda で揃っていること)
+//! sta ptr
+//! lda hi,x
+//! sta ptr+1
+//! ```
+//!
+//! 2 つの配列は隣接していることが多いので、検出したものについては開始アドレスの差を要素数とする。
+//! (差が 1-256 でなければ、あるいは配列が逆アセンブル対象バンクに収まらなければ無視する)
+//!
+//! テーブルは `NotCode` とし、両配列の先頭および各要素の参照先 (ロードされているバンク内のもの) にラベルを振る。
+//! テーブルがコードや構造体の配列、他のテーブルと重なる場合は無視する (宣言されたものについては警告を出す)。
+//!
+//! テーブル内の `Statement::Byte` は配列ごとに `Statement::LoBytes`, `Statement::HiBytes` にまとめる。
+
+use std::num::NonZeroUsize;
+
+use log::warn;
+
+use crate::address::{Address, AddressRange};
+use crate::assembly::{Label, Labels, Statement};
+use crate::input::Input;
+use crate::layout::SplitTable;
+use crate::op::Op;
+
+use super::constant::Constants;
+use super::{Analysis, AnalysisKind};
+
+/// 有効な分割ポインタテーブルたちを返す。
+pub(super) fn analyze(
+    analysis: &mut Analysis,
+    labels: &mut Labels,
+    input: &Input,
+    consts: &Constants,
+) -> Vec<SplitTable> {
+    let memory = input.memory();
+    let bank = input.target_bank();
+
+    let mut res = Vec::<SplitTable>::new();
+
+    let declared = input.split_tables().iter().map(|&table| (table, true));
+    let detected = detect(analysis, input, consts)
+        .into_iter()
+        .map(|table| (table, false));
+    for (table, declared) in declared.chain(detected) {
+        let ranges = [table.lo_range(), table.hi_range()];
+
+        let code = ranges
+            .into_iter()
+            .flatten()
+            .find(|&addr| analysis[addr] == AnalysisKind::Code);
+        let intersects = |range: AddressRange| ranges.iter().any(|r| r.intersects(range));
+        let overlapped = input
+            .struct_arrays()
+            .iter()
+            .any(|array| intersects(array.addr_range()))
+            || res
+                .iter()
+                .any(|other| intersects(other.lo_range()) || intersects(other.hi_range()));
+
+        if code.is_some() || overlapped {
+            if declared {
+                warn!(
+                    "split table (lo={:#06X}, hi={:#06X}) overlaps code or other data",
+                    table.lo(),
+                    table.hi()
+                );
+            }
+            continue;
+        }
+
+        for range in ranges {
+            analysis[range].fill(AnalysisKind::NotCode);
+            labels.set(range.min(), Label::new(false));
+        }
+
+        let los = bank.get_bytes(table.lo_range()).unwrap();
+        let his = bank.get_bytes(table.hi_range()).unwrap();
+        for (&lo, &hi) in los.iter().zip(his) {
+            let dst = Address::new(u16::from_le_bytes([lo, hi]));
+            if memory.find_bank_id(dst).is_some() {
+                labels.set(dst, Label::new(false));
+            }
+        }
+
+        res.push(table);
+    }
+
+    res
+}
+
+/// コード中の命令列から分割ポインタテーブルを検出する。
+fn detect(analysis: &Analysis, input: &Input, consts: &Constants) -> Vec<SplitTable> {
+    let memory = input.memory();
+    let bank = input.target_bank();
+
+    // 連続する 4 命令を取得する。全てコードでなければならない。
+    let fetch_ops = |site: Address| -> Option<[Op; 4]> {
+        let mut ops = Vec::<Op>::with_capacity(4);
+        let mut addr = site;
+        for _ in 0..4 {
+            if analysis[addr] != AnalysisKind::Code {
+                return None;
+            }
+            let (op, _) = memory.fetch_op(addr).ok()?;
+            ops.push(op);
+            addr = addr.checked_add_unsigned(op.len())?;
+        }
+        ops.try_into().ok()
+    };
+
+    let mut res = Vec::<SplitTable>::new();
+
+    for site in Address::all() {
+        let Some(ops) = fetch_ops(site) else {
+            continue;
+        };
+
+        let (first, second) = match ops[..2] {
+            [Op::LdaAbsX(first), _] => match ops[2] {
+                Op::LdaAbsX(second) => (first, second),
+                _ => continue,
+            },
+            [Op::LdaAbsY(first), _] => match ops[2] {
+                Op::LdaAbsY(second) => (first, second),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let (Some(ptr_first), Some(ptr_second)) = (store_dst(ops[1]), store_dst(ops[3])) else {
+            continue;
+        };
+
+        // 上位バイトを先に読む場合もある。
+        let (lo, hi) = if ptr_first.checked_add_unsigned(1_usize) == Some(ptr_second) {
+            (first, second)
+        } else if ptr_second.checked_add_unsigned(1_usize) == Some(ptr_first) {
+            (second, first)
+        } else {
+            continue;
+        };
+
+        let target = Some(input.target_bank_id());
+        if consts.resolve_bank_id(input, site, lo) != target
+            || consts.resolve_bank_id(input, site, hi) != target
+        {
+            continue;
+        }
+
+        let dist = usize::from(lo).abs_diff(usize::from(hi));
+        let Some(count) = NonZeroUsize::new(dist).filter(|count| count.get() <= 0x100) else {
+            continue;
+        };
+        let Ok(table) = SplitTable::new(lo, hi, count) else {
+            continue;
+        };
+        if !(bank.contains_range(table.lo_range()) && bank.contains_range(table.hi_range())) {
+            continue;
+        }
+
+        if !res.contains(&table) {
+            res.push(table);
+        }
+    }
+
+    res
+}
+
+/// ポインタへの書き込み命令なら書き込み先を返す。
+fn store_dst(op: Op) -> Option<Address> {
+    match op {
+        Op::StaZp(zp) => Some(Address::from(zp)),
+        Op::StaAbs(abs) => Some(abs),
+        _ => None,
+    }
+}
+
+/// 分割ポインタテーブルの範囲内の `Statement::Byte` を配列ごとに `Statement::LoBytes`, `Statement::HiBytes` にまとめる。
+pub(super) fn merge_statements(
+    stmts: Vec<Statement>,
+    input: &Input,
+    tables: &[SplitTable],
+) -> Vec<Statement> {
+    let bank = input.target_bank();
+
+    // (配列のアドレス範囲, 上位バイト配列か, 要素たち) を開始アドレスの昇順に並べる。
+    let mut arrays: Vec<(AddressRange, bool, Vec<Address>)> = tables
+        .iter()
+        .flat_map(|table| {
+            let los = bank.get_bytes(table.lo_range()).unwrap();
+            let his = bank.get_bytes(table.hi_range()).unwrap();
+            let addrs: Vec<_> = los
+                .iter()
+                .zip(his)
+                .map(|(&lo, &hi)| Address::new(u16::from_le_bytes([lo, hi])))
+                .collect();
+            [
+                (table.lo_range(), false, addrs.clone()),
+                (table.hi_range(), true, addrs),
+            ]
+        })
+        .collect();
+    arrays.sort_unstable_by_key(|(range, _, _)| range.min());
+
+    let mut res = Vec::<Statement>::with_capacity(stmts.len());
+    let mut arrays = arrays.into_iter().peekable();
+    // 現在の配列内の Byte たち。配列の末尾に達したら配列全体を 1 つの文にまとめる。
+    let mut pending = Vec::<Statement>::new();
+
+    // 配列の範囲が Byte だけで埋まっていなければ、その配列はまとめない。
+    let mut addr = bank.addr();
+    for stmt in stmts {
+        let stmt_len = stmt.len();
+
+        // 終了した配列を読み飛ばす。
+        while arrays.next_if(|(range, _, _)| range.max() < addr).is_some() {}
+
+        match stmt {
+            Statement::Byte(_)
+                if arrays.peek().is_some_and(|(range, _, _)| {
+                    range.contains_addr(addr) && (addr == range.min() || !pending.is_empty())
+                }) =>
+            {
+                pending.push(stmt);
+                if arrays.peek().unwrap().0.max() == addr {
+                    let (_, hi, addrs) = arrays.next().unwrap();
+                    pending.clear();
+                    res.push(if hi {
+                        Statement::HiBytes(addrs)
+                    } else {
+                        Statement::LoBytes(addrs)
+                    });
+                }
+            }
+            stmt => {
+                if arrays
+                    .peek()
+                    .is_some_and(|(range, _, _)| range.contains_addr(addr))
+                {
+                    arrays.next();
+                }
+                res.append(&mut pending);
+                res.push(stmt);
+            }
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt_len) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+    res.append(&mut pending);
+
+    res
+}
@@ -0,0 +1,333 @@
+//! サブルーチン解析。
+//!
+//! jsr 命令の飛び先となりうる各ルーチンについて、呼び出し元へ正常に戻るか、決して戻らないか、
+//! あるいはスタックを操作する(リターンアドレスを捨てる、rts を間接ジャンプとして使う、など)かを判定する。
+//!
+//! 結果は制御フロー解析において jsr 命令の直後への制御フローを認めるかどうかの判断に使われる。
+
+use std::collections::HashSet;
+
+use log::warn;
+
+use crate::address::{Address, ArrayByAddress};
+use crate::input::Input;
+use crate::memory::{FetchOpError, OpSuccResolved};
+use crate::op::Op;
+
+use super::constant::Constants;
+use super::{Analysis, AnalysisKind};
+
+/// ルーチン内で追跡するスタック深さの上限。これを超える push を行うルーチンは判定不能とする。
+const STACK_DEPTH_MAX: u8 = 8;
+
+/// 全体の反復回数の上限。
+const ITERATION_MAX: usize = 32;
+
+/// 論理アドレス空間全体のサブルーチン解析結果。
+/// jsr 命令の飛び先となりうるアドレスについてのみ値を持つ。
+#[derive(Debug, Default)]
+pub struct Subroutines(ArrayByAddress<Option<ReturnKind>>);
+
+impl Subroutines {
+    /// 指定したアドレスから始まるルーチンの判定結果を返す。
+    /// 解析対象でないアドレスについては `ReturnKind::Unknown` を返す。
+    pub fn get(&self, entry: Address) -> ReturnKind {
+        self.0[entry].unwrap_or(ReturnKind::Unknown)
+    }
+
+    /// 指定したアドレスから始まるルーチンが呼び出し元へ正常に戻ると判定されたかどうかを返す。
+    /// (`ReturnKind::Returns` の場合のみ `true`)
+    pub fn returns(&self, entry: Address) -> bool {
+        self.get(entry) == ReturnKind::Returns
+    }
+}
+
+/// あるルーチンが呼び出し元へどのように戻るか。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReturnKind {
+    /// rts により呼び出し元へ正常に戻る。
+    ///
+    /// 戻る経路が存在し、他の経路は全て決して戻らない (無限ループなど) ことを意味する。
+    /// 制御フロー解析と同様、条件分岐はどちらの向きにも実行されうるとみなす。
+    Returns,
+    /// rts により呼び出し元へ戻る経路があるが、判定できない経路もある。
+    /// 呼び出し元へ戻るかどうかは確定できない。
+    MayReturn,
+    /// 決して呼び出し元へ戻らない。
+    NoReturn,
+    /// スタックを操作する。呼び出し元へ戻るかどうかは不明。
+    StackManip,
+    /// 判定できない (間接ジャンプや別バンクへの移動を含む、など)。
+    Unknown,
+}
+
+pub(super) fn analyze(analysis: &Analysis, input: &Input, consts: &Constants) -> Subroutines {
+    let memory = input.memory();
+
+    // NotCode でない jsr 命令の飛び先を全て解析対象とする。
+    let mut entries = Vec::<Address>::new();
+    let mut subs = Subroutines::default();
+    for addr in Address::all() {
+        if analysis[addr] == AnalysisKind::NotCode {
+            continue;
+        }
+        if let Ok((Op::Jsr(dst), _)) = memory.fetch_op(addr) {
+            if subs.0[dst].is_none() {
+                subs.0[dst] = Some(ReturnKind::NoReturn);
+                entries.push(dst);
+            }
+        }
+    }
+
+    // 最初は全てのルーチンが戻らないものとし、変化がなくなるまで判定を繰り返す。
+    // (再帰呼び出しのみで戻るルーチンなどを正しく NoReturn とするため)
+    let mut converged = false;
+    for _ in 0..ITERATION_MAX {
+        let mut changed = false;
+
+        for &entry in &entries {
+            let kind = analyze_routine(analysis, input, consts, &subs, entry);
+            if subs.0[entry] != Some(kind) {
+                subs.0[entry] = Some(kind);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            converged = true;
+            break;
+        }
+    }
+    if !converged {
+        warn!("subroutine analysis did not converge in {ITERATION_MAX} iterations");
+    }
+
+    subs
+}
+
+/// 逆アセンブル対象バンク内の `Code` である jsr 命令のうち、呼び出し先が決して戻らないか、
+/// スタックを操作すると判定されたもののアドレスを列挙する。
+pub(super) fn collect_no_return_calls(
+    subs: &Subroutines,
+    analysis: &Analysis,
+    input: &Input,
+) -> Vec<Address> {
+    let memory = input.memory();
+
+    input
+        .target_bank()
+        .addr_range()
+        .into_iter()
+        .filter(|&site| analysis[site] == AnalysisKind::Code)
+        .filter(|&site| match memory.fetch_op(site) {
+            Ok((Op::Jsr(dst), _)) => {
+                matches!(subs.get(dst), ReturnKind::NoReturn | ReturnKind::StackManip)
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+/// `entry` から始まるルーチンを(現時点の解析結果を用いて)判定する。
+fn analyze_routine(
+    analysis: &Analysis,
+    input: &Input,
+    consts: &Constants,
+    subs: &Subroutines,
+    entry: Address,
+) -> ReturnKind {
+    let memory = input.memory();
+
+    let mut returns = false;
+    let mut manip = false;
+    let mut unknown = false;
+
+    // (アドレス, スタック深さ) を状態として探索する。
+    let mut visited = HashSet::<(Address, u8)>::new();
+    let mut stack = vec![(entry, 0_u8)];
+
+    while let Some((addr, depth)) = stack.pop() {
+        if !visited.insert((addr, depth)) {
+            continue;
+        }
+
+        // NotCode を経由する経路は実際には実行されないので無視する。
+        if analysis[addr] == AnalysisKind::NotCode {
+            continue;
+        }
+
+        // 命令が尻切れになる経路も同様に無視する。
+        // ロードされていないアドレス(RAM など)に入る経路は判定不能とする。
+        let (op, _) = match memory.fetch_op(addr) {
+            Ok(x) => x,
+            Err(FetchOpError::Nothing) => {
+                unknown = true;
+                continue;
+            }
+            Err(FetchOpError::Incomplete(_)) => continue,
+        };
+
+        // 飛び先がロードされているバンク上に確定できれば探索を続け、さもなくば判定不能とする。
+        macro_rules! push {
+            ($dst:expr, $depth:expr) => {{
+                let dst: Address = $dst;
+                if consts.resolve_bank_id(input, addr, dst).is_some() {
+                    stack.push((dst, $depth));
+                } else {
+                    unknown = true;
+                }
+            }};
+        }
+
+        match memory.resolve_op_succ(addr, op.succ()) {
+            OpSuccResolved::Normal(dst) => match op {
+                Op::Pha | Op::Php => {
+                    if depth == STACK_DEPTH_MAX {
+                        unknown = true;
+                    } else {
+                        push!(dst, depth + 1);
+                    }
+                }
+                // スタック深さ 0 での pop はリターンアドレスを捨てることを意味する。
+                Op::Pla | Op::Plp => {
+                    if depth == 0 {
+                        manip = true;
+                    } else {
+                        push!(dst, depth - 1);
+                    }
+                }
+                Op::Tsx | Op::Txs => manip = true,
+                _ => push!(dst, depth),
+            },
+            OpSuccResolved::Brk(_) => unknown = true,
+            OpSuccResolved::Kil => {}
+            OpSuccResolved::Branch { taken, not_taken } => {
+                // 分岐結果が判明していれば、実際に辿る側のみを考える。
+                let taken_known = consts.regs(addr).branch_taken(op);
+                if taken_known != Some(false) {
+                    push!(taken, depth);
+                }
+                if taken_known != Some(true) {
+                    push!(not_taken, depth);
+                }
+            }
+            OpSuccResolved::Jsr(dst) => match subs.get(dst) {
+                ReturnKind::Returns => push!(addr.wrapping_add_unsigned(op.len()), depth),
+                // 戻った場合の経路は辿るが、このルーチン自体の判定も不確定となる。
+                ReturnKind::MayReturn => {
+                    unknown = true;
+                    push!(addr.wrapping_add_unsigned(op.len()), depth);
+                }
+                ReturnKind::NoReturn => {}
+                ReturnKind::StackManip | ReturnKind::Unknown => unknown = true,
+            },
+            OpSuccResolved::Rti => manip = true,
+            // スタック深さ 0 でない rts は間接ジャンプとして使われている。
+            OpSuccResolved::Rts => {
+                if depth == 0 {
+                    returns = true;
+                } else {
+                    manip = true;
+                }
+            }
+            OpSuccResolved::JmpAbs(dst) => push!(dst, depth),
+            OpSuccResolved::JmpInd(dst) => match dst {
+                Some(dst) => push!(dst, depth),
+                None => unknown = true,
+            },
+        }
+    }
+
+    if manip {
+        ReturnKind::StackManip
+    } else if returns && !unknown {
+        ReturnKind::Returns
+    } else if returns {
+        ReturnKind::MayReturn
+    } else if unknown {
+        ReturnKind::Unknown
+    } else {
+        ReturnKind::NoReturn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::make_input;
+    use super::*;
+
+    /// $C000 から各ルーチンを jsr で呼ぶプログラムを解析し、各ルーチンの判定結果を返す。
+    fn analyze_routines(routines: &[(u16, &[u8])]) -> Vec<ReturnKind> {
+        let mut prog = Vec::new();
+        for &(addr, _) in routines {
+            prog.push(0x20);
+            prog.extend(addr.to_le_bytes());
+        }
+        let [lo, hi] = (0xC000 + prog.len() as u16).to_le_bytes();
+        prog.extend([0x4C, lo, hi]);
+
+        let mut chunks = vec![(0xC000, &prog[..])];
+        chunks.extend(routines);
+        let input = make_input(&chunks);
+        let analysis = Analysis::default();
+        let consts = super::super::constant::analyze(&analysis, &input);
+        let subs = analyze(&analysis, &input, &consts);
+
+        routines
+            .iter()
+            .map(|&(addr, _)| subs.get(Address::new(addr)))
+            .collect()
+    }
+
+    #[test]
+    fn test_return_kind() {
+        #[rustfmt::skip]
+        let kinds = analyze_routines(&[
+            // 正常に戻る。
+            (0xC100, &[
+                0xA9, 0x00, // $C100: lda #0
+                0x60,       // $C102: rts
+            ]),
+            // 自身への jmp で終わり、決して戻らない。
+            (0xC110, &[
+                0xA9, 0x00,       // $C110: lda #0
+                0x4C, 0x12, 0xC1, // $C112: jmp $C112
+            ]),
+            // リターンアドレスを捨てる。
+            (0xC120, &[
+                0x68, // $C120: pla
+                0x68, // $C121: pla
+                0x60, // $C122: rts
+            ]),
+            // 間接ジャンプする経路があり、戻るとは限らない。
+            (0xC130, &[
+                0xA5, 0x00,       // $C130: lda $00
+                0xF0, 0x03,       // $C132: beq $C137
+                0x6C, 0x02, 0x00, // $C134: jmp ($0002)
+                0x60,             // $C137: rts
+            ]),
+            // 戻らないルーチンを呼んでから戻る経路は存在しない。
+            (0xC140, &[
+                0x20, 0x10, 0xC1, // $C140: jsr $C110
+                0x60,             // $C143: rts
+            ]),
+            // 戻るとは限らないルーチンを呼んでから戻る。
+            (0xC150, &[
+                0x20, 0x30, 0xC1, // $C150: jsr $C130
+                0x60,             // $C153: rts
+            ]),
+        ]);
+
+        assert_eq!(
+            kinds,
+            [
+                ReturnKind::Returns,
+                ReturnKind::NoReturn,
+                ReturnKind::StackManip,
+                ReturnKind::MayReturn,
+                ReturnKind::NoReturn,
+                ReturnKind::MayReturn,
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! 解析のテスト用のユーティリティ。
+
+use crate::address::{Address, AddressRange};
+use crate::bank::Bank;
+use crate::cdl::Cdl;
+use crate::config::AnalysisConfig;
+use crate::input::{Input, InputBuilder};
+use crate::mapper::Mapper;
+use crate::memory::Memory;
+use crate::permission::{Permission, Permissions};
+
+use super::pass::{AnalysisContext, AnalysisPass};
+
+/// $C000 から始まる 16KB の固定バンクの内容を作る。
+///
+/// `chunks` の各要素は (アドレス, 機械語)。残りは非公式命令 ($FF) で埋める。
+/// 全ての割り込みベクタは最初の要素のアドレスを指す。
+pub(crate) fn make_bank_body(chunks: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut body = vec![0xFF; 0x4000];
+    for &(addr, code) in chunks {
+        body[usize::from(addr - 0xC000)..][..code.len()].copy_from_slice(code);
+    }
+
+    let [lo, hi] = chunks[0].0.to_le_bytes();
+    body[0x3FFA..].copy_from_slice(&[lo, hi, lo, hi, lo, hi]);
+
+    body
+}
+
+/// 指定したバンクたちからなる入力のビルダーを返す。逆アセンブル対象は $C000 のバンク。
+///
+/// パーミッションは RAM ($0000-$07FF)、PRG-RAM ($6000-$7FFF)、PPU/APU レジスタ、
+/// および $8000-$FFFF を読み書き可能とし、RAM と $8000-$FFFF を実行可能とする。
+pub(crate) fn input_builder(banks: Vec<Bank>) -> InputBuilder {
+    let mut perms = Permissions::default();
+    let mut set = |min: u16, max: u16, perm: Permission| {
+        perms[AddressRange::from_min_max(Address::new(min), Address::new(max))].fill(perm);
+    };
+    set(0x0000, 0x07FF, Permission::new(true, true, true));
+    set(0x2000, 0x2007, Permission::new(true, true, false));
+    set(0x4000, 0x4017, Permission::new(true, true, false));
+    set(0x6000, 0x7FFF, Permission::new(true, true, false));
+    set(0x8000, 0xFFFF, Permission::new(true, true, true));
+
+    InputBuilder::new()
+        .memory(Memory::new(banks))
+        .permissions(perms)
+        .cdl(Cdl::default())
+        .target_bank_addr(Address::new(0xC000))
+        .target_bank_name("PRG")
+}
+
+/// $C000 から始まる 16KB の固定バンク 1 つからなる入力を作る。`chunks` は `make_bank_body()` と同じ。
+pub(crate) fn make_input(chunks: &[(u16, &[u8])]) -> Input {
+    let bank = Bank::new(Address::new(0xC000), make_bank_body(chunks), true);
+    input_builder(vec![bank]).build().unwrap()
+}
+
+/// $8000 から始まる `len` バイトの非固定バンク (バンク番号 `number`) を逆アセンブル対象とし、
+/// $C000 から始まる 16KB の固定バンクを持つ入力を作る。
+///
+/// `prg`, `fixed` はそれぞれのバンクの (アドレス, 機械語) たち。`fixed` は `make_bank_body()` と同じ。
+pub(crate) fn make_switchable_input(
+    mapper: Mapper,
+    number: usize,
+    len: usize,
+    prg: &[(u16, &[u8])],
+    fixed: &[(u16, &[u8])],
+) -> Input {
+    let mut body = vec![0xFF; len];
+    for &(addr, code) in prg {
+        body[usize::from(addr - 0x8000)..][..code.len()].copy_from_slice(code);
+    }
+    let banks = vec![
+        Bank::new(Address::new(0x8000), body, false),
+        Bank::new(Address::new(0xC000), make_bank_body(fixed), true),
+    ];
+
+    input_builder(banks)
+        .target_bank_addr(Address::new(0x8000))
+        .target_bank_number(number)
+        .mapper(mapper)
+        .build()
+        .unwrap()
+}
+
+/// 固定バンクから非固定バンクへの呼び出しを含む UxROM の入力を作る。逆アセンブル対象はバンク番号 1。
+///
+/// 固定バンクは、バンク切り替えなしで $8010 を、バンク 1 に切り替えてから $8000 を呼び出す。
+/// $8000 は固定バンクの $C020 へ jmp する。
+pub(crate) fn make_cross_bank_input() -> Input {
+    #[rustfmt::skip]
+    let prg: [(u16, &[u8]); 2] = [
+        (0x8000, &[0x4C, 0x20, 0xC0]), // $8000: jmp $C020
+        (0x8010, &[0x60]),             // $8010: rts
+    ];
+    #[rustfmt::skip]
+    let main = [
+        0x20, 0x10, 0x80, // $C000: jsr $8010
+        0xA9, 0x01,       // $C003: lda #1
+        0x8D, 0x00, 0x80, // $C005: sta $8000
+        0x20, 0x00, 0x80, // $C008: jsr $8000
+        0x4C, 0x0B, 0xC0, // $C00B: jmp $C00B
+    ];
+    #[rustfmt::skip]
+    let tail = [
+        0x60, // $C020: rts
+    ];
+
+    make_switchable_input(
+        Mapper::UxRom,
+        1,
+        0x4000,
+        &prg,
+        &[(0xC000, &main), (0xC020, &tail)],
+    )
+}
+
+/// 2 つのサブルーチンを呼び出す固定バンクの入力を作る。
+///
+/// $C010 のサブルーチンはループを含み、$C020 のサブルーチンは $C010 のサブルーチンの末尾 ($C015 の rts) へ jmp する。
+pub(crate) fn make_shared_tail_input() -> Input {
+    #[rustfmt::skip]
+    let main = [
+        0x20, 0x10, 0xC0, // $C000: jsr $C010
+        0x20, 0x20, 0xC0, // $C003: jsr $C020
+        0x4C, 0x06, 0xC0, // $C006: jmp $C006
+    ];
+    #[rustfmt::skip]
+    let sub1 = [
+        0xA2, 0x03, // $C010: ldx #3
+        0xCA,       // $C012: dex
+        0xD0, 0xFD, // $C013: bne $C012
+        0x60,       // $C015: rts
+    ];
+    #[rustfmt::skip]
+    let sub2 = [
+        0xA9, 0x00,       // $C020: lda #0
+        0x4C, 0x15, 0xC0, // $C022: jmp $C015
+    ];
+
+    make_input(&[(0xC000, &main), (0xC010, &sub1), (0xC020, &sub2)])
+}
+
+/// 指定したパスたちを順に 1 回ずつ実行し、コンテキストを返す。
+pub(crate) fn run_passes<'a>(
+    input: &'a Input,
+    config: &'a AnalysisConfig,
+    passes: &[&dyn AnalysisPass],
+) -> AnalysisContext<'a> {
+    let mut cx = AnalysisContext::new(input, config);
+    for pass in passes {
+        pass.run(&mut cx);
+    }
+    cx
+}
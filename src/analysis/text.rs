@@ -0,0 +1,109 @@
+//! テキスト検出。
+//!
+//! 逆アセンブル対象バンク内で連続する `Statement::Byte` のうち、文字テーブルで解釈できるものを
+//! `Statement::Text` にまとめる。
+//!
+//! テキストはラベルの位置、および終端文字の直後で区切る。
+//! 終端、改行、制御コードを除く文字数が `text_min_len` 未満のものはテキストとみなさない。
+
+use crate::assembly::{Labels, Statement, Text, TextChar};
+use crate::config::AnalysisConfig;
+use crate::input::Input;
+use crate::text::{CharKind, CharTable};
+
+pub(super) fn analyze(
+    stmts: Vec<Statement>,
+    labels: &Labels,
+    input: &Input,
+    config: &AnalysisConfig,
+) -> Vec<Statement> {
+    let mut res = Vec::<Statement>::with_capacity(stmts.len());
+
+    // 連続する Byte を、ラベルの位置および文字テーブルが変わる位置で区切ったもの。
+    let mut run = Vec::<u8>::new();
+    let mut run_table: Option<&CharTable> = None;
+
+    macro_rules! flush {
+        () => {{
+            match run_table {
+                Some(table) => res.extend(detect_texts(table, config, &run)),
+                None => res.extend(run.iter().map(|&b| Statement::Byte(b))),
+            }
+            run.clear();
+        }};
+    }
+
+    let mut addr = input.target_bank().addr();
+    for stmt in stmts {
+        let stmt_len = stmt.len();
+
+        match stmt {
+            Statement::Byte(b) => {
+                let table = input.char_table(addr);
+                if labels.get(addr).is_some() || !same_table(table, run_table) {
+                    flush!();
+                }
+                run_table = table;
+                run.push(b);
+            }
+            stmt => {
+                flush!();
+                res.push(stmt);
+            }
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt_len) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+    flush!();
+
+    res
+}
+
+fn same_table(lhs: Option<&CharTable>, rhs: Option<&CharTable>) -> bool {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => std::ptr::eq(lhs, rhs),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// バイト列からテキストを検出し、`Statement` 列に変換する。
+fn detect_texts(table: &CharTable, config: &AnalysisConfig, buf: &[u8]) -> Vec<Statement> {
+    let mut res = Vec::<Statement>::new();
+    let charmap = table.charmap();
+
+    let mut i = 0;
+    while i < buf.len() {
+        // i から文字テーブルで解釈できる限り (終端文字を含めて) 読み進める。
+        let mut chars = Vec::<TextChar>::new();
+        let mut j = i;
+        while let Some((len, entry)) = table.decode_one(&buf[j..]) {
+            chars.push(TextChar::new(&buf[j..j + len], entry.clone()));
+            j += len;
+            if entry.kind() == CharKind::End {
+                break;
+            }
+        }
+
+        let normal_count = chars
+            .iter()
+            .filter(|ch| ch.entry().kind() == CharKind::Normal)
+            .count();
+        if normal_count >= config.text_min_len().max(1) {
+            res.push(Statement::Text(Text::new(chars, charmap)));
+        } else {
+            // 途中から読み始めても文字数は増えないとみなし、読んだ範囲は全て単なるバイトとする。
+            let j = j.max(i + 1);
+            res.extend(buf[i..j].iter().map(|&b| Statement::Byte(b)));
+            i = j;
+            continue;
+        }
+
+        i = j;
+    }
+
+    res
+}
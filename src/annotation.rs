@@ -0,0 +1,624 @@
+//! 利用者による注釈。
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context as _};
+use serde::{Deserialize, Serialize};
+
+use crate::address::{Address, AddressRange};
+
+/// 利用者による注釈 (ラベル名、コメント、データ型、コード/データの指定)。解析結果より優先される。
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Annotations {
+    labels: BTreeMap<Address, String>,
+    comments: BTreeMap<Address, String>,
+    types: BTreeMap<Address, DataType>,
+    code: BTreeSet<Address>,
+    data: Vec<AddressRange>,
+}
+
+impl Annotations {
+    /// ラベル名たちをアドレスの昇順に返す。
+    pub fn labels(&self) -> impl Iterator<Item = (Address, &str)> {
+        self.labels
+            .iter()
+            .map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    /// 指定したアドレスのラベル名を返す。
+    pub fn label(&self, addr: Address) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// 指定したアドレスにラベル名を付ける。
+    ///
+    /// 名前が ca65 の識別子として不正な場合、自動生成されるラベル名 (`L_XXXX`) と紛らわしい場合、
+    /// 他のアドレスで使われている場合はエラーを返す。
+    pub fn set_label(&mut self, addr: Address, name: impl Into<String>) -> anyhow::Result<()> {
+        let name = name.into();
+
+        ensure!(is_identifier(&name), "invalid label name: '{name}'");
+        ensure!(
+            !is_auto_label_name(&name),
+            "label name '{name}' conflicts with generated names"
+        );
+        if let Some((&other, _)) = self.labels.iter().find(|&(&a, n)| a != addr && *n == name) {
+            bail!("label name '{name}' is already used at {other:#06X}");
+        }
+
+        self.labels.insert(addr, name);
+
+        Ok(())
+    }
+
+    /// 指定したアドレスのラベル名を取り除き、それを返す。
+    pub fn remove_label(&mut self, addr: Address) -> Option<String> {
+        self.labels.remove(&addr)
+    }
+
+    /// コメントたちをアドレスの昇順に返す。
+    pub fn comments(&self) -> impl Iterator<Item = (Address, &str)> {
+        self.comments
+            .iter()
+            .map(|(&addr, comment)| (addr, comment.as_str()))
+    }
+
+    /// 指定したアドレスのコメントを返す。
+    pub fn comment(&self, addr: Address) -> Option<&str> {
+        self.comments.get(&addr).map(String::as_str)
+    }
+
+    /// 指定したアドレスにコメントを付ける。コメントは 1 行でなければならない。
+    pub fn set_comment(&mut self, addr: Address, comment: impl Into<String>) -> anyhow::Result<()> {
+        let comment = comment.into();
+
+        ensure!(
+            !comment.contains(['\n', '\r']),
+            "comment must be a single line"
+        );
+
+        self.comments.insert(addr, comment);
+
+        Ok(())
+    }
+
+    /// 指定したアドレスのコメントを取り除き、それを返す。
+    pub fn remove_comment(&mut self, addr: Address) -> Option<String> {
+        self.comments.remove(&addr)
+    }
+
+    /// データ型の指定たちをアドレスの昇順に返す。
+    pub fn types(&self) -> impl Iterator<Item = (Address, &DataType)> {
+        self.types.iter().map(|(&addr, ty)| (addr, ty))
+    }
+
+    /// 指定したアドレスのデータ型の指定を返す。
+    pub fn data_type(&self, addr: Address) -> Option<&DataType> {
+        self.types.get(&addr)
+    }
+
+    /// 指定したアドレスから並ぶデータの型を指定する。
+    pub fn set_type(&mut self, addr: Address, ty: DataType) {
+        self.types.insert(addr, ty);
+    }
+
+    /// 指定したアドレスのデータ型の指定を取り除き、それを返す。
+    pub fn remove_type(&mut self, addr: Address) -> Option<DataType> {
+        self.types.remove(&addr)
+    }
+
+    /// コードのエントリポイントとして指定されたアドレスたちを昇順に返す。
+    pub fn code_entries(&self) -> impl Iterator<Item = Address> + '_ {
+        self.code.iter().copied()
+    }
+
+    /// データとして指定されたアドレス範囲たちを返す。
+    pub fn data_ranges(&self) -> &[AddressRange] {
+        &self.data
+    }
+
+    /// 指定したアドレスをコードのエントリポイントとする。
+    /// そのアドレスを含むデータ指定は取り除く (範囲は分割される)。
+    pub fn mark_code(&mut self, addr: Address) {
+        let data = std::mem::take(&mut self.data);
+        self.data = data
+            .into_iter()
+            .flat_map(|range| {
+                if !range.contains_addr(addr) {
+                    return vec![range];
+                }
+                let lo = addr
+                    .checked_add_signed(-1_isize)
+                    .filter(|&max| range.min() <= max)
+                    .map(|max| AddressRange::from_min_max(range.min(), max));
+                let hi = addr
+                    .checked_add_unsigned(1_usize)
+                    .filter(|&min| min <= range.max())
+                    .map(|min| AddressRange::from_min_max(min, range.max()));
+                lo.into_iter().chain(hi).collect()
+            })
+            .collect();
+
+        self.code.insert(addr);
+    }
+
+    /// 指定したアドレス範囲をデータとする。範囲内のコード指定は取り除く。
+    pub fn mark_data(&mut self, range: AddressRange) {
+        self.code.retain(|&addr| !range.contains_addr(addr));
+        self.data.push(range);
+    }
+
+    /// 注釈が空かどうかを返す。
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+            && self.comments.is_empty()
+            && self.types.is_empty()
+            && self.code.is_empty()
+            && self.data.is_empty()
+    }
+
+    /// `other` の注釈を加える。同じアドレスに対する指定が衝突する場合は `other` を優先する。
+    pub fn merge(&mut self, other: &Self) {
+        self.labels.extend(
+            other
+                .labels
+                .iter()
+                .map(|(&addr, name)| (addr, name.clone())),
+        );
+        self.comments.extend(
+            other
+                .comments
+                .iter()
+                .map(|(&addr, comment)| (addr, comment.clone())),
+        );
+        self.types
+            .extend(other.types.iter().map(|(&addr, ty)| (addr, ty.clone())));
+        self.code.extend(other.code.iter().copied());
+        self.data.extend(other.data.iter().copied());
+    }
+}
+
+/// 注釈で指定するデータ型。`"ptr"`, `"ptr[16]"`, `"EnemySpawn[8]"` のように表記する。
+///
+/// 型名は `byte`, `word`, `ptr` のいずれか、またはマニフェストで定義した構造体名。
+/// 指定したアドレスから要素数だけ並ぶ配列として扱われる。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DataType {
+    name: String,
+    count: NonZeroUsize,
+}
+
+impl DataType {
+    pub fn new(name: impl Into<String>, count: NonZeroUsize) -> anyhow::Result<Self> {
+        let name = name.into();
+
+        ensure!(is_identifier(&name), "invalid type name: '{name}'");
+
+        Ok(Self { name, count })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn count(&self) -> NonZeroUsize {
+        self.count
+    }
+}
+
+impl FromStr for DataType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, count) = match s.strip_suffix(']').and_then(|s| s.split_once('[')) {
+            Some((name, count)) => {
+                let count = count
+                    .parse()
+                    .with_context(|| format!("invalid type: '{s}'"))?;
+                (name, count)
+            }
+            None => (s, NonZeroUsize::MIN),
+        };
+
+        Self::new(name, count)
+    }
+}
+
+impl Display for DataType {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if self.count == NonZeroUsize::MIN {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}[{}]", self.name, self.count)
+        }
+    }
+}
+
+/// 注釈ファイル (マニフェストと並べて置くサイドカーファイル)。
+///
+/// どのバンクにも属さないアドレス (RAM など) に対するグローバルな注釈と、バンクごとの注釈を持つ。
+/// TOML 形式で、アドレスは 4 桁の 16 進文字列で表す:
+///
+/// ```toml
+/// [labels]
+/// "0300" = "player_x"
+///
+/// [banks.PRG3]
+/// code = ["C000"]
+/// data = ["8800-88FF"]
+///
+/// [banks.PRG3.labels]
+/// C123 = "UpdatePlayer"
+///
+/// [banks.PRG3.comments]
+/// C123 = "プレイヤーの位置を更新"
+///
+/// [banks.PRG3.types]
+/// "9000" = "ptr[16]"
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AnnotationFile {
+    global: Annotations,
+    banks: BTreeMap<String, Annotations>,
+}
+
+impl AnnotationFile {
+    pub fn from_toml(s: impl AsRef<str>) -> anyhow::Result<Self> {
+        let desc: AnnotationFileDesc = toml::from_str(s.as_ref())?;
+
+        let global = desc
+            .global()
+            .into_annotations()
+            .context("global annotations")?;
+        let banks = desc
+            .banks
+            .into_iter()
+            .map(|(name, desc)| {
+                let annotations = desc
+                    .into_annotations()
+                    .with_context(|| format!("annotations for bank '{name}'"))?;
+                Ok((name, annotations))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { global, banks })
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        let global = AnnotationsDesc::from_annotations(&self.global);
+        let desc = AnnotationFileDesc {
+            code: global.code,
+            data: global.data,
+            labels: global.labels,
+            comments: global.comments,
+            types: global.types,
+            banks: self
+                .banks
+                .iter()
+                .filter(|(_, annotations)| !annotations.is_empty())
+                .map(|(name, annotations)| {
+                    (name.clone(), AnnotationsDesc::from_annotations(annotations))
+                })
+                .collect(),
+        };
+
+        Ok(toml::to_string(&desc)?)
+    }
+
+    /// 指定したパスから注釈ファイルを読み込む。ファイルが存在しなければ空の注釈を返す。
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read annotations '{}'", path.display()))?;
+
+        Self::from_toml(s).with_context(|| format!("invalid annotations '{}'", path.display()))
+    }
+
+    /// 指定したパスに注釈ファイルを書き出す。
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+
+        std::fs::write(path, self.to_toml()?)
+            .with_context(|| format!("can't write annotations '{}'", path.display()))
+    }
+
+    /// どのバンクにも属さないアドレスに対する注釈を返す。
+    pub fn global(&self) -> &Annotations {
+        &self.global
+    }
+
+    pub fn global_mut(&mut self) -> &mut Annotations {
+        &mut self.global
+    }
+
+    /// 指定したバンクに対する注釈を返す。
+    pub fn bank(&self, bank_name: &str) -> Option<&Annotations> {
+        self.banks.get(bank_name)
+    }
+
+    /// 指定したバンクに対する注釈を返す。なければ空の注釈を作る。
+    pub fn bank_mut(&mut self, bank_name: &str) -> &mut Annotations {
+        self.banks.entry(bank_name.to_owned()).or_default()
+    }
+
+    /// グローバルな注釈と、指定したバンクたち (逆アセンブル時にロードされるもの) の注釈を合わせたものを返す。
+    ///
+    /// 合わせた結果、同じラベル名が複数のアドレスに付く場合はエラーを返す
+    /// (アセンブル時にシンボルが重複するため)。
+    pub fn annotations_for<'a>(
+        &self,
+        bank_names: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<Annotations> {
+        let mut res = self.global.clone();
+        for name in bank_names {
+            if let Some(annotations) = self.banks.get(name) {
+                res.merge(annotations);
+            }
+        }
+
+        let mut name_to_addr = HashMap::<&str, Address>::new();
+        for (addr, name) in res.labels() {
+            if let Some(other) = name_to_addr.insert(name, addr) {
+                bail!("label name '{name}' is used at both {other:#06X} and {addr:#06X}");
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AnnotationFileDesc {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    code: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    data: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    comments: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    types: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    banks: BTreeMap<String, AnnotationsDesc>,
+}
+
+impl AnnotationFileDesc {
+    fn global(&self) -> AnnotationsDesc {
+        AnnotationsDesc {
+            code: self.code.clone(),
+            data: self.data.clone(),
+            labels: self.labels.clone(),
+            comments: self.comments.clone(),
+            types: self.types.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AnnotationsDesc {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    code: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    data: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    comments: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    types: BTreeMap<String, String>,
+}
+
+impl AnnotationsDesc {
+    fn from_annotations(annotations: &Annotations) -> Self {
+        Self {
+            code: annotations.code_entries().map(format_addr).collect(),
+            data: annotations
+                .data_ranges()
+                .iter()
+                .map(|range| format!("{}-{}", format_addr(range.min()), format_addr(range.max())))
+                .collect(),
+            labels: annotations
+                .labels()
+                .map(|(addr, name)| (format_addr(addr), name.to_owned()))
+                .collect(),
+            comments: annotations
+                .comments()
+                .map(|(addr, comment)| (format_addr(addr), comment.to_owned()))
+                .collect(),
+            types: annotations
+                .types()
+                .map(|(addr, ty)| (format_addr(addr), ty.to_string()))
+                .collect(),
+        }
+    }
+
+    fn into_annotations(self) -> anyhow::Result<Annotations> {
+        let mut res = Annotations::default();
+
+        for s in self.data {
+            let range = parse_addr_range(&s)?;
+            res.mark_data(range);
+        }
+        for s in self.code {
+            res.mark_code(parse_addr(&s)?);
+        }
+        for (s, name) in self.labels {
+            res.set_label(parse_addr(&s)?, name)?;
+        }
+        for (s, comment) in self.comments {
+            res.set_comment(parse_addr(&s)?, comment)?;
+        }
+        for (s, ty) in self.types {
+            res.set_type(parse_addr(&s)?, ty.parse()?);
+        }
+
+        Ok(res)
+    }
+}
+
+fn format_addr(addr: Address) -> String {
+    format!("{:04X}", addr.get())
+}
+
+/// "$C000", "0xC000", "C000" のような 16 進表記のアドレスをパースする。
+pub fn parse_addr(s: &str) -> anyhow::Result<Address> {
+    let digits = s
+        .strip_prefix('$')
+        .or_else(|| s.strip_prefix("0x"))
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+
+    let addr =
+        u16::from_str_radix(digits, 16).with_context(|| format!("invalid address: '{s}'"))?;
+
+    Ok(Address::new(addr))
+}
+
+/// "$8800-$88FF" のようなアドレス範囲、または単一のアドレスをパースする。
+pub fn parse_addr_range(s: &str) -> anyhow::Result<AddressRange> {
+    let range = match s.split_once('-') {
+        Some((min, max)) => {
+            let (min, max) = (parse_addr(min)?, parse_addr(max)?);
+            ensure!(min <= max, "invalid address range: '{s}'");
+            AddressRange::from_min_max(min, max)
+        }
+        None => AddressRange::from_start_len(parse_addr(s)?, NonZeroUsize::MIN),
+    };
+
+    Ok(range)
+}
+
+/// ca65 の識別子として正しいかどうかを返す。
+pub(crate) fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// 自動生成されるラベル名 (`L_XXXX`) の形かどうかを返す。
+pub(crate) fn is_auto_label_name(s: &str) -> bool {
+    s.strip_prefix("L_")
+        .is_some_and(|hex| hex.len() == 4 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(min: u16, max: u16) -> AddressRange {
+        AddressRange::from_min_max(Address::new(min), Address::new(max))
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut ann = Annotations::default();
+
+        ann.set_label(Address::new(0xC123), "UpdatePlayer").unwrap();
+        ann.set_label(Address::new(0xC123), "update_player")
+            .unwrap();
+        assert_eq!(ann.label(Address::new(0xC123)), Some("update_player"));
+        assert!(ann
+            .set_label(Address::new(0xC200), "update_player")
+            .is_err());
+        assert!(ann.set_label(Address::new(0xC200), "1up").is_err());
+        assert!(ann.set_label(Address::new(0xC200), "foo.bar").is_err());
+        assert!(ann.set_label(Address::new(0xC200), "L_C200").is_err());
+        assert!(ann.set_label(Address::new(0xC200), "L_Main").is_ok());
+
+        ann.mark_data(range(0x8800, 0x88FF));
+        ann.mark_code(Address::new(0x8810));
+        assert_eq!(
+            ann.data_ranges(),
+            [range(0x8800, 0x880F), range(0x8811, 0x88FF)]
+        );
+        ann.mark_code(Address::new(0x8800));
+        assert_eq!(
+            ann.data_ranges(),
+            [range(0x8801, 0x880F), range(0x8811, 0x88FF)]
+        );
+        ann.mark_data(range(0x8810, 0x8810));
+        itertools::assert_equal(ann.code_entries(), [Address::new(0x8800)]);
+    }
+
+    #[test]
+    fn test_annotation_file() {
+        let mut file = AnnotationFile::default();
+        file.global_mut()
+            .set_label(Address::new(0x0300), "player_x")
+            .unwrap();
+        let bank = file.bank_mut("PRG3");
+        bank.set_label(Address::new(0xC123), "UpdatePlayer")
+            .unwrap();
+        bank.mark_code(Address::new(0xC000));
+        bank.mark_data(range(0x8800, 0x88FF));
+        bank.set_comment(Address::new(0xC123), "プレイヤーの位置を更新")
+            .unwrap();
+        bank.set_type(Address::new(0x9000), "ptr[16]".parse().unwrap());
+        file.bank_mut("PRG4");
+
+        let s = file.to_toml().unwrap();
+        assert!(!s.contains("PRG4"));
+        let file2 = AnnotationFile::from_toml(&s).unwrap();
+        assert_eq!(file2.global(), file.global());
+        assert_eq!(file2.bank("PRG3"), file.bank("PRG3"));
+
+        let ann = file.annotations_for(["PRG3", "PRG7"]).unwrap();
+        assert_eq!(ann.label(Address::new(0x0300)), Some("player_x"));
+        assert_eq!(ann.label(Address::new(0xC123)), Some("UpdatePlayer"));
+
+        // ロードされるバンクどうし、またはグローバルな注釈とバンクの注釈とでラベル名が重複してはならない。
+        file.bank_mut("PRG7")
+            .set_label(Address::new(0xC456), "UpdatePlayer")
+            .unwrap();
+        file.bank_mut("PRG4")
+            .set_label(Address::new(0x8000), "player_x")
+            .unwrap();
+        assert!(file.annotations_for(["PRG3", "PRG7"]).is_err());
+        assert!(file.annotations_for(["PRG4"]).is_err());
+        assert!(file.annotations_for(["PRG3"]).is_ok());
+
+        assert!(AnnotationFile::from_toml("[labels]\nC000 = \"1x\"\n").is_err());
+        assert!(AnnotationFile::from_toml("data = [\"8000-7FFF\"]\n").is_err());
+        assert!(AnnotationFile::from_toml("[types]\n\"0300\" = \"ptr[0]\"\n").is_err());
+    }
+
+    #[test]
+    fn test_data_type() {
+        let ty: DataType = "ptr[16]".parse().unwrap();
+        assert_eq!(ty.name(), "ptr");
+        assert_eq!(ty.count().get(), 16);
+        assert_eq!(ty.to_string(), "ptr[16]");
+
+        let ty: DataType = "EnemySpawn".parse().unwrap();
+        assert_eq!(ty.name(), "EnemySpawn");
+        assert_eq!(ty.count().get(), 1);
+        assert_eq!(ty.to_string(), "EnemySpawn");
+
+        assert!("ptr[".parse::<DataType>().is_err());
+        assert!("ptr[x]".parse::<DataType>().is_err());
+        assert!("[4]".parse::<DataType>().is_err());
+    }
+}
@@ -1,10 +1,13 @@
 use std::num::NonZeroUsize;
+use std::sync::Arc;
 
 use anyhow::{bail, ensure};
 use arrayvec::ArrayVec;
 
 use crate::address::{Address, AddressRange};
+use crate::layout::{FieldType, StructField, StructType};
 use crate::op::Op;
+use crate::text::{CharEntry, CharKind, Charmap};
 
 /// アセンブリ全体。
 ///
@@ -15,6 +18,12 @@ pub struct Assembly {
     bank_name: String,
     statements: Vec<Statement>,
     labels: Labels,
+    routines: Vec<Routine>,
+    bank_switches: Vec<BankSwitch>,
+    always_taken_branches: Vec<Address>,
+    no_return_calls: Vec<Address>,
+    imm_addrs: Vec<ImmAddr>,
+    comments: Vec<(Address, String)>,
 }
 
 impl Assembly {
@@ -42,6 +51,78 @@ impl Assembly {
     pub fn labels(&self) -> &Labels {
         &self.labels
     }
+
+    /// ルーチンたちを返す (エントリポイントの昇順)。
+    pub fn routines(&self) -> &[Routine] {
+        &self.routines
+    }
+
+    /// 指定したアドレスをエントリポイントとするルーチンを返す。
+    pub fn find_routine(&self, entry: Address) -> Option<&Routine> {
+        self.routines
+            .binary_search_by_key(&entry, Routine::entry)
+            .ok()
+            .map(|i| &self.routines[i])
+    }
+
+    /// バンク切り替えを伴う制御フロー命令たちを返す (命令アドレスの昇順)。
+    pub fn bank_switches(&self) -> &[BankSwitch] {
+        &self.bank_switches
+    }
+
+    /// 指定したアドレス上の制御フロー命令に対するバンク切り替えを返す。
+    pub fn find_bank_switch(&self, site: Address) -> Option<&BankSwitch> {
+        self.bank_switches
+            .binary_search_by_key(&site, BankSwitch::site)
+            .ok()
+            .map(|i| &self.bank_switches[i])
+    }
+
+    /// 直前の命令列から推定されるフラグにより常に分岐する分岐命令のアドレスたちを返す (昇順)。
+    pub fn always_taken_branches(&self) -> &[Address] {
+        &self.always_taken_branches
+    }
+
+    /// 指定したアドレス上の命令が常に分岐する分岐命令かどうかを返す。
+    pub fn is_always_taken_branch(&self, site: Address) -> bool {
+        self.always_taken_branches.binary_search(&site).is_ok()
+    }
+
+    /// サブルーチン解析により呼び出し先が正常に戻らないと判明した jsr 命令のアドレスたちを返す (昇順)。
+    pub fn no_return_calls(&self) -> &[Address] {
+        &self.no_return_calls
+    }
+
+    /// 指定したアドレス上の命令が、呼び出し先が正常に戻らない jsr 命令かどうかを返す。
+    pub fn is_no_return_call(&self, site: Address) -> bool {
+        self.no_return_calls.binary_search(&site).is_ok()
+    }
+
+    /// 即値オペランドがアドレスの一部と判明した命令たちを返す (命令アドレスの昇順)。
+    pub fn imm_addrs(&self) -> &[ImmAddr] {
+        &self.imm_addrs
+    }
+
+    /// 指定したアドレス上の命令の即値オペランドがアドレスの一部ならば、それを返す。
+    pub fn find_imm_addr(&self, site: Address) -> Option<&ImmAddr> {
+        self.imm_addrs
+            .binary_search_by_key(&site, ImmAddr::site)
+            .ok()
+            .map(|i| &self.imm_addrs[i])
+    }
+
+    /// 利用者によるコメントたちを返す (アドレスの昇順)。
+    pub fn comments(&self) -> &[(Address, String)] {
+        &self.comments
+    }
+
+    /// 指定したアドレスに対する利用者によるコメントを返す。
+    pub fn find_comment(&self, addr: Address) -> Option<&str> {
+        self.comments
+            .binary_search_by_key(&addr, |&(addr, _)| addr)
+            .ok()
+            .map(|i| self.comments[i].1.as_str())
+    }
 }
 
 #[derive(Debug, Default)]
@@ -50,6 +131,12 @@ pub struct AssemblyBuilder {
     bank_name: Option<String>,
     statements: Option<Vec<Statement>>,
     labels: Option<Labels>,
+    routines: Option<Vec<Routine>>,
+    bank_switches: Option<Vec<BankSwitch>>,
+    always_taken_branches: Option<Vec<Address>>,
+    no_return_calls: Option<Vec<Address>>,
+    imm_addrs: Option<Vec<ImmAddr>>,
+    comments: Option<Vec<(Address, String)>>,
 }
 
 impl AssemblyBuilder {
@@ -70,6 +157,24 @@ impl AssemblyBuilder {
         let Some(labels) = self.labels else {
             bail!("AssemblyBuilder: labels is none");
         };
+        let Some(mut routines) = self.routines else {
+            bail!("AssemblyBuilder: routines is none");
+        };
+        let Some(mut bank_switches) = self.bank_switches else {
+            bail!("AssemblyBuilder: bank_switches is none");
+        };
+        let Some(mut always_taken_branches) = self.always_taken_branches else {
+            bail!("AssemblyBuilder: always_taken_branches is none");
+        };
+        let Some(mut no_return_calls) = self.no_return_calls else {
+            bail!("AssemblyBuilder: no_return_calls is none");
+        };
+        let Some(mut imm_addrs) = self.imm_addrs else {
+            bail!("AssemblyBuilder: imm_addrs is none");
+        };
+        let Some(mut comments) = self.comments else {
+            bail!("AssemblyBuilder: comments is none");
+        };
 
         ensure!(!statements.is_empty(), "AssemblyBuilder: 0 byte assembly");
 
@@ -82,11 +187,71 @@ impl AssemblyBuilder {
             );
         }
 
+        for routine in routines.iter() {
+            ensure!(
+                bank_addr_range.contains_addr(routine.entry()),
+                "AssemblyBuilder: routine entry {:#06X} is out of bank",
+                routine.entry()
+            );
+        }
+        routines.sort_unstable_by_key(Routine::entry);
+
+        for bank_switch in bank_switches.iter() {
+            ensure!(
+                bank_addr_range.contains_addr(bank_switch.site()),
+                "AssemblyBuilder: bank switch site {:#06X} is out of bank",
+                bank_switch.site()
+            );
+        }
+        bank_switches.sort_unstable_by_key(BankSwitch::site);
+
+        for &site in always_taken_branches.iter() {
+            ensure!(
+                bank_addr_range.contains_addr(site),
+                "AssemblyBuilder: always-taken branch {:#06X} is out of bank",
+                site
+            );
+        }
+        always_taken_branches.sort_unstable();
+
+        for &site in no_return_calls.iter() {
+            ensure!(
+                bank_addr_range.contains_addr(site),
+                "AssemblyBuilder: no-return call {:#06X} is out of bank",
+                site
+            );
+        }
+        no_return_calls.sort_unstable();
+
+        for imm_addr in imm_addrs.iter() {
+            ensure!(
+                bank_addr_range.contains_addr(imm_addr.site()),
+                "AssemblyBuilder: immediate address site {:#06X} is out of bank",
+                imm_addr.site()
+            );
+        }
+        imm_addrs.sort_unstable_by_key(ImmAddr::site);
+
+        for &(addr, _) in comments.iter() {
+            ensure!(
+                bank_addr_range.contains_addr(addr),
+                "AssemblyBuilder: comment {:#06X} is out of bank",
+                addr
+            );
+        }
+        comments.sort_unstable_by_key(|&(addr, _)| addr);
+
         Ok(Assembly {
             bank_addr_range,
             bank_name,
             statements,
             labels,
+            routines,
+            bank_switches,
+            always_taken_branches,
+            no_return_calls,
+            imm_addrs,
+            comments,
         })
     }
 
@@ -109,6 +274,36 @@ impl AssemblyBuilder {
         self.labels = Some(labels);
         self
     }
+
+    pub fn routines(mut self, routines: impl Into<Vec<Routine>>) -> Self {
+        self.routines = Some(routines.into());
+        self
+    }
+
+    pub fn bank_switches(mut self, bank_switches: impl Into<Vec<BankSwitch>>) -> Self {
+        self.bank_switches = Some(bank_switches.into());
+        self
+    }
+
+    pub fn always_taken_branches(mut self, always_taken_branches: impl Into<Vec<Address>>) -> Self {
+        self.always_taken_branches = Some(always_taken_branches.into());
+        self
+    }
+
+    pub fn no_return_calls(mut self, no_return_calls: impl Into<Vec<Address>>) -> Self {
+        self.no_return_calls = Some(no_return_calls.into());
+        self
+    }
+
+    pub fn imm_addrs(mut self, imm_addrs: impl Into<Vec<ImmAddr>>) -> Self {
+        self.imm_addrs = Some(imm_addrs.into());
+        self
+    }
+
+    pub fn comments(mut self, comments: impl Into<Vec<(Address, String)>>) -> Self {
+        self.comments = Some(comments.into());
+        self
+    }
 }
 
 /// アセンブリの文。
@@ -118,6 +313,21 @@ pub enum Statement {
     /// NOTE: 中身は空であってはならない。
     IncompleteOp(ArrayVec<u8, 2>),
     Byte(u8),
+    Text(Text),
+    /// DPCM サンプル。
+    /// NOTE: 中身は空であってはならない。
+    Dpcm(Vec<u8>),
+    /// `.incbin` で出力するデータ。
+    /// NOTE: 中身は空であってはならない。
+    Blob(Vec<u8>),
+    /// 構造体の配列の 1 要素。
+    Record(Record),
+    /// 分割ポインタテーブルの下位バイト配列 (各アドレスの下位バイトからなる)。
+    /// NOTE: 中身は空であってはならない。
+    LoBytes(Vec<Address>),
+    /// 分割ポインタテーブルの上位バイト配列 (各アドレスの上位バイトからなる)。
+    /// NOTE: 中身は空であってはならない。
+    HiBytes(Vec<Address>),
 }
 
 impl Statement {
@@ -127,8 +337,148 @@ impl Statement {
             Self::Op(op) => op.len(),
             Self::IncompleteOp(buf) => NonZeroUsize::new(buf.len()).unwrap(),
             Self::Byte(_) => NonZeroUsize::new(1).unwrap(),
+            Self::Text(text) => text.len(),
+            Self::Dpcm(buf) | Self::Blob(buf) => NonZeroUsize::new(buf.len()).unwrap(),
+            Self::Record(record) => record.ty().len(),
+            Self::LoBytes(addrs) | Self::HiBytes(addrs) => NonZeroUsize::new(addrs.len()).unwrap(),
+        }
+    }
+
+    /// バイト列に変換する。
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Op(op) => op.to_bytes().into_iter().collect(),
+            Self::IncompleteOp(buf) => buf.to_vec(),
+            Self::Byte(b) => vec![*b],
+            Self::Text(text) => text.bytes().collect(),
+            Self::Dpcm(buf) | Self::Blob(buf) => buf.clone(),
+            Self::Record(record) => record.bytes().to_vec(),
+            Self::LoBytes(addrs) => addrs.iter().map(|addr| addr.get() as u8).collect(),
+            Self::HiBytes(addrs) => addrs.iter().map(|addr| (addr.get() >> 8) as u8).collect(),
+        }
+    }
+}
+
+/// 構造体の配列の 1 要素。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    ty: Arc<StructType>,
+    bytes: Vec<u8>,
+}
+
+impl Record {
+    /// NOTE: `bytes` のバイト数は `ty` のバイト数と一致しなければならない。
+    pub fn new(ty: Arc<StructType>, bytes: impl Into<Vec<u8>>) -> Self {
+        let bytes = bytes.into();
+        assert_eq!(bytes.len(), ty.len().get());
+
+        Self { ty, bytes }
+    }
+
+    pub fn ty(&self) -> &StructType {
+        &self.ty
+    }
+
+    /// 元のバイト列を返す。
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// 各フィールドについて、(レコード先頭からのオフセット, フィールド, 値) を返す。
+    pub fn fields(&self) -> impl Iterator<Item = (usize, &StructField, u16)> {
+        self.ty.field_offsets().map(|(offset, field)| {
+            let value = match field.ty() {
+                FieldType::Byte => u16::from(self.bytes[offset]),
+                FieldType::Word | FieldType::Ptr => {
+                    u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+                }
+            };
+            (offset, field, value)
+        })
+    }
+}
+
+/// 文字テーブルによりテキストと判定されたバイト列。
+///
+/// 0 文字ではないことが保証される。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Text {
+    chars: Vec<TextChar>,
+    charmap: Box<Charmap>,
+}
+
+impl Text {
+    /// `charmap` は判定に使った文字テーブルに対応する `.charmap`。
+    pub fn new(chars: impl Into<Vec<TextChar>>, charmap: Charmap) -> Self {
+        let chars = chars.into();
+        assert!(!chars.is_empty());
+
+        Self {
+            chars,
+            charmap: Box::new(charmap),
         }
     }
+
+    /// 文字たちを返す。
+    pub fn chars(&self) -> &[TextChar] {
+        &self.chars
+    }
+
+    /// 判定に使った文字テーブルに対応する `.charmap` を返す。
+    pub fn charmap(&self) -> &Charmap {
+        &self.charmap
+    }
+
+    /// バイト数を返す。
+    pub fn len(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.chars.iter().map(|ch| ch.bytes().len()).sum()).unwrap()
+    }
+
+    /// 元のバイト列を返す。
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.chars.iter().flat_map(|ch| ch.bytes().iter().copied())
+    }
+
+    /// 人間が読むためのテキストを返す。改行は `\n` とし、制御コードは `[...]` で囲む。
+    pub fn decode(&self) -> String {
+        self.chars
+            .iter()
+            .map(|ch| {
+                let text = ch.entry().text();
+                match ch.entry().kind() {
+                    CharKind::Normal | CharKind::End => text.to_owned(),
+                    CharKind::Newline => "\\n".to_owned(),
+                    CharKind::Control if text.starts_with('[') => text.to_owned(),
+                    CharKind::Control => format!("[{text}]"),
+                }
+            })
+            .collect()
+    }
+}
+
+/// テキスト中の 1 文字。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TextChar {
+    bytes: Vec<u8>,
+    entry: CharEntry,
+}
+
+impl TextChar {
+    /// NOTE: `bytes` は空であってはならない。
+    pub fn new(bytes: impl Into<Vec<u8>>, entry: CharEntry) -> Self {
+        let bytes = bytes.into();
+        assert!(!bytes.is_empty());
+
+        Self { bytes, entry }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn entry(&self) -> &CharEntry {
+        &self.entry
+    }
 }
 
 /// 論理アドレス空間上のラベルたち。
@@ -151,30 +501,215 @@ impl Labels {
     /// 指定したアドレスにラベルを振る。
     ///
     /// 元々ラベルが振られていた場合、エントリポイントラベルを優先する。
+    /// ローカルラベルかどうかは両者がともにローカルラベルである場合のみ維持される。
+    /// 名前は元のラベルのものを優先する。
     pub fn set(&mut self, addr: Address, label: Label) {
         let new = if let Some(orig) = self.0[usize::from(addr)].take() {
-            Label::new(orig.is_entrypoint() || label.is_entrypoint())
+            Label {
+                entrypoint: orig.is_entrypoint() || label.is_entrypoint(),
+                local: orig.is_local() && label.is_local(),
+                name: orig.name.or(label.name),
+            }
         } else {
             label
         };
 
         self.0[usize::from(addr)] = Some(new);
     }
+
+    /// 指定したアドレスのラベルをローカルラベルとするかどうかを設定する。
+    /// ラベルが振られていなければ何もしない。
+    pub fn set_local(&mut self, addr: Address, local: bool) {
+        if let Some(label) = self.0[usize::from(addr)].as_mut() {
+            label.local = local;
+        }
+    }
+
+    /// 指定したアドレスのラベルの名前を設定する。
+    /// ラベルが振られていなければ何もしない。
+    pub fn set_name(&mut self, addr: Address, name: impl Into<String>) {
+        if let Some(label) = self.0[usize::from(addr)].as_mut() {
+            label.name = Some(name.into());
+        }
+    }
 }
 
 /// ラベル。
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Label {
     entrypoint: bool,
+    local: bool,
+    name: Option<String>,
 }
 
 impl Label {
     pub fn new(entrypoint: bool) -> Self {
-        Self { entrypoint }
+        Self {
+            entrypoint,
+            local: false,
+            name: None,
+        }
     }
 
     /// ラベルがルーチンのエントリポイントかどうかを返す。
     pub fn is_entrypoint(&self) -> bool {
         self.entrypoint
     }
+
+    /// ラベルがローカルラベル (ca65 の cheap local label) かどうかを返す。
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    /// 利用者が付けた名前を返す。なければ `None` を返す (`L_XXXX` の形で出力される)。
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// ルーチン。エントリポイントから他のエントリポイントを経由せずに到達できるコードからなる。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Routine {
+    entry: Address,
+    len: usize,
+    op_addrs: Vec<Address>,
+    callers: Vec<Address>,
+    callees: Vec<Address>,
+    exits: Vec<Address>,
+}
+
+impl Routine {
+    /// (エントリポイント, バイト数, 命令のアドレス, 呼び出し元, 呼び出し先, 出口) を指定してルーチンを作る。
+    /// 各アドレスリストは昇順にソートされ、重複が除かれる。
+    pub fn new(
+        entry: Address,
+        len: usize,
+        op_addrs: impl Into<Vec<Address>>,
+        callers: impl Into<Vec<Address>>,
+        callees: impl Into<Vec<Address>>,
+        exits: impl Into<Vec<Address>>,
+    ) -> Self {
+        fn normalize(mut addrs: Vec<Address>) -> Vec<Address> {
+            addrs.sort_unstable();
+            addrs.dedup();
+            addrs
+        }
+
+        Self {
+            entry,
+            len,
+            op_addrs: normalize(op_addrs.into()),
+            callers: normalize(callers.into()),
+            callees: normalize(callees.into()),
+            exits: normalize(exits.into()),
+        }
+    }
+
+    /// エントリポイントを返す。
+    pub fn entry(&self) -> Address {
+        self.entry
+    }
+
+    /// ルーチンに属する命令の総バイト数を返す。
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// ルーチンに属する命令がないかどうかを返す。
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// ルーチンに属する命令のアドレスたちを昇順で返す。
+    pub fn op_addrs(&self) -> &[Address] {
+        &self.op_addrs
+    }
+
+    /// このルーチンを呼び出すルーチンのエントリポイントたちを返す。
+    pub fn callers(&self) -> &[Address] {
+        &self.callers
+    }
+
+    /// このルーチンが呼び出すルーチンのエントリポイントたちを返す。
+    pub fn callees(&self) -> &[Address] {
+        &self.callees
+    }
+
+    /// ルーチンから制御が出ていく命令 (rts, rti, 他ルーチンへの jmp など) のアドレスたちを返す。
+    pub fn exits(&self) -> &[Address] {
+        &self.exits
+    }
+}
+
+/// バンク切り替えにより行き先のバンクが推定された制御フロー命令。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BankSwitch {
+    site: Address,
+    dst: Address,
+    number: usize,
+}
+
+impl BankSwitch {
+    /// (命令アドレス, 行き先アドレス, 行き先のマッパー上のバンク番号) を指定して `BankSwitch` を作る。
+    pub fn new(site: Address, dst: Address, number: usize) -> Self {
+        Self { site, dst, number }
+    }
+
+    /// 命令アドレスを返す。
+    pub fn site(&self) -> Address {
+        self.site
+    }
+
+    /// 行き先アドレスを返す。
+    pub fn dst(&self) -> Address {
+        self.dst
+    }
+
+    /// 行き先のマッパー上のバンク番号を返す。
+    pub fn number(&self) -> usize {
+        self.number
+    }
+}
+
+/// 即値オペランドがアドレスの下位バイトまたは上位バイトであると判明した命令。
+///
+/// 即値は `dst + offset` の下位/上位バイトである (RTS トリックでは `offset` が -1 となる)。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ImmAddr {
+    site: Address,
+    dst: Address,
+    offset: i16,
+    hi: bool,
+}
+
+impl ImmAddr {
+    /// (命令アドレス, 参照先アドレス, オフセット, 上位バイトか) を指定して `ImmAddr` を作る。
+    pub fn new(site: Address, dst: Address, offset: i16, hi: bool) -> Self {
+        Self {
+            site,
+            dst,
+            offset,
+            hi,
+        }
+    }
+
+    /// 命令アドレスを返す。
+    pub fn site(&self) -> Address {
+        self.site
+    }
+
+    /// 参照先アドレスを返す。
+    pub fn dst(&self) -> Address {
+        self.dst
+    }
+
+    /// 即値が表すアドレスの、参照先アドレスからのオフセットを返す。
+    pub fn offset(&self) -> i16 {
+        self.offset
+    }
+
+    /// 即値が上位バイトかどうかを返す。
+    pub fn is_hi(&self) -> bool {
+        self.hi
+    }
 }
@@ -0,0 +1,343 @@
+mod shell;
+
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use disnes::*;
+
+#[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[arg(long, default_value = "disnes.toml", global = true)]
+    manifest: PathBuf,
+
+    /// 注釈ファイル。省略時はマニフェストの拡張子を ".annot.toml" に変えたもの。
+    #[arg(long, global = true)]
+    annotations: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    dis: DisArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// 指定したバンクを逆アセンブルする (サブコマンド省略時のデフォルト)。
+    Dis(DisArgs),
+
+    /// 指定したエントリポイントからの制御フローグラフを DOT 形式で出力する。
+    Cfg(CfgArgs),
+
+    /// 全バンクにわたる呼び出しグラフを出力する。
+    Callgraph(CallgraphArgs),
+
+    /// マニフェストを一度だけ読み込み、対話的に逆アセンブル結果を調べる。
+    Shell(ShellArgs),
+
+    /// 注釈を 1 つ編集し、注釈ファイルに書き出す (`shell` の編集コマンドと同じ書式)。
+    Annotate(AnnotateArgs),
+
+    /// 手で編集された逆アセンブル結果からラベル名とコメントを読み取り、注釈ファイルに書き出す。
+    Import(ImportArgs),
+
+    /// 別リビジョンのマニフェストと比較し、変更されたルーチンを報告し、注釈を移す。
+    Diff(DiffArgs),
+}
+
+#[derive(Debug, Args)]
+struct DisArgs {
+    bank_name: Option<String>,
+
+    /// `.incbin` で参照するバイナリファイル (DPCM サンプルなど) の出力先ディレクトリ。
+    #[arg(long, default_value = ".")]
+    bin_dir: PathBuf,
+
+    /// 組み込みエミュレータでリセットから指定フレーム数だけ実行し、得られたカバレッジを CDL に加える。
+    #[arg(long, value_name = "N")]
+    emu_frames: Option<u64>,
+
+    /// 組み込みエミュレータに与えるコントローラ入力のスクリプト。
+    #[arg(long, value_name = "FILE", requires = "emu_frames")]
+    emu_input: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct CfgArgs {
+    bank_name: String,
+
+    /// エントリポイントのアドレス ("$C000", "0xC000", "C000" など)。
+    #[arg(value_parser = parse_addr)]
+    entry: Address,
+
+    /// 出力先ファイル。省略時は標準出力。
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct CallgraphArgs {
+    /// 出力形式。
+    #[arg(long, value_enum, default_value_t = CallgraphFormat::Dot)]
+    format: CallgraphFormat,
+
+    /// 出力先ファイル。省略時は標準出力。
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct ShellArgs {
+    /// 最初に読み込むバンク名。
+    bank_name: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct AnnotateArgs {
+    /// 非固定バンク内のアドレスを編集する場合、そのバンク名。
+    #[arg(long)]
+    bank: Option<String>,
+
+    /// 編集コマンド ("label $C123 UpdatePlayer", "mark data $8800-$88FF" など)。
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct ImportArgs {
+    bank_name: String,
+
+    /// 編集された逆アセンブル結果 (`disnes <バンク名>` の出力を編集したもの)。
+    path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct DiffArgs {
+    /// 比較対象 (注釈の移し先) のマニフェスト。
+    other: PathBuf,
+
+    /// 比較対象の注釈ファイル。省略時は比較対象のマニフェストの拡張子を ".annot.toml" に変えたもの。
+    #[arg(long)]
+    other_annotations: Option<PathBuf>,
+
+    /// 比較するバンク名 (複数指定可)。省略時は両方に存在する全ての同名バンク。
+    #[arg(long = "bank")]
+    banks: Vec<String>,
+
+    /// 比較結果を報告するのみで、注釈を移さない。
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CallgraphFormat {
+    Dot,
+    Json,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+
+    let cli = Cli::parse();
+
+    let manifest_toml = std::fs::read_to_string(&cli.manifest)
+        .with_context(|| format!("can't read manifest '{}'", cli.manifest.display()))?;
+    let mut manifest = Manifest::from_toml(manifest_toml)?;
+
+    let annotation_path = cli
+        .annotations
+        .unwrap_or_else(|| cli.manifest.with_extension("annot.toml"));
+    manifest.set_annotations(AnnotationFile::load(&annotation_path)?);
+
+    match cli.command {
+        Some(Command::Dis(args)) => cmd_dis(manifest, args),
+        Some(Command::Cfg(args)) => cmd_cfg(manifest, args),
+        Some(Command::Callgraph(args)) => cmd_callgraph(manifest, args),
+        Some(Command::Shell(args)) => cmd_shell(manifest, annotation_path, args),
+        Some(Command::Annotate(args)) => cmd_annotate(manifest, annotation_path, args),
+        Some(Command::Import(args)) => cmd_import(manifest, annotation_path, args),
+        Some(Command::Diff(args)) => cmd_diff(manifest, args),
+        None => cmd_dis(manifest, cli.dis),
+    }
+}
+
+fn cmd_dis(manifest: Manifest, args: DisArgs) -> anyhow::Result<()> {
+    let Some(bank_name) = args.bank_name else {
+        bail!("bank name is not specified");
+    };
+
+    let (mut input, config) = manifest.into_input_config(bank_name)?;
+
+    if let Some(frames) = args.emu_frames {
+        let script = match args.emu_input.as_ref() {
+            Some(path) => {
+                let s = std::fs::read_to_string(path)
+                    .with_context(|| format!("can't read input script '{}'", path.display()))?;
+                InputScript::parse(s)?
+            }
+            None => InputScript::default(),
+        };
+        let cdl = trace_coverage(input.memory(), frames, &script);
+        input.merge_cdl(&cdl);
+    }
+
+    let asm = analyze(&input, config.analysis());
+
+    for file in binary_files(&asm, config.output()) {
+        let path = args.bin_dir.join(file.name());
+        std::fs::write(&path, file.body())
+            .with_context(|| format!("can't write '{}'", path.display()))?;
+    }
+
+    let mut wtr = BufWriter::new(std::io::stdout().lock());
+    output_assembly(&mut wtr, &asm, config.output())?;
+
+    Ok(())
+}
+
+fn cmd_cfg(manifest: Manifest, args: CfgArgs) -> anyhow::Result<()> {
+    let (input, config) = manifest.into_input_config(args.bank_name)?;
+
+    let asm = analyze(&input, config.analysis());
+    let cfg = Cfg::new(&input, &asm, args.entry)?;
+
+    let mut wtr = create_output(args.output.as_deref())?;
+    output_cfg_dot(&mut wtr, &cfg, asm.labels())?;
+    wtr.flush()?;
+
+    Ok(())
+}
+
+fn cmd_callgraph(manifest: Manifest, args: CallgraphArgs) -> anyhow::Result<()> {
+    let graph = CallGraph::from_manifest(&manifest)?;
+
+    let mut wtr = create_output(args.output.as_deref())?;
+    match args.format {
+        CallgraphFormat::Dot => output_callgraph_dot(&mut wtr, &graph)?,
+        CallgraphFormat::Json => output_callgraph_json(&mut wtr, &graph)?,
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+fn cmd_shell(manifest: Manifest, annotation_path: PathBuf, args: ShellArgs) -> anyhow::Result<()> {
+    let mut shell = shell::Shell::new(manifest, annotation_path);
+    if let Some(bank_name) = args.bank_name {
+        shell.load_bank(&bank_name)?;
+    }
+
+    let mut wtr = std::io::stdout().lock();
+    shell.run(std::io::stdin().lock(), &mut wtr)?;
+
+    Ok(())
+}
+
+fn cmd_annotate(
+    manifest: Manifest,
+    annotation_path: PathBuf,
+    args: AnnotateArgs,
+) -> anyhow::Result<()> {
+    let mut shell = shell::Shell::new(manifest, annotation_path);
+    if let Some(bank_name) = args.bank {
+        shell.load_bank(&bank_name)?;
+    }
+
+    let tokens: Vec<&str> = args.command.iter().map(String::as_str).collect();
+    let mut wtr = std::io::stdout().lock();
+    shell.edit(&tokens)?;
+    shell.save(&mut wtr)?;
+
+    Ok(())
+}
+
+fn cmd_import(
+    mut manifest: Manifest,
+    annotation_path: PathBuf,
+    args: ImportArgs,
+) -> anyhow::Result<()> {
+    let (input, config) = manifest.input_config(&args.bank_name)?;
+    let asm = analyze(&input, config.analysis());
+
+    let file = std::fs::File::open(&args.path)
+        .with_context(|| format!("can't open '{}'", args.path.display()))?;
+    let imported = import_assembly(&asm, config.output(), BufReader::new(file))
+        .with_context(|| format!("can't import '{}'", args.path.display()))?;
+
+    let bank_name = Some(args.bank_name.as_str());
+    let (mut label_count, mut comment_count) = (0, 0);
+    for (addr, name) in imported.labels() {
+        let res = manifest
+            .annotations_at_mut(addr, bank_name)
+            .and_then(|annotations| annotations.set_label(addr, name));
+        match res {
+            Ok(()) => label_count += 1,
+            Err(e) => log::warn!("can't import label '{name}' at {addr:#06X}: {e:#}"),
+        }
+    }
+    for (addr, comment) in imported.comments() {
+        let res = manifest
+            .annotations_at_mut(addr, bank_name)
+            .and_then(|annotations| annotations.set_comment(addr, comment));
+        match res {
+            Ok(()) => comment_count += 1,
+            Err(e) => log::warn!("can't import comment at {addr:#06X}: {e:#}"),
+        }
+    }
+
+    manifest.annotations().save(&annotation_path)?;
+    eprintln!(
+        "imported {label_count} labels and {comment_count} comments into {}",
+        annotation_path.display()
+    );
+
+    Ok(())
+}
+
+fn cmd_diff(manifest: Manifest, args: DiffArgs) -> anyhow::Result<()> {
+    let other_toml = std::fs::read_to_string(&args.other)
+        .with_context(|| format!("can't read manifest '{}'", args.other.display()))?;
+    let mut other = Manifest::from_toml(other_toml)?;
+    let other_annotation_path = args
+        .other_annotations
+        .unwrap_or_else(|| args.other.with_extension("annot.toml"));
+    other.set_annotations(AnnotationFile::load(&other_annotation_path)?);
+
+    let bank_names = (!args.banks.is_empty()).then_some(args.banks.as_slice());
+    let diff = ManifestDiff::new(&manifest, &other, bank_names)?;
+
+    let mut wtr = BufWriter::new(std::io::stdout().lock());
+    output_diff(&mut wtr, &diff)?;
+    wtr.flush()?;
+
+    if !args.dry_run {
+        let count = diff.transfer_annotations(&manifest, &mut other)?;
+        other.annotations().save(&other_annotation_path)?;
+        eprintln!(
+            "transferred {} labels and {} comments into {}",
+            count.labels,
+            count.comments,
+            other_annotation_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// 出力先を作る。パスが指定されなければ標準出力とする。
+fn create_output(path: Option<&Path>) -> anyhow::Result<Box<dyn Write>> {
+    let wtr: Box<dyn Write> = match path {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("can't create '{}'", path.display()))?;
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+
+    Ok(wtr)
+}
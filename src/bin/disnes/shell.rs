@@ -0,0 +1,480 @@
+//! `disnes shell`: マニフェストを一度だけ読み込み、対話的に逆アセンブル結果を調べる。
+//!
+//! 注釈 (ラベル名、コメント、データ型、コード/データ指定) の編集は `reanalyze` で解析結果に反映され、
+//! `save` または終了時に注釈ファイルに保存される。
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, ensure, Context as _};
+
+use disnes::*;
+
+const HELP: &str = "\
+bank <name>                逆アセンブル対象バンクを切り替える
+dis <addr> [count]         指定したアドレスから count 個 (デフォルト 20) の文を表示する
+xref <addr>                指定したアドレスを参照している箇所を表示する
+label <addr> [<name>|-]    ラベル名を表示/設定/削除する
+comment <addr> [<text>|-]  コメントを表示/設定/削除する
+type <addr> [<type>|-]     データ型 (byte, word, ptr, 構造体名。ptr[16] のように要素数も指定可) を表示/設定/削除する
+mark code <addr>           指定したアドレスをコードのエントリポイントとする
+mark data <addr>[-<addr>]  指定したアドレス範囲をデータとする
+reanalyze                  注釈を反映して再解析する
+save                       注釈を注釈ファイルに保存する
+help                       このヘルプを表示する
+quit                       終了する (未保存の注釈は保存される)";
+
+#[derive(Debug)]
+pub struct Shell {
+    manifest: Manifest,
+    annotation_path: PathBuf,
+    bank: Option<LoadedBank>,
+    /// 一度読み込んだバンクの、注釈に依存しないデータ (再解析時にファイルを読み直さないため)。
+    bank_data: HashMap<String, BankData>,
+    /// 保存されていない注釈の変更があるかどうか。
+    modified: bool,
+}
+
+/// 読み込み済みのバンク。
+#[derive(Debug)]
+struct LoadedBank {
+    name: String,
+    config: Config,
+    asm: Assembly,
+    /// 解析後に注釈が変更されたかどうか。
+    stale: bool,
+}
+
+impl Shell {
+    /// マニフェスト (注釈ファイルから読み込んだ注釈を含む) と、注釈の保存先を指定してシェルを作る。
+    pub fn new(manifest: Manifest, annotation_path: PathBuf) -> Self {
+        Self {
+            manifest,
+            annotation_path,
+            bank: None,
+            bank_data: HashMap::new(),
+            modified: false,
+        }
+    }
+
+    /// 入力が尽きるか `quit` が入力されるまでコマンドを実行する。
+    pub fn run<R: BufRead, W: Write>(&mut self, mut rdr: R, wtr: &mut W) -> anyhow::Result<()> {
+        loop {
+            write!(wtr, "disnes> ")?;
+            wtr.flush()?;
+
+            let mut line = String::new();
+            if rdr.read_line(&mut line)? == 0 {
+                writeln!(wtr)?;
+                break;
+            }
+
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            if matches!(tokens[0], "quit" | "exit") {
+                break;
+            }
+
+            if let Err(e) = self.exec(wtr, &tokens) {
+                writeln!(wtr, "error: {e:#}")?;
+            }
+        }
+
+        if self.modified {
+            self.save(wtr)?;
+        }
+
+        Ok(())
+    }
+
+    fn exec<W: Write>(&mut self, wtr: &mut W, tokens: &[&str]) -> anyhow::Result<()> {
+        let (&cmd, args) = tokens.split_first().unwrap();
+
+        match (cmd, args) {
+            ("bank", &[name]) => {
+                self.load_bank(name)?;
+                let bank = self.bank()?;
+                let range = bank.asm.bank_addr_range();
+                writeln!(
+                    wtr,
+                    "bank {} (${:04X}-${:04X})",
+                    bank.name,
+                    range.min().get(),
+                    range.max().get()
+                )?;
+            }
+            ("dis", &[addr]) => self.dis(wtr, parse_addr(addr)?, 20)?,
+            ("dis", &[addr, count]) => {
+                let count = count
+                    .parse()
+                    .with_context(|| format!("invalid count: '{count}'"))?;
+                self.dis(wtr, parse_addr(addr)?, count)?;
+            }
+            ("xref", &[addr]) => self.xref(wtr, parse_addr(addr)?)?,
+            ("label", &[addr]) => {
+                let addr = parse_addr(addr)?;
+                match self.annotations_at(addr)?.label(addr) {
+                    Some(name) => writeln!(wtr, "{name}")?,
+                    None => writeln!(wtr, "(no name)")?,
+                }
+            }
+            ("comment", &[addr]) => {
+                let addr = parse_addr(addr)?;
+                match self.annotations_at(addr)?.comment(addr) {
+                    Some(comment) => writeln!(wtr, "{comment}")?,
+                    None => writeln!(wtr, "(no comment)")?,
+                }
+            }
+            ("type", &[addr]) => {
+                let addr = parse_addr(addr)?;
+                match self.annotations_at(addr)?.data_type(addr) {
+                    Some(ty) => writeln!(wtr, "{ty}")?,
+                    None => writeln!(wtr, "(no type)")?,
+                }
+            }
+            ("label" | "comment" | "type" | "mark", _) => {
+                self.edit(tokens)?;
+                if let Some(bank) = self.bank.as_mut() {
+                    bank.stale = true;
+                    writeln!(wtr, "(run 'reanalyze' to apply)")?;
+                }
+            }
+            ("reanalyze", &[]) => {
+                let name = self.bank()?.name.clone();
+                self.load_bank(&name)?;
+                writeln!(wtr, "reanalyzed bank {name}")?;
+            }
+            ("save", &[]) => self.save(wtr)?,
+            ("help", &[]) => writeln!(wtr, "{HELP}")?,
+            _ => bail!("invalid command (type 'help' for usage)"),
+        }
+
+        Ok(())
+    }
+
+    /// 注釈を編集するコマンドを実行する。
+    pub fn edit(&mut self, tokens: &[&str]) -> anyhow::Result<()> {
+        match *tokens {
+            ["label", addr, "-"] => {
+                let addr = parse_addr(addr)?;
+                self.annotations_at(addr)?.remove_label(addr);
+            }
+            ["label", addr, name] => {
+                let addr = parse_addr(addr)?;
+                self.annotations_at(addr)?.set_label(addr, name)?;
+            }
+            ["comment", addr, "-"] => {
+                let addr = parse_addr(addr)?;
+                self.annotations_at(addr)?.remove_comment(addr);
+            }
+            ["comment", addr, ref text @ ..] if !text.is_empty() => {
+                let addr = parse_addr(addr)?;
+                self.annotations_at(addr)?
+                    .set_comment(addr, text.join(" "))?;
+            }
+            ["type", addr, "-"] => {
+                let addr = parse_addr(addr)?;
+                self.annotations_at(addr)?.remove_type(addr);
+            }
+            ["type", addr, ty] => {
+                let addr = parse_addr(addr)?;
+                let ty: DataType = ty.parse()?;
+                self.annotations_at(addr)?.set_type(addr, ty);
+            }
+            ["mark", "code", addr] => {
+                let addr = parse_addr(addr)?;
+                self.annotations_at(addr)?.mark_code(addr);
+            }
+            ["mark", "data", range] => {
+                let range = parse_addr_range(range)?;
+                self.annotations_at(range.min())?.mark_data(range);
+            }
+            _ => bail!("invalid editing command (type 'help' in shell for usage)"),
+        }
+
+        self.modified = true;
+
+        Ok(())
+    }
+
+    /// 注釈を注釈ファイルに保存する。
+    pub fn save<W: Write>(&mut self, wtr: &mut W) -> anyhow::Result<()> {
+        self.manifest.annotations().save(&self.annotation_path)?;
+        self.modified = false;
+        writeln!(wtr, "saved to {}", self.annotation_path.display())?;
+
+        Ok(())
+    }
+
+    /// 指定したバンクを読み込み、注釈を反映して解析する。
+    ///
+    /// ROM などのファイルはバンクごとに最初の 1 回だけ読み込む。
+    pub fn load_bank(&mut self, name: &str) -> anyhow::Result<()> {
+        let data = match self.bank_data.entry(name.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.manifest.load_bank_data(name)?),
+        };
+        let (input, config) = self.manifest.input_config_with(data)?;
+
+        let asm = analyze(&input, config.analysis());
+
+        self.bank = Some(LoadedBank {
+            name: name.to_owned(),
+            config,
+            asm,
+            stale: false,
+        });
+
+        Ok(())
+    }
+
+    fn bank(&self) -> anyhow::Result<&LoadedBank> {
+        self.bank
+            .as_ref()
+            .context("no bank is loaded (use 'bank <name>')")
+    }
+
+    fn dis<W: Write>(&self, wtr: &mut W, addr: Address, count: usize) -> anyhow::Result<()> {
+        let bank = self.bank()?;
+        ensure!(
+            bank.asm.bank_addr_range().contains_addr(addr),
+            "{addr:#06X} is out of bank {}",
+            bank.name
+        );
+
+        self.note_stale(wtr)?;
+        output_listing(wtr, &bank.asm, bank.config.output(), addr, count)?;
+
+        Ok(())
+    }
+
+    fn xref<W: Write>(&self, wtr: &mut W, dst: Address) -> anyhow::Result<()> {
+        let bank = self.bank()?;
+
+        self.note_stale(wtr)?;
+        let sites = find_refs(&bank.asm, dst);
+        if sites.is_empty() {
+            writeln!(wtr, "(no references in bank {})", bank.name)?;
+        }
+        for site in sites {
+            output_listing(wtr, &bank.asm, bank.config.output(), site, 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// 指定したアドレスに対する注釈の格納先を返す (読み込み済みのバンクを優先する)。
+    fn annotations_at(&mut self, addr: Address) -> anyhow::Result<&mut Annotations> {
+        let bank_name = self.bank.as_ref().map(|bank| bank.name.as_str());
+
+        self.manifest.annotations_at_mut(addr, bank_name)
+    }
+
+    fn note_stale<W: Write>(&self, wtr: &mut W) -> anyhow::Result<()> {
+        if self.bank.as_ref().is_some_and(|bank| bank.stale) {
+            writeln!(wtr, "(annotations are edited; run 'reanalyze' to apply)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// 指定したアドレスを参照している文のアドレスたちを返す。
+///
+/// 命令のオペランド (インデックス付きのものはベースアドレス)、即値によるアドレス指定、
+/// 構造体のポインタ型フィールド、分割ポインタテーブルの要素を参照とみなす。
+fn find_refs(asm: &Assembly, dst: Address) -> Vec<Address> {
+    let mut res = Vec::<Address>::new();
+
+    let mut addr = asm.bank_addr();
+    for stmt in asm.statements() {
+        let refers = match *stmt {
+            Statement::Op(op) => match op.operand() {
+                Operand::Zp(zp)
+                | Operand::ZpX(zp)
+                | Operand::ZpY(zp)
+                | Operand::IndX(zp)
+                | Operand::IndY(zp) => Address::from(zp) == dst,
+                Operand::Abs(abs) | Operand::AbsX(abs) | Operand::AbsY(abs) | Operand::Ind(abs) => {
+                    abs == dst
+                }
+                Operand::Rel(rel) => {
+                    addr.wrapping_add_unsigned(2_usize).wrapping_add_signed(rel) == dst
+                }
+                _ => false,
+            },
+            Statement::Record(ref record) => record.fields().any(|(_, field, value)| {
+                field.ty() == FieldType::Ptr && Address::new(value) == dst
+            }),
+            Statement::LoBytes(ref addrs) | Statement::HiBytes(ref addrs) => addrs.contains(&dst),
+            _ => false,
+        };
+        let refers = refers
+            || asm
+                .find_imm_addr(addr)
+                .is_some_and(|imm_addr| imm_addr.dst() == dst);
+        if refers {
+            res.push(addr);
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt.len()) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// 固定バンク "FIX" ($C000-$FFFF) のみからなるマニフェストと、ROM を置いたディレクトリを返す。
+    fn make_manifest() -> (Manifest, PathBuf) {
+        #[rustfmt::skip]
+        let main = [
+            0x20, 0x10, 0xC0, // $C000: jsr $C010
+            0xBD, 0x20, 0xC0, // $C003: lda $C020,x
+            0x4C, 0x00, 0xC0, // $C006: jmp $C000
+        ];
+        #[rustfmt::skip]
+        let sub = [
+            0xA2, 0x04, // $C010: ldx #4
+            0xCA,       // $C012: dex
+            0xD0, 0xFD, // $C013: bne $C012
+            0x60,       // $C015: rts
+        ];
+        let mut rom = vec![0xFF; 0x4000];
+        rom[0x00..][..main.len()].copy_from_slice(&main);
+        rom[0x10..][..sub.len()].copy_from_slice(&sub);
+        rom[0x20..][..4].copy_from_slice(&[1, 2, 3, 4]);
+        for vector in rom[0x3FFA..].chunks_exact_mut(2) {
+            vector.copy_from_slice(&[0x00, 0xC0]);
+        }
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "disnes-shell-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prg.bin");
+        std::fs::write(&path, rom).unwrap();
+
+        let manifest = Manifest::from_toml(format!(
+            r#"
+memory = [
+    {{ start = 0, len = 0x800, readable = true, writable = true, executable = true }},
+    {{ start = 0x8000, len = 0x8000, readable = true, writable = true, executable = true }},
+]
+banks = [
+    {{ name = "FIX", start = 0xC000, len = 0x4000, file = {path:?}, fixed = true }},
+]
+
+[config]
+"#,
+        ))
+        .unwrap();
+
+        (manifest, dir)
+    }
+
+    fn exec(shell: &mut Shell, line: &str) -> String {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let mut wtr = Vec::<u8>::new();
+        shell.exec(&mut wtr, &tokens).unwrap();
+
+        String::from_utf8(wtr).unwrap()
+    }
+
+    #[test]
+    fn test_find_refs() {
+        let (manifest, dir) = make_manifest();
+        let mut shell = Shell::new(manifest, dir.join("annot.toml"));
+        shell.load_bank("FIX").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let asm = &shell.bank().unwrap().asm;
+        let refs = |dst: u16| find_refs(asm, Address::new(dst));
+
+        assert_eq!(refs(0xC010), [Address::new(0xC000)]);
+        assert_eq!(refs(0xC012), [Address::new(0xC013)]);
+        assert_eq!(refs(0xC020), [Address::new(0xC003)]);
+        assert!(refs(0xC016).is_empty());
+    }
+
+    #[test]
+    fn test_edit() {
+        let (manifest, dir) = make_manifest();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut shell = Shell::new(manifest, dir.join("annot.toml"));
+
+        shell.edit(&["label", "$C010", "wait"]).unwrap();
+        shell
+            .edit(&["comment", "C010", "wait", "a", "moment"])
+            .unwrap();
+        shell.edit(&["type", "0xC020", "byte[4]"]).unwrap();
+        shell.edit(&["mark", "code", "C030"]).unwrap();
+        shell.edit(&["mark", "data", "C040-C04F"]).unwrap();
+        // 0 ページは固定バンク外なので、グローバルな注釈となる。
+        shell.edit(&["label", "$0010", "player_x"]).unwrap();
+        assert!(shell.modified);
+
+        let annotations = shell.manifest.annotations();
+        let bank = annotations.bank("FIX").unwrap();
+        assert_eq!(bank.label(Address::new(0xC010)), Some("wait"));
+        assert_eq!(bank.comment(Address::new(0xC010)), Some("wait a moment"));
+        assert_eq!(
+            bank.data_type(Address::new(0xC020))
+                .map(ToString::to_string),
+            Some("byte[4]".to_owned())
+        );
+        assert!(bank.code_entries().eq([Address::new(0xC030)]));
+        assert_eq!(
+            bank.data_ranges(),
+            [AddressRange::from_min_max(
+                Address::new(0xC040),
+                Address::new(0xC04F)
+            )]
+        );
+        assert_eq!(
+            annotations.global().label(Address::new(0x0010)),
+            Some("player_x")
+        );
+
+        shell.edit(&["label", "C010", "-"]).unwrap();
+        shell.edit(&["comment", "C010", "-"]).unwrap();
+        shell.edit(&["type", "C020", "-"]).unwrap();
+        let bank = shell.manifest.annotations().bank("FIX").unwrap();
+        assert_eq!(bank.label(Address::new(0xC010)), None);
+        assert_eq!(bank.comment(Address::new(0xC010)), None);
+        assert_eq!(bank.data_type(Address::new(0xC020)), None);
+
+        assert!(shell.edit(&["label", "C010", "1st"]).is_err());
+        assert!(shell.edit(&["mark", "data", "C04F-C040"]).is_err());
+        assert!(shell.edit(&["comment", "C010"]).is_err());
+    }
+
+    #[test]
+    fn test_reanalyze_uses_cached_bank() {
+        let (manifest, dir) = make_manifest();
+        let mut shell = Shell::new(manifest, dir.join("annot.toml"));
+        exec(&mut shell, "bank FIX");
+        // 再解析ではファイルを読み直さない。
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(exec(&mut shell, "label C010 wait").contains("reanalyze"));
+        assert!(exec(&mut shell, "dis C000 1").contains("L_C010"));
+
+        exec(&mut shell, "reanalyze");
+        assert!(!shell.bank().unwrap().stale);
+        assert!(exec(&mut shell, "dis C000 1").contains("jsr     wait"));
+    }
+}
@@ -0,0 +1,347 @@
+//! 全バンクにわたるルーチン間の呼び出しグラフ。
+
+use std::collections::BTreeMap;
+
+use crate::address::Address;
+use crate::analysis::analyze;
+use crate::input::Input;
+use crate::manifest::Manifest;
+use crate::op::Op;
+
+/// 呼び出しグラフ。
+///
+/// 各バンクを順に逆アセンブル対象として解析し、jsr 命令およびポインタを読める間接 jmp 命令から
+/// ルーチン間の呼び出し関係を求める。
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CallGraph {
+    nodes: Vec<CallGraphNode>,
+    edges: Vec<CallGraphEdge>,
+}
+
+impl CallGraph {
+    /// `Manifest` 内の全バンクを解析して呼び出しグラフを作る。
+    pub fn from_manifest(manifest: &Manifest) -> anyhow::Result<Self> {
+        let mut builder = Builder::default();
+
+        for bank_name in manifest.bank_names() {
+            let (input, config) = manifest.input_config(bank_name)?;
+            let asm = analyze(&input, config.analysis());
+
+            for routine in asm.routines() {
+                let src = builder.node(Some(bank_name), routine.entry());
+                builder.nodes[src].size = Some(routine.len());
+
+                for &site in routine.op_addrs() {
+                    let Ok((op, _)) = input.memory().fetch_op(site) else {
+                        continue;
+                    };
+
+                    let (kind, dst) = match op {
+                        Op::Jsr(dst) => (CallKind::Jsr, dst),
+                        Op::JmpInd(ptr) => match input.memory().fetch_addr(ptr) {
+                            Some((dst, _)) => (CallKind::JmpInd, dst),
+                            None => continue,
+                        },
+                        _ => continue,
+                    };
+
+                    // 呼び出し先のバンクを求める。
+                    // バンク切り替え解析により行き先のバンク番号が判明していればそれを使う。
+                    let bank_switch = asm
+                        .find_bank_switch(site)
+                        .map(|bank_switch| bank_switch.number());
+                    let dst_bank = dst_bank_name(manifest, &input, bank_name, dst, bank_switch);
+
+                    let dst = builder.node(dst_bank, dst);
+                    builder.edge(src, dst, kind, site, bank_switch);
+
+                    // 固定バンク内でバンク切り替えを伴う呼び出しを行うルーチンはトランポリンとみなす。
+                    if bank_switch.is_some() && input.target_bank().is_fixed() {
+                        builder.nodes[src].trampoline = true;
+                    }
+                }
+            }
+        }
+
+        Ok(builder.build())
+    }
+
+    /// ノードたちを返す。ノード ID はこのスライスのインデックス。
+    pub fn nodes(&self) -> &[CallGraphNode] {
+        &self.nodes
+    }
+
+    /// 辺たちを返す。
+    pub fn edges(&self) -> &[CallGraphEdge] {
+        &self.edges
+    }
+}
+
+/// 呼び出しグラフのノード (ルーチン)。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CallGraphNode {
+    bank: Option<String>,
+    entry: Address,
+    size: Option<usize>,
+    trampoline: bool,
+}
+
+impl CallGraphNode {
+    /// ルーチンが属するバンク名を返す。推定できなかった場合は `None` を返す。
+    pub fn bank(&self) -> Option<&str> {
+        self.bank.as_deref()
+    }
+
+    /// エントリポイントを返す。
+    pub fn entry(&self) -> Address {
+        self.entry
+    }
+
+    /// ルーチンのバイト数を返す。ルーチンとして解析されていなければ `None` を返す。
+    pub fn size(&self) -> Option<usize> {
+        self.size
+    }
+
+    /// 固定バンク上でバンク切り替えを伴う呼び出しを行うルーチン (トランポリン) かどうかを返す。
+    ///
+    /// バンク切り替え (マッパーレジスタへの書き込み) と呼び出しが同じルーチン内にある場合のみ検出される。
+    /// 切り替えを別のルーチンに任せる場合 (`jsr SetBank` の後に呼び出すなど) はトランポリンとみなされない。
+    pub fn is_trampoline(&self) -> bool {
+        self.trampoline
+    }
+}
+
+/// 呼び出しグラフの辺。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CallGraphEdge {
+    src: usize,
+    dst: usize,
+    kind: CallKind,
+    sites: Vec<Address>,
+    bank_switch: Option<usize>,
+}
+
+impl CallGraphEdge {
+    /// 呼び出し元ノード ID を返す。
+    pub fn src(&self) -> usize {
+        self.src
+    }
+
+    /// 呼び出し先ノード ID を返す。
+    pub fn dst(&self) -> usize {
+        self.dst
+    }
+
+    pub fn kind(&self) -> CallKind {
+        self.kind
+    }
+
+    /// 呼び出し命令のアドレスたちを昇順で返す。
+    pub fn sites(&self) -> &[Address] {
+        &self.sites
+    }
+
+    /// 呼び出し直前にバンク切り替えが行われていれば、そのバンク番号を返す。
+    pub fn bank_switch(&self) -> Option<usize> {
+        self.bank_switch
+    }
+}
+
+/// 呼び出しの種類。
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum CallKind {
+    /// jsr 命令。
+    Jsr,
+    /// ポインタを読める間接 jmp 命令。
+    JmpInd,
+}
+
+#[derive(Debug, Default)]
+struct Builder {
+    nodes: Vec<CallGraphNode>,
+    node_ids: BTreeMap<(Option<String>, Address), usize>,
+    edges: BTreeMap<(usize, usize, CallKind, Option<usize>), Vec<Address>>,
+}
+
+impl Builder {
+    /// (バンク名, エントリポイント) に対応するノード ID を返す。なければ作る。
+    fn node(&mut self, bank: Option<&str>, entry: Address) -> usize {
+        let key = (bank.map(str::to_owned), entry);
+
+        *self.node_ids.entry(key).or_insert_with(|| {
+            self.nodes.push(CallGraphNode {
+                bank: bank.map(str::to_owned),
+                entry,
+                size: None,
+                trampoline: false,
+            });
+            self.nodes.len() - 1
+        })
+    }
+
+    fn edge(&mut self, src: usize, dst: usize, kind: CallKind, site: Address, bank: Option<usize>) {
+        self.edges
+            .entry((src, dst, kind, bank))
+            .or_default()
+            .push(site);
+    }
+
+    fn build(self) -> CallGraph {
+        let edges = self
+            .edges
+            .into_iter()
+            .map(|((src, dst, kind, bank_switch), mut sites)| {
+                sites.sort_unstable();
+                sites.dedup();
+                CallGraphEdge {
+                    src,
+                    dst,
+                    kind,
+                    sites,
+                    bank_switch,
+                }
+            })
+            .collect();
+
+        CallGraph {
+            nodes: self.nodes,
+            edges,
+        }
+    }
+}
+
+/// 呼び出し先アドレスが属するバンク名を求める。
+///
+/// 固定バンク外への呼び出しについては、行き先のバンク番号が判明していればそれに対応するバンクとし、
+/// さもなくば逆アセンブル対象バンク内への呼び出しのみ逆アセンブル対象バンクとする。
+fn dst_bank_name<'a>(
+    manifest: &'a Manifest,
+    input: &Input,
+    target_bank_name: &'a str,
+    dst: Address,
+    bank_switch: Option<usize>,
+) -> Option<&'a str> {
+    if let Some(name) = manifest.find_fixed_bank_name(dst) {
+        return Some(name);
+    }
+
+    if let Some(number) = bank_switch {
+        return manifest.find_switchable_bank_name(dst, number);
+    }
+
+    input
+        .target_bank()
+        .contains_addr(dst)
+        .then_some(target_bank_name)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::analysis::test_util::make_bank_body;
+
+    use super::*;
+
+    /// 固定バンク上のトランポリンから非固定バンクのルーチンを呼ぶ UxROM の ROM から呼び出しグラフを作る。
+    ///
+    /// バンクの内容は一時ディレクトリに書き出す。
+    pub(crate) fn make_call_graph() -> CallGraph {
+        #[rustfmt::skip]
+        let main = [
+            0x20, 0x10, 0xC0, // $C000: jsr $C010
+            0x4C, 0x03, 0xC0, // $C003: jmp $C003
+        ];
+        #[rustfmt::skip]
+        let trampoline = [
+            0xA9, 0x01,       // $C010: lda #1
+            0x8D, 0x00, 0x80, // $C012: sta $8000
+            0x20, 0x00, 0x80, // $C015: jsr $8000
+            0x60,             // $C018: rts
+        ];
+        let fixed = make_bank_body(&[(0xC000, &main), (0xC010, &trampoline)]);
+        let mut prg1 = vec![0xFF; 0x4000];
+        prg1[0] = 0x60; // $8000: rts
+
+        let mut rom = vec![0xFF; 0x4000];
+        rom.extend(prg1);
+        rom.extend(fixed);
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "disnes-callgraph-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prg.bin");
+        std::fs::write(&path, rom).unwrap();
+
+        let manifest = Manifest::from_toml(format!(
+            r#"
+mapper = "uxrom"
+memory = [
+    {{ start = 0, len = 0x800, readable = true, writable = true, executable = true }},
+    {{ start = 0x8000, len = 0x8000, readable = true, writable = true, executable = true }},
+]
+banks = [
+    {{ name = "PRG0", start = 0x8000, len = 0x4000, file = {path:?}, file_offset = 0 }},
+    {{ name = "PRG1", start = 0x8000, len = 0x4000, file = {path:?}, file_offset = 0x4000 }},
+    {{ name = "FIX", start = 0xC000, len = 0x4000, file = {path:?}, file_offset = 0x8000, fixed = true }},
+]
+
+[config]
+"#,
+        ))
+        .unwrap();
+
+        let graph = CallGraph::from_manifest(&manifest).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        graph
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let graph = make_call_graph();
+
+        let node_id = |bank: &str, entry: u16| {
+            graph
+                .nodes()
+                .iter()
+                .position(|node| node.bank() == Some(bank) && node.entry() == Address::new(entry))
+                .unwrap()
+        };
+        let (main, trampoline, callee) = (
+            node_id("FIX", 0xC000),
+            node_id("FIX", 0xC010),
+            node_id("PRG1", 0x8000),
+        );
+
+        // バンク切り替えと呼び出しを同じルーチン内で行うもののみトランポリンとみなす。
+        assert!(!graph.nodes()[main].is_trampoline());
+        assert!(graph.nodes()[trampoline].is_trampoline());
+        assert!(!graph.nodes()[callee].is_trampoline());
+        assert_eq!(graph.nodes()[callee].size(), Some(1));
+
+        assert_eq!(
+            graph.edges(),
+            [
+                CallGraphEdge {
+                    src: main,
+                    dst: trampoline,
+                    kind: CallKind::Jsr,
+                    sites: vec![Address::new(0xC000)],
+                    bank_switch: None,
+                },
+                CallGraphEdge {
+                    src: trampoline,
+                    dst: callee,
+                    kind: CallKind::Jsr,
+                    sites: vec![Address::new(0xC015)],
+                    bank_switch: Some(1),
+                },
+            ]
+        );
+    }
+}
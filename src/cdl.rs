@@ -20,14 +20,14 @@ impl Cdl {
         self[addr].is_indirect_data()
             && addr
                 .checked_add_signed(-1_isize)
-                .map_or(true, |addr_pre| !self[addr_pre].is_indirect_data())
+                .is_none_or(|addr_pre| !self[addr_pre].is_indirect_data())
     }
 
     pub fn is_pcm_data_start(&self, addr: Address) -> bool {
         self[addr].is_pcm_data()
             && addr
                 .checked_add_signed(-1_isize)
-                .map_or(true, |addr_pre| !self[addr_pre].is_pcm_data())
+                .is_none_or(|addr_pre| !self[addr_pre].is_pcm_data())
     }
 
     pub fn is_opcode(&self, addr: Address) -> bool {
@@ -57,6 +57,18 @@ impl Cdl {
     pub fn is_entrypoint(&self, addr: Address) -> bool {
         self[addr].is_entrypoint()
     }
+
+    /// 指定したアドレスにフラグを追加する。
+    pub fn mark(&mut self, addr: Address, flags: CdlElement) {
+        self[addr] = self[addr].union(flags);
+    }
+
+    /// 全アドレスについて、`other` のフラグを追加する。
+    pub fn merge(&mut self, other: &Self) {
+        for (lhs, rhs) in self.0.iter_mut().zip(other.0.iter()) {
+            *lhs = lhs.union(*rhs);
+        }
+    }
 }
 
 impl std::ops::Index<Address> for Cdl {
@@ -92,10 +104,27 @@ impl std::ops::IndexMut<AddressRange> for Cdl {
 pub struct CdlElement(u8);
 
 impl CdlElement {
+    pub const OPCODE: Self = Self(1 << 0);
+    pub const DATA: Self = Self(1 << 1);
+    pub const OPERAND: Self = Self(1 << 2);
+    pub const JUMP_TARGET: Self = Self(1 << 4);
+    pub const INDIRECT_DATA: Self = Self(1 << 5);
+    pub const PCM_DATA: Self = Self(1 << 6);
+    pub const ENTRYPOINT: Self = Self(1 << 7);
+
     pub const fn new(inner: u8) -> Self {
         Self(inner)
     }
 
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// 両者のフラグを合わせたものを返す。
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
     pub const fn is_opcode(self) -> bool {
         (self.0 & (1 << 0)) != 0
     }
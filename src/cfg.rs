@@ -0,0 +1,317 @@
+//! 基本ブロック単位の制御フローグラフ。
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::ensure;
+
+use crate::address::{Address, ArrayByAddress};
+use crate::assembly::{Assembly, Statement};
+use crate::input::Input;
+use crate::memory::OpSuccResolved;
+use crate::op::Op;
+
+/// あるエントリポイントから到達できる基本ブロックたちからなる制御フローグラフ。
+///
+/// 逆アセンブル対象バンク内で `Op` と確定したアドレスのみを辿る。
+/// バンク外やコードでないアドレスへの制御フローは `CfgDst::External` として扱う。
+///
+/// jsr 命令については、直後が命令であり、かつサブルーチン解析により呼び出し先が正常に戻らないと
+/// 判明していなければ、呼び出し先から戻ってくるものとみなす。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cfg {
+    entry: Address,
+    blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    /// `Assembly` 内の指定したエントリポイントから到達できる基本ブロックたちを求める。
+    /// エントリポイントが逆アセンブル対象バンク内の命令でなければエラーを返す。
+    pub fn new(input: &Input, asm: &Assembly, entry: Address) -> anyhow::Result<Self> {
+        let ops = ops_by_address(asm);
+        ensure!(
+            ops[entry].is_some(),
+            "entry {entry:#06X} is not an op in bank '{}'",
+            asm.bank_name()
+        );
+
+        let memory = input.memory();
+
+        // 到達可能な命令を全て辿り、基本ブロックの先頭 (leader) を求める。
+        let mut leaders = BTreeSet::<Address>::from([entry]);
+        let mut visited = ArrayByAddress::<bool>::default();
+        let mut stack = vec![entry];
+        while let Some(addr) = stack.pop() {
+            if visited[addr] {
+                continue;
+            }
+            visited[addr] = true;
+
+            let op = ops[addr].unwrap();
+            for edge in op_edges(asm, &ops, memory.resolve_op_succ(addr, op.succ()), addr, op) {
+                if let CfgDst::Block(dst) = edge.dst {
+                    if edge.kind != CfgEdgeKind::Normal {
+                        leaders.insert(dst);
+                    }
+                    stack.push(dst);
+                }
+            }
+        }
+
+        // 各 leader から、制御フロー命令または次の leader の直前までを基本ブロックとする。
+        let mut blocks = BTreeMap::<Address, BasicBlock>::new();
+        for &leader in &leaders {
+            let mut block = BasicBlock {
+                addr: leader,
+                ops: vec![],
+                succs: vec![],
+            };
+
+            let mut addr = leader;
+            loop {
+                let op = ops[addr].unwrap();
+                block.ops.push((addr, op));
+
+                let edges = op_edges(asm, &ops, memory.resolve_op_succ(addr, op.succ()), addr, op);
+                let next = match edges.as_slice() {
+                    [CfgEdge {
+                        kind: CfgEdgeKind::Normal,
+                        dst: CfgDst::Block(dst),
+                    }] if !leaders.contains(dst) => Some(*dst),
+                    _ => None,
+                };
+                match next {
+                    Some(dst) => addr = dst,
+                    None => {
+                        block.succs = edges;
+                        break;
+                    }
+                }
+            }
+
+            blocks.insert(leader, block);
+        }
+
+        Ok(Self {
+            entry,
+            blocks: blocks.into_values().collect(),
+        })
+    }
+
+    /// エントリポイントを返す。
+    pub fn entry(&self) -> Address {
+        self.entry
+    }
+
+    /// 基本ブロックたちを先頭アドレスの昇順で返す。
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+}
+
+/// 基本ブロック。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BasicBlock {
+    addr: Address,
+    ops: Vec<(Address, Op)>,
+    succs: Vec<CfgEdge>,
+}
+
+impl BasicBlock {
+    /// 先頭アドレスを返す。
+    pub fn addr(&self) -> Address {
+        self.addr
+    }
+
+    /// (アドレス, 命令) たちを返す。空でないことが保証される。
+    pub fn ops(&self) -> &[(Address, Op)] {
+        &self.ops
+    }
+
+    /// 末尾の命令から出ていく辺たちを返す。
+    pub fn succs(&self) -> &[CfgEdge] {
+        &self.succs
+    }
+}
+
+/// 制御フローグラフの辺。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CfgEdge {
+    kind: CfgEdgeKind,
+    dst: CfgDst,
+}
+
+impl CfgEdge {
+    pub fn kind(self) -> CfgEdgeKind {
+        self.kind
+    }
+
+    pub fn dst(self) -> CfgDst {
+        self.dst
+    }
+}
+
+/// 制御フローグラフの辺の種類。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CfgEdgeKind {
+    /// 非制御フロー命令による次の命令への移動。
+    Normal,
+    /// 分岐命令の分岐時。
+    Taken,
+    /// 分岐命令の非分岐時。
+    NotTaken,
+    /// jmp 命令。
+    Jump,
+    /// jsr 命令の呼び出し先から戻った後。
+    Return,
+}
+
+/// 制御フローグラフの辺の行き先。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CfgDst {
+    /// 逆アセンブル対象バンク内の命令。
+    Block(Address),
+    /// 逆アセンブル対象バンク外、またはコードでないアドレス。
+    External(Address),
+    /// 行き先を確定できない (ポインタが読めない間接 jmp など)。
+    Unresolved,
+}
+
+/// `Assembly` の各文をアドレスごとの命令に変換する。
+fn ops_by_address(asm: &Assembly) -> ArrayByAddress<Option<Op>> {
+    let mut ops = ArrayByAddress::<Option<Op>>::default();
+
+    let mut addr = asm.bank_addr();
+    for stmt in asm.statements() {
+        if let Statement::Op(op) = *stmt {
+            ops[addr] = Some(op);
+        }
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt.len()) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+
+    ops
+}
+
+/// 命令から出ていく辺たちを返す。
+fn op_edges(
+    asm: &Assembly,
+    ops: &ArrayByAddress<Option<Op>>,
+    succ: OpSuccResolved,
+    addr: Address,
+    op: Op,
+) -> Vec<CfgEdge> {
+    let edge = |kind: CfgEdgeKind, dst: Address| {
+        let dst = if ops[dst].is_some() {
+            CfgDst::Block(dst)
+        } else {
+            CfgDst::External(dst)
+        };
+        CfgEdge { kind, dst }
+    };
+
+    match succ {
+        OpSuccResolved::Normal(dst) => vec![edge(CfgEdgeKind::Normal, dst)],
+        OpSuccResolved::Branch { taken, not_taken } => vec![
+            edge(CfgEdgeKind::Taken, taken),
+            edge(CfgEdgeKind::NotTaken, not_taken),
+        ],
+        // jsr の直後が命令であり、呼び出し先が戻らないと判明していなければ、戻ってくるものとみなす。
+        OpSuccResolved::Jsr(_) => {
+            let ret = addr.wrapping_add_unsigned(op.len());
+            if ops[ret].is_some() && !asm.is_no_return_call(addr) {
+                vec![edge(CfgEdgeKind::Return, ret)]
+            } else {
+                vec![]
+            }
+        }
+        OpSuccResolved::JmpAbs(dst) => vec![edge(CfgEdgeKind::Jump, dst)],
+        OpSuccResolved::JmpInd(dst) => vec![match dst {
+            Some(dst) => edge(CfgEdgeKind::Jump, dst),
+            None => CfgEdge {
+                kind: CfgEdgeKind::Jump,
+                dst: CfgDst::Unresolved,
+            },
+        }],
+        OpSuccResolved::Brk(_)
+        | OpSuccResolved::Kil
+        | OpSuccResolved::Rti
+        | OpSuccResolved::Rts => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::analyze;
+    use crate::analysis::test_util::make_input;
+    use crate::config::AnalysisConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_cfg() {
+        #[rustfmt::skip]
+        let main = [
+            0xA2, 0x03,       // $C000: ldx #3
+            0xCA,             // $C002: dex
+            0xD0, 0xFD,       // $C003: bne $C002
+            0x20, 0x10, 0xC0, // $C005: jsr $C010
+            0x20, 0x20, 0xC0, // $C008: jsr $C020
+            0x4C, 0x00, 0xC0, // $C00B: jmp $C000
+        ];
+        #[rustfmt::skip]
+        let returns = [
+            0x60, // $C010: rts
+        ];
+        #[rustfmt::skip]
+        let no_return = [
+            0x4C, 0x20, 0xC0, // $C020: jmp $C020
+        ];
+        let input = make_input(&[(0xC000, &main), (0xC010, &returns), (0xC020, &no_return)]);
+        let asm = analyze(&input, &AnalysisConfig::default());
+        assert!(asm.is_no_return_call(Address::new(0xC008)));
+        assert!(ops_by_address(&asm)[Address::new(0xC00B)].is_some());
+
+        let cfg = Cfg::new(&input, &asm, Address::new(0xC000)).unwrap();
+
+        let edge = |kind: CfgEdgeKind, dst: u16| CfgEdge {
+            kind,
+            dst: CfgDst::Block(Address::new(dst)),
+        };
+        let blocks: Vec<_> = cfg
+            .blocks()
+            .iter()
+            .map(|block| {
+                let addrs: Vec<_> = block.ops().iter().map(|&(addr, _)| addr.get()).collect();
+                (block.addr().get(), addrs, block.succs().to_vec())
+            })
+            .collect();
+
+        assert_eq!(
+            blocks,
+            [
+                (
+                    0xC000,
+                    vec![0xC000],
+                    vec![edge(CfgEdgeKind::Normal, 0xC002)]
+                ),
+                (
+                    0xC002,
+                    vec![0xC002, 0xC003],
+                    vec![
+                        edge(CfgEdgeKind::Taken, 0xC002),
+                        edge(CfgEdgeKind::NotTaken, 0xC005),
+                    ]
+                ),
+                (
+                    0xC005,
+                    vec![0xC005],
+                    vec![edge(CfgEdgeKind::Return, 0xC008)]
+                ),
+                // 呼び出し先が戻らないので、直後 ($C00B) への辺はない。
+                (0xC008, vec![0xC008], vec![]),
+            ]
+        );
+    }
+}
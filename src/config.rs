@@ -1,3 +1,5 @@
+use std::num::NonZeroUsize;
+
 use serde::Deserialize;
 
 /// 逆アセンブラの設定。
@@ -5,12 +7,17 @@ use serde::Deserialize;
 #[serde(deny_unknown_fields, default)]
 pub struct Config {
     analysis: AnalysisConfig,
+    output: OutputConfig,
 }
 
 impl Config {
     pub fn analysis(&self) -> &AnalysisConfig {
         &self.analysis
     }
+
+    pub fn output(&self) -> &OutputConfig {
+        &self.output
+    }
 }
 
 /// 解析に関する設定。
@@ -34,6 +41,20 @@ pub struct AnalysisConfig {
 
     /// sed 命令を許可するか。デフォルトは `false`。
     allow_sed: bool,
+
+    /// テキストとみなす最小文字数 (終端、改行、制御コードを除く)。デフォルトは `4`。
+    text_min_len: usize,
+
+    /// 連続するデータをまとめて `.incbin` で出力する最小バイト数。`0` なら `.incbin` を使わない。
+    /// デフォルトは `0`。
+    incbin_threshold: usize,
+
+    /// 組み込みのシグネチャで既知のルーチンを認識するか。デフォルトは `true`。
+    builtin_signatures: bool,
+
+    /// 制御フロー解析などを、コード/非コードの解析結果が変化しなくなるまで繰り返す最大回数。
+    /// `1` なら繰り返さない (`0` は指定できない)。デフォルトは `16`。
+    max_iterations: NonZeroUsize,
 }
 
 impl Default for AnalysisConfig {
@@ -45,6 +66,10 @@ impl Default for AnalysisConfig {
             allow_brk: false,
             allow_clv: false,
             allow_sed: false,
+            text_min_len: 4,
+            incbin_threshold: 0,
+            builtin_signatures: true,
+            max_iterations: NonZeroUsize::new(16).unwrap(),
         }
     }
 }
@@ -73,4 +98,74 @@ impl AnalysisConfig {
     pub fn allow_sed(&self) -> bool {
         self.allow_sed
     }
+
+    pub fn text_min_len(&self) -> usize {
+        self.text_min_len
+    }
+
+    pub fn incbin_threshold(&self) -> usize {
+        self.incbin_threshold
+    }
+
+    pub fn builtin_signatures(&self) -> bool {
+        self.builtin_signatures
+    }
+
+    pub fn max_iterations(&self) -> NonZeroUsize {
+        self.max_iterations
+    }
+}
+
+/// 出力に関する設定。
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct OutputConfig {
+    /// DPCM サンプルを別ファイル (.dmc) に書き出し、`.incbin` で参照するか。デフォルトは `false`。
+    ///
+    /// `false` の場合、`.byte` で出力する。
+    /// いずれの場合も、64 バイト境界上にあることを `.assert` で検査する。
+    dpcm_incbin: bool,
+
+    /// データの `.byte` 1 行あたりのバイト数。デフォルトは `16`。
+    bytes_per_line: usize,
+
+    /// データの `.byte` 各行に、先頭バイトのアドレスをコメントとして付けるか。デフォルトは `false`。
+    byte_addr_comment: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            dpcm_incbin: false,
+            bytes_per_line: 16,
+            byte_addr_comment: false,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn dpcm_incbin(&self) -> bool {
+        self.dpcm_incbin
+    }
+
+    pub fn bytes_per_line(&self) -> usize {
+        self.bytes_per_line
+    }
+
+    pub fn byte_addr_comment(&self) -> bool {
+        self.byte_addr_comment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_iterations() {
+        let config: AnalysisConfig = toml::from_str("max_iterations = 1").unwrap();
+        assert_eq!(config.max_iterations().get(), 1);
+
+        assert!(toml::from_str::<AnalysisConfig>("max_iterations = 0").is_err());
+    }
 }
@@ -0,0 +1,675 @@
+//! 同じゲームの 2 つのリビジョン (地域版、改訂版など) の比較。
+//!
+//! 両者の同名バンクを解析し、ルーチンどうしを命令列の類似度で対応付ける。
+//! 対応付いた命令のアドレス、およびそのオペランドが指す ROM 上のアドレスを対応付け、
+//! 一方に付けられた注釈 (ラベル名、コメント) を他方に移すのに使う。
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use log::warn;
+
+use crate::address::Address;
+use crate::analysis::analyze;
+use crate::annotation::Annotations;
+use crate::assembly::Assembly;
+use crate::input::Input;
+use crate::manifest::Manifest;
+use crate::op::{Op, Operand};
+
+/// 命令列が似ているとみなす類似度 (0 以上 1 以下) の下限。
+const SIMILARITY_MIN: f64 = 0.5;
+
+/// 2 つのマニフェストの比較結果。
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ManifestDiff {
+    banks: Vec<BankDiff>,
+}
+
+impl ManifestDiff {
+    /// 両方のマニフェストに存在する同名バンクたちを比較する。
+    /// `bank_names` を指定した場合、それらのバンクのみを比較する。
+    pub fn new(
+        manifest: &Manifest,
+        other: &Manifest,
+        bank_names: Option<&[String]>,
+    ) -> anyhow::Result<Self> {
+        let mut banks = Vec::<BankDiff>::new();
+
+        for bank_name in manifest.bank_names() {
+            if bank_names.is_some_and(|names| !names.iter().any(|name| name == bank_name)) {
+                continue;
+            }
+            if !other.bank_names().any(|name| name == bank_name) {
+                continue;
+            }
+            banks.push(BankDiff::new(manifest, other, bank_name)?);
+        }
+
+        Ok(Self { banks })
+    }
+
+    /// バンクごとの比較結果を返す。
+    pub fn banks(&self) -> &[BankDiff] {
+        &self.banks
+    }
+
+    /// `from` の注釈のうち、対応するアドレスが分かるものを `to` に移す。
+    ///
+    /// ROM 上のラベル名とコメントは対応付いたアドレスに、どのバンクにも属さないアドレス (RAM など) の
+    /// ラベル名とコメントはそのままのアドレスに移す。`to` に既にラベル名やコメントがあるアドレスは変更しない。
+    ///
+    /// `from` の注釈でロードされるバンクどうしのラベル名が重複している場合はエラーを返す。
+    pub fn transfer_annotations(
+        &self,
+        from: &Manifest,
+        to: &mut Manifest,
+    ) -> anyhow::Result<TransferCount> {
+        let mut count = TransferCount::default();
+
+        let global = from.annotations().global();
+        let global_pairs = global
+            .labels()
+            .chain(global.comments())
+            .map(|(addr, _)| (addr, addr));
+        transfer(global, global_pairs, to, None, &mut count);
+
+        for bank in self.banks.iter() {
+            let bank_names =
+                std::iter::once(bank.bank_name.as_str()).chain(from.fixed_bank_names());
+            let annotations = from.annotations().annotations_for(bank_names)?;
+            let pairs = bank.addr_map.iter().map(|(&src, &dst)| (src, dst));
+            transfer(&annotations, pairs, to, Some(&bank.bank_name), &mut count);
+        }
+
+        Ok(count)
+    }
+}
+
+fn transfer(
+    annotations: &Annotations,
+    pairs: impl Iterator<Item = (Address, Address)>,
+    to: &mut Manifest,
+    bank_name: Option<&str>,
+    count: &mut TransferCount,
+) {
+    for (src, dst) in pairs {
+        let Ok(dst_annotations) = to.annotations_at_mut(dst, bank_name) else {
+            continue;
+        };
+
+        if let Some(name) = annotations.label(src) {
+            match dst_annotations.label(dst) {
+                None => match dst_annotations.set_label(dst, name) {
+                    Ok(()) => count.labels += 1,
+                    Err(e) => warn!("can't transfer label '{name}' to {dst:#06X}: {e:#}"),
+                },
+                Some(dst_name) if dst_name != name => {
+                    warn!("label '{name}' is not transferred: {dst:#06X} is already named '{dst_name}'");
+                }
+                Some(_) => {}
+            }
+        }
+
+        if let Some(comment) = annotations.comment(src) {
+            if dst_annotations.comment(dst).is_none() {
+                match dst_annotations.set_comment(dst, comment) {
+                    Ok(()) => count.comments += 1,
+                    Err(e) => warn!("can't transfer comment to {dst:#06X}: {e:#}"),
+                }
+            }
+        }
+    }
+}
+
+/// 移した注釈の数。
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TransferCount {
+    pub labels: usize,
+    pub comments: usize,
+}
+
+/// 1 つのバンクの比較結果。
+#[derive(Clone, Debug, PartialEq)]
+pub struct BankDiff {
+    bank_name: String,
+    routines: Vec<RoutineMatch>,
+    added: Vec<Address>,
+    addr_map: BTreeMap<Address, Address>,
+}
+
+impl BankDiff {
+    fn new(manifest: &Manifest, other: &Manifest, bank_name: &str) -> anyhow::Result<Self> {
+        let (input, config) = manifest.input_config(bank_name)?;
+        let asm = analyze(&input, config.analysis());
+        let (other_input, other_config) = other.input_config(bank_name)?;
+        let other_asm = analyze(&other_input, other_config.analysis());
+
+        Ok(Self::compare(
+            bank_name,
+            (&input, &asm),
+            (&other_input, &other_asm),
+        ))
+    }
+
+    /// 同名バンクの (入力, 解析結果) どうしを比較する。
+    fn compare(
+        bank_name: &str,
+        (input, asm): (&Input, &Assembly),
+        (other_input, other_asm): (&Input, &Assembly),
+    ) -> Self {
+        let lhs = RoutineOps::collect(input, asm);
+        let rhs = RoutineOps::collect(other_input, other_asm);
+
+        let mut pairs: Vec<Option<(usize, bool)>> = vec![None; lhs.len()];
+        let mut rhs_used = vec![false; rhs.len()];
+
+        // まず命令列が同一のルーチンどうしを対応付ける。
+        // 同一の命令列を持つルーチンが複数ある場合、個数が一致すれば出現順に対応付ける。
+        let mut lhs_by_tokens = HashMap::<&[Token], Vec<usize>>::new();
+        for (i, routine) in lhs.iter().enumerate() {
+            lhs_by_tokens.entry(&routine.tokens).or_default().push(i);
+        }
+        let mut rhs_by_tokens = HashMap::<&[Token], Vec<usize>>::new();
+        for (j, routine) in rhs.iter().enumerate() {
+            rhs_by_tokens.entry(&routine.tokens).or_default().push(j);
+        }
+        for (tokens, is) in lhs_by_tokens.iter() {
+            let Some(js) = rhs_by_tokens.get(tokens) else {
+                continue;
+            };
+            if is.len() != js.len() {
+                continue;
+            }
+            for (&i, &j) in is.iter().zip(js) {
+                pairs[i] = Some((j, true));
+                rhs_used[j] = true;
+            }
+        }
+
+        // 残りのルーチンは、前後の対応付いたルーチンの間にあるものの中から最も似ているものと対応付ける。
+        for i in 0..lhs.len() {
+            if pairs[i].is_some() {
+                continue;
+            }
+            let prev = pairs[..i].iter().rev().flatten().next().map(|&(j, _)| j);
+            let next = pairs[i + 1..].iter().flatten().next().map(|&(j, _)| j);
+            let (lo, hi) = match (prev, next) {
+                (Some(prev), Some(next)) => (prev.min(next), prev.max(next)),
+                (Some(prev), None) => (prev, rhs.len()),
+                (None, Some(next)) => (0, next),
+                (None, None) => (0, rhs.len()),
+            };
+
+            let best = (lo..hi)
+                .filter(|&j| !rhs_used[j])
+                .map(|j| (j, similarity(&lhs[i].opcodes, &rhs[j].opcodes)))
+                .filter(|&(_, sim)| sim >= SIMILARITY_MIN)
+                .max_by(|(_, lhs), (_, rhs)| lhs.total_cmp(rhs));
+            if let Some((j, _)) = best {
+                pairs[i] = Some((j, false));
+                rhs_used[j] = true;
+            }
+        }
+
+        // 対応付いたルーチンの命令どうしを対応付け、アドレスの対応を作る。
+        let mut addr_map = BTreeMap::<Address, Address>::new();
+        let mut routines = Vec::<RoutineMatch>::with_capacity(lhs.len());
+        for (routine, pair) in lhs.iter().zip(&pairs) {
+            let name = asm
+                .labels()
+                .get(routine.entry)
+                .and_then(|label| label.name())
+                .map(str::to_owned);
+            let Some((j, identical)) = *pair else {
+                routines.push(RoutineMatch {
+                    entry: routine.entry,
+                    name,
+                    other_entry: None,
+                    identical: false,
+                    similarity: 0.0,
+                });
+                continue;
+            };
+            let other_routine = &rhs[j];
+
+            for (k, l) in lcs_pairs(&routine.opcodes, &other_routine.opcodes) {
+                let (src, dst) = (routine.ops[k], other_routine.ops[l]);
+                addr_map.entry(src.0).or_insert(dst.0);
+                if let (Some(src_ref), Some(dst_ref)) = (src.1, dst.1) {
+                    addr_map.entry(src_ref).or_insert(dst_ref);
+                }
+            }
+
+            routines.push(RoutineMatch {
+                entry: routine.entry,
+                name,
+                other_entry: Some(other_routine.entry),
+                identical,
+                similarity: similarity(&routine.opcodes, &other_routine.opcodes),
+            });
+        }
+
+        let added = rhs
+            .iter()
+            .zip(&rhs_used)
+            .filter(|(_, &used)| !used)
+            .map(|(routine, _)| routine.entry)
+            .collect();
+
+        Self {
+            bank_name: bank_name.to_owned(),
+            routines,
+            added,
+            addr_map,
+        }
+    }
+
+    pub fn bank_name(&self) -> &str {
+        &self.bank_name
+    }
+
+    /// 一方のルーチンたちの対応付け結果を返す (エントリポイントの昇順)。
+    pub fn routines(&self) -> &[RoutineMatch] {
+        &self.routines
+    }
+
+    /// 他方にのみ存在する (対応付かなかった) ルーチンのエントリポイントたちを返す (昇順)。
+    pub fn added(&self) -> &[Address] {
+        &self.added
+    }
+
+    /// 一方のアドレスに対応する他方のアドレスを返す。
+    pub fn map_addr(&self, addr: Address) -> Option<Address> {
+        self.addr_map.get(&addr).copied()
+    }
+}
+
+/// 一方のルーチンの対応付け結果。
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutineMatch {
+    entry: Address,
+    name: Option<String>,
+    other_entry: Option<Address>,
+    identical: bool,
+    similarity: f64,
+}
+
+impl RoutineMatch {
+    pub fn entry(&self) -> Address {
+        self.entry
+    }
+
+    /// 利用者が付けた名前を返す。
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// 対応する他方のルーチンのエントリポイントを返す。対応するものがなければ `None` を返す。
+    pub fn other_entry(&self) -> Option<Address> {
+        self.other_entry
+    }
+
+    /// オペコード列の類似度 (0 以上 1 以下) を返す。
+    pub fn similarity(&self) -> f64 {
+        self.similarity
+    }
+
+    /// 命令列が同一 (ROM 上のアドレスを指すオペランドおよび分岐先の値は無視する) かどうかを返す。
+    pub fn is_identical(&self) -> bool {
+        self.identical
+    }
+}
+
+/// 比較結果を人間向けのテキストで出力する。命令列が同一のルーチンは個数のみ出力する。
+pub fn output_diff<W: Write>(wtr: &mut W, diff: &ManifestDiff) -> anyhow::Result<()> {
+    for bank in diff.banks() {
+        let identical = bank.routines().iter().filter(|r| r.is_identical()).count();
+        let changed = bank
+            .routines()
+            .iter()
+            .filter(|r| r.other_entry().is_some() && !r.is_identical())
+            .count();
+        let removed = bank
+            .routines()
+            .iter()
+            .filter(|r| r.other_entry().is_none())
+            .count();
+        writeln!(
+            wtr,
+            "bank {}: {identical} identical, {changed} changed, {removed} removed, {} added",
+            bank.bank_name(),
+            bank.added().len()
+        )?;
+
+        for routine in bank.routines() {
+            let name = routine.name().unwrap_or("");
+            match routine.other_entry() {
+                Some(_) if routine.is_identical() => {}
+                Some(other) => writeln!(
+                    wtr,
+                    "  changed  ${:04X} -> ${:04X}  {name} ({:.0}%)",
+                    routine.entry(),
+                    other,
+                    routine.similarity() * 100.0
+                )?,
+                None => writeln!(wtr, "  removed  ${:04X}  {name}", routine.entry())?,
+            }
+        }
+        for &entry in bank.added() {
+            writeln!(wtr, "  added    ${entry:04X}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// 命令の比較に使う値。オペコードと、ROM 上のアドレスを指すもの以外のオペランドの値からなる。
+type Token = (u8, Option<u16>);
+
+/// 1 つのルーチンの命令列。
+#[derive(Debug)]
+struct RoutineOps {
+    entry: Address,
+    /// (命令のアドレス, オペランドが指す ROM 上のアドレス)
+    ops: Vec<(Address, Option<Address>)>,
+    opcodes: Vec<u8>,
+    tokens: Vec<Token>,
+}
+
+impl RoutineOps {
+    fn collect(input: &Input, asm: &Assembly) -> Vec<Self> {
+        let memory = input.memory();
+
+        asm.routines()
+            .iter()
+            .map(|routine| {
+                let mut ops = Vec::with_capacity(routine.op_addrs().len());
+                let mut opcodes = Vec::with_capacity(routine.op_addrs().len());
+                let mut tokens = Vec::with_capacity(routine.op_addrs().len());
+                for &addr in routine.op_addrs() {
+                    let Ok((op, _)) = memory.fetch_op(addr) else {
+                        continue;
+                    };
+                    let rom_ref =
+                        operand_addr(op).filter(|&dst| memory.find_bank_id(dst).is_some());
+                    let value = match op.operand() {
+                        _ if rom_ref.is_some() => None,
+                        Operand::Rel(_) => None,
+                        operand => {
+                            let bytes = operand.to_bytes();
+                            (!bytes.is_empty()).then(|| {
+                                u16::from_le_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0)])
+                            })
+                        }
+                    };
+                    ops.push((addr, rom_ref));
+                    opcodes.push(op.opcode().get());
+                    tokens.push((op.opcode().get(), value));
+                }
+
+                Self {
+                    entry: routine.entry(),
+                    ops,
+                    opcodes,
+                    tokens,
+                }
+            })
+            .collect()
+    }
+}
+
+/// 命令のオペランドが指す絶対アドレスを返す。
+fn operand_addr(op: Op) -> Option<Address> {
+    match op.operand() {
+        Operand::Abs(abs) | Operand::AbsX(abs) | Operand::AbsY(abs) | Operand::Ind(abs) => {
+            Some(abs)
+        }
+        _ => None,
+    }
+}
+
+/// 2 つの列の類似度 (最長共通部分列の長さの 2 倍を両者の長さの和で割ったもの) を返す。
+fn similarity(lhs: &[u8], rhs: &[u8]) -> f64 {
+    if lhs.is_empty() && rhs.is_empty() {
+        return 1.0;
+    }
+
+    let mut row = vec![0_usize; rhs.len() + 1];
+    for &x in lhs {
+        let mut diag = 0;
+        for (j, &y) in rhs.iter().enumerate() {
+            let up = row[j + 1];
+            row[j + 1] = if x == y { diag + 1 } else { up.max(row[j]) };
+            diag = up;
+        }
+    }
+
+    (2 * row[rhs.len()]) as f64 / (lhs.len() + rhs.len()) as f64
+}
+
+/// 2 つの列の最長共通部分列を、対応するインデックスの組の列として返す。
+fn lcs_pairs(lhs: &[u8], rhs: &[u8]) -> Vec<(usize, usize)> {
+    let (n, m) = (lhs.len(), rhs.len());
+
+    // dp[i][j]: lhs[i..] と rhs[j..] の最長共通部分列の長さ。
+    let mut dp = vec![0_usize; (n + 1) * (m + 1)];
+    let idx = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            dp[idx(i, j)] = if lhs[i] == rhs[j] {
+                dp[idx(i + 1, j + 1)] + 1
+            } else {
+                dp[idx(i + 1, j)].max(dp[idx(i, j + 1)])
+            };
+        }
+    }
+
+    let mut res = Vec::<(usize, usize)>::with_capacity(dp[0]);
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if lhs[i] == rhs[j] {
+            res.push((i, j));
+            i += 1;
+            j += 1;
+        } else if dp[idx(i + 1, j)] >= dp[idx(i, j + 1)] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::test_util::make_input;
+
+    use super::*;
+
+    /// 一方と、命令の挿入とルーチンの移動・差し替えを行った他方のリビジョンの比較結果を返す。
+    fn make_bank_diff() -> BankDiff {
+        #[rustfmt::skip]
+        let main = [
+            0x20, 0x10, 0xC0, // $C000: jsr $C010
+            0xAD, 0x30, 0xC0, // $C003: lda $C030
+            0x20, 0x20, 0xC0, // $C006: jsr $C020
+            0x20, 0x40, 0xC0, // $C009: jsr $C040
+            0x60,             // $C00C: rts
+        ];
+        #[rustfmt::skip]
+        let sub1 = [
+            0xA2, 0x04, // $C010: ldx #4
+            0xCA,       // $C012: dex
+            0xD0, 0xFD, // $C013: bne $C012
+            0x60,       // $C015: rts
+        ];
+        #[rustfmt::skip]
+        let sub2 = [
+            0xA9, 0x01,       // $C020: lda #1
+            0x8D, 0x00, 0x03, // $C022: sta $0300
+            0x60,             // $C025: rts
+        ];
+        #[rustfmt::skip]
+        let sub3 = [
+            0xA9, 0x02, // $C040: lda #2
+            0x60,       // $C042: rts
+        ];
+        let input = make_input(&[
+            (0xC000, &main),
+            (0xC010, &sub1),
+            (0xC020, &sub2),
+            (0xC040, &sub3),
+        ]);
+
+        #[rustfmt::skip]
+        let other_main = [
+            0x20, 0x13, 0xC0, // $C000: jsr $C013
+            0xAD, 0x33, 0xC0, // $C003: lda $C033
+            0xEA,             // $C006: nop
+            0x20, 0x23, 0xC0, // $C007: jsr $C023
+            0x20, 0x50, 0xC0, // $C00A: jsr $C050
+            0x60,             // $C00D: rts
+        ];
+        #[rustfmt::skip]
+        let other_sub4 = [
+            0xA0, 0x10, // $C050: ldy #$10
+            0xC8,       // $C052: iny
+            0xC8,       // $C053: iny
+            0xC8,       // $C054: iny
+            0x60,       // $C055: rts
+        ];
+        let other_input = make_input(&[
+            (0xC000, &other_main),
+            (0xC013, &sub1),
+            (0xC023, &sub2),
+            (0xC050, &other_sub4),
+        ]);
+
+        let asm = analyze(&input, &Default::default());
+        let other_asm = analyze(&other_input, &Default::default());
+
+        BankDiff::compare("PRG", (&input, &asm), (&other_input, &other_asm))
+    }
+
+    #[test]
+    fn test_bank_diff() {
+        let diff = make_bank_diff();
+        let addr = Address::new;
+
+        let routines: Vec<_> = diff
+            .routines()
+            .iter()
+            .map(|r| (r.entry(), r.other_entry(), r.is_identical()))
+            .collect();
+        assert_eq!(
+            routines,
+            [
+                (addr(0xC000), Some(addr(0xC000)), false),
+                (addr(0xC010), Some(addr(0xC013)), true),
+                (addr(0xC020), Some(addr(0xC023)), true),
+                (addr(0xC040), None, false),
+            ]
+        );
+        assert!((diff.routines()[0].similarity() - 10.0 / 11.0).abs() < 1e-9);
+        assert_eq!(diff.added(), [addr(0xC050)]);
+
+        // 対応付いた命令のアドレス。
+        assert_eq!(diff.map_addr(addr(0xC006)), Some(addr(0xC007)));
+        assert_eq!(diff.map_addr(addr(0xC00C)), Some(addr(0xC00D)));
+        assert_eq!(diff.map_addr(addr(0xC012)), Some(addr(0xC015)));
+        assert_eq!(diff.map_addr(addr(0xC022)), Some(addr(0xC025)));
+        // オペランドが指す ROM 上のアドレス (対応付かなかったルーチンも含む)。
+        assert_eq!(diff.map_addr(addr(0xC030)), Some(addr(0xC033)));
+        assert_eq!(diff.map_addr(addr(0xC040)), Some(addr(0xC050)));
+        // RAM は対応付けない。
+        assert_eq!(diff.map_addr(addr(0x0300)), None);
+        assert_eq!(diff.map_addr(addr(0xC041)), None);
+    }
+
+    #[test]
+    fn test_transfer_annotations() {
+        let manifest = || {
+            Manifest::from_toml(
+                r#"
+memory = [
+    { start = 0, len = 0x800, readable = true, writable = true, executable = true },
+    { start = 0x8000, len = 0x8000, readable = true, writable = true, executable = true },
+]
+banks = [
+    { name = "PRG", start = 0xC000, len = 0x4000, file = "prg.bin", fixed = true },
+]
+
+[config]
+"#,
+            )
+            .unwrap()
+        };
+        let addr = Address::new;
+
+        let mut from = manifest();
+        let global = from.annotations_mut().global_mut();
+        global.set_label(addr(0x0300), "counter").unwrap();
+        global.set_comment(addr(0x0300), "ram").unwrap();
+        let bank = from.annotations_mut().bank_mut("PRG");
+        bank.set_label(addr(0xC010), "Wait").unwrap();
+        bank.set_label(addr(0xC020), "Store").unwrap();
+        bank.set_label(addr(0xC030), "table").unwrap();
+        bank.set_label(addr(0xC040), "Old").unwrap();
+        bank.set_label(addr(0xC003), "Load").unwrap();
+        bank.set_comment(addr(0xC006), "call store").unwrap();
+        bank.set_comment(addr(0xC012), "count down").unwrap();
+
+        let mut to = manifest();
+        let bank = to.annotations_mut().bank_mut("PRG");
+        // 既に名前やコメントがあるアドレスは変更しない。
+        bank.set_label(addr(0xC023), "Save").unwrap();
+        bank.set_comment(addr(0xC007), "existing").unwrap();
+        // 既に他のアドレスで使われている名前は移さない。
+        bank.set_label(addr(0xC100), "Load").unwrap();
+
+        let diff = ManifestDiff {
+            banks: vec![make_bank_diff()],
+        };
+        let count = diff.transfer_annotations(&from, &mut to).unwrap();
+        assert_eq!(
+            count,
+            TransferCount {
+                labels: 4,
+                comments: 2
+            }
+        );
+
+        let global = to.annotations().global();
+        assert_eq!(global.label(addr(0x0300)), Some("counter"));
+        assert_eq!(global.comment(addr(0x0300)), Some("ram"));
+        let bank = to.annotations().bank("PRG").unwrap();
+        itertools::assert_equal(
+            bank.labels(),
+            [
+                (addr(0xC013), "Wait"),
+                (addr(0xC023), "Save"),
+                (addr(0xC033), "table"),
+                (addr(0xC050), "Old"),
+                (addr(0xC100), "Load"),
+            ],
+        );
+        itertools::assert_equal(
+            bank.comments(),
+            [(addr(0xC007), "existing"), (addr(0xC015), "count down")],
+        );
+    }
+
+    #[test]
+    fn test_lcs() {
+        let lhs = [0xA9, 0x8D, 0x60];
+        let rhs = [0xA9, 0xE8, 0x8D, 0x60];
+        assert_eq!(lcs_pairs(&lhs, &rhs), [(0, 0), (1, 2), (2, 3)]);
+        assert!((similarity(&lhs, &rhs) - 6.0 / 7.0).abs() < 1e-9);
+
+        assert_eq!(similarity(&lhs, &lhs), 1.0);
+        assert_eq!(similarity(&lhs, &[]), 0.0);
+        assert!(lcs_pairs(&lhs, &[0xEA]).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+//! Graphviz DOT 形式での出力。
+
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::address::Address;
+use crate::assembly::Labels;
+use crate::callgraph::{CallGraph, CallKind};
+use crate::cfg::{Cfg, CfgDst, CfgEdgeKind};
+use crate::output::{FormatOp, ResolveAddr};
+
+/// 制御フローグラフを DOT 形式で出力する。
+///
+/// 各基本ブロックはその逆アセンブル結果をラベルとするノードになる。
+/// 分岐の辺には taken/not taken のラベルを付け、
+/// バンク外への辺と行き先不明の間接 jmp は破線で区別する。
+pub fn output_cfg_dot<W: Write>(wtr: &mut W, cfg: &Cfg, labels: &Labels) -> anyhow::Result<()> {
+    writeln!(
+        wtr,
+        "digraph \"{}\" {{",
+        ResolveAddr::new(labels, cfg.entry())
+    )?;
+    writeln!(wtr, "    node [shape=box, fontname=\"monospace\"];")?;
+
+    for block in cfg.blocks() {
+        let mut text = String::new();
+        if labels.get(block.addr()).is_some() {
+            writeln!(text, "{}:", ResolveAddr::new(labels, block.addr()))?;
+        }
+        for &(addr, op) in block.ops() {
+            writeln!(text, "{:04X}  {}", addr, FormatOp::new(labels, addr, op))?;
+        }
+
+        let style = if block.addr() == cfg.entry() {
+            ", style=bold"
+        } else {
+            ""
+        };
+        writeln!(
+            wtr,
+            "    {} [label=\"{}\"{style}];",
+            block_node(block.addr()),
+            escape_left(&text)
+        )?;
+    }
+
+    for block in cfg.blocks() {
+        let src = block_node(block.addr());
+        let (src_addr, _) = *block.ops().last().unwrap();
+
+        for edge in block.succs() {
+            let mut attrs = Vec::<&str>::new();
+            match edge.kind() {
+                CfgEdgeKind::Normal | CfgEdgeKind::Jump => {}
+                CfgEdgeKind::Taken => attrs.extend(["label=\"taken\"", "color=\"darkgreen\""]),
+                CfgEdgeKind::NotTaken => attrs.extend(["label=\"not taken\"", "color=\"red\""]),
+                CfgEdgeKind::Return => attrs.extend(["label=\"ret\"", "style=dotted"]),
+            }
+
+            let dst = match edge.dst() {
+                CfgDst::Block(dst) => block_node(dst),
+                CfgDst::External(dst) => {
+                    let node = format!("x_{dst:04X}");
+                    writeln!(
+                        wtr,
+                        "    {node} [label=\"{}\", shape=ellipse, style=dashed];",
+                        escape_left(&ResolveAddr::new(labels, dst).to_string())
+                    )?;
+                    attrs.push("style=dashed");
+                    node
+                }
+                CfgDst::Unresolved => {
+                    let node = format!("u_{src_addr:04X}");
+                    writeln!(
+                        wtr,
+                        "    {node} [label=\"?\", shape=diamond, style=dashed, color=\"red\"];"
+                    )?;
+                    attrs.push("style=dashed");
+                    node
+                }
+            };
+
+            if attrs.is_empty() {
+                writeln!(wtr, "    {src} -> {dst};")?;
+            } else {
+                writeln!(wtr, "    {src} -> {dst} [{}];", attrs.join(", "))?;
+            }
+        }
+    }
+
+    writeln!(wtr, "}}")?;
+
+    Ok(())
+}
+
+/// 呼び出しグラフを DOT 形式で出力する。
+///
+/// ルーチンはバンクごとのクラスタにまとめる。バンクを推定できなかったルーチンはクラスタに入れない。
+/// 間接 jmp による辺は破線とし、バンク切り替えを伴う辺には切り替え先のバンク番号を付ける。
+/// トランポリンは二重枠で区別する。
+pub fn output_callgraph_dot<W: Write>(wtr: &mut W, graph: &CallGraph) -> anyhow::Result<()> {
+    writeln!(wtr, "digraph callgraph {{")?;
+    writeln!(wtr, "    node [shape=box, fontname=\"monospace\"];")?;
+
+    // バンク名の初出順にクラスタを作る。
+    let mut banks = Vec::<Option<&str>>::new();
+    for node in graph.nodes() {
+        if !banks.contains(&node.bank()) {
+            banks.push(node.bank());
+        }
+    }
+
+    for (i, &bank) in banks.iter().enumerate() {
+        let indent = if let Some(bank) = bank {
+            writeln!(wtr, "    subgraph cluster_{i} {{")?;
+            writeln!(wtr, "        label=\"{}\";", escape_left(bank))?;
+            "        "
+        } else {
+            "    "
+        };
+
+        for (id, node) in graph.nodes().iter().enumerate() {
+            if node.bank() != bank {
+                continue;
+            }
+
+            let mut label = format!("{:04X}", node.entry());
+            if let Some(len) = node.size() {
+                write!(label, "\n{len} bytes")?;
+            }
+
+            let mut attrs = vec![format!("label=\"{}\"", escape_left(&label))];
+            if node.size().is_none() {
+                attrs.push("style=dashed".to_owned());
+            }
+            if node.is_trampoline() {
+                attrs.push("peripheries=2".to_owned());
+            }
+            writeln!(wtr, "{indent}r_{id} [{}];", attrs.join(", "))?;
+        }
+
+        if bank.is_some() {
+            writeln!(wtr, "    }}")?;
+        }
+    }
+
+    for edge in graph.edges() {
+        let mut attrs = Vec::<String>::new();
+        if edge.kind() == CallKind::JmpInd {
+            attrs.push("style=dashed".to_owned());
+        }
+        if let Some(number) = edge.bank_switch() {
+            attrs.push(format!("label=\"bank {number}\""));
+            attrs.push("color=\"blue\"".to_owned());
+        }
+
+        let (src, dst) = (edge.src(), edge.dst());
+        if attrs.is_empty() {
+            writeln!(wtr, "    r_{src} -> r_{dst};")?;
+        } else {
+            writeln!(wtr, "    r_{src} -> r_{dst} [{}];", attrs.join(", "))?;
+        }
+    }
+
+    writeln!(wtr, "}}")?;
+
+    Ok(())
+}
+
+fn block_node(addr: Address) -> String {
+    format!("b_{addr:04X}")
+}
+
+/// DOT の文字列リテラル用にエスケープする。改行は左寄せの改行 `\l` に変換する。
+fn escape_left(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\l"),
+            _ => res.push(c),
+        }
+    }
+
+    res
+}
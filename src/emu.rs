@@ -0,0 +1,744 @@
+//! 組み込みの 6502 (2A03) エミュレータ。
+//!
+//! 外部エミュレータなしで CDL 相当のカバレッジを得るためのもの。命令単位で実行し、
+//! PPU/APU などのハードウェアは最小限のスタブとする:
+//!
+//! * $2000 への書き込みは NMI 許可ビットのみ解釈する。
+//! * $2002 の読み取りは VBlank フラグ (フレーム先頭から約 20 ライン) と、読むたびに反転するスプライト 0 ヒットフラグを返す。
+//! * $4014 への書き込みは OAM DMA の分のサイクルを消費する。
+//! * $4016 の読み取りはスクリプトで与えたコントローラ入力 (1P) を返す。
+//! * その他の I/O レジスタの読み取りは 0 を返し、書き込みは無視する。
+//!
+//! バンク切り替えはエミュレートしない (ロードされたバンクが常に見えているものとする)。
+//! ロードされたバンクへの書き込み (マッパーレジスタへの書き込みなど) は無視する。
+//! 非公式命令は nop 系のみサポートし、それ以外の非公式命令に到達したら停止する。
+//! また、ロードされたバンク外 (RAM など) のコードは実行できない。
+
+use anyhow::{bail, ensure, Context as _};
+use log::warn;
+use thiserror::Error;
+
+use crate::address::{Address, ZpAddress};
+use crate::cdl::{Cdl, CdlElement};
+use crate::memory::Memory;
+use crate::op::{Op, OpSucc, Operand};
+
+/// 1 フレームあたりの CPU サイクル数 (NTSC)。
+const CYCLES_PER_FRAME: u64 = 29781;
+
+/// フレーム先頭から VBlank が終わるまでの CPU サイクル数。
+const CYCLES_VBLANK: u64 = 2273;
+
+const FLAG_C: u8 = 1 << 0;
+const FLAG_Z: u8 = 1 << 1;
+const FLAG_I: u8 = 1 << 2;
+const FLAG_D: u8 = 1 << 3;
+const FLAG_B: u8 = 1 << 4;
+const FLAG_U: u8 = 1 << 5;
+const FLAG_V: u8 = 1 << 6;
+const FLAG_N: u8 = 1 << 7;
+
+const VECTOR_NMI: Address = Address::new(0xFFFA);
+const VECTOR_RESET: Address = Address::new(0xFFFC);
+const VECTOR_IRQ: Address = Address::new(0xFFFE);
+
+/// 指定した論理アドレス空間をリセットから `frames` フレーム実行し、得られたカバレッジを返す。
+///
+/// 途中で実行できなくなった場合は警告を出し、それまでのカバレッジを返す。
+pub fn trace_coverage(memory: &Memory, frames: u64, script: &InputScript) -> Cdl {
+    let mut emu = match Emulator::new(memory) {
+        Ok(emu) => emu,
+        Err(e) => {
+            warn!("emulation failed: {e}");
+            return Cdl::default();
+        }
+    };
+
+    for frame in 0..frames {
+        if let Err(e) = emu.run_frame(script.buttons(frame)) {
+            warn!("emulation stopped at frame {frame}: {e}");
+            break;
+        }
+    }
+
+    emu.into_cdl()
+}
+
+/// エミュレータが実行を続けられなくなった原因。
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+pub enum EmuError {
+    /// 割り込みベクタがロードされていない。
+    #[error("vector {0:#06X} is not loaded")]
+    MissingVector(Address),
+    /// プログラムカウンタの指す先から命令を読み取れない。
+    #[error("can't fetch op at {0:#06X}")]
+    InvalidPc(Address),
+    /// サポートしていない命令 (kil および nop 以外の非公式命令)。
+    #[error("unsupported op {opcode:#04X} at {addr:#06X}")]
+    UnsupportedOp { addr: Address, opcode: u8 },
+}
+
+/// 命令単位の CPU エミュレータ。実行した命令や読み取ったデータをカバレッジとして記録する。
+#[derive(Debug)]
+pub struct Emulator<'a> {
+    memory: &'a Memory,
+    ram: Box<[u8; 0x800]>,
+    prg_ram: Box<[u8; 0x2000]>,
+
+    a: u8,
+    x: u8,
+    y: u8,
+    s: u8,
+    p: u8,
+    pc: Address,
+
+    ppu_ctrl: u8,
+    vblank: bool,
+    sprite0: bool,
+
+    buttons: u8,
+    pad_shift: u8,
+    pad_strobe: bool,
+
+    cycle: u64,
+    cdl: Cdl,
+}
+
+impl<'a> Emulator<'a> {
+    /// 電源投入直後の状態を作る。プログラムカウンタはリセットベクタの指す先となる。
+    pub fn new(memory: &'a Memory) -> Result<Self, EmuError> {
+        let mut this = Self {
+            memory,
+            ram: Box::new([0; 0x800]),
+            prg_ram: Box::new([0; 0x2000]),
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0xFD,
+            p: FLAG_I | FLAG_U,
+            pc: Address::new(0),
+            ppu_ctrl: 0,
+            vblank: false,
+            sprite0: false,
+            buttons: 0,
+            pad_shift: 0,
+            pad_strobe: false,
+            cycle: 0,
+            cdl: Cdl::default(),
+        };
+
+        this.pc = this.enter_vector(VECTOR_RESET)?;
+
+        Ok(this)
+    }
+
+    /// 現在のプログラムカウンタを返す。
+    pub fn pc(&self) -> Address {
+        self.pc
+    }
+
+    /// 電源投入からの CPU サイクル数を返す。
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// 記録したカバレッジを返す。
+    pub fn cdl(&self) -> &Cdl {
+        &self.cdl
+    }
+
+    pub fn into_cdl(self) -> Cdl {
+        self.cdl
+    }
+
+    /// コントローラ入力 `buttons` のもとで 1 フレーム実行する。
+    ///
+    /// フレーム先頭で VBlank に入り、NMI が許可されていれば NMI を発生させる。
+    pub fn run_frame(&mut self, buttons: u8) -> Result<(), EmuError> {
+        self.buttons = buttons;
+
+        let start = self.cycle;
+        self.vblank = true;
+        if (self.ppu_ctrl & 0x80) != 0 {
+            self.interrupt(VECTOR_NMI, false)?;
+        }
+
+        while self.cycle - start < CYCLES_PER_FRAME {
+            if self.cycle - start >= CYCLES_VBLANK {
+                self.vblank = false;
+            }
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    /// 1 命令実行する。
+    pub fn step(&mut self) -> Result<(), EmuError> {
+        let addr = self.pc;
+        let (op, _) = self
+            .memory
+            .fetch_op(addr)
+            .map_err(|_| EmuError::InvalidPc(addr))?;
+        let opcode = op.opcode();
+
+        if opcode.is_kil() || (!opcode.is_official() && opcode.mnemonic() != "nop") {
+            return Err(EmuError::UnsupportedOp {
+                addr,
+                opcode: opcode.get(),
+            });
+        }
+
+        self.mark(addr, CdlElement::OPCODE);
+        for i in 1..op.len().get() {
+            self.mark(addr.wrapping_add_unsigned(i), CdlElement::OPERAND);
+        }
+
+        self.cycle += op_cycles(op);
+        self.pc = addr.wrapping_add_unsigned(op.len());
+
+        match op.succ() {
+            OpSucc::Normal(_) => self.exec(op),
+            OpSucc::Brk => {
+                self.pc = addr.wrapping_add_unsigned(2_usize);
+                self.interrupt(VECTOR_IRQ, true)?;
+            }
+            OpSucc::Kil => unreachable!(),
+            OpSucc::Branch(rel) => {
+                if self.branch_cond(opcode.mnemonic()) {
+                    self.cycle += 1;
+                    self.pc = self.pc.wrapping_add_signed(rel);
+                    self.mark(self.pc, CdlElement::JUMP_TARGET);
+                }
+            }
+            OpSucc::Jsr(dst) => {
+                let [lo, hi] = addr.wrapping_add_unsigned(2_usize).to_le_bytes();
+                self.push(hi);
+                self.push(lo);
+                self.pc = dst;
+                self.mark(dst, CdlElement::JUMP_TARGET.union(CdlElement::ENTRYPOINT));
+            }
+            OpSucc::Rti => {
+                let p = self.pull();
+                self.set_p(p);
+                let lo = self.pull();
+                let hi = self.pull();
+                self.pc = Address::from_le_bytes([lo, hi]);
+            }
+            OpSucc::Rts => {
+                let lo = self.pull();
+                let hi = self.pull();
+                self.pc = Address::from_le_bytes([lo, hi]).wrapping_add_unsigned(1_usize);
+            }
+            OpSucc::JmpAbs(dst) => {
+                self.pc = dst;
+                self.mark(dst, CdlElement::JUMP_TARGET);
+            }
+            OpSucc::JmpInd(ptr) => {
+                // 6502 のバグにより、ポインタの上位バイトはページをまたがずに読まれる。
+                let ptr_hi =
+                    Address::new((ptr.get() & 0xFF00) | (ptr.get().wrapping_add(1) & 0xFF));
+                let lo = self.read_data(ptr, false);
+                let hi = self.read_data(ptr_hi, false);
+                self.pc = Address::from_le_bytes([lo, hi]);
+                self.mark(self.pc, CdlElement::JUMP_TARGET);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 非制御フロー命令を実行する。
+    fn exec(&mut self, op: Op) {
+        let opcode = op.opcode();
+        let operand = op.operand();
+        let indirect = matches!(operand, Operand::IndX(_) | Operand::IndY(_));
+        let ea = self.effective_addr(operand);
+
+        let value = match operand {
+            Operand::Imm(imm) => imm,
+            Operand::Acc => self.a,
+            _ if opcode.is_read() => self.read_data(ea.unwrap(), indirect),
+            _ => 0,
+        };
+
+        // 読み書き両方を行う命令の結果を書き戻す。
+        macro_rules! write_back {
+            ($value:expr) => {{
+                let value = $value;
+                self.set_nz(value);
+                match ea {
+                    Some(ea) => self.write(ea, value),
+                    None => self.a = value,
+                }
+            }};
+        }
+
+        match opcode.mnemonic() {
+            "lda" => self.a = self.set_nz(value),
+            "ldx" => self.x = self.set_nz(value),
+            "ldy" => self.y = self.set_nz(value),
+            "sta" => self.write(ea.unwrap(), self.a),
+            "stx" => self.write(ea.unwrap(), self.x),
+            "sty" => self.write(ea.unwrap(), self.y),
+            "and" => self.a = self.set_nz(self.a & value),
+            "ora" => self.a = self.set_nz(self.a | value),
+            "eor" => self.a = self.set_nz(self.a ^ value),
+            "adc" => self.adc(value),
+            "sbc" => self.adc(!value),
+            "cmp" => self.compare(self.a, value),
+            "cpx" => self.compare(self.x, value),
+            "cpy" => self.compare(self.y, value),
+            "bit" => {
+                self.set_flag(FLAG_Z, (self.a & value) == 0);
+                self.set_flag(FLAG_V, (value & 0x40) != 0);
+                self.set_flag(FLAG_N, (value & 0x80) != 0);
+            }
+            "asl" => {
+                self.set_flag(FLAG_C, (value & 0x80) != 0);
+                write_back!(value << 1);
+            }
+            "lsr" => {
+                self.set_flag(FLAG_C, (value & 0x01) != 0);
+                write_back!(value >> 1);
+            }
+            "rol" => {
+                let carry = self.p & FLAG_C;
+                self.set_flag(FLAG_C, (value & 0x80) != 0);
+                write_back!((value << 1) | carry);
+            }
+            "ror" => {
+                let carry = (self.p & FLAG_C) << 7;
+                self.set_flag(FLAG_C, (value & 0x01) != 0);
+                write_back!((value >> 1) | carry);
+            }
+            "inc" => write_back!(value.wrapping_add(1)),
+            "dec" => write_back!(value.wrapping_sub(1)),
+            "inx" => self.x = self.set_nz(self.x.wrapping_add(1)),
+            "iny" => self.y = self.set_nz(self.y.wrapping_add(1)),
+            "dex" => self.x = self.set_nz(self.x.wrapping_sub(1)),
+            "dey" => self.y = self.set_nz(self.y.wrapping_sub(1)),
+            "tax" => self.x = self.set_nz(self.a),
+            "tay" => self.y = self.set_nz(self.a),
+            "txa" => self.a = self.set_nz(self.x),
+            "tya" => self.a = self.set_nz(self.y),
+            "tsx" => self.x = self.set_nz(self.s),
+            "txs" => self.s = self.x,
+            "pha" => self.push(self.a),
+            "php" => self.push(self.p | FLAG_B | FLAG_U),
+            "pla" => {
+                let value = self.pull();
+                self.a = self.set_nz(value);
+            }
+            "plp" => {
+                let p = self.pull();
+                self.set_p(p);
+            }
+            "clc" => self.set_flag(FLAG_C, false),
+            "sec" => self.set_flag(FLAG_C, true),
+            "cli" => self.set_flag(FLAG_I, false),
+            "sei" => self.set_flag(FLAG_I, true),
+            "clv" => self.set_flag(FLAG_V, false),
+            "cld" => self.set_flag(FLAG_D, false),
+            "sed" => self.set_flag(FLAG_D, true),
+            "nop" => {}
+            mnemonic => unreachable!("unexpected op: {mnemonic}"),
+        }
+    }
+
+    /// オペランドの実効アドレスを返す。実効アドレスのないオペランドなら `None` を返す。
+    fn effective_addr(&mut self, operand: Operand) -> Option<Address> {
+        match operand {
+            Operand::Zp(zp) => Some(zp.into()),
+            Operand::ZpX(zp) => Some(zp.wrapping_add_unsigned(self.x).into()),
+            Operand::ZpY(zp) => Some(zp.wrapping_add_unsigned(self.y).into()),
+            Operand::Abs(abs) => Some(abs),
+            Operand::AbsX(abs) => Some(abs.wrapping_add_unsigned(self.x)),
+            Operand::AbsY(abs) => Some(abs.wrapping_add_unsigned(self.y)),
+            Operand::IndX(zp) => Some(self.read_zp_ptr(zp.wrapping_add_unsigned(self.x))),
+            Operand::IndY(zp) => Some(self.read_zp_ptr(zp).wrapping_add_unsigned(self.y)),
+            _ => None,
+        }
+    }
+
+    fn read_zp_ptr(&mut self, zp: ZpAddress) -> Address {
+        let lo = self.read(zp.into());
+        let hi = self.read(zp.wrapping_add_unsigned(1_usize).into());
+        Address::from_le_bytes([lo, hi])
+    }
+
+    fn branch_cond(&self, mnemonic: &str) -> bool {
+        let flag = |mask: u8| (self.p & mask) != 0;
+        match mnemonic {
+            "bpl" => !flag(FLAG_N),
+            "bmi" => flag(FLAG_N),
+            "bvc" => !flag(FLAG_V),
+            "bvs" => flag(FLAG_V),
+            "bcc" => !flag(FLAG_C),
+            "bcs" => flag(FLAG_C),
+            "bne" => !flag(FLAG_Z),
+            "beq" => flag(FLAG_Z),
+            _ => unreachable!("not a branch: {mnemonic}"),
+        }
+    }
+
+    /// 割り込みを発生させ、ハンドラへ飛ぶ。`brk` が真なら brk 命令によるものとする。
+    fn interrupt(&mut self, vector: Address, brk: bool) -> Result<(), EmuError> {
+        let [lo, hi] = self.pc.to_le_bytes();
+        self.push(hi);
+        self.push(lo);
+        if brk {
+            self.push(self.p | FLAG_B | FLAG_U);
+        } else {
+            self.push(self.p | FLAG_U);
+            self.cycle += 7;
+        }
+        self.set_flag(FLAG_I, true);
+
+        self.pc = self.enter_vector(vector)?;
+
+        Ok(())
+    }
+
+    /// 割り込みベクタを読み取り、ハンドラをエントリポイントとして記録する。
+    fn enter_vector(&mut self, vector: Address) -> Result<Address, EmuError> {
+        let (handler, _) = self
+            .memory
+            .fetch_addr(vector)
+            .ok_or(EmuError::MissingVector(vector))?;
+        self.mark(handler, CdlElement::ENTRYPOINT);
+
+        Ok(handler)
+    }
+
+    fn adc(&mut self, value: u8) {
+        let sum = u16::from(self.a) + u16::from(value) + u16::from(self.p & FLAG_C);
+        let res = sum as u8;
+        self.set_flag(FLAG_C, sum > 0xFF);
+        self.set_flag(FLAG_V, ((self.a ^ res) & (value ^ res) & 0x80) != 0);
+        self.a = self.set_nz(res);
+    }
+
+    fn compare(&mut self, reg: u8, value: u8) {
+        self.set_flag(FLAG_C, reg >= value);
+        self.set_nz(reg.wrapping_sub(value));
+    }
+
+    fn set_nz(&mut self, value: u8) -> u8 {
+        self.set_flag(FLAG_Z, value == 0);
+        self.set_flag(FLAG_N, (value & 0x80) != 0);
+        value
+    }
+
+    fn set_flag(&mut self, mask: u8, on: bool) {
+        if on {
+            self.p |= mask;
+        } else {
+            self.p &= !mask;
+        }
+    }
+
+    fn set_p(&mut self, p: u8) {
+        self.p = (p & !FLAG_B) | FLAG_U;
+    }
+
+    fn push(&mut self, value: u8) {
+        self.ram[0x100 | usize::from(self.s)] = value;
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        self.ram[0x100 | usize::from(self.s)]
+    }
+
+    /// 命令の実効アドレスからデータを読み取り、カバレッジに記録する。
+    fn read_data(&mut self, addr: Address, indirect: bool) -> u8 {
+        let flags = if indirect {
+            CdlElement::DATA.union(CdlElement::INDIRECT_DATA)
+        } else {
+            CdlElement::DATA
+        };
+        self.mark(addr, flags);
+
+        self.read(addr)
+    }
+
+    fn read(&mut self, addr: Address) -> u8 {
+        if let Some((value, _)) = self.memory.get_byte(addr) {
+            return value;
+        }
+
+        let raw = usize::from(addr);
+        match raw {
+            0x0000..=0x1FFF => self.ram[raw & 0x7FF],
+            0x2000..=0x3FFF if (raw & 7) == 2 => {
+                let value = (u8::from(self.vblank) << 7) | (u8::from(self.sprite0) << 6);
+                self.vblank = false;
+                self.sprite0 = !self.sprite0;
+                value
+            }
+            0x4016 => {
+                if self.pad_strobe {
+                    self.pad_shift = self.buttons;
+                }
+                let value = self.pad_shift & 1;
+                self.pad_shift = (self.pad_shift >> 1) | 0x80;
+                value
+            }
+            0x6000..=0x7FFF => self.prg_ram[raw - 0x6000],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: Address, value: u8) {
+        if self.memory.find_bank_id(addr).is_some() {
+            return;
+        }
+
+        let raw = usize::from(addr);
+        match raw {
+            0x0000..=0x1FFF => self.ram[raw & 0x7FF] = value,
+            0x2000..=0x3FFF if (raw & 7) == 0 => self.ppu_ctrl = value,
+            0x4014 => self.cycle += 513,
+            0x4016 => {
+                self.pad_strobe = (value & 1) != 0;
+                if self.pad_strobe {
+                    self.pad_shift = self.buttons;
+                }
+            }
+            0x6000..=0x7FFF => self.prg_ram[raw - 0x6000] = value,
+            _ => {}
+        }
+    }
+
+    /// ロードされたバンク内のアドレスならカバレッジに記録する。
+    fn mark(&mut self, addr: Address, flags: CdlElement) {
+        if self.memory.find_bank_id(addr).is_some() {
+            self.cdl.mark(addr, flags);
+        }
+    }
+}
+
+/// 命令の (分岐成立やページクロスによる増分を除いた) 所要サイクル数を返す。
+fn op_cycles(op: Op) -> u64 {
+    let opcode = op.opcode();
+    let rmw = opcode.is_read() && opcode.is_write();
+    let store = !opcode.is_read() && opcode.is_write();
+
+    match op.operand() {
+        Operand::Imp => match opcode.mnemonic() {
+            "pha" | "php" => 3,
+            "pla" | "plp" => 4,
+            "rts" | "rti" => 6,
+            "brk" => 7,
+            _ => 2,
+        },
+        Operand::Acc | Operand::Imm(_) | Operand::Rel(_) => 2,
+        Operand::Zp(_) => 3 + 2 * u64::from(rmw),
+        Operand::ZpX(_) | Operand::ZpY(_) => 4 + 2 * u64::from(rmw),
+        Operand::Abs(_) => match opcode.mnemonic() {
+            "jmp" => 3,
+            "jsr" => 6,
+            _ => 4 + 2 * u64::from(rmw),
+        },
+        Operand::AbsX(_) | Operand::AbsY(_) => match (rmw, store) {
+            (true, _) => 7,
+            (false, true) => 5,
+            (false, false) => 4,
+        },
+        Operand::Ind(_) => 5,
+        Operand::IndX(_) => 6 + 2 * u64::from(rmw),
+        Operand::IndY(_) => match (rmw, store) {
+            (true, _) => 8,
+            (false, true) => 6,
+            (false, false) => 5,
+        },
+    }
+}
+
+/// フレームごとのコントローラ入力のスクリプト。
+///
+/// テキスト形式で、各行に `<フレーム番号> <ボタン>` を書く。ボタンは `A`, `B`, `Select`, `Start`,
+/// `Up`, `Down`, `Left`, `Right` を `+` でつないだもの (大文字小文字は区別しない) で、
+/// 何も押さない場合は `-` とする。各行の入力は次の行のフレームの直前まで続く。
+/// 空行および `#` 以降は無視する。
+///
+/// ```text
+/// # タイトル画面で Start を押す
+/// 120 Start
+/// 125 -
+/// 300 Right+A
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InputScript(Vec<(u64, u8)>);
+
+impl InputScript {
+    pub fn parse(s: impl AsRef<str>) -> anyhow::Result<Self> {
+        let mut entries = Vec::<(u64, u8)>::new();
+
+        for (i, line) in s.as_ref().lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse_line = || -> anyhow::Result<(u64, u8)> {
+                let mut tokens = line.split_whitespace();
+                let (Some(frame), Some(buttons), None) =
+                    (tokens.next(), tokens.next(), tokens.next())
+                else {
+                    bail!("expected '<frame> <buttons>'");
+                };
+                let frame: u64 = frame
+                    .parse()
+                    .with_context(|| format!("invalid frame: '{frame}'"))?;
+                let buttons = parse_buttons(buttons)?;
+                Ok((frame, buttons))
+            };
+            let (frame, buttons) =
+                parse_line().with_context(|| format!("input script line {}", i + 1))?;
+
+            if let Some(&(frame_pre, _)) = entries.last() {
+                ensure!(
+                    frame_pre < frame,
+                    "input script line {}: frames must be in ascending order",
+                    i + 1
+                );
+            }
+            entries.push((frame, buttons));
+        }
+
+        Ok(Self(entries))
+    }
+
+    /// 指定したフレームのボタン入力を返す (bit0 から順に A, B, Select, Start, Up, Down, Left, Right)。
+    pub fn buttons(&self, frame: u64) -> u8 {
+        let idx = self.0.partition_point(|&(f, _)| f <= frame);
+        idx.checked_sub(1).map_or(0, |idx| self.0[idx].1)
+    }
+}
+
+fn parse_buttons(s: &str) -> anyhow::Result<u8> {
+    const NAMES: [&str; 8] = ["a", "b", "select", "start", "up", "down", "left", "right"];
+
+    if s == "-" {
+        return Ok(0);
+    }
+
+    s.split('+').try_fold(0, |acc, name| {
+        let Some(bit) = NAMES.iter().position(|x| x.eq_ignore_ascii_case(name)) else {
+            bail!("unknown button: '{name}'");
+        };
+        Ok(acc | (1 << bit))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bank::Bank;
+
+    use super::*;
+
+    /// $C000 から始まる 16KB の固定バンクにプログラムとベクタを配置した論理アドレス空間を作る。
+    fn make_memory(prog: &[u8], nmi: &[u8]) -> Memory {
+        let mut body = vec![0xEA; 0x4000];
+        body[..prog.len()].copy_from_slice(prog);
+        body[0x100..][..nmi.len()].copy_from_slice(nmi);
+        body[0x3FFA..].copy_from_slice(&[0x00, 0xC1, 0x00, 0xC0, 0x00, 0xC0]);
+
+        Memory::new(vec![Bank::new(Address::new(0xC000), body, true)])
+    }
+
+    #[test]
+    fn test_emulator() {
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x80,       // $C000: lda #$80
+            0x8D, 0x00, 0x20, // $C002: sta $2000
+            0xA2, 0x03,       // $C005: ldx #3
+            0xBD, 0x20, 0xC0, // $C007: lda $C020,x
+            0x20, 0x10, 0xC0, // $C00A: jsr $C010
+            0x4C, 0x0D, 0xC0, // $C00D: jmp $C00D
+            0xCA,             // $C010: dex
+            0x10, 0xFD,       // $C011: bpl $C010
+            0x60,             // $C013: rts
+        ];
+        #[rustfmt::skip]
+        let nmi = [
+            0xE6, 0x10, // $C100: inc $10
+            0x40,       // $C102: rti
+        ];
+        let memory = make_memory(&prog, &nmi);
+
+        let mut emu = Emulator::new(&memory).unwrap();
+        emu.run_frame(0).unwrap();
+        emu.run_frame(0).unwrap();
+        assert_eq!(emu.ram[0x10], 1);
+
+        let cdl = emu.cdl();
+        let at = |addr: u16| cdl[Address::new(addr)];
+        assert!(at(0xC000).is_opcode() && at(0xC000).is_entrypoint());
+        assert!(at(0xC001).is_operand());
+        assert!(at(0xC023).is_data());
+        assert!(!at(0xC022).is_data());
+        assert!(at(0xC010).is_entrypoint() && at(0xC010).is_jump_target());
+        assert!(at(0xC013).is_opcode());
+        assert!(at(0xC100).is_entrypoint() && at(0xC102).is_opcode());
+        assert!(!at(0xC014).is_opcode());
+    }
+
+    #[test]
+    fn test_emulator_pad() {
+        #[rustfmt::skip]
+        let prog = [
+            0xA9, 0x01,       // $C000: lda #1
+            0x8D, 0x16, 0x40, // $C002: sta $4016
+            0xA9, 0x00,       // $C005: lda #0
+            0x8D, 0x16, 0x40, // $C007: sta $4016
+            0xA2, 0x08,       // $C00A: ldx #8
+            0xAD, 0x16, 0x40, // $C00C: lda $4016
+            0x4A,             // $C00F: lsr
+            0x26, 0x10,       // $C010: rol $10
+            0xCA,             // $C012: dex
+            0xD0, 0xF7,       // $C013: bne $C00C
+            0x4C, 0x15, 0xC0, // $C015: jmp $C015
+        ];
+        let memory = make_memory(&prog, &[]);
+
+        let mut emu = Emulator::new(&memory).unwrap();
+        emu.run_frame(0x09).unwrap();
+        // A ボタンが最上位ビットとなる。
+        assert_eq!(emu.ram[0x10], 0x90);
+    }
+
+    #[test]
+    fn test_emulator_unsupported() {
+        let memory = make_memory(&[0x02], &[]);
+
+        let mut emu = Emulator::new(&memory).unwrap();
+        assert_eq!(
+            emu.step(),
+            Err(EmuError::UnsupportedOp {
+                addr: Address::new(0xC000),
+                opcode: 0x02
+            })
+        );
+    }
+
+    #[test]
+    fn test_input_script() {
+        let script =
+            InputScript::parse("# comment\n10 Start\n\n12 -\n20 right+A # jump\n").unwrap();
+
+        assert_eq!(script.buttons(0), 0);
+        assert_eq!(script.buttons(10), 0x08);
+        assert_eq!(script.buttons(11), 0x08);
+        assert_eq!(script.buttons(12), 0);
+        assert_eq!(script.buttons(1000), 0x81);
+
+        assert!(InputScript::parse("10 Turbo").is_err());
+        assert!(InputScript::parse("10").is_err());
+        assert!(InputScript::parse("10 A\n5 B").is_err());
+    }
+}
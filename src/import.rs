@@ -0,0 +1,552 @@
+//! 手で編集された ca65 アセンブリ (disnes の出力) からの注釈の取り込み。
+//!
+//! 現在の解析結果から出力し直したアセンブリと編集後のアセンブリを行単位で対応付け、
+//! 付け替えられたラベル名と追加されたコメントをアドレスに結び付ける。
+//! ラベル名の違いは無視して対応付けるので、ラベル名を変えても対応は崩れない。
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::BufRead;
+
+use log::warn;
+
+use crate::address::Address;
+use crate::annotation::{is_auto_label_name, is_identifier, Annotations};
+use crate::assembly::Assembly;
+use crate::config::OutputConfig;
+use crate::output::assembly_lines;
+
+/// 対応付けが崩れた後、再び対応付けを試みる範囲 (両者で読み飛ばす行数の和の上限)。
+const RESYNC_DISTANCE_MAX: usize = 64;
+
+/// 再び対応付けたとみなすのに必要な、連続して一致する行数。
+const RESYNC_LEN: usize = 4;
+
+/// `asm` から出力されたアセンブリを編集したものを読み、追加されたラベル名とコメントを注釈として返す。
+///
+/// 出力し直したアセンブリと対応しない行は警告を出して無視する。
+pub fn import_assembly<R: BufRead>(
+    asm: &Assembly,
+    config: &OutputConfig,
+    rdr: R,
+) -> anyhow::Result<Annotations> {
+    let edited: Vec<String> = rdr.lines().collect::<Result<_, _>>()?;
+    let generated = assembly_lines(asm, config)?;
+
+    // 出力し直したアセンブリのラベル名たち。
+    let mut gen_names = HashSet::<String>::new();
+    for addr in Address::all() {
+        if let Some(label) = asm.labels().get(addr) {
+            let name = label
+                .name()
+                .map_or_else(|| format!("L_{:04X}", addr.get()), str::to_owned);
+            gen_names.insert(name);
+        }
+    }
+
+    // 編集後のアセンブリで定義されているラベル名たち。
+    let edited_lines: Vec<AsmLine> = edited.iter().map(|line| AsmLine::parse(line)).collect();
+    let edited_names: HashSet<String> = edited_lines
+        .iter()
+        .filter_map(|line| line.defined_name())
+        .map(str::to_owned)
+        .collect();
+
+    let mut res = Annotations::default();
+
+    // 前置きの定数定義 (`name := $0300`) は対応付けによらずアドレスが分かる。
+    for line in edited_lines.iter() {
+        if let Some(Code::Const(name, addr)) = line.code {
+            if !is_auto_label_name(name) {
+                import_label(&mut res, addr, name);
+            }
+        }
+    }
+
+    let edited_units = Unit::collect(
+        edited
+            .iter()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.as_str())),
+        &edited_names,
+    );
+    let gen_units = Unit::collect(
+        generated.iter().map(|(addr, line)| (*addr, line.as_str())),
+        &gen_names,
+    );
+
+    let mut comments = BTreeMap::<Address, Vec<String>>::new();
+
+    let (mut i, mut j) = (0, 0);
+    while i < edited_units.len() && j < gen_units.len() {
+        if edited_units[i].norm != gen_units[j].norm {
+            let Some((di, dj)) = resync(&edited_units[i..], &gen_units[j..]) else {
+                warn!(
+                    "line {}: can't match the rest of the file with the current disassembly",
+                    edited_units[i].tag
+                );
+                for unit in &edited_units[i..] {
+                    warn_dropped(unit);
+                }
+                i = edited_units.len();
+                break;
+            };
+            for unit in &edited_units[i..i + di] {
+                warn!(
+                    "line {}: doesn't match the current disassembly: '{}'",
+                    unit.tag,
+                    unit.code.trim()
+                );
+                warn_dropped(unit);
+            }
+            i += di;
+            j += dj;
+            continue;
+        }
+
+        let (unit, gen_unit) = (&edited_units[i], &gen_units[j]);
+        let addr = gen_unit.addr();
+
+        // 注釈によるコメントは、編集後も残っていれば改めて取り込む
+        // (同じアドレスに追加されたコメントで上書きされないように)。
+        let annotated = asm.find_comment(addr);
+        for comment in unit.comments.iter() {
+            if !gen_unit.has_comment(comment, annotated, &edited_names, &gen_names) {
+                comments.entry(addr).or_default().push(comment.clone());
+            }
+        }
+
+        if let (Some(name), Some(gen_name)) = (
+            unit.code_line.defined_name(),
+            gen_unit.code_line.defined_name(),
+        ) {
+            if name != gen_name && !is_auto_label_name(name) {
+                import_label(&mut res, addr, name);
+            }
+        }
+
+        i += 1;
+        j += 1;
+    }
+    for unit in &edited_units[i..] {
+        warn!(
+            "line {}: doesn't match the current disassembly: '{}'",
+            unit.tag,
+            unit.code.trim()
+        );
+        warn_dropped(unit);
+    }
+
+    // 同じアドレスに対する複数のコメントは 1 つにまとめる。
+    for (addr, group) in comments {
+        if let Err(e) = res.set_comment(addr, group.join(" ")) {
+            warn!("can't import comment at {addr:#06X}: {e:#}");
+        }
+    }
+
+    Ok(res)
+}
+
+fn import_label(annotations: &mut Annotations, addr: Address, name: &str) {
+    if let Err(e) = annotations.set_label(addr, name) {
+        warn!("can't import label '{name}' at {addr:#06X}: {e:#}");
+    }
+}
+
+fn warn_dropped(unit: &Unit<'_, usize>) {
+    if let Some(name) = unit.code_line.defined_name() {
+        if !is_auto_label_name(name) {
+            warn!("line {}: label '{name}' is dropped", unit.tag);
+        }
+    }
+    for comment in unit.comments.iter() {
+        warn!("line {}: comment '{comment}' is dropped", unit.tag);
+    }
+}
+
+/// 対応付けが崩れた位置から、再び連続して一致する位置を探し、(編集後, 出力し直したもの) の読み飛ばす行数を返す。
+fn resync<T, U>(edited: &[Unit<'_, T>], generated: &[Unit<'_, U>]) -> Option<(usize, usize)> {
+    for dist in 1..=RESYNC_DISTANCE_MAX {
+        for di in 0..=dist {
+            let dj = dist - di;
+            if di >= edited.len() || dj >= generated.len() {
+                continue;
+            }
+            let len = RESYNC_LEN.min(edited.len() - di).min(generated.len() - dj);
+            let matched = edited[di..di + len]
+                .iter()
+                .zip(&generated[dj..dj + len])
+                .all(|(e, g)| e.norm == g.norm);
+            if matched {
+                return Some((di, dj));
+            }
+        }
+    }
+
+    None
+}
+
+/// 対応付けの単位。ラベル定義または文の 1 行と、その前に置かれたコメント行たち。
+#[derive(Debug)]
+struct Unit<'a, T> {
+    /// 編集後のアセンブリでは行番号、出力し直したアセンブリではアドレス。
+    tag: T,
+    code: &'a str,
+    code_line: AsmLine<'a>,
+    /// ラベル名を正規化した行の内容 (対応付けに使う)。
+    norm: String,
+    /// 前置きのコメント行および行末コメントたち。
+    comments: Vec<String>,
+}
+
+impl<'a, T: Copy> Unit<'a, T> {
+    fn collect(
+        lines: impl IntoIterator<Item = (T, &'a str)>,
+        names: &HashSet<String>,
+    ) -> Vec<Self> {
+        let mut res = Vec::<Self>::new();
+        let mut pending = Vec::<String>::new();
+
+        for (tag, s) in lines {
+            let line = AsmLine::parse(s);
+            match line.code {
+                None => {
+                    pending.extend(line.comment.map(str::to_owned));
+                    continue;
+                }
+                Some(Code::Const(..) | Code::Segment) => continue,
+                Some(_) => {}
+            }
+            let code_str = split_comment(s).0.trim();
+
+            let mut comments = std::mem::take(&mut pending);
+            comments.extend(line.comment.map(str::to_owned));
+            res.push(Self {
+                tag,
+                code: code_str,
+                code_line: line,
+                norm: normalize(code_str, names),
+                comments,
+            });
+        }
+
+        res
+    }
+
+    /// 出力し直したものと同じコメント (ラベル名の違いは無視する) を持つかどうかを返す。
+    /// 注釈によるコメント `annotated` は比較の対象としない。
+    fn has_comment(
+        &self,
+        comment: &str,
+        annotated: Option<&str>,
+        edited_names: &HashSet<String>,
+        names: &HashSet<String>,
+    ) -> bool {
+        let comment = normalize(comment, edited_names);
+        self.comments
+            .iter()
+            .filter(|gen| Some(gen.as_str()) != annotated)
+            .any(|gen| normalize(gen, names) == comment)
+    }
+}
+
+impl Unit<'_, Address> {
+    /// 行が表すアドレスを返す (文の途中のラベルの場合はそのラベルのアドレス)。
+    fn addr(&self) -> Address {
+        match self.code_line.code {
+            Some(Code::MidLabel(_, _, offset)) => self.tag.wrapping_add_unsigned(offset),
+            _ => self.tag,
+        }
+    }
+}
+
+/// アセンブリの 1 行。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct AsmLine<'a> {
+    code: Option<Code<'a>>,
+    /// 行末または行全体のコメント (`;` を除く)。`;;;` で始まるヘッダや区切り線は含まない。
+    comment: Option<&'a str>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Code<'a> {
+    /// `name:`
+    Label(&'a str),
+    /// `name := base + offset`
+    MidLabel(&'a str, &'a str, usize),
+    /// `name := $XXXX`
+    Const(&'a str, Address),
+    /// `.segment "..."`
+    Segment,
+    /// 命令やデータなどの文。
+    Body(&'a str),
+}
+
+impl<'a> AsmLine<'a> {
+    fn parse(line: &'a str) -> Self {
+        let (code, comment) = split_comment(line);
+
+        let comment = comment
+            .filter(|comment| !(comment.starts_with(';') || comment.starts_with('-')))
+            .map(str::trim)
+            .filter(|comment| !comment.is_empty());
+
+        let code = code.trim_end();
+        if code.trim().is_empty() {
+            return Self {
+                code: None,
+                comment,
+            };
+        }
+
+        let code = if let Some(name) = code
+            .strip_suffix(':')
+            .filter(|name| is_identifier(name.strip_prefix('@').unwrap_or(name)))
+        {
+            Code::Label(name)
+        } else if let Some((name, rhs)) = code.split_once(":=") {
+            let (name, rhs) = (name.trim(), rhs.trim());
+            if let Some(addr) = rhs
+                .strip_prefix('$')
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+            {
+                Code::Const(name, Address::new(addr))
+            } else if let Some((base, offset)) = rhs
+                .split_once('+')
+                .and_then(|(base, offset)| Some((base.trim(), offset.trim().parse().ok()?)))
+            {
+                Code::MidLabel(name, base, offset)
+            } else {
+                Code::Body(code)
+            }
+        } else if code.starts_with(".segment") {
+            Code::Segment
+        } else {
+            Code::Body(code)
+        };
+
+        Self {
+            code: Some(code),
+            comment,
+        }
+    }
+
+    /// ラベルを定義する行ならば、そのラベル名 (`@` を除く) を返す。
+    fn defined_name(&self) -> Option<&'a str> {
+        match self.code? {
+            Code::Label(name) | Code::MidLabel(name, ..) | Code::Const(name, _) => {
+                Some(name.trim_start_matches('@'))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 行をコードとコメント (`;` より後) に分ける。文字列リテラル内の `;` は無視する。
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return (&line[..i], Some(&line[i + 1..])),
+            _ => {}
+        }
+    }
+
+    (line, None)
+}
+
+/// `names` に含まれる識別子 (`@` を除いたもの) を `_` に置き換え、空白を詰める。
+fn normalize(s: &str, names: &HashSet<String>) -> String {
+    let mut res = String::with_capacity(s.len());
+
+    let mut rest = s.trim();
+    while let Some(c) = rest.chars().next() {
+        if c == '@' || c == '_' || c.is_ascii_alphabetic() {
+            let len = rest[1..]
+                .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+                .map_or(rest.len(), |i| i + 1);
+            let ident = &rest[..len];
+            if names.contains(ident.trim_start_matches('@')) {
+                res.push('_');
+            } else {
+                res.push_str(ident);
+            }
+            rest = &rest[len..];
+        } else if c.is_whitespace() {
+            res.push(' ');
+            rest = rest.trim_start();
+        } else {
+            res.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::test_util::{input_builder, make_bank_body};
+    use crate::bank::Bank;
+    use crate::output::output_assembly;
+
+    use super::*;
+
+    #[test]
+    fn test_import_assembly() {
+        #[rustfmt::skip]
+        let main = [
+            0x20, 0x10, 0xC0, // $C000: jsr $C010
+            0x8D, 0x00, 0x03, // $C003: sta $0300
+            0x4C, 0x00, 0xC0, // $C006: jmp $C000
+        ];
+        #[rustfmt::skip]
+        let sub = [
+            0xA2, 0x04,       // $C010: ldx #4
+            0xCA,             // $C012: dex
+            0xD0, 0xFD,       // $C013: bne $C012
+            0xAD, 0x00, 0x03, // $C015: lda $0300
+            0x60,             // $C018: rts
+        ];
+        let body = make_bank_body(&[(0xC000, &main), (0xC010, &sub)]);
+        let mut annotations = Annotations::default();
+        annotations
+            .set_label(Address::new(0x0300), "counter")
+            .unwrap();
+        annotations
+            .set_comment(Address::new(0xC010), "wait")
+            .unwrap();
+        let input = input_builder(vec![Bank::new(Address::new(0xC000), body, true)])
+            .annotations(annotations)
+            .build()
+            .unwrap();
+        let asm = crate::analysis::analyze(&input, &Default::default());
+        let config = OutputConfig::default();
+
+        let mut buf = Vec::<u8>::new();
+        output_assembly(&mut buf, &asm, &config).unwrap();
+        let generated = String::from_utf8(buf).unwrap();
+
+        // 出力し直したアセンブリのうち、編集で変わる行は文の部分に含まれていること。
+        let lines: Vec<String> = assembly_lines(&asm, &config)
+            .unwrap()
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect();
+        for line in ["        jmp     L_C000", "@L_C012:", "        ldx     #4"] {
+            assert!(lines.iter().any(|l| l == line), "{line}");
+        }
+
+        let mut edited = Vec::<String>::new();
+        for line in generated.lines() {
+            match line {
+                // 定数の名前を付け替え、新たな定数を加える。
+                "counter := $0300" => {
+                    edited.push("player_x := $0300".to_owned());
+                    edited.push("joy1 := $4016".to_owned());
+                }
+                "        sta     counter" => {
+                    edited.push("        sta     player_x ; save count".to_owned())
+                }
+                // 行を削除する。
+                "        jmp     L_C000" => {}
+                // ラベル名を付け替える。
+                "L_C010:" => edited.push("WaitLoop:".to_owned()),
+                // 行を挿入する (対応しないので、そのコメントは取り込まれない)。
+                "        ldx     #4" => {
+                    edited.push(line.to_owned());
+                    edited.push("        nop ; lost".to_owned());
+                }
+                // ローカルラベルの名前を付け替え、コメント行を加える。
+                "@L_C012:" => {
+                    edited.push("@loop:".to_owned());
+                    edited.push("        ; count down".to_owned());
+                }
+                _ => edited.push(
+                    line.replace("L_C010", "WaitLoop")
+                        .replace("@L_C012", "@loop")
+                        .replace("counter", "player_x"),
+                ),
+            }
+        }
+        let edited = edited.join("\n");
+
+        let res = import_assembly(&asm, &config, edited.as_bytes()).unwrap();
+
+        itertools::assert_equal(
+            res.labels(),
+            [
+                (Address::new(0x0300), "player_x"),
+                (Address::new(0x4016), "joy1"),
+                (Address::new(0xC010), "WaitLoop"),
+                (Address::new(0xC012), "loop"),
+            ],
+        );
+        // 注釈によるコメントは編集後も残っているので改めて取り込まれる。
+        itertools::assert_equal(
+            res.comments(),
+            [
+                (Address::new(0xC003), "save count"),
+                (Address::new(0xC010), "wait"),
+                (Address::new(0xC012), "count down"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_asm_line() {
+        assert_eq!(
+            AsmLine::parse("@L_C284:"),
+            AsmLine {
+                code: Some(Code::Label("@L_C284")),
+                comment: None
+            }
+        );
+        assert_eq!(
+            AsmLine::parse("UpdateX := UpdatePlayer + 1 ; mid"),
+            AsmLine {
+                code: Some(Code::MidLabel("UpdateX", "UpdatePlayer", 1)),
+                comment: Some("mid")
+            }
+        );
+        assert_eq!(
+            AsmLine::parse("player_x := $0300"),
+            AsmLine {
+                code: Some(Code::Const("player_x", Address::new(0x0300))),
+                comment: None
+            }
+        );
+        assert_eq!(
+            AsmLine::parse(r#"        .byte   "A;B" ; text"#),
+            AsmLine {
+                code: Some(Code::Body(r#"        .byte   "A;B""#)),
+                comment: Some("text")
+            }
+        );
+        assert_eq!(
+            AsmLine::parse(";;; callers: Main"),
+            AsmLine {
+                code: None,
+                comment: None
+            }
+        );
+        assert_eq!(AsmLine::parse("  ; note").comment, Some("note"));
+        assert_eq!(
+            AsmLine::parse(r#".segment "PRG0""#).code,
+            Some(Code::Segment)
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let names: HashSet<String> = ["UpdatePlayer", "L_C284"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+
+        assert_eq!(normalize("  jsr     UpdatePlayer", &names), "jsr _");
+        assert_eq!(normalize("bne     @L_C284", &names), "bne _");
+        assert_eq!(normalize("lda     #<UpdatePlayer+1", &names), "lda #<_+1");
+        assert_eq!(normalize("lda     L_0300,x", &names), "lda L_0300,x");
+    }
+}
@@ -1,10 +1,15 @@
-use anyhow::bail;
+use anyhow::{bail, ensure};
 
-use crate::address::Address;
+use crate::address::{Address, AddressRange};
+use crate::annotation::Annotations;
 use crate::bank::Bank;
 use crate::cdl::Cdl;
+use crate::layout::{SplitTable, StructArray};
+use crate::mapper::Mapper;
 use crate::memory::Memory;
 use crate::permission::Permissions;
+use crate::signature::Signature;
+use crate::text::CharTable;
 
 /// 逆アセンブラに対する入力。
 #[derive(Debug)]
@@ -14,6 +19,15 @@ pub struct Input {
     cdl: Cdl,
     target_bank_id: usize,
     target_bank_name: String,
+    target_bank_number: Option<usize>,
+    mapper: Option<Mapper>,
+    char_table: Option<CharTable>,
+    char_table_ranges: Vec<(AddressRange, CharTable)>,
+    incbin_ranges: Vec<(AddressRange, bool)>,
+    struct_arrays: Vec<StructArray>,
+    split_tables: Vec<SplitTable>,
+    annotations: Annotations,
+    signatures: Vec<Signature>,
 }
 
 impl Input {
@@ -29,6 +43,11 @@ impl Input {
         &self.cdl
     }
 
+    /// CDL にフラグを追加する (組み込みエミュレータで得たカバレッジなどを合わせるためのもの)。
+    pub fn merge_cdl(&mut self, cdl: &Cdl) {
+        self.cdl.merge(cdl);
+    }
+
     pub fn target_bank_id(&self) -> usize {
         self.target_bank_id
     }
@@ -40,6 +59,62 @@ impl Input {
     pub fn target_bank(&self) -> &Bank {
         &self.memory.banks()[self.target_bank_id]
     }
+
+    /// 逆アセンブル対象バンクのマッパー上のバンク番号を返す。固定バンクの場合は `None` を返す。
+    pub fn target_bank_number(&self) -> Option<usize> {
+        self.target_bank_number
+    }
+
+    /// マッパーを返す。指定されていなければ `None` を返す (バンク切り替えは認識されない)。
+    pub fn mapper(&self) -> Option<Mapper> {
+        self.mapper
+    }
+
+    /// 指定したアドレスのテキスト検出に使う文字テーブルを返す。
+    /// 指定されていなければ `None` を返す (テキスト検出は行われない)。
+    ///
+    /// アドレス範囲ごとの文字テーブルがあればそれを、なければバンク全体の文字テーブルを返す。
+    pub fn char_table(&self, addr: Address) -> Option<&CharTable> {
+        self.char_table_ranges
+            .iter()
+            .find(|(range, _)| range.contains_addr(addr))
+            .map(|(_, table)| table)
+            .or(self.char_table.as_ref())
+    }
+
+    /// 指定したアドレスのデータを `.incbin` で出力するかどうかの指定を返す。
+    /// 指定されていなければ `None` を返す (`incbin_threshold` に従う)。
+    pub fn incbin_override(&self, addr: Address) -> Option<bool> {
+        self.incbin_ranges
+            .iter()
+            .find(|(range, _)| range.contains_addr(addr))
+            .map(|&(_, incbin)| incbin)
+    }
+
+    /// 逆アセンブル対象バンク内の構造体の配列たちを返す (開始アドレスの昇順)。
+    pub fn struct_arrays(&self) -> &[StructArray] {
+        &self.struct_arrays
+    }
+
+    /// 逆アセンブル対象バンク内の、マニフェストで宣言された分割ポインタテーブルたちを返す。
+    pub fn split_tables(&self) -> &[SplitTable] {
+        &self.split_tables
+    }
+
+    /// 利用者による注釈を返す。
+    pub fn annotations(&self) -> &Annotations {
+        &self.annotations
+    }
+
+    /// 利用者による注釈を差し替える (対話的に注釈を編集しながら再解析するためのもの)。
+    pub fn set_annotations(&mut self, annotations: Annotations) {
+        self.annotations = annotations;
+    }
+
+    /// 利用者によるシグネチャたちを返す (組み込みのシグネチャは含まない)。
+    pub fn signatures(&self) -> &[Signature] {
+        &self.signatures
+    }
 }
 
 #[derive(Debug, Default)]
@@ -49,6 +124,15 @@ pub struct InputBuilder {
     cdl: Option<Cdl>,
     target_bank_addr: Option<Address>,
     target_bank_name: Option<String>,
+    target_bank_number: Option<usize>,
+    mapper: Option<Mapper>,
+    char_table: Option<CharTable>,
+    char_table_ranges: Vec<(AddressRange, CharTable)>,
+    incbin_ranges: Vec<(AddressRange, bool)>,
+    struct_arrays: Vec<StructArray>,
+    split_tables: Vec<SplitTable>,
+    annotations: Annotations,
+    signatures: Vec<Signature>,
 }
 
 impl InputBuilder {
@@ -77,12 +161,49 @@ impl InputBuilder {
             bail!("InputBuilder: target bank not found");
         };
 
+        let mut struct_arrays = self.struct_arrays;
+        struct_arrays.sort_unstable_by_key(StructArray::addr);
+        for pair in struct_arrays.windows(2) {
+            ensure!(
+                !pair[0].addr_range().intersects(pair[1].addr_range()),
+                "InputBuilder: struct array {:#06X} intersects with struct array {:#06X}",
+                pair[0].addr(),
+                pair[1].addr()
+            );
+        }
+        for array in struct_arrays.iter() {
+            ensure!(
+                memory.banks()[target_bank_id].contains_range(array.addr_range()),
+                "InputBuilder: struct array {:#06X} is out of target bank",
+                array.addr()
+            );
+        }
+
+        for table in self.split_tables.iter() {
+            let bank = &memory.banks()[target_bank_id];
+            ensure!(
+                bank.contains_range(table.lo_range()) && bank.contains_range(table.hi_range()),
+                "InputBuilder: split table (lo={:#06X}, hi={:#06X}) is out of target bank",
+                table.lo(),
+                table.hi()
+            );
+        }
+
         Ok(Input {
             memory,
             permissions,
             cdl,
             target_bank_id,
             target_bank_name,
+            target_bank_number: self.target_bank_number,
+            mapper: self.mapper,
+            char_table: self.char_table,
+            char_table_ranges: self.char_table_ranges,
+            incbin_ranges: self.incbin_ranges,
+            struct_arrays,
+            split_tables: self.split_tables,
+            annotations: self.annotations,
+            signatures: self.signatures,
         })
     }
 
@@ -110,4 +231,62 @@ impl InputBuilder {
         self.target_bank_name = Some(target_bank_name.into());
         self
     }
+
+    /// 逆アセンブル対象バンクのマッパー上のバンク番号を指定する (省略可)。
+    pub fn target_bank_number(mut self, target_bank_number: usize) -> Self {
+        self.target_bank_number = Some(target_bank_number);
+        self
+    }
+
+    /// マッパーを指定する (省略可)。
+    pub fn mapper(mut self, mapper: Mapper) -> Self {
+        self.mapper = Some(mapper);
+        self
+    }
+
+    /// テキスト検出に使う文字テーブルを指定する (省略可)。
+    pub fn char_table(mut self, char_table: CharTable) -> Self {
+        self.char_table = Some(char_table);
+        self
+    }
+
+    /// 指定したアドレス範囲のテキスト検出に使う文字テーブルを追加する (省略可)。
+    /// 範囲が重なる場合、先に追加したものが優先される。
+    pub fn char_table_range(mut self, range: AddressRange, char_table: CharTable) -> Self {
+        self.char_table_ranges.push((range, char_table));
+        self
+    }
+
+    /// 指定したアドレス範囲のデータを `.incbin` で出力するかどうかを指定する (省略可)。
+    /// 範囲が重なる場合、先に追加したものが優先される。
+    pub fn incbin_range(mut self, range: AddressRange, incbin: bool) -> Self {
+        self.incbin_ranges.push((range, incbin));
+        self
+    }
+
+    /// 逆アセンブル対象バンク内の構造体の配列を追加する (省略可)。
+    /// 配列どうしは重なっていてはならない。
+    pub fn struct_array(mut self, struct_array: StructArray) -> Self {
+        self.struct_arrays.push(struct_array);
+        self
+    }
+
+    /// 逆アセンブル対象バンク内の分割ポインタテーブルを追加する (省略可)。
+    pub fn split_table(mut self, split_table: SplitTable) -> Self {
+        self.split_tables.push(split_table);
+        self
+    }
+
+    /// 利用者による注釈を指定する (省略可)。
+    pub fn annotations(mut self, annotations: Annotations) -> Self {
+        self.annotations = annotations;
+        self
+    }
+
+    /// 既知のルーチンを認識するためのシグネチャたちを追加する (省略可)。
+    /// 組み込みのシグネチャより優先される。
+    pub fn signatures(mut self, signatures: impl IntoIterator<Item = Signature>) -> Self {
+        self.signatures.extend(signatures);
+        self
+    }
 }
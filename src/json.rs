@@ -0,0 +1,105 @@
+//! JSON 形式での出力。
+
+use std::io::Write;
+
+use serde_json::json;
+
+use crate::callgraph::{CallGraph, CallKind};
+
+/// 呼び出しグラフを JSON 形式で出力する。
+///
+/// アドレスは "$C000" のような文字列とする。辺の `src`, `dst` は `nodes` 内のインデックス。
+pub fn output_callgraph_json<W: Write>(wtr: &mut W, graph: &CallGraph) -> anyhow::Result<()> {
+    let nodes: Vec<_> = graph
+        .nodes()
+        .iter()
+        .map(|node| {
+            json!({
+                "bank": node.bank(),
+                "entry": format!("${:04X}", node.entry()),
+                "size": node.size(),
+                "trampoline": node.is_trampoline(),
+            })
+        })
+        .collect();
+
+    let edges: Vec<_> = graph
+        .edges()
+        .iter()
+        .map(|edge| {
+            let kind = match edge.kind() {
+                CallKind::Jsr => "jsr",
+                CallKind::JmpInd => "jmp_ind",
+            };
+            let sites: Vec<_> = edge
+                .sites()
+                .iter()
+                .map(|site| format!("${site:04X}"))
+                .collect();
+            json!({
+                "src": edge.src(),
+                "dst": edge.dst(),
+                "kind": kind,
+                "sites": sites,
+                "bank_switch": edge.bank_switch(),
+            })
+        })
+        .collect();
+
+    serde_json::to_writer_pretty(&mut *wtr, &json!({ "nodes": nodes, "edges": edges }))?;
+    writeln!(wtr)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::callgraph::tests::make_call_graph;
+
+    use super::*;
+
+    #[test]
+    fn test_output_callgraph_json() {
+        let graph = make_call_graph();
+
+        let mut buf = Vec::<u8>::new();
+        output_callgraph_json(&mut buf, &graph).unwrap();
+        let value: Value = serde_json::from_slice(&buf).unwrap();
+
+        let keys = |value: &Value| {
+            let mut keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+            keys.sort_unstable();
+            keys
+        };
+        assert_eq!(keys(&value), ["edges", "nodes"]);
+
+        let nodes = value["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), graph.nodes().len());
+        for node in nodes {
+            assert_eq!(keys(node), ["bank", "entry", "size", "trampoline"]);
+        }
+        let trampoline = nodes.iter().find(|node| node["entry"] == "$C010").unwrap();
+        assert_eq!(trampoline["bank"], "FIX");
+        assert_eq!(trampoline["trampoline"], true);
+
+        let edges = value["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), graph.edges().len());
+        for edge in edges {
+            assert_eq!(keys(edge), ["bank_switch", "dst", "kind", "sites", "src"]);
+        }
+        let call = edges.iter().find(|edge| edge["bank_switch"] == 1).unwrap();
+        assert_eq!(call["kind"], "jsr");
+        assert_eq!(call["sites"], serde_json::json!(["$C015"]));
+        assert_eq!(
+            nodes[call["src"].as_u64().unwrap() as usize]["entry"],
+            "$C010"
+        );
+        assert_eq!(
+            nodes[call["dst"].as_u64().unwrap() as usize]["entry"],
+            "$8000"
+        );
+        assert_eq!(nodes[call["dst"].as_u64().unwrap() as usize]["size"], 1);
+    }
+}
@@ -0,0 +1,240 @@
+//! データの構造体レイアウト。
+//!
+//! 敵配置テーブルなどの、同じ形のレコードが並ぶデータを表すのに使う。
+
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use anyhow::ensure;
+use serde::Deserialize;
+
+use crate::address::{Address, AddressRange};
+
+/// 構造体型。1 つ以上のフィールドからなる。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StructType {
+    name: String,
+    fields: Vec<StructField>,
+}
+
+impl StructType {
+    pub fn new(
+        name: impl Into<String>,
+        fields: impl Into<Vec<StructField>>,
+    ) -> anyhow::Result<Self> {
+        let name = name.into();
+        let fields = fields.into();
+
+        ensure!(!fields.is_empty(), "struct '{name}' has no fields");
+
+        Ok(Self { name, fields })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fields(&self) -> &[StructField] {
+        &self.fields
+    }
+
+    /// バイト数を返す。
+    pub fn len(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.fields.iter().map(|field| field.ty().size()).sum()).unwrap()
+    }
+
+    /// 各フィールドについて、(レコード先頭からのオフセット, フィールド) を返す。
+    pub fn field_offsets(&self) -> impl Iterator<Item = (usize, &StructField)> {
+        self.fields.iter().scan(0, |offset, field| {
+            let res = (*offset, field);
+            *offset += field.ty().size();
+            Some(res)
+        })
+    }
+}
+
+/// 構造体のフィールド。
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructField {
+    name: String,
+    #[serde(rename = "type")]
+    ty: FieldType,
+}
+
+impl StructField {
+    pub fn new(name: impl Into<String>, ty: FieldType) -> Self {
+        Self {
+            name: name.into(),
+            ty,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ty(&self) -> FieldType {
+        self.ty
+    }
+}
+
+/// フィールドの型。
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    /// 1 バイトの値。
+    Byte,
+    /// 2 バイトの値 (リトルエンディアン)。
+    Word,
+    /// 2 バイトのポインタ (リトルエンディアン)。参照先にはラベルが振られる。
+    Ptr,
+}
+
+impl FieldType {
+    /// バイト数を返す。
+    pub fn size(self) -> usize {
+        match self {
+            Self::Byte => 1,
+            Self::Word | Self::Ptr => 2,
+        }
+    }
+}
+
+/// 指定したアドレスから並ぶ構造体の配列。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StructArray {
+    addr: Address,
+    ty: Arc<StructType>,
+    count: NonZeroUsize,
+}
+
+impl StructArray {
+    /// (開始アドレス, 要素の型, 要素数) を指定して構造体の配列を作る。
+    /// 配列は論理アドレス空間に収まらなければならない。
+    pub fn new(addr: Address, ty: Arc<StructType>, count: NonZeroUsize) -> anyhow::Result<Self> {
+        let len = ty.len().get() * count.get();
+        ensure!(
+            addr.checked_add_unsigned(len - 1).is_some(),
+            "struct array '{}' (start={addr:#X}, count={count}) overflows",
+            ty.name()
+        );
+
+        Ok(Self { addr, ty, count })
+    }
+
+    pub fn addr(&self) -> Address {
+        self.addr
+    }
+
+    pub fn ty(&self) -> &Arc<StructType> {
+        &self.ty
+    }
+
+    pub fn count(&self) -> NonZeroUsize {
+        self.count
+    }
+
+    /// 配列全体のアドレス範囲を返す。
+    pub fn addr_range(&self) -> AddressRange {
+        AddressRange::from_start_len(
+            self.addr,
+            NonZeroUsize::new(self.ty.len().get() * self.count.get()).unwrap(),
+        )
+    }
+}
+
+/// 下位バイトと上位バイトを別々の配列に分けて並べたポインタテーブル。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SplitTable {
+    lo: Address,
+    hi: Address,
+    count: NonZeroUsize,
+}
+
+impl SplitTable {
+    /// (下位バイト配列の開始アドレス, 上位バイト配列の開始アドレス, 要素数) を指定してテーブルを作る。
+    /// 2 つの配列は論理アドレス空間に収まり、かつ互いに重なっていてはならない。
+    pub fn new(lo: Address, hi: Address, count: NonZeroUsize) -> anyhow::Result<Self> {
+        for start in [lo, hi] {
+            ensure!(
+                start.checked_add_unsigned(count.get() - 1).is_some(),
+                "split table (lo={lo:#X}, hi={hi:#X}, count={count}) overflows"
+            );
+        }
+
+        let this = Self { lo, hi, count };
+        ensure!(
+            !this.lo_range().intersects(this.hi_range()),
+            "split table (lo={lo:#X}, hi={hi:#X}, count={count}) has overlapping lo/hi arrays"
+        );
+
+        Ok(this)
+    }
+
+    /// 下位バイト配列の開始アドレスを返す。
+    pub fn lo(&self) -> Address {
+        self.lo
+    }
+
+    /// 上位バイト配列の開始アドレスを返す。
+    pub fn hi(&self) -> Address {
+        self.hi
+    }
+
+    pub fn count(&self) -> NonZeroUsize {
+        self.count
+    }
+
+    /// 下位バイト配列のアドレス範囲を返す。
+    pub fn lo_range(&self) -> AddressRange {
+        AddressRange::from_start_len(self.lo, self.count)
+    }
+
+    /// 上位バイト配列のアドレス範囲を返す。
+    pub fn hi_range(&self) -> AddressRange {
+        AddressRange::from_start_len(self.hi, self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::assert_equal;
+
+    use super::*;
+
+    #[test]
+    fn test_struct_type() {
+        let ty = StructType::new(
+            "Enemy",
+            [
+                StructField::new("x", FieldType::Byte),
+                StructField::new("y", FieldType::Byte),
+                StructField::new("ptr", FieldType::Ptr),
+                StructField::new("hp", FieldType::Word),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(ty.len().get(), 6);
+        assert_equal(
+            ty.field_offsets()
+                .map(|(offset, field)| (offset, field.name())),
+            [(0, "x"), (1, "y"), (2, "ptr"), (4, "hp")],
+        );
+
+        assert!(StructType::new("Empty", []).is_err());
+    }
+
+    #[test]
+    fn test_split_table() {
+        let count = NonZeroUsize::new(4).unwrap();
+
+        let table = SplitTable::new(Address::new(0x8000), Address::new(0x8004), count).unwrap();
+        assert_eq!(table.lo_range().max(), Address::new(0x8003));
+        assert_eq!(table.hi_range().max(), Address::new(0x8007));
+
+        assert!(SplitTable::new(Address::new(0x8000), Address::new(0x8003), count).is_err());
+        assert!(SplitTable::new(Address::new(0xFFFE), Address::new(0x8000), count).is_err());
+    }
+}
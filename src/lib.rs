@@ -7,27 +7,55 @@
 
 mod address;
 mod analysis;
+mod annotation;
 mod assembly;
 mod bank;
+mod callgraph;
 mod cdl;
+mod cfg;
 mod config;
+mod diff;
+mod dot;
+mod emu;
+mod import;
 mod input;
+mod json;
+mod layout;
 mod manifest;
+mod mapper;
 mod memory;
 mod op;
 mod output;
 mod permission;
+mod regs;
+mod signature;
+mod text;
+mod trace;
 mod util;
 
 pub use self::address::*;
 pub use self::analysis::*;
+pub use self::annotation::*;
 pub use self::assembly::*;
 pub use self::bank::*;
+pub use self::callgraph::*;
 pub use self::cdl::*;
+pub use self::cfg::*;
 pub use self::config::*;
+pub use self::diff::*;
+pub use self::dot::*;
+pub use self::emu::*;
+pub use self::import::*;
 pub use self::input::*;
+pub use self::json::*;
+pub use self::layout::*;
 pub use self::manifest::*;
+pub use self::mapper::*;
 pub use self::memory::*;
 pub use self::op::*;
 pub use self::output::*;
 pub use self::permission::*;
+pub use self::regs::*;
+pub use self::signature::*;
+pub use self::text::*;
+pub use self::trace::*;
@@ -1,23 +1,50 @@
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{bail, Context as _};
 use itertools::Itertools as _;
+use log::warn;
 use serde::{de::Error as _, Deserialize, Deserializer};
 
 use crate::address::{Address, AddressRange};
+use crate::annotation::{AnnotationFile, Annotations};
 use crate::bank::Bank;
 use crate::cdl::{Cdl, CdlElement};
 use crate::config::Config;
 use crate::input::{Input, InputBuilder};
+use crate::layout::{FieldType, SplitTable, StructArray, StructField, StructType};
+use crate::mapper::Mapper;
 use crate::memory::Memory;
 use crate::permission::{Permission, Permissions};
+use crate::signature::{load_signature_dir, Signature};
+use crate::text::CharTable;
+use crate::trace::read_trace_log;
 use crate::util;
 
 /// TOML ファイルから読み込まれる構成。
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Manifest {
+    /// マッパー。指定するとバンク切り替えを認識し、別バンクへの参照先を推定する。
+    mapper: Option<Mapper>,
+
+    /// テキスト検出に使う文字テーブル。TBL ファイルのパス、または `"ascii"` を指定する。
+    /// 省略時はテキスト検出を行わない。バンクごとに上書きできる。
+    text_table: Option<PathBuf>,
+
+    /// エミュレータのトレースログのパスたち。CDL と同様にコード/データ判別に使われる。
+    #[serde(default)]
+    trace_logs: Vec<PathBuf>,
+
+    /// シグネチャファイル (`*.toml`) を置いたディレクトリたち。既知のルーチンの認識に使われる。
+    #[serde(default)]
+    signature_dirs: Vec<PathBuf>,
+
+    /// データの構造体型たち。バンクの `struct_arrays` から名前で参照される。
+    #[serde(default, rename = "structs")]
+    struct_descs: StructDescs,
+
     #[serde(rename = "memory")]
     memory_regions: MemoryRegions,
 
@@ -25,6 +52,10 @@ pub struct Manifest {
     bank_descs: BankDescs,
 
     config: Config,
+
+    /// 利用者による注釈。マニフェスト自体ではなく注釈ファイルから読み込まれる。
+    #[serde(skip)]
+    annotations: AnnotationFile,
 }
 
 impl Manifest {
@@ -32,15 +63,143 @@ impl Manifest {
         Ok(toml::from_str(s.as_ref())?)
     }
 
+    /// 利用者による注釈を返す。
+    pub fn annotations(&self) -> &AnnotationFile {
+        &self.annotations
+    }
+
+    pub fn annotations_mut(&mut self) -> &mut AnnotationFile {
+        &mut self.annotations
+    }
+
+    /// 利用者による注釈を設定する。以降に作る `Input` に反映される。
+    pub fn set_annotations(&mut self, annotations: AnnotationFile) {
+        self.annotations = annotations;
+    }
+
+    /// 指定したアドレスに対する注釈の格納先を返す。
+    ///
+    /// `bank_name` のバンクまたは固定バンク内ならそのバンクの注釈、
+    /// どのバンクにも属さなければグローバルな注釈とする。
+    /// それ以外の非固定バンク内のアドレスの場合はどのバンクか決められないので、エラーを返す。
+    pub fn annotations_at_mut(
+        &mut self,
+        addr: Address,
+        bank_name: Option<&str>,
+    ) -> anyhow::Result<&mut Annotations> {
+        let bank = self.bank_descs.0.iter().find(|bd| {
+            (bd.fixed || Some(bd.name.as_str()) == bank_name) && bd.addr_range().contains_addr(addr)
+        });
+        if let Some(bd) = bank {
+            let name = bd.name.clone();
+            return Ok(self.annotations.bank_mut(&name));
+        }
+
+        if self
+            .bank_descs
+            .0
+            .iter()
+            .any(|bd| bd.addr_range().contains_addr(addr))
+        {
+            bail!("{addr:#06X} is in a switchable bank which is not specified");
+        }
+
+        Ok(self.annotations.global_mut())
+    }
+
     /// 逆アセンブル対象のバンク名を指定して `Input` と `Config` を作る。
     pub fn into_input_config(
         self,
         target_bank_name: impl Into<String>,
     ) -> anyhow::Result<(Input, Config)> {
-        self._into_input_config(target_bank_name.into())
+        self._input_config(target_bank_name.into())
+    }
+
+    /// `into_input_config` と同様だが、`Manifest` を消費しない。
+    /// 複数のバンクを順に逆アセンブルする場合に使う。
+    pub fn input_config(
+        &self,
+        target_bank_name: impl Into<String>,
+    ) -> anyhow::Result<(Input, Config)> {
+        self._input_config(target_bank_name.into())
+    }
+
+    /// 全バンク名を定義順に返す。
+    pub fn bank_names(&self) -> impl Iterator<Item = &str> {
+        self.bank_descs.0.iter().map(|bd| bd.name.as_str())
+    }
+
+    /// 指定したバンクのアドレス範囲を返す。なければ `None` を返す。
+    pub fn bank_addr_range(&self, bank_name: &str) -> Option<AddressRange> {
+        self.bank_descs
+            .0
+            .iter()
+            .find(|bd| bd.name == bank_name)
+            .map(BankDesc::addr_range)
+    }
+
+    /// 固定バンクの名前たちを定義順に返す。
+    pub fn fixed_bank_names(&self) -> impl Iterator<Item = &str> {
+        self.bank_descs
+            .0
+            .iter()
+            .filter(|bd| bd.fixed)
+            .map(|bd| bd.name.as_str())
+    }
+
+    /// 指定したアドレスを含む固定バンクがあればその名前を返す。
+    pub fn find_fixed_bank_name(&self, addr: Address) -> Option<&str> {
+        self.bank_descs
+            .0
+            .iter()
+            .find(|bd| bd.fixed && bd.addr_range().contains_addr(addr))
+            .map(|bd| bd.name.as_str())
+    }
+
+    /// 指定したアドレスを含む非固定バンクのうち、マッパー上のバンク番号が `number` であるものの名前を返す。
+    ///
+    /// バンク番号が明示されていないバンクについては、
+    /// 同じ開始アドレスを持つ非固定バンクたちの中での定義順をバンク番号とみなす。
+    pub fn find_switchable_bank_name(&self, addr: Address, number: usize) -> Option<&str> {
+        self.bank_descs
+            .0
+            .iter()
+            .filter(|bd| !bd.fixed && bd.addr_range().contains_addr(addr))
+            .find(|bd| self.bank_number(bd) == number)
+            .map(|bd| bd.name.as_str())
+    }
+
+    /// 非固定バンクのマッパー上のバンク番号を返す。
+    fn bank_number(&self, bank_desc: &BankDesc) -> usize {
+        bank_desc.number.unwrap_or_else(|| {
+            self.bank_descs
+                .0
+                .iter()
+                .filter(|bd| !bd.fixed && bd.start == bank_desc.start)
+                .position(|bd| bd.name == bank_desc.name)
+                .unwrap()
+        })
+    }
+
+    /// 逆アセンブル対象のバンク名を指定して、注釈に依存しないデータをファイルから読み込む。
+    ///
+    /// 結果を `input_config_with` に渡すと `input_config` と同じ `Input` と `Config` が得られる。
+    /// 注釈を変更しながら同じバンクを繰り返し逆アセンブルする場合、ファイルの再読み込みを避けられる。
+    pub fn load_bank_data(&self, target_bank_name: impl Into<String>) -> anyhow::Result<BankData> {
+        self._load_bank_data(target_bank_name.into())
     }
 
-    fn _into_input_config(self, target_bank_name: String) -> anyhow::Result<(Input, Config)> {
+    /// `load_bank_data` で読み込んだデータに現在の注釈を反映して `Input` と `Config` を作る。
+    pub fn input_config_with(&self, data: &BankData) -> anyhow::Result<(Input, Config)> {
+        self._input_config_with(data.clone())
+    }
+
+    fn _input_config(&self, target_bank_name: String) -> anyhow::Result<(Input, Config)> {
+        let data = self._load_bank_data(target_bank_name)?;
+        self._input_config_with(data)
+    }
+
+    fn _load_bank_data(&self, target_bank_name: String) -> anyhow::Result<BankData> {
         // アドレス空間全体のパーミッションを設定。
         let mut perms = Permissions::default();
         for mr in self.memory_regions.0.iter() {
@@ -84,30 +243,276 @@ impl Manifest {
             }
         }
 
-        // 逆アセンブル対象バンクのアドレスを取得。
-        let target_bank_addr = self
+        // 逆アセンブル対象バンクを取得。
+        let target_bd = self
             .bank_descs
             .0
             .iter()
-            .find_map(|bd| (bd.name == target_bank_name).then_some(bd.start));
-        let Some(target_bank_addr) = target_bank_addr else {
+            .find(|bd| bd.name == target_bank_name);
+        let Some(target_bd) = target_bd else {
             bail!("target bank '{target_bank_name}' not found");
         };
 
         let memory = Memory::new(banks);
 
-        let input = InputBuilder::new()
+        // トレースログから得た情報を CDL に加える。
+        // 行のバンク番号は、非固定バンクについてのみ照合する。
+        let bank_numbers: Vec<Option<usize>> = self
+            .bank_descs
+            .0
+            .iter()
+            .filter(|bd| bd.name == target_bank_name || bd.fixed)
+            .map(|bd| (!bd.fixed).then(|| self.bank_number(bd)))
+            .collect();
+        for path in &self.trace_logs {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("can't open trace log '{}'", path.display()))?;
+            let trace_cdl =
+                read_trace_log(&memory, &bank_numbers, std::io::BufReader::new(file))
+                    .with_context(|| format!("can't read trace log '{}'", path.display()))?;
+            cdl.merge(&trace_cdl);
+        }
+
+        let mut signatures = Vec::new();
+        for dir in self.signature_dirs.iter() {
+            signatures.extend(load_signature_dir(dir)?);
+        }
+        let char_table = target_bd
+            .text_table
+            .as_ref()
+            .or(self.text_table.as_ref())
+            .map(|path| load_char_table(path))
+            .transpose()?;
+        let mut char_table_ranges = Vec::with_capacity(target_bd.text_ranges.len());
+        for tr in target_bd.text_ranges.iter() {
+            char_table_ranges.push((tr.addr_range(), load_char_table(&tr.table)?));
+        }
+
+        Ok(BankData {
+            target_bank_name,
+            perms,
+            memory,
+            cdl,
+            signatures,
+            char_table,
+            char_table_ranges,
+        })
+    }
+
+    fn _input_config_with(&self, data: BankData) -> anyhow::Result<(Input, Config)> {
+        let BankData {
+            target_bank_name,
+            perms,
+            memory,
+            cdl,
+            signatures,
+            char_table,
+            char_table_ranges,
+        } = data;
+        let Some(target_bd) = self
+            .bank_descs
+            .0
+            .iter()
+            .find(|bd| bd.name == target_bank_name)
+        else {
+            bail!("target bank '{target_bank_name}' not found");
+        };
+
+        // グローバルな注釈と、ロードされるバンクの注釈を合わせる。
+        let bank_names = std::iter::once(target_bd.name.as_str()).chain(self.fixed_bank_names());
+        let annotations = self.annotations.annotations_for(bank_names)?;
+
+        // 注釈によるデータ型指定のうち、逆アセンブル対象バンク内のものは構造体の配列とする。
+        let mut annotated_arrays = Vec::<StructArray>::new();
+        for (addr, ty) in annotations.types() {
+            if !target_bd.addr_range().contains_addr(addr) {
+                continue;
+            }
+            let struct_ty = self
+                .annotated_struct_type(ty.name())
+                .with_context(|| format!("annotated type at {addr:#06X}"))?;
+            let array = StructArray::new(addr, struct_ty, ty.count())?;
+            if !target_bd.addr_range().contains_range(array.addr_range()) {
+                bail!(
+                    "annotated type '{ty}' at {addr:#06X} is out of bank '{}'",
+                    target_bd.name
+                );
+            }
+            annotated_arrays.push(array);
+        }
+
+        let mut builder = InputBuilder::new()
             .memory(memory)
             .permissions(perms)
             .cdl(cdl)
-            .target_bank_addr(target_bank_addr)
+            .target_bank_addr(target_bd.start);
+        if !target_bd.fixed {
+            builder = builder.target_bank_number(self.bank_number(target_bd));
+        }
+        if let Some(mapper) = self.mapper {
+            builder = builder.mapper(mapper);
+        }
+        builder = builder.signatures(signatures);
+        if let Some(char_table) = char_table {
+            builder = builder.char_table(char_table);
+        }
+        for (range, char_table) in char_table_ranges {
+            builder = builder.char_table_range(range, char_table);
+        }
+        for ir in target_bd.incbin_ranges.iter() {
+            builder = builder.incbin_range(ir.addr_range(), ir.incbin);
+        }
+        for sa in target_bd.struct_arrays.iter() {
+            let Some(sd) = self.struct_descs.0.iter().find(|sd| sd.name == sa.ty) else {
+                bail!("struct '{}' not found (bank '{}')", sa.ty, target_bd.name);
+            };
+            let ty = Arc::new(StructType::new(&sd.name, sd.fields.clone())?);
+            let array = StructArray::new(sa.start, ty, sa.count)?;
+            if !target_bd.addr_range().contains_range(array.addr_range()) {
+                bail!(
+                    "struct array '{}' (start={:#X}, count={}) is out of bank '{}'",
+                    sa.ty,
+                    sa.start,
+                    sa.count,
+                    target_bd.name
+                );
+            }
+            // 注釈によるデータ型指定と重なる場合は注釈を優先する。
+            if let Some(other) = annotated_arrays
+                .iter()
+                .find(|other| other.addr_range().intersects(array.addr_range()))
+            {
+                warn!(
+                    "struct array '{}' (start={:#X}) is overridden by annotated type at {:#06X}",
+                    sa.ty,
+                    sa.start,
+                    other.addr()
+                );
+                continue;
+            }
+            builder = builder.struct_array(array);
+        }
+        for array in annotated_arrays {
+            builder = builder.struct_array(array);
+        }
+        for st in target_bd.split_tables.iter() {
+            let table = SplitTable::new(st.lo, st.hi, st.count)?;
+            if !(target_bd.addr_range().contains_range(table.lo_range())
+                && target_bd.addr_range().contains_range(table.hi_range()))
+            {
+                bail!(
+                    "split table (lo={:#X}, hi={:#X}, count={}) is out of bank '{}'",
+                    st.lo,
+                    st.hi,
+                    st.count,
+                    target_bd.name
+                );
+            }
+            builder = builder.split_table(table);
+        }
+        let input = builder
+            .annotations(annotations)
             .target_bank_name(target_bank_name)
             .build()?;
 
-        Ok((input, self.config))
+        Ok((input, self.config.clone()))
+    }
+
+    /// 注釈で指定された型名に対応する構造体型を返す。
+    ///
+    /// `byte`, `word`, `ptr` は同名のフィールド 1 つからなる構造体型とし、
+    /// それ以外はマニフェストで定義された構造体型とする。
+    fn annotated_struct_type(&self, name: &str) -> anyhow::Result<Arc<StructType>> {
+        let field_ty = match name {
+            "byte" => Some(FieldType::Byte),
+            "word" => Some(FieldType::Word),
+            "ptr" => Some(FieldType::Ptr),
+            _ => None,
+        };
+        if let Some(field_ty) = field_ty {
+            return Ok(Arc::new(StructType::new(
+                name,
+                [StructField::new(name, field_ty)],
+            )?));
+        }
+
+        let Some(sd) = self.struct_descs.0.iter().find(|sd| sd.name == name) else {
+            bail!("struct '{name}' not found");
+        };
+
+        Ok(Arc::new(StructType::new(&sd.name, sd.fields.clone())?))
+    }
+}
+
+/// 逆アセンブル対象バンクについてファイルから読み込んだ、注釈に依存しないデータ。
+///
+/// ROM, CDL, トレースログ, シグネチャ, 文字テーブルの内容を保持する。
+/// `Manifest::load_bank_data` で作り、`Manifest::input_config_with` に渡して使う。
+#[derive(Clone, Debug)]
+pub struct BankData {
+    target_bank_name: String,
+    perms: Permissions,
+    memory: Memory,
+    cdl: Cdl,
+    signatures: Vec<Signature>,
+    char_table: Option<CharTable>,
+    char_table_ranges: Vec<(AddressRange, CharTable)>,
+}
+
+impl BankData {
+    /// 逆アセンブル対象バンクの名前を返す。
+    pub fn target_bank_name(&self) -> &str {
+        &self.target_bank_name
+    }
+}
+
+/// 各構造体型の構成。
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, remote = "Self")]
+struct StructDescs(Vec<StructDesc>);
+
+impl<'de> Deserialize<'de> for StructDescs {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let this = Self::deserialize(deserializer)?;
+
+        // 各構造体型は一意な名前と 1 つ以上のフィールドを持たねばならない。
+        for pair in this.0.iter().combinations(2) {
+            let (lhs, rhs) = (pair[0], pair[1]);
+
+            if lhs.name == rhs.name {
+                return Err(D::Error::custom(format!(
+                    "duplicated struct name: '{}'",
+                    lhs.name
+                )));
+            }
+        }
+        for sd in this.0.iter() {
+            if sd.fields.is_empty() {
+                return Err(D::Error::custom(format!(
+                    "struct '{}' has no fields",
+                    sd.name
+                )));
+            }
+        }
+
+        Ok(this)
     }
 }
 
+/// 1 つの構造体型の構成。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructDesc {
+    /// 構造体名。
+    name: String,
+
+    /// フィールドたち (`{ name = "x", type = "byte" }` の形。型は `byte`, `word`, `ptr` のいずれか)。
+    fields: Vec<StructField>,
+}
+
 /// 各メモリ領域の構成。
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields, remote = "Self")]
@@ -255,6 +660,31 @@ struct BankDesc {
     /// `true` の場合、他のバンクを逆アセンブルする際にもロードされ、解析に利用される。
     #[serde(default)]
     fixed: bool,
+
+    /// マッパー上のバンク番号 (非固定バンクのみ意味を持つ)。
+    ///
+    /// 省略時は同じ開始アドレスを持つ非固定バンクたちの中での定義順とみなす。
+    /// バンク切り替えを伴う呼び出しの行き先を推定するのに使われる。
+    number: Option<usize>,
+
+    /// このバンクのテキスト検出に使う文字テーブル。省略時はトップレベルの `text_table` を使う。
+    text_table: Option<PathBuf>,
+
+    /// アドレス範囲ごとのテキスト検出に使う文字テーブル。
+    #[serde(default)]
+    text_ranges: Vec<TextRange>,
+
+    /// アドレス範囲ごとに、データを `.incbin` で出力するかどうかの指定。
+    #[serde(default)]
+    incbin_ranges: Vec<IncbinRange>,
+
+    /// 構造体の配列たち。
+    #[serde(default)]
+    struct_arrays: Vec<StructArrayDesc>,
+
+    /// 分割ポインタテーブルたち。
+    #[serde(default)]
+    split_tables: Vec<SplitTableDesc>,
 }
 
 impl<'de> Deserialize<'de> for BankDesc {
@@ -273,6 +703,28 @@ impl<'de> Deserialize<'de> for BankDesc {
             )));
         }
 
+        // テキスト範囲、incbin 範囲はバンク内に収まらなければならない。
+        let ranges = std::iter::empty()
+            .chain(this.text_ranges.iter().map(|tr| ("text", tr.start, tr.len)))
+            .chain(
+                this.incbin_ranges
+                    .iter()
+                    .map(|ir| ("incbin", ir.start, ir.len)),
+            );
+        for (kind, start, len) in ranges {
+            let inside = start
+                .checked_add_unsigned(len.get() - 1)
+                .is_some_and(|max| {
+                    this.addr_range().contains_addr(start) && this.addr_range().contains_addr(max)
+                });
+            if !inside {
+                return Err(D::Error::custom(format!(
+                    "{kind} range (start={start:#X}, len={len:#X}) is out of bank '{}'",
+                    this.name
+                )));
+            }
+        }
+
         Ok(this)
     }
 }
@@ -283,6 +735,99 @@ impl BankDesc {
     }
 }
 
+/// アドレス範囲ごとの文字テーブルの指定。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextRange {
+    /// 開始アドレス。
+    #[serde(deserialize_with = "deserialize_addr")]
+    start: Address,
+
+    /// バイト数。
+    len: NonZeroUsize,
+
+    /// TBL ファイルのパス、または `"ascii"`。
+    table: PathBuf,
+}
+
+impl TextRange {
+    fn addr_range(&self) -> AddressRange {
+        AddressRange::from_start_len(self.start, self.len)
+    }
+}
+
+/// アドレス範囲ごとの `.incbin` 出力の指定。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncbinRange {
+    /// 開始アドレス。
+    #[serde(deserialize_with = "deserialize_addr")]
+    start: Address,
+
+    /// バイト数。
+    len: NonZeroUsize,
+
+    /// 範囲内のデータを (`incbin_threshold` によらず) `.incbin` で出力するかどうか。
+    incbin: bool,
+}
+
+impl IncbinRange {
+    fn addr_range(&self) -> AddressRange {
+        AddressRange::from_start_len(self.start, self.len)
+    }
+}
+
+/// 構造体の配列の指定。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructArrayDesc {
+    /// 開始アドレス。
+    #[serde(deserialize_with = "deserialize_addr")]
+    start: Address,
+
+    /// 要素の構造体名。
+    #[serde(rename = "struct")]
+    ty: String,
+
+    /// 要素数。デフォルトは `1`。
+    #[serde(default = "StructArrayDesc::default_count")]
+    count: NonZeroUsize,
+}
+
+impl StructArrayDesc {
+    fn default_count() -> NonZeroUsize {
+        NonZeroUsize::MIN
+    }
+}
+
+/// 分割ポインタテーブルの指定。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SplitTableDesc {
+    /// 下位バイト配列の開始アドレス。
+    #[serde(deserialize_with = "deserialize_addr")]
+    lo: Address,
+
+    /// 上位バイト配列の開始アドレス。
+    #[serde(deserialize_with = "deserialize_addr")]
+    hi: Address,
+
+    /// 要素数。
+    count: NonZeroUsize,
+}
+
+/// 文字テーブルを読み込む。パスが `"ascii"` ならば ASCII の文字テーブルを返す。
+fn load_char_table(path: &Path) -> anyhow::Result<CharTable> {
+    if path == Path::new("ascii") {
+        return Ok(CharTable::ascii());
+    }
+
+    let tbl = std::fs::read_to_string(path)
+        .with_context(|| format!("can't read TBL '{}'", path.display()))?;
+
+    CharTable::from_tbl(tbl).with_context(|| format!("can't parse TBL '{}'", path.display()))
+}
+
 fn deserialize_addr<'de, D>(deserializer: D) -> Result<Address, D::Error>
 where
     D: Deserializer<'de>,
@@ -0,0 +1,17 @@
+use serde::Deserialize;
+
+/// バンク切り替え解析で認識するマッパー。
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mapper {
+    /// UxROM (mapper 2)。$8000-$FFFF への書き込み値が $8000 からの 16KB バンク番号となる。
+    UxRom,
+    /// MMC1 (mapper 1)。$8000-$FFFF への 5 回のシリアル書き込みでレジスタを設定する。
+    ///
+    /// PRG バンクモードは常に 3 ($8000 からの 16KB を切り替え、$C000 は固定) とみなす。
+    Mmc1,
+    /// MMC3 (mapper 4)。$8000 (偶数アドレス) でレジスタを選択し、$8001 (奇数アドレス) で値を設定する。
+    ///
+    /// R6, R7 がそれぞれ 8KB バンクを切り替える。
+    Mmc3,
+}
@@ -1627,8 +1627,9 @@ mod tests {
 
     #[test]
     fn test_operand() {
-        assert_equal(Operand::Imp.to_bytes(), []);
-        assert_equal(Operand::Acc.to_bytes(), []);
+        // serde_json の `PartialEq<Value>` 実装があるため、空配列の要素型を明示する必要がある。
+        assert_equal(Operand::Imp.to_bytes(), [0_u8; 0]);
+        assert_equal(Operand::Acc.to_bytes(), [0_u8; 0]);
         assert_equal(Operand::Zp(ZpAddress::new(0xFF)).to_bytes(), [0xFF]);
         assert_equal(Operand::ZpX(ZpAddress::new(0xFF)).to_bytes(), [0xFF]);
         assert_equal(Operand::ZpY(ZpAddress::new(0xFF)).to_bytes(), [0xFF]);
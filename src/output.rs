@@ -1,18 +1,162 @@
 use std::fmt::{Display, Formatter};
 use std::io::Write;
 
+use itertools::Itertools as _;
+
 use crate::address::{Address, ZpAddress};
-use crate::assembly::{Assembly, Label, Labels, Statement};
+use crate::assembly::{Assembly, ImmAddr, Label, Labels, Record, Routine, Statement, Text};
+use crate::config::OutputConfig;
+use crate::layout::FieldType;
 use crate::op::{Op, Operand};
+use crate::text::{CharKind, Charmap};
 
 /// ca65 用のアセンブリを出力する。
-pub fn output_assembly<W: Write>(wtr: &mut W, asm: &Assembly) -> anyhow::Result<()> {
+///
+/// `.incbin` で参照するバイナリファイルは出力しないので、必要に応じて `binary_files` で得たものを書き出すこと。
+pub fn output_assembly<W: Write>(
+    wtr: &mut W,
+    asm: &Assembly,
+    config: &OutputConfig,
+) -> anyhow::Result<()> {
     out_preamble(wtr, asm)?;
-    out_statements(wtr, asm)?;
+    out_statements(asm, config, |_, buf| {
+        wtr.write_all(buf)?;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// `output_assembly` が出力する文の部分 (前置きを除く) の各行を、その行が属する文のアドレスと組にして返す。
+///
+/// 連続する `Byte` をまとめた行については、行の先頭バイトのアドレスとなる。
+/// 文の前に置かれる空行、ラベル、コメントなども、その文のアドレスを持つ。
+pub fn assembly_lines(
+    asm: &Assembly,
+    config: &OutputConfig,
+) -> anyhow::Result<Vec<(Address, String)>> {
+    let mut res = Vec::<(Address, String)>::new();
+
+    out_statements(asm, config, |addr, buf| {
+        for line in std::str::from_utf8(buf)?.lines() {
+            res.push((addr, line.to_owned()));
+        }
+        Ok(())
+    })?;
+
+    Ok(res)
+}
+
+/// `addr` を含む文から最大 `count` 個の文を、各行の先頭に文のアドレスを付けて出力する (対話的な表示用)。
+///
+/// `addr` を含む文がなければ何も出力しない。
+pub fn output_listing<W: Write>(
+    wtr: &mut W,
+    asm: &Assembly,
+    config: &OutputConfig,
+    addr: Address,
+    count: usize,
+) -> anyhow::Result<()> {
+    let mut stmt_addr = asm.bank_addr();
+    let mut printed = 0;
+    for stmt in asm.statements() {
+        if printed == count {
+            break;
+        }
+
+        let Some(addr_nxt) = stmt_addr.checked_add_unsigned(stmt.len()) else {
+            break;
+        };
+        if printed > 0 || addr < addr_nxt {
+            let mut buf = Vec::<u8>::new();
+            out_statement(&mut buf, asm, config, stmt_addr, stmt)?;
+            // 文の本体 (インデントされた行) にのみアドレスを付ける。
+            for line in String::from_utf8(buf)?.lines() {
+                if line.starts_with(' ') {
+                    writeln!(wtr, "{:04X}  {line}", stmt_addr.get())?;
+                } else {
+                    writeln!(wtr, "      {line}")?;
+                }
+            }
+            printed += 1;
+        }
+
+        stmt_addr = addr_nxt;
+    }
 
     Ok(())
 }
 
+/// `.incbin` で参照されるバイナリファイル。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BinaryFile {
+    name: String,
+    body: Vec<u8>,
+}
+
+impl BinaryFile {
+    /// ファイル名を返す。
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// ファイルの内容を返す。
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+/// `output_assembly` が出力するアセンブリから `.incbin` で参照されるバイナリファイルたちを返す。
+///
+/// `Statement::Blob` があれば、バンク全体の内容を持つファイルを含む。
+pub fn binary_files(asm: &Assembly, config: &OutputConfig) -> Vec<BinaryFile> {
+    let mut files = Vec::<BinaryFile>::new();
+
+    if asm
+        .statements()
+        .iter()
+        .any(|stmt| matches!(stmt, Statement::Blob(_)))
+    {
+        files.push(BinaryFile {
+            name: bank_file_name(asm),
+            body: asm
+                .statements()
+                .iter()
+                .flat_map(Statement::to_bytes)
+                .collect(),
+        });
+    }
+
+    let mut addr = asm.bank_addr();
+    for stmt in asm.statements() {
+        if let Statement::Dpcm(ref buf) = *stmt {
+            if config.dpcm_incbin() {
+                files.push(BinaryFile {
+                    name: dpcm_file_name(asm, addr),
+                    body: buf.clone(),
+                });
+            }
+        }
+
+        let Some(addr_nxt) = addr.checked_add_unsigned(stmt.len()) else {
+            break;
+        };
+        addr = addr_nxt;
+    }
+
+    files
+}
+
+/// バンク全体の内容を持つファイルのファイル名を返す。
+fn bank_file_name(asm: &Assembly) -> String {
+    format!("{}.bin", asm.bank_name())
+}
+
+/// 指定したアドレスから始まる DPCM サンプルのファイル名を返す。
+fn dpcm_file_name(asm: &Assembly, addr: Address) -> String {
+    format!("{}_{:04X}.dmc", asm.bank_name(), addr.get())
+}
+
 /// アセンブリ先頭の `.segment` 宣言やラベル定義などを出力する。
 fn out_preamble<W: Write>(wtr: &mut W, asm: &Assembly) -> anyhow::Result<()> {
     // .segment 宣言。
@@ -32,7 +176,12 @@ fn out_preamble<W: Write>(wtr: &mut W, asm: &Assembly) -> anyhow::Result<()> {
     for addr in Address::all() {
         if asm.labels().get(addr).is_some() && !asm.bank_addr_range().contains_addr(addr) {
             defined_label = true;
-            writeln!(wtr, "{} := {}", LabelAddr(addr), HexAddr(addr))?;
+            writeln!(
+                wtr,
+                "{} := {}",
+                LabelAddr::new(asm.labels(), addr),
+                HexAddr(addr)
+            )?;
         }
     }
     if defined_label {
@@ -43,26 +192,90 @@ fn out_preamble<W: Write>(wtr: &mut W, asm: &Assembly) -> anyhow::Result<()> {
 }
 
 /// アセンブリの文たちを出力する。
-fn out_statements<W: Write>(wtr: &mut W, asm: &Assembly) -> anyhow::Result<()> {
+///
+/// 1 つの文 (連続する `Byte` の場合は 1 行) ごとに、そのアドレスと出力内容を `emit` に渡す。
+fn out_statements(
+    asm: &Assembly,
+    config: &OutputConfig,
+    mut emit: impl FnMut(Address, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
     let mut addr = asm.bank_addr();
+    let mut addr_pre = addr;
     let mut stmt_pre: Option<Statement> = None;
+    let mut charmap = Charmap::default();
+
+    let stmts = asm.statements();
+    let mut i = 0;
+    while i < stmts.len() {
+        let stmt = &stmts[i];
+        let mut buf = Vec::<u8>::new();
+        let wtr = &mut buf;
 
-    for stmt in asm.statements() {
         // 必要に応じて空行を挿入。
-        if stmt_pre.as_ref().map_or(false, |stmt_pre| {
-            needs_blank_line(asm.labels(), addr, stmt_pre, stmt)
-        }) {
+        if stmt_pre
+            .as_ref()
+            .is_some_and(|stmt_pre| needs_blank_line(asm, addr_pre, addr, stmt_pre, stmt))
+        {
             writeln!(wtr)?;
         }
 
-        out_statement(wtr, asm, addr, stmt)?;
+        // テキストの文字テーブルが変わるなら `.charmap` を切り替える。
+        if let Statement::Text(ref text) = *stmt {
+            out_charmap(wtr, &charmap, text.charmap())?;
+            charmap = *text.charmap();
+        }
+
+        // DPCM サンプルは 64 バイト境界に置く必要がある。
+        // `.align` は境界がずれた場合にパディングを挿入して後続の配置を変えてしまうので、
+        // 境界上にあることを `.assert` で検査する (再アセンブル時にサンプルがずれればリンクエラーとなる)。
+        // 境界上にないサンプル (CDL 由来の断片など) は元々そのアドレスから再生されないので検査しない。
+        if matches!(stmt, Statement::Dpcm(_)) && addr.get().is_multiple_of(64) {
+            writeln!(
+                wtr,
+                r#"        .assert (* & $3F) = 0, error, "DPCM sample must be 64-byte aligned""#
+            )?;
+        }
 
-        let Some(addr_nxt) = addr.checked_add_unsigned(stmt.len()) else {
+        // 連続する Byte は 1 行にまとめる。ただしラベルおよびコメントの位置で行を区切る。
+        let row_len = if matches!(stmt, Statement::Byte(_)) {
+            let row_len = stmts[i..]
+                .iter()
+                .take(config.bytes_per_line().max(1))
+                .enumerate()
+                .take_while(|&(j, stmt)| {
+                    matches!(stmt, Statement::Byte(_))
+                        && (j == 0
+                            || addr.checked_add_unsigned(j).is_some_and(|addr| {
+                                asm.labels().get(addr).is_none() && asm.find_comment(addr).is_none()
+                            }))
+                })
+                .count();
+            let row: Vec<u8> = stmts[i..i + row_len]
+                .iter()
+                .flat_map(Statement::to_bytes)
+                .collect();
+            out_labels(wtr, asm, addr, 1)?;
+            out_byte_rows(wtr, config, addr, &row)?;
+            row_len
+        } else {
+            out_statement(wtr, asm, config, addr, stmt)?;
+            1
+        };
+        emit(addr, &buf)?;
+
+        let row = &stmts[i..i + row_len];
+        i += row_len;
+
+        // 行の最後の文を直前の文とする。
+        let (last, init) = row.split_last().unwrap();
+        addr_pre =
+            addr.wrapping_add_unsigned(init.iter().map(|stmt| stmt.len().get()).sum::<usize>());
+        let Some(addr_nxt) = addr_pre.checked_add_unsigned(last.len()) else {
             break;
         };
 
         addr = addr_nxt;
-        stmt_pre = Some(stmt.clone());
+        stmt_pre = Some(last.clone());
     }
 
     Ok(())
@@ -71,36 +284,117 @@ fn out_statements<W: Write>(wtr: &mut W, asm: &Assembly) -> anyhow::Result<()> {
 fn out_statement<W: Write>(
     wtr: &mut W,
     asm: &Assembly,
+    config: &OutputConfig,
     addr: Address,
     stmt: &Statement,
+) -> anyhow::Result<()> {
+    out_labels(wtr, asm, addr, stmt.len().get())?;
+
+    match *stmt {
+        Statement::Op(op) => out_op(wtr, asm, addr, op)?,
+        Statement::IncompleteOp(ref buf) => out_incomplete_op(wtr, buf)?,
+        Statement::Byte(b) => out_byte(wtr, b)?,
+        Statement::Text(ref text) => out_text(wtr, text)?,
+        Statement::Dpcm(ref buf) => {
+            if config.dpcm_incbin() {
+                writeln!(wtr, r#"        .incbin "{}""#, dpcm_file_name(asm, addr))?;
+            } else {
+                out_byte_rows(wtr, config, addr, buf)?;
+            }
+        }
+        Statement::Blob(ref buf) => {
+            let offset = addr.get() - asm.bank_addr().get();
+            writeln!(
+                wtr,
+                r#"        .incbin "{}", ${offset:04X}, ${:04X}"#,
+                bank_file_name(asm),
+                buf.len()
+            )?;
+        }
+        Statement::Record(ref record) => out_record(wtr, asm, record)?,
+        Statement::LoBytes(ref addrs) => {
+            out_addr_rows(wtr, asm, config, addr, ".lobytes", addrs)?;
+        }
+        Statement::HiBytes(ref addrs) => {
+            out_addr_rows(wtr, asm, config, addr, ".hibytes", addrs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `addr` から始まる `len` バイトの文の前に置くラベル (およびエントリポイントのコメント欄、利用者によるコメント) を出力する。
+fn out_labels<W: Write>(
+    wtr: &mut W,
+    asm: &Assembly,
+    addr: Address,
+    len: usize,
 ) -> anyhow::Result<()> {
     // addr にエントリポイントラベルがあればコメント欄を挿入。
-    let entrypoint = asm.labels().get(addr).map_or(false, Label::is_entrypoint);
+    // ルーチン情報があればそれも出力する。
+    let entrypoint = asm.labels().get(addr).is_some_and(Label::is_entrypoint);
     if entrypoint {
         writeln!(wtr, ";;; ")?;
+        if let Some(routine) = asm.find_routine(addr) {
+            out_routine_header(wtr, asm, routine)?;
+        }
     }
 
     // stmt の範囲内いずれかにラベルがあれば addr にラベルを振る必要がある。
-    let need_label = (0..stmt.len().get()).any(|i| {
+    let need_label = (0..len).any(|i| {
         asm.labels()
             .get(addr.checked_add_unsigned(i).unwrap())
             .is_some()
     });
     if need_label {
-        writeln!(wtr, "{}:", LabelAddr(addr))?;
+        writeln!(wtr, "{}:", LabelAddr::new(asm.labels(), addr))?;
     }
     // stmt の途中 (addr 以外) にラベルがあるなら、addr のラベルからの相対位置として定義する。
-    for i in 1..stmt.len().get() {
+    for i in 1..len {
         let addr_mid = addr.checked_add_unsigned(i).unwrap();
         if asm.labels().get(addr_mid).is_some() {
-            writeln!(wtr, "{} := {} + {i}", LabelAddr(addr_mid), LabelAddr(addr))?;
+            writeln!(
+                wtr,
+                "{} := {} + {i}",
+                LabelAddr::new(asm.labels(), addr_mid),
+                LabelAddr::new(asm.labels(), addr)
+            )?;
         }
     }
 
-    match *stmt {
-        Statement::Op(op) => out_op(wtr, asm, addr, op)?,
-        Statement::IncompleteOp(ref buf) => out_incomplete_op(wtr, buf)?,
-        Statement::Byte(b) => out_byte(wtr, b)?,
+    // stmt の範囲内の利用者によるコメントを出力。
+    for i in 0..len {
+        let addr_cur = addr.checked_add_unsigned(i).unwrap();
+        if let Some(comment) = asm.find_comment(addr_cur) {
+            writeln!(wtr, "        ; {comment}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// ルーチンのヘッダコメント (サイズ、呼び出し元、呼び出し先、出口) を出力する。
+fn out_routine_header<W: Write>(
+    wtr: &mut W,
+    asm: &Assembly,
+    routine: &Routine,
+) -> anyhow::Result<()> {
+    let join = |addrs: &[Address]| {
+        addrs
+            .iter()
+            .map(|&addr| ResolveAddr::new(asm.labels(), addr).to_string())
+            .join(", ")
+    };
+
+    writeln!(wtr, ";;; size: {} bytes", routine.len())?;
+    if !routine.callers().is_empty() {
+        writeln!(wtr, ";;; callers: {}", join(routine.callers()))?;
+    }
+    if !routine.callees().is_empty() {
+        writeln!(wtr, ";;; callees: {}", join(routine.callees()))?;
+    }
+    if !routine.exits().is_empty() {
+        writeln!(wtr, ";;; exits: {}", join(routine.exits()))?;
     }
 
     Ok(())
@@ -108,7 +402,9 @@ fn out_statement<W: Write>(
 
 fn out_op<W: Write>(wtr: &mut W, asm: &Assembly, addr: Address, op: Op) -> anyhow::Result<()> {
     if op.is_official() {
-        writeln!(wtr, "        {}", FormatOp::new(asm.labels(), addr, op))?;
+        let format_op =
+            FormatOp::new(asm.labels(), addr, op).imm_addr(asm.find_imm_addr(addr).copied());
+        writeln!(wtr, "        {format_op}")?;
     } else {
         // 非公式命令の場合、ca65 でサポートされていないものもあるため、
         // コメントを付けた上で単なるバイト列として出力する。
@@ -137,9 +433,161 @@ fn out_byte<W: Write>(wtr: &mut W, b: u8) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// `addr` から始まるバイト列を 1 行 `bytes_per_line` バイトずつ出力する。
+/// 設定に応じて、各行の先頭バイトのアドレスをコメントとして付ける。
+fn out_byte_rows<W: Write>(
+    wtr: &mut W,
+    config: &OutputConfig,
+    addr: Address,
+    buf: &[u8],
+) -> anyhow::Result<()> {
+    for (i, row) in buf.chunks(config.bytes_per_line().max(1)).enumerate() {
+        let row_str = row.iter().map(|&b| HexU8(b)).join(", ");
+        if config.byte_addr_comment() {
+            let row_addr = addr.wrapping_add_unsigned(i * config.bytes_per_line().max(1));
+            writeln!(wtr, "        .byte   {row_str} ; ${:04X}", row_addr)?;
+        } else {
+            writeln!(wtr, "        .byte   {row_str}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// 分割ポインタテーブルの配列を、`.lobytes` または `.hibytes` で 1 行 `bytes_per_line` 要素ずつ出力する。
+/// 各要素は参照先にラベルがあればラベルで表す。
+fn out_addr_rows<W: Write>(
+    wtr: &mut W,
+    asm: &Assembly,
+    config: &OutputConfig,
+    addr: Address,
+    directive: &str,
+    addrs: &[Address],
+) -> anyhow::Result<()> {
+    for (i, row) in addrs.chunks(config.bytes_per_line().max(1)).enumerate() {
+        let row_str = row
+            .iter()
+            .map(|&dst| ResolveAddr::new(asm.labels(), dst))
+            .join(", ");
+        if config.byte_addr_comment() {
+            let row_addr = addr.wrapping_add_unsigned(i * config.bytes_per_line().max(1));
+            writeln!(wtr, "        {directive} {row_str} ; ${:04X}", row_addr)?;
+        } else {
+            writeln!(wtr, "        {directive} {row_str}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// 構造体の配列の 1 要素を 1 行で出力し、コメントとしてフィールド名を付ける。
+///
+/// 全フィールドが 2 バイトなら `.word` とし、さもなくば `.byte` とする
+/// (`.byte` の場合、2 バイトのフィールドは `<`, `>` で下位/上位バイトに分ける)。
+/// ポインタ型のフィールドは参照先にラベルがあればラベルで表す。
+fn out_record<W: Write>(wtr: &mut W, asm: &Assembly, record: &Record) -> anyhow::Result<()> {
+    let all_word = record
+        .ty()
+        .fields()
+        .iter()
+        .all(|field| field.ty().size() == 2);
+
+    let mut items = Vec::<String>::new();
+    for (_, field, value) in record.fields() {
+        let value_str = match field.ty() {
+            FieldType::Byte => HexU8(value as u8).to_string(),
+            FieldType::Word => format!("${value:04X}"),
+            FieldType::Ptr => ResolveAddr::new(asm.labels(), Address::new(value)).to_string(),
+        };
+        if field.ty() == FieldType::Byte || all_word {
+            items.push(value_str);
+        } else {
+            items.push(format!("<{value_str}"));
+            items.push(format!(">{value_str}"));
+        }
+    }
+
+    writeln!(
+        wtr,
+        "        {}   {} ; {}",
+        if all_word { ".word" } else { ".byte" },
+        items.join(", "),
+        record
+            .ty()
+            .fields()
+            .iter()
+            .map(|field| field.name())
+            .join(", ")
+    )?;
+
+    Ok(())
+}
+
+/// `.charmap` を `from` から `to` に切り替える。
+fn out_charmap<W: Write>(wtr: &mut W, from: &Charmap, to: &Charmap) -> anyhow::Result<()> {
+    for (c, b) in from.diff(to) {
+        writeln!(
+            wtr,
+            "        .charmap {}, {} ; '{}'",
+            HexU8(c),
+            HexU8(b),
+            char::from(c)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// テキストを出力する。
+///
+/// `.charmap` により 1 文字で表せる文字は文字列リテラルとし、それ以外はバイト値とする。
+/// バイト値で表した文字がある場合、コメントとして解釈したテキストを付ける。
+fn out_text<W: Write>(wtr: &mut W, text: &Text) -> anyhow::Result<()> {
+    let mut items = Vec::<String>::new();
+    let mut literal = String::new();
+    let mut needs_comment = false;
+
+    for ch in text.chars() {
+        let literal_char = match (ch.bytes(), ch.entry().text().as_bytes()) {
+            (&[b], &[c]) if ch.entry().kind() == CharKind::Normal => {
+                (Charmap::is_mappable(c) && text.charmap().get(c) == b).then_some(c)
+            }
+            _ => None,
+        };
+        if let Some(c) = literal_char {
+            literal.push(char::from(c));
+            continue;
+        }
+
+        if !literal.is_empty() {
+            items.push(format!(r#""{literal}""#));
+            literal.clear();
+        }
+        items.extend(ch.bytes().iter().map(|&b| HexU8(b).to_string()));
+        needs_comment |= ch.entry().kind() != CharKind::End || !ch.entry().text().is_empty();
+    }
+    if !literal.is_empty() {
+        items.push(format!(r#""{literal}""#));
+    }
+
+    if needs_comment {
+        writeln!(
+            wtr,
+            "        .byte   {} ; \"{}\"",
+            items.join(", "),
+            text.decode()
+        )?;
+    } else {
+        writeln!(wtr, "        .byte   {}", items.join(", "))?;
+    }
+
+    Ok(())
+}
+
 /// 2 つの文の間に空行を入れるべきかどうかを返す。
 fn needs_blank_line(
-    labels: &Labels,
+    asm: &Assembly,
+    addr_pre: Address,
     addr: Address,
     stmt_pre: &Statement,
     stmt: &Statement,
@@ -150,29 +598,60 @@ fn needs_blank_line(
         (
             Statement::Op(_) | Statement::IncompleteOp(_),
             Statement::Byte(_)
+                | Statement::Text(_)
+                | Statement::Dpcm(_)
+                | Statement::Blob(_)
+                | Statement::Record(_)
+                | Statement::LoBytes(_)
+                | Statement::HiBytes(_)
         ) | (
-            Statement::Byte(_),
+            Statement::Byte(_)
+                | Statement::Text(_)
+                | Statement::Dpcm(_)
+                | Statement::Blob(_)
+                | Statement::Record(_)
+                | Statement::LoBytes(_)
+                | Statement::HiBytes(_),
             Statement::Op(_) | Statement::IncompleteOp(_)
         )
     ) {
         return true;
     }
 
+    // 構造体の配列、分割ポインタテーブルの各配列の前後には空行を入れる。
+    if matches!(stmt_pre, Statement::Record(_)) != matches!(stmt, Statement::Record(_)) {
+        return true;
+    }
+    if matches!(stmt_pre, Statement::LoBytes(_) | Statement::HiBytes(_))
+        || matches!(stmt, Statement::LoBytes(_) | Statement::HiBytes(_))
+    {
+        return true;
+    }
+
+    // DPCM サンプルの前後には空行を入れる。
+    if matches!(stmt_pre, Statement::Dpcm(_)) || matches!(stmt, Statement::Dpcm(_)) {
+        return true;
+    }
+
     // 現在の文がエントリポイントなら空行を入れる。
-    if labels
+    if asm
+        .labels()
         .get(addr)
-        .map_or(false, |label| label.is_entrypoint())
+        .is_some_and(|label| label.is_entrypoint())
     {
         return true;
     }
 
-    // 直前の文がコード終端 (rti, rts, jmp) なら空行を入れる。
+    // 直前の文がコード終端 (rti, rts, jmp, 常に分岐する分岐命令) なら空行を入れる。
     if matches!(
         stmt_pre,
         Statement::Op(Op::Rti | Op::Rts | Op::JmpAbs(_) | Op::JmpInd(_))
     ) {
         return true;
     }
+    if asm.is_always_taken_branch(addr_pre) {
+        return true;
+    }
 
     false
 }
@@ -180,15 +659,28 @@ fn needs_blank_line(
 /// 命令を ca65 形式にフォーマットする。
 /// NOTE: 非公式命令の場合、必ずしも ca65 上で正しい表現になるとは限らない。
 #[derive(Debug)]
-struct FormatOp<'a> {
+pub(crate) struct FormatOp<'a> {
     labels: &'a Labels,
     addr: Address,
     op: Op,
+    imm_addr: Option<ImmAddr>,
 }
 
 impl<'a> FormatOp<'a> {
-    fn new(labels: &'a Labels, addr: Address, op: Op) -> Self {
-        Self { labels, addr, op }
+    pub(crate) fn new(labels: &'a Labels, addr: Address, op: Op) -> Self {
+        Self {
+            labels,
+            addr,
+            op,
+            imm_addr: None,
+        }
+    }
+
+    /// 即値オペランドがアドレスの一部と判明していれば指定する。
+    /// 指定した場合、即値を `<label`, `>label` の形でフォーマットする。
+    pub(crate) fn imm_addr(mut self, imm_addr: Option<ImmAddr>) -> Self {
+        self.imm_addr = imm_addr;
+        self
     }
 }
 
@@ -199,7 +691,16 @@ impl Display for FormatOp<'_> {
         match self.op.operand() {
             Operand::Imp => write!(f, "{mne}"),
             Operand::Acc => write!(f, "{mne}"),
-            Operand::Imm(imm) => write!(f, "{mne}     #{}", ResolveImm::new(self.op, imm)),
+            Operand::Imm(imm) => match self.imm_addr {
+                Some(imm_addr) => {
+                    write!(
+                        f,
+                        "{mne}     #{}",
+                        ResolveImmAddr::new(self.labels, imm_addr)
+                    )
+                }
+                None => write!(f, "{mne}     #{}", ResolveImm::new(self.op, imm)),
+            },
             Operand::Zp(zp) => write!(f, "{mne}     {}", ResolveZpAddr::new(self.labels, zp)),
             Operand::ZpX(zp) => write!(f, "{mne}     {},x", ResolveZpAddr::new(self.labels, zp)),
             Operand::ZpY(zp) => write!(f, "{mne}     {},y", ResolveZpAddr::new(self.labels, zp)),
@@ -228,13 +729,13 @@ impl Display for FormatOp<'_> {
 /// 対応するラベルがあればラベル文字列にし、さもなくば 16 進フォーマットする。
 /// 16 進フォーマット時の桁数はゼロページなら 2, さもなくば 4 とする。
 #[derive(Debug)]
-struct ResolveAddr<'a> {
+pub(crate) struct ResolveAddr<'a> {
     labels: &'a Labels,
     abs: Address,
 }
 
 impl<'a> ResolveAddr<'a> {
-    fn new(labels: &'a Labels, abs: Address) -> Self {
+    pub(crate) fn new(labels: &'a Labels, abs: Address) -> Self {
         Self { labels, abs }
     }
 }
@@ -242,7 +743,7 @@ impl<'a> ResolveAddr<'a> {
 impl Display for ResolveAddr<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if self.labels.get(self.abs).is_some() {
-            LabelAddr(self.abs).fmt(f)
+            LabelAddr::new(self.labels, self.abs).fmt(f)
         } else {
             HexAddr(self.abs).fmt(f)
         }
@@ -270,7 +771,7 @@ impl Display for ResolveAbsAddr<'_> {
         }
 
         if self.labels.get(self.abs).is_some() {
-            LabelAddr(self.abs).fmt(f)
+            LabelAddr::new(self.labels, self.abs).fmt(f)
         } else {
             HexAddr(self.abs).fmt(f)
         }
@@ -294,7 +795,7 @@ impl<'a> ResolveZpAddr<'a> {
 impl Display for ResolveZpAddr<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if self.labels.get(Address::from(self.zp)).is_some() {
-            LabelAddr(Address::from(self.zp)).fmt(f)
+            LabelAddr::new(self.labels, Address::from(self.zp)).fmt(f)
         } else {
             HexZpAddr(self.zp).fmt(f)
         }
@@ -332,13 +833,61 @@ impl Display for ResolveImm {
     }
 }
 
+/// アドレスの一部である即値を `<label`, `>label` の形で文字列化する。
+/// オフセットがあれば `<(label-1)` のようにする。
+#[derive(Debug)]
+struct ResolveImmAddr<'a> {
+    labels: &'a Labels,
+    imm_addr: ImmAddr,
+}
+
+impl<'a> ResolveImmAddr<'a> {
+    fn new(labels: &'a Labels, imm_addr: ImmAddr) -> Self {
+        Self { labels, imm_addr }
+    }
+}
+
+impl Display for ResolveImmAddr<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.imm_addr.is_hi() { ">" } else { "<" })?;
+
+        let dst = ResolveAddr::new(self.labels, self.imm_addr.dst());
+        match self.imm_addr.offset() {
+            0 => write!(f, "{dst}"),
+            offset => write!(f, "({dst}{offset:+})"),
+        }
+    }
+}
+
 /// 指定したアドレス用のラベル文字列を作る。
+/// 名前が付いていればそれを使う。ローカルラベルの場合、"@" プレフィックスを付ける。
 #[derive(Debug)]
-struct LabelAddr(Address);
+struct LabelAddr<'a> {
+    addr: Address,
+    local: bool,
+    name: Option<&'a str>,
+}
+
+impl<'a> LabelAddr<'a> {
+    fn new(labels: &'a Labels, addr: Address) -> Self {
+        let label = labels.get(addr);
+        let local = label.is_some_and(Label::is_local);
+        let name = label.and_then(Label::name);
 
-impl Display for LabelAddr {
+        Self { addr, local, name }
+    }
+}
+
+impl Display for LabelAddr<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "L_{:04X}", self.0)
+        if self.local {
+            f.write_str("@")?;
+        }
+
+        match self.name {
+            Some(name) => f.write_str(name),
+            None => write!(f, "L_{:04X}", self.addr),
+        }
     }
 }
 
@@ -376,3 +925,79 @@ impl Display for HexU8 {
         write!(f, "${:02X}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::address::AddressRange;
+    use crate::assembly::AssemblyBuilder;
+
+    use super::*;
+
+    /// $8000 から始まる 12 バイトのデータ ($00, $01, ...) からなり、$8005 にラベル、$8009 にコメントを持つアセンブリ。
+    fn make_data_assembly() -> Assembly {
+        let mut labels = Labels::default();
+        labels.set(Address::new(0x8005), Label::new(false));
+
+        AssemblyBuilder::new()
+            .bank_addr_range(AddressRange::from_min_max(
+                Address::new(0x8000),
+                Address::new(0x800B),
+            ))
+            .bank_name("PRG")
+            .statements((0..12).map(Statement::Byte).collect::<Vec<_>>())
+            .labels(labels)
+            .routines([])
+            .bank_switches([])
+            .always_taken_branches([])
+            .no_return_calls([])
+            .imm_addrs([])
+            .comments([(Address::new(0x8009), "comment".to_owned())])
+            .build()
+            .unwrap()
+    }
+
+    fn lines(asm: &Assembly, config_toml: &str) -> Vec<(u16, String)> {
+        let config: OutputConfig = toml::from_str(config_toml).unwrap();
+        assembly_lines(asm, &config)
+            .unwrap()
+            .into_iter()
+            .map(|(addr, line)| (addr.get(), line))
+            .collect()
+    }
+
+    #[test]
+    fn test_byte_rows() {
+        let asm = make_data_assembly();
+
+        // bytes_per_line ごとに行を区切り、さらにラベルおよびコメントの位置でも区切る。
+        assert_eq!(
+            lines(&asm, "bytes_per_line = 4"),
+            [
+                (0x8000, "        .byte   $00, $01, $02, $03".to_owned()),
+                (0x8004, "        .byte   $04".to_owned()),
+                (0x8005, "L_8005:".to_owned()),
+                (0x8005, "        .byte   $05, $06, $07, $08".to_owned()),
+                (0x8009, "        ; comment".to_owned()),
+                (0x8009, "        .byte   $09, $0A, $0B".to_owned()),
+            ]
+        );
+
+        // 行が長ければラベルおよびコメントの位置でのみ区切る。行の先頭アドレスをコメントとして付ける。
+        assert_eq!(
+            lines(&asm, "bytes_per_line = 16\nbyte_addr_comment = true"),
+            [
+                (
+                    0x8000,
+                    "        .byte   $00, $01, $02, $03, $04 ; $8000".to_owned()
+                ),
+                (0x8005, "L_8005:".to_owned()),
+                (
+                    0x8005,
+                    "        .byte   $05, $06, $07, $08 ; $8005".to_owned()
+                ),
+                (0x8009, "        ; comment".to_owned()),
+                (0x8009, "        .byte   $09, $0A, $0B ; $8009".to_owned()),
+            ]
+        );
+    }
+}
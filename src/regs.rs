@@ -0,0 +1,438 @@
+//! レジスタ値の追跡。
+//!
+//! A/X/Y レジスタおよび C/Z/N/V フラグについて、定数であることが判明している値を伝播させる簡易的な抽象解釈器。
+
+use std::collections::BTreeMap;
+
+use crate::address::Address;
+use crate::cfg::{Cfg, CfgDst, CfgEdgeKind};
+use crate::op::{Op, Operand};
+
+/// ある命令を実行する直前のレジスタ状態。各値は判明していなければ `None`。
+///
+/// デフォルト値は全ての値が不明な状態。
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RegState {
+    a: Option<u8>,
+    x: Option<u8>,
+    y: Option<u8>,
+    c: Option<bool>,
+    z: Option<bool>,
+    n: Option<bool>,
+    v: Option<bool>,
+}
+
+impl RegState {
+    pub fn a(self) -> Option<u8> {
+        self.a
+    }
+
+    pub fn x(self) -> Option<u8> {
+        self.x
+    }
+
+    pub fn y(self) -> Option<u8> {
+        self.y
+    }
+
+    pub fn c(self) -> Option<bool> {
+        self.c
+    }
+
+    pub fn z(self) -> Option<bool> {
+        self.z
+    }
+
+    pub fn n(self) -> Option<bool> {
+        self.n
+    }
+
+    pub fn v(self) -> Option<bool> {
+        self.v
+    }
+
+    /// 2 つの状態のうち、共通する値のみを残したものを返す。
+    /// (制御フローの合流点における状態)
+    pub fn meet(self, other: Self) -> Self {
+        fn meet<T: Eq>(lhs: Option<T>, rhs: Option<T>) -> Option<T> {
+            if lhs == rhs {
+                lhs
+            } else {
+                None
+            }
+        }
+
+        Self {
+            a: meet(self.a, other.a),
+            x: meet(self.x, other.x),
+            y: meet(self.y, other.y),
+            c: meet(self.c, other.c),
+            z: meet(self.z, other.z),
+            n: meet(self.n, other.n),
+            v: meet(self.v, other.v),
+        }
+    }
+
+    /// 命令を 1 つ実行した後の状態を返す。
+    ///
+    /// 制御フロー命令については、制御が次に移る先での状態を返す。
+    /// ただし jsr, rti, brk の場合は全ての値を不明とする。
+    /// 分岐結果による絞り込みは `refine_branch` で行う。
+    pub fn step(mut self, op: Op) -> Self {
+        match op {
+            Op::LdaImm(value) => self.a = self.set_nz(Some(value)),
+            Op::LdxImm(value) => self.x = self.set_nz(Some(value)),
+            Op::LdyImm(value) => self.y = self.set_nz(Some(value)),
+            Op::Tax => self.x = self.set_nz(self.a),
+            Op::Tay => self.y = self.set_nz(self.a),
+            Op::Txa => self.a = self.set_nz(self.x),
+            Op::Tya => self.a = self.set_nz(self.y),
+            Op::Inx => self.x = self.set_nz(self.x.map(|x| x.wrapping_add(1))),
+            Op::Dex => self.x = self.set_nz(self.x.map(|x| x.wrapping_sub(1))),
+            Op::Iny => self.y = self.set_nz(self.y.map(|y| y.wrapping_add(1))),
+            Op::Dey => self.y = self.set_nz(self.y.map(|y| y.wrapping_sub(1))),
+
+            // and #0, ora #$FF の結果は A によらない。
+            Op::AndImm(value) => {
+                let res = if value == 0 {
+                    Some(0)
+                } else {
+                    self.a.map(|a| a & value)
+                };
+                self.a = self.set_nz(res);
+            }
+            Op::OraImm(value) => {
+                let res = if value == 0xFF {
+                    Some(0xFF)
+                } else {
+                    self.a.map(|a| a | value)
+                };
+                self.a = self.set_nz(res);
+            }
+            Op::EorImm(value) => self.a = self.set_nz(self.a.map(|a| a ^ value)),
+
+            Op::AdcImm(value) => self.adc(value),
+            Op::SbcImmE9(value) | Op::SbcImmEB(value) => self.adc(!value),
+
+            Op::CmpImm(value) => self.compare(self.a, value),
+            Op::CpxImm(value) => self.compare(self.x, value),
+            Op::CpyImm(value) => self.compare(self.y, value),
+
+            Op::AslAcc => {
+                self.c = self.a.map(|a| a & 0x80 != 0);
+                self.a = self.set_nz(self.a.map(|a| a << 1));
+            }
+            Op::LsrAcc => {
+                self.c = self.a.map(|a| a & 1 != 0);
+                self.a = self.set_nz(self.a.map(|a| a >> 1));
+            }
+            Op::RolAcc => {
+                let res = self.a.zip(self.c).map(|(a, c)| (a << 1) | u8::from(c));
+                self.c = self.a.map(|a| a & 0x80 != 0);
+                self.a = self.set_nz(res);
+            }
+            Op::RorAcc => {
+                let res = self
+                    .a
+                    .zip(self.c)
+                    .map(|(a, c)| (a >> 1) | (u8::from(c) << 7));
+                self.c = self.a.map(|a| a & 1 != 0);
+                self.a = self.set_nz(res);
+            }
+
+            Op::Clc => self.c = Some(false),
+            Op::Sec => self.c = Some(true),
+            Op::Clv => self.v = Some(false),
+
+            Op::Jsr(_) | Op::Rti | Op::Brk => self = Self::default(),
+
+            _ => self.clobber(op),
+        }
+
+        self
+    }
+
+    /// 分岐命令 `op` の分岐結果が `taken` だったときの状態を返す。
+    /// `op` が分岐命令でなければそのまま返す。
+    pub fn refine_branch(mut self, op: Op, taken: bool) -> Self {
+        match op {
+            Op::Bpl(_) => self.n = Some(!taken),
+            Op::Bmi(_) => self.n = Some(taken),
+            Op::Bvc(_) => self.v = Some(!taken),
+            Op::Bvs(_) => self.v = Some(taken),
+            Op::Bcc(_) => self.c = Some(!taken),
+            Op::Bcs(_) => self.c = Some(taken),
+            Op::Bne(_) => self.z = Some(!taken),
+            Op::Beq(_) => self.z = Some(taken),
+            _ => {}
+        }
+
+        self
+    }
+
+    /// 分岐命令 `op` が必ず分岐するなら `Some(true)`、決して分岐しないなら `Some(false)` を返す。
+    /// 分岐結果が不明な場合、および `op` が分岐命令でない場合は `None` を返す。
+    pub fn branch_taken(self, op: Op) -> Option<bool> {
+        match op {
+            Op::Bpl(_) => self.n.map(|n| !n),
+            Op::Bmi(_) => self.n,
+            Op::Bvc(_) => self.v.map(|v| !v),
+            Op::Bvs(_) => self.v,
+            Op::Bcc(_) => self.c.map(|c| !c),
+            Op::Bcs(_) => self.c,
+            Op::Bne(_) => self.z.map(|z| !z),
+            Op::Beq(_) => self.z,
+            _ => None,
+        }
+    }
+
+    /// 値に応じて Z, N フラグを設定し、値をそのまま返す。
+    fn set_nz(&mut self, value: Option<u8>) -> Option<u8> {
+        self.z = value.map(|value| value == 0);
+        self.n = value.map(|value| value & 0x80 != 0);
+        value
+    }
+
+    /// adc 命令の動作 (sbc 命令はオペランドをビット反転した adc とみなせる)。
+    /// NES の CPU には 10 進モードがないので考慮しない。
+    fn adc(&mut self, value: u8) {
+        match self.a.zip(self.c) {
+            Some((a, c)) => {
+                let sum = u16::from(a) + u16::from(value) + u16::from(c);
+                let res = sum as u8;
+                self.c = Some(sum > 0xFF);
+                self.v = Some((a ^ res) & (value ^ res) & 0x80 != 0);
+                self.a = self.set_nz(Some(res));
+            }
+            None => {
+                self.c = None;
+                self.v = None;
+                self.a = self.set_nz(None);
+            }
+        }
+    }
+
+    /// cmp, cpx, cpy 命令の動作。
+    fn compare(&mut self, reg: Option<u8>, value: u8) {
+        self.c = reg.map(|reg| reg >= value);
+        self.set_nz(reg.map(|reg| reg.wrapping_sub(value)));
+    }
+
+    /// 個別に扱わない命令について、変化しうる値を全て不明とする。
+    fn clobber(&mut self, op: Op) {
+        let mnemonic = op.opcode().mnemonic();
+        let acc = matches!(op.operand(), Operand::Acc);
+
+        let writes_a = matches!(
+            mnemonic,
+            "lda"
+                | "lax"
+                | "pla"
+                | "txa"
+                | "tya"
+                | "adc"
+                | "sbc"
+                | "and"
+                | "ora"
+                | "eor"
+                | "slo"
+                | "rla"
+                | "sre"
+                | "rra"
+                | "isc"
+                | "anc"
+                | "alr"
+                | "arr"
+                | "xaa"
+                | "las"
+        ) || (acc && matches!(mnemonic, "asl" | "lsr" | "rol" | "ror"));
+        let writes_x = matches!(
+            mnemonic,
+            "ldx" | "lax" | "tax" | "tsx" | "inx" | "dex" | "axs" | "las"
+        );
+        let writes_y = matches!(mnemonic, "ldy" | "tay" | "iny" | "dey");
+
+        if writes_a {
+            self.a = None;
+        }
+        if writes_x {
+            self.x = None;
+        }
+        if writes_y {
+            self.y = None;
+        }
+
+        match mnemonic {
+            // フラグを変えない命令。
+            "sta" | "stx" | "sty" | "sax" | "ahx" | "tas" | "shx" | "shy" | "nop" | "pha"
+            | "php" | "txs" | "cli" | "sei" | "cld" | "sed" | "jmp" | "rts" | "kil" | "bpl"
+            | "bmi" | "bvc" | "bvs" | "bcc" | "bcs" | "bne" | "beq" => {}
+            // Z, N のみを変える命令。
+            "lda" | "ldx" | "ldy" | "lax" | "tax" | "tay" | "txa" | "tya" | "tsx" | "inx"
+            | "iny" | "dex" | "dey" | "inc" | "dec" | "and" | "ora" | "eor" | "pla" | "las"
+            | "xaa" => {
+                self.z = None;
+                self.n = None;
+            }
+            // Z, N, V を変える命令。
+            "bit" => {
+                self.z = None;
+                self.n = None;
+                self.v = None;
+            }
+            // C, Z, N を変える命令。
+            "asl" | "lsr" | "rol" | "ror" | "cmp" | "cpx" | "cpy" | "slo" | "sre" | "rla"
+            | "dcp" | "anc" | "alr" | "axs" => {
+                self.c = None;
+                self.z = None;
+                self.n = None;
+            }
+            // それ以外 (adc, sbc, rra, isc, arr, plp など) は全てのフラグを変えうる。
+            _ => {
+                self.c = None;
+                self.z = None;
+                self.n = None;
+                self.v = None;
+            }
+        }
+    }
+}
+
+/// 制御フローグラフ上で、各命令を実行する直前のレジスタ状態を求める。
+///
+/// エントリポイントでは全ての値を不明とし、基本ブロックをまたいで値を伝播させる。
+/// 分岐の辺ではその分岐結果に応じてフラグを絞り込む。
+/// jsr 命令の戻り先では、呼び出し先で値が変わりうるので全ての値を不明とする。
+pub fn track_registers(cfg: &Cfg) -> BTreeMap<Address, RegState> {
+    // 各基本ブロックの入口での状態。まだ到達していないブロックは値を持たない。
+    let mut block_ins = BTreeMap::<Address, RegState>::new();
+    block_ins.insert(cfg.entry(), RegState::default());
+
+    let mut stack = vec![cfg.entry()];
+    while let Some(addr) = stack.pop() {
+        let block = cfg
+            .blocks()
+            .binary_search_by_key(&addr, |block| block.addr())
+            .map(|i| &cfg.blocks()[i])
+            .unwrap();
+
+        let mut state = block_ins[&addr];
+        for &(_, op) in block.ops() {
+            if op.is_branch() {
+                break;
+            }
+            state = state.step(op);
+        }
+        let (_, op_last) = *block.ops().last().unwrap();
+
+        for edge in block.succs() {
+            let CfgDst::Block(dst) = edge.dst() else {
+                continue;
+            };
+
+            let state_dst = match edge.kind() {
+                CfgEdgeKind::Taken => state.refine_branch(op_last, true),
+                CfgEdgeKind::NotTaken => state.refine_branch(op_last, false),
+                CfgEdgeKind::Return => RegState::default(),
+                CfgEdgeKind::Normal | CfgEdgeKind::Jump => state,
+            };
+
+            let state_new = match block_ins.get(&dst) {
+                Some(&state_old) => state_old.meet(state_dst),
+                None => state_dst,
+            };
+            if block_ins.get(&dst) != Some(&state_new) {
+                block_ins.insert(dst, state_new);
+                stack.push(dst);
+            }
+        }
+    }
+
+    // 各ブロック内の命令ごとの状態を求める。
+    let mut states = BTreeMap::<Address, RegState>::new();
+    for block in cfg.blocks() {
+        let Some(&state) = block_ins.get(&block.addr()) else {
+            continue;
+        };
+        let mut state = state;
+        for &(addr, op) in block.ops() {
+            states.insert(addr, state);
+            state = state.step(op);
+        }
+    }
+
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ops: &[Op]) -> RegState {
+        ops.iter()
+            .fold(RegState::default(), |state, &op| state.step(op))
+    }
+
+    #[test]
+    fn test_load_transfer() {
+        let state = run(&[Op::LdaImm(0x80), Op::Tax, Op::Inx, Op::LdyImm(0)]);
+        assert_eq!(state.a(), Some(0x80));
+        assert_eq!(state.x(), Some(0x81));
+        assert_eq!(state.y(), Some(0));
+        assert_eq!(state.z(), Some(true));
+        assert_eq!(state.n(), Some(false));
+        assert_eq!(state.c(), None);
+    }
+
+    #[test]
+    fn test_arith() {
+        let state = run(&[Op::LdaImm(0xFF), Op::Clc, Op::AdcImm(1)]);
+        assert_eq!(state.a(), Some(0));
+        assert_eq!(state.c(), Some(true));
+        assert_eq!(state.z(), Some(true));
+        assert_eq!(state.v(), Some(false));
+
+        let state = run(&[Op::LdaImm(0x50), Op::Sec, Op::SbcImmE9(0xB0)]);
+        assert_eq!(state.a(), Some(0xA0));
+        assert_eq!(state.c(), Some(false));
+        assert_eq!(state.v(), Some(true));
+
+        let state = run(&[Op::LdaImm(3), Op::CmpImm(3)]);
+        assert_eq!(state.c(), Some(true));
+        assert_eq!(state.z(), Some(true));
+
+        // A が不明でも and #0 の結果は定まる。
+        let state = run(&[Op::AndImm(0)]);
+        assert_eq!(state.a(), Some(0));
+        assert_eq!(state.z(), Some(true));
+    }
+
+    #[test]
+    fn test_clobber() {
+        let state = run(&[
+            Op::LdaImm(1),
+            Op::LdxImm(2),
+            Op::Sec,
+            Op::LdaAbs(Address::new(0x0300)),
+        ]);
+        assert_eq!(state.a(), None);
+        assert_eq!(state.x(), Some(2));
+        assert_eq!(state.c(), Some(true));
+        assert_eq!(state.z(), None);
+
+        let state = run(&[Op::LdaImm(1), Op::Sec, Op::Jsr(Address::new(0x8000))]);
+        assert_eq!(state, RegState::default());
+    }
+
+    #[test]
+    fn test_branch() {
+        let state = run(&[Op::Clc]);
+        assert_eq!(state.branch_taken(Op::Bcs(0)), Some(false));
+        assert_eq!(state.branch_taken(Op::Bcc(0)), Some(true));
+        assert_eq!(state.branch_taken(Op::Beq(0)), None);
+        assert_eq!(state.branch_taken(Op::Nop1A), None);
+
+        let state = RegState::default().refine_branch(Op::Bne(0), false);
+        assert_eq!(state.branch_taken(Op::Beq(0)), Some(true));
+    }
+}
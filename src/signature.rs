@@ -0,0 +1,282 @@
+//! 既知のルーチンを認識するためのシグネチャ。
+//!
+//! シグネチャファイルは TOML で、以下のように書く:
+//!
+//! ```toml
+//! [[signatures]]
+//! name = "ReadJoypad"
+//! pattern = "A9 01 8D 16 40 85 ?? 4A 8D 16 40 AD 16 40 4A 26 ?? 90 F8 60"
+//! refs = [{ offset = 5, name = "joy1_state" }]
+//! ```
+//!
+//! `pattern` はルーチン先頭からの機械語で、`??` は任意のバイトにマッチする
+//! (再配置によって変わるオペランドに使う)。
+//! `refs` は、パターン先頭から `offset` バイト目の命令のオペランドが指すアドレスに付ける名前。
+
+use std::path::Path;
+
+use anyhow::{ensure, Context as _};
+use serde::Deserialize;
+
+use crate::annotation::is_identifier;
+
+/// 組み込みのシグネチャファイル。
+const BUILTIN_SIGNATURES: &str = include_str!("signatures.toml");
+
+/// 既知のルーチンのシグネチャ。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Signature {
+    name: String,
+    pattern: Vec<Option<u8>>,
+    refs: Vec<SignatureRef>,
+}
+
+impl Signature {
+    /// ルーチン名、パターン (`None` は任意のバイト)、参照先の名前たちを指定してシグネチャを作る。
+    ///
+    /// パターンが空の場合、先頭が任意のバイトの場合、名前が ca65 の識別子として不正な場合、
+    /// 参照のオフセットがパターン外の場合はエラーを返す。
+    pub fn new(
+        name: impl Into<String>,
+        pattern: impl Into<Vec<Option<u8>>>,
+        refs: impl Into<Vec<SignatureRef>>,
+    ) -> anyhow::Result<Self> {
+        let name = name.into();
+        let pattern = pattern.into();
+        let refs = refs.into();
+
+        ensure!(is_identifier(&name), "invalid routine name: '{name}'");
+        ensure!(!pattern.is_empty(), "signature '{name}': empty pattern");
+        ensure!(
+            pattern[0].is_some(),
+            "signature '{name}': pattern must not start with a wildcard"
+        );
+        for r in refs.iter() {
+            ensure!(
+                is_identifier(&r.name),
+                "signature '{name}': invalid ref name: '{}'",
+                r.name
+            );
+            ensure!(
+                r.offset < pattern.len(),
+                "signature '{name}': ref '{}' (offset={}) is out of pattern",
+                r.name,
+                r.offset
+            );
+        }
+
+        Ok(Self {
+            name,
+            pattern,
+            refs,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn pattern(&self) -> &[Option<u8>] {
+        &self.pattern
+    }
+
+    /// パターンの先頭バイト (任意のバイトではないことが保証される)。
+    pub fn first_byte(&self) -> u8 {
+        self.pattern[0].unwrap()
+    }
+
+    pub fn refs(&self) -> &[SignatureRef] {
+        &self.refs
+    }
+
+    /// 指定したバイト列の先頭がパターンにマッチするかどうかを返す。
+    pub fn matches(&self, buf: &[u8]) -> bool {
+        buf.len() >= self.pattern.len()
+            && self
+                .pattern
+                .iter()
+                .zip(buf)
+                .all(|(&pat, &b)| pat.is_none_or(|pat| pat == b))
+    }
+}
+
+/// シグネチャ内の命令のオペランドが指すアドレスに付ける名前。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SignatureRef {
+    offset: usize,
+    name: String,
+}
+
+impl SignatureRef {
+    /// パターン先頭から `offset` バイト目の命令のオペランドが指すアドレスに `name` を付ける。
+    pub fn new(offset: usize, name: impl Into<String>) -> Self {
+        Self {
+            offset,
+            name: name.into(),
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// シグネチャファイルをパースし、シグネチャたちを返す。
+pub fn parse_signatures(s: impl AsRef<str>) -> anyhow::Result<Vec<Signature>> {
+    let desc: SignatureFileDesc = toml::from_str(s.as_ref())?;
+
+    desc.signatures
+        .into_iter()
+        .map(|sd| {
+            let pattern = parse_pattern(&sd.pattern)
+                .with_context(|| format!("signature '{}': invalid pattern", sd.name))?;
+            let refs: Vec<_> = sd
+                .refs
+                .into_iter()
+                .map(|rd| SignatureRef::new(rd.offset, rd.name))
+                .collect();
+            Signature::new(sd.name, pattern, refs)
+        })
+        .collect()
+}
+
+/// 組み込みのシグネチャたちを返す。
+///
+/// ハードウェアのアクセス手順から形がほぼ決まる、定番のルーチンのみを含む。
+pub fn builtin_signatures() -> Vec<Signature> {
+    parse_signatures(BUILTIN_SIGNATURES).expect("builtin signatures should be valid")
+}
+
+/// 指定したディレクトリ内のシグネチャファイル (`*.toml`) を全て読み込む。
+///
+/// ファイルはファイル名の順に読み込まれる。
+pub fn load_signature_dir(dir: impl AsRef<Path>) -> anyhow::Result<Vec<Signature>> {
+    let dir = dir.as_ref();
+
+    let mut paths = Vec::new();
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("can't read signature directory '{}'", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            paths.push(path);
+        }
+    }
+    paths.sort_unstable();
+
+    let mut sigs = Vec::new();
+    for path in paths {
+        let s = std::fs::read_to_string(&path)
+            .with_context(|| format!("can't read signature file '{}'", path.display()))?;
+        let file_sigs = parse_signatures(s)
+            .with_context(|| format!("invalid signature file '{}'", path.display()))?;
+        sigs.extend(file_sigs);
+    }
+
+    Ok(sigs)
+}
+
+/// "A9 01 8D ?? ??" のようなパターンをパースする。
+fn parse_pattern(s: &str) -> anyhow::Result<Vec<Option<u8>>> {
+    s.split_whitespace()
+        .map(|tok| {
+            if tok == "??" {
+                return Ok(None);
+            }
+            ensure!(tok.len() == 2, "invalid byte: '{tok}'");
+            let b =
+                u8::from_str_radix(tok, 16).with_context(|| format!("invalid byte: '{tok}'"))?;
+            Ok(Some(b))
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureFileDesc {
+    #[serde(default)]
+    signatures: Vec<SignatureDesc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureDesc {
+    name: String,
+    pattern: String,
+    #[serde(default)]
+    refs: Vec<SignatureRefDesc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureRefDesc {
+    offset: usize,
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signatures() {
+        let sigs = parse_signatures(
+            r#"
+[[signatures]]
+name = "Foo"
+pattern = "A9 01 85 ?? 60"
+refs = [{ offset = 2, name = "foo_var" }]
+
+[[signatures]]
+name = "Bar"
+pattern = "60"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            sigs,
+            [
+                Signature::new(
+                    "Foo",
+                    [Some(0xA9), Some(0x01), Some(0x85), None, Some(0x60)],
+                    [SignatureRef::new(2, "foo_var")]
+                )
+                .unwrap(),
+                Signature::new("Bar", [Some(0x60)], []).unwrap(),
+            ]
+        );
+
+        // 不正なパターン。
+        assert!(parse_signatures("[[signatures]]\nname = \"X\"\npattern = \"\"").is_err());
+        assert!(parse_signatures("[[signatures]]\nname = \"X\"\npattern = \"?? 60\"").is_err());
+        assert!(parse_signatures("[[signatures]]\nname = \"X\"\npattern = \"A9 1\"").is_err());
+        assert!(parse_signatures("[[signatures]]\nname = \"X\"\npattern = \"GG\"").is_err());
+        // 不正な名前。
+        assert!(parse_signatures("[[signatures]]\nname = \"1X\"\npattern = \"60\"").is_err());
+        // パターン外の参照。
+        assert!(parse_signatures(
+            "[[signatures]]\nname = \"X\"\npattern = \"60\"\nrefs = [{ offset = 1, name = \"y\" }]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_signature_matches() {
+        let sig = Signature::new("Foo", [Some(0xA9), None, Some(0x60)], []).unwrap();
+
+        assert!(sig.matches(&[0xA9, 0x00, 0x60]));
+        assert!(sig.matches(&[0xA9, 0xFF, 0x60, 0xEA]));
+        assert!(!sig.matches(&[0xA9, 0x00, 0x61]));
+        assert!(!sig.matches(&[0xA9, 0x00]));
+    }
+
+    #[test]
+    fn test_builtin_signatures() {
+        assert!(!builtin_signatures().is_empty());
+    }
+}
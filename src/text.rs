@@ -0,0 +1,293 @@
+//! 文字テーブル。
+//!
+//! ゲーム固有の文字コードとテキストの対応を表す。
+//! ROM ハック界隈で使われる TBL ファイル (Thingy 形式) を読み込める。
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, ensure, Context as _};
+
+/// 文字テーブル。1 バイト以上のバイト列と `CharEntry` の対応。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CharTable {
+    entries: BTreeMap<Vec<u8>, CharEntry>,
+    max_key_len: usize,
+}
+
+impl CharTable {
+    /// ASCII の印字可能文字 ($20-$7E) と、終端文字 $00 からなる文字テーブルを返す。
+    pub fn ascii() -> Self {
+        let mut entries: BTreeMap<_, _> = (0x20..=0x7E)
+            .map(|b: u8| (vec![b], CharEntry::new(char::from(b), CharKind::Normal)))
+            .collect();
+        entries.insert(vec![0], CharEntry::new("", CharKind::End));
+
+        Self {
+            entries,
+            max_key_len: 1,
+        }
+    }
+
+    /// TBL ファイルの内容をパースする。
+    ///
+    /// 以下の形式の行を解釈する (16 進数部分は 2 桁単位で任意のバイト数):
+    ///
+    /// * `XX=文字列`: 通常の文字。文字列が `[...]` の形ならば制御コードとみなす。
+    /// * `$XX=文字列`: 制御コード。
+    /// * `/XX` または `/XX=文字列`: 文字列の終端。
+    /// * `*XX` または `*XX=文字列`: 改行。
+    ///
+    /// 空行、およびテーブル切り替え (`!`, `@` で始まる行) は無視する。
+    pub fn from_tbl(s: impl AsRef<str>) -> anyhow::Result<Self> {
+        let mut entries = BTreeMap::<Vec<u8>, CharEntry>::new();
+
+        for (i, line) in s.as_ref().lines().enumerate() {
+            let line = line.trim_start_matches('\u{FEFF}').trim_end_matches('\r');
+            if line.is_empty() || line.starts_with(['!', '@']) {
+                continue;
+            }
+
+            let (key, entry) =
+                parse_tbl_line(line).with_context(|| format!("TBL line {}: '{line}'", i + 1))?;
+            entries.insert(key, entry);
+        }
+
+        ensure!(!entries.is_empty(), "TBL has no entries");
+
+        let max_key_len = entries.keys().map(Vec::len).max().unwrap();
+
+        Ok(Self {
+            entries,
+            max_key_len,
+        })
+    }
+
+    /// `buf` の先頭に最長一致するエントリを探し、(キーのバイト数, エントリ) を返す。
+    pub fn decode_one(&self, buf: &[u8]) -> Option<(usize, &CharEntry)> {
+        (1..=self.max_key_len.min(buf.len()))
+            .rev()
+            .find_map(|len| self.entries.get(&buf[..len]).map(|entry| (len, entry)))
+    }
+
+    /// この文字テーブルに対応する ca65 の `.charmap` を返す。
+    ///
+    /// 1 バイトのキーを持ち、テキストが ASCII の 1 文字である通常の文字のみを対象とする。
+    /// 同じ文字に対応するキーが複数あれば、最小のキーを採用する。
+    pub fn charmap(&self) -> Charmap {
+        let mut charmap = Charmap::default();
+        let mut mapped = [false; 0x100];
+
+        for (key, entry) in self.entries() {
+            let (&[code], &[c]) = (key, entry.text().as_bytes()) else {
+                continue;
+            };
+            if entry.kind() == CharKind::Normal
+                && Charmap::is_mappable(c)
+                && !mapped[usize::from(c)]
+            {
+                charmap.0[usize::from(c)] = code;
+                mapped[usize::from(c)] = true;
+            }
+        }
+
+        charmap
+    }
+
+    /// 全エントリを (キー, エントリ) の形でキーの昇順に返す。
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], &CharEntry)> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| (key.as_slice(), entry))
+    }
+}
+
+fn parse_tbl_line(line: &str) -> anyhow::Result<(Vec<u8>, CharEntry)> {
+    let (kind, line) = match line.as_bytes()[0] {
+        b'$' => (Some(CharKind::Control), &line[1..]),
+        b'/' => (Some(CharKind::End), &line[1..]),
+        b'*' => (Some(CharKind::Newline), &line[1..]),
+        _ => (None, line),
+    };
+
+    let (hex, text) = match line.split_once('=') {
+        Some((hex, text)) => (hex, Some(text)),
+        None => (line, None),
+    };
+
+    let key = parse_hex_bytes(hex)?;
+
+    let entry = match (kind, text) {
+        (Some(kind), text) => CharEntry::new(text.unwrap_or_default(), kind),
+        (None, Some(text)) if text.starts_with('[') && text.ends_with(']') => {
+            CharEntry::new(text, CharKind::Control)
+        }
+        (None, Some(text)) if !text.is_empty() => CharEntry::new(text, CharKind::Normal),
+        (None, _) => bail!("missing text"),
+    };
+
+    Ok((key, entry))
+}
+
+fn parse_hex_bytes(hex: &str) -> anyhow::Result<Vec<u8>> {
+    ensure!(
+        !hex.is_empty() && hex.len().is_multiple_of(2) && hex.is_ascii(),
+        "invalid hex '{hex}'"
+    );
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).with_context(|| format!("invalid hex '{hex}'"))
+        })
+        .collect()
+}
+
+/// ca65 の `.charmap` に相当する、ソース上の文字からバイト値への対応。
+///
+/// デフォルト値は恒等写像 (ca65 の初期状態)。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Charmap([u8; 0x100]);
+
+impl Default for Charmap {
+    fn default() -> Self {
+        Self(std::array::from_fn(|i| i as u8))
+    }
+}
+
+impl Charmap {
+    /// 文字列リテラル中に置ける文字 (`"` を除く ASCII の印字可能文字) かどうかを返す。
+    pub fn is_mappable(c: u8) -> bool {
+        (0x20..=0x7E).contains(&c) && c != b'"'
+    }
+
+    /// 文字 `c` に対応するバイト値を返す。
+    pub fn get(&self, c: u8) -> u8 {
+        self.0[usize::from(c)]
+    }
+
+    /// 文字列リテラル中に置ける文字のうち、`self` と `other` で対応が異なるものを
+    /// (文字, `other` でのバイト値) の形で返す。
+    pub fn diff<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = (u8, u8)> + 'a {
+        (0..=0xFF)
+            .filter(|&c| Self::is_mappable(c) && self.get(c) != other.get(c))
+            .map(|c| (c, other.get(c)))
+    }
+}
+
+/// 文字テーブルの 1 エントリ。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CharEntry {
+    text: String,
+    kind: CharKind,
+}
+
+impl CharEntry {
+    pub fn new(text: impl Into<String>, kind: CharKind) -> Self {
+        Self {
+            text: text.into(),
+            kind,
+        }
+    }
+
+    /// 対応するテキストを返す。終端、改行の場合は空のこともある。
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn kind(&self) -> CharKind {
+        self.kind
+    }
+}
+
+/// 文字テーブルのエントリの種類。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CharKind {
+    /// 通常の文字。
+    Normal,
+    /// 制御コード (ウェイト、名前挿入など)。
+    Control,
+    /// 改行。
+    Newline,
+    /// 文字列の終端。
+    End,
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::assert_equal;
+
+    use super::*;
+
+    #[test]
+    fn test_ascii() {
+        let table = CharTable::ascii();
+
+        assert_eq!(
+            table.decode_one(b"AB"),
+            Some((1, &CharEntry::new("A", CharKind::Normal)))
+        );
+        assert_eq!(
+            table.decode_one(&[0]),
+            Some((1, &CharEntry::new("", CharKind::End)))
+        );
+        assert_eq!(table.decode_one(&[0x0A]), None);
+        assert_eq!(table.decode_one(&[]), None);
+    }
+
+    #[test]
+    fn test_charmap() {
+        assert_eq!(CharTable::ascii().charmap(), Charmap::default());
+
+        let table = CharTable::from_tbl("0A=A\n0B=B\n0C=A\n0D=\"\n0E=[A]\n0F10=C\n").unwrap();
+        let charmap = table.charmap();
+        assert_eq!(charmap.get(b'A'), 0x0A);
+        assert_eq!(charmap.get(b'B'), 0x0B);
+        assert_eq!(charmap.get(b'C'), b'C');
+        assert_eq!(charmap.get(b'"'), b'"');
+        assert_equal(
+            Charmap::default().diff(&charmap),
+            [(b'A', 0x0A), (b'B', 0x0B)],
+        );
+    }
+
+    #[test]
+    fn test_from_tbl() {
+        let table = CharTable::from_tbl(
+            "\u{FEFF}0A=あ\r\n0A0B=が\n@table1\n$F0=wait\n10=[name]\n/FF=<end>\n*FE\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            table.decode_one(&[0x0A, 0x0B]),
+            Some((2, &CharEntry::new("が", CharKind::Normal)))
+        );
+        assert_eq!(
+            table.decode_one(&[0x0A, 0x0C]),
+            Some((1, &CharEntry::new("あ", CharKind::Normal)))
+        );
+        assert_eq!(
+            table.decode_one(&[0xF0]),
+            Some((1, &CharEntry::new("wait", CharKind::Control)))
+        );
+        assert_eq!(
+            table.decode_one(&[0x10]),
+            Some((1, &CharEntry::new("[name]", CharKind::Control)))
+        );
+        assert_eq!(
+            table.decode_one(&[0xFF]),
+            Some((1, &CharEntry::new("<end>", CharKind::End)))
+        );
+        assert_eq!(
+            table.decode_one(&[0xFE]),
+            Some((1, &CharEntry::new("", CharKind::Newline)))
+        );
+
+        let charmap = table.charmap();
+        assert_eq!(charmap.get(b'A'), b'A');
+
+        assert!(CharTable::from_tbl("").is_err());
+        assert!(CharTable::from_tbl("0=a").is_err());
+        assert!(CharTable::from_tbl("ZZ=a").is_err());
+        assert!(CharTable::from_tbl("0A").is_err());
+    }
+}
@@ -0,0 +1,420 @@
+//! エミュレータのトレースログの読み込み。
+//!
+//! Mesen, FCEUX などが出力するトレースログの各行から、実行された命令のアドレスと機械語、
+//! レジスタ、実効アドレスなどを読み取り、CDL 相当の情報に変換する:
+//!
+//! * 実行された命令はオペコード/オペランドとする。
+//! * 実際に飛んだ間接 jmp の行き先、および jsr の戻り先でない rts の行き先 (RTS トリック) はエントリポイントとする。
+//! * 割り込みにより実行が移った先もエントリポイントとする。
+//! * 命令が読み取ったアドレスはデータとする。
+//!
+//! 行の先頭は `8000`, `$8000:A9 00`, `$07:8000:A9 00` のように、(バンク番号と) アドレス、
+//! および機械語のバイト列であるものとする。アドレスで始まらない行は無視する。
+//! 以下の行は、別のバンクを実行しているものとみなして無視する:
+//!
+//! * バンク番号があり、アドレスを含むロードされたバンクのバンク番号が判明していて、それと一致しない行。
+//!   (同一の機械語を持つ別の非固定バンクの実行を取り違えないため)
+//! * 機械語がロードされたバンクの内容と一致しない行。
+//!
+//! 実効アドレスは `[$1234]` (Mesen) や `@ $1234` (FCEUX) の形の注釈があればそれを使い、
+//! なければ `X:nn`, `Y:nn` のレジスタ値からオペランドを元に計算する。
+//! 読み取った値の注釈 (`= $12`, `= #$12`) がロードされたバンクの内容と一致しない場合は無視する。
+
+use std::io::BufRead;
+
+use arrayvec::ArrayVec;
+
+use crate::address::Address;
+use crate::cdl::{Cdl, CdlElement};
+use crate::memory::{Memory, OpSuccResolved};
+use crate::op::{Op, Operand};
+
+/// 追跡する呼び出しスタックの最大の深さ。
+const STACK_DEPTH_MAX: usize = 256;
+
+/// トレースログを読み込み、指定した論理アドレス空間に対する CDL 相当の情報を返す。
+///
+/// `bank_numbers` は `memory` の各バンクのマッパー上のバンク番号 (不明なら `None`)。
+/// トレースログの行のバンク番号と照合するのに使われる。
+pub fn read_trace_log(
+    memory: &Memory,
+    bank_numbers: &[Option<usize>],
+    rdr: impl BufRead,
+) -> anyhow::Result<Cdl> {
+    let mut tracer = Tracer::new(memory, bank_numbers);
+
+    for line in rdr.lines() {
+        tracer.feed(&line?);
+    }
+
+    Ok(tracer.cdl)
+}
+
+#[derive(Debug)]
+struct Tracer<'a> {
+    memory: &'a Memory,
+    /// 各バンクのマッパー上のバンク番号。
+    bank_numbers: &'a [Option<usize>],
+    cdl: Cdl,
+    /// 直前の行で実行された命令。
+    prev: Option<(Address, Op)>,
+    /// jsr および割り込みによる戻り先アドレスのスタック。
+    stack: Vec<Address>,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(memory: &'a Memory, bank_numbers: &'a [Option<usize>]) -> Self {
+        Self {
+            memory,
+            bank_numbers,
+            cdl: Cdl::default(),
+            prev: None,
+            stack: vec![],
+        }
+    }
+
+    fn feed(&mut self, line: &str) {
+        // 割り込みの発生などを示す行は読み飛ばす。
+        let Some(entry) = TraceEntry::parse(line) else {
+            return;
+        };
+
+        let op = match self.memory.fetch_op(entry.pc) {
+            Ok((op, bank_id))
+                if op.to_bytes().starts_with(&entry.bytes)
+                    && self.bank_matches(&entry, bank_id) =>
+            {
+                op
+            }
+            _ => {
+                self.prev = None;
+                return;
+            }
+        };
+
+        if let Some((prev_pc, prev_op)) = self.prev {
+            self.follow(prev_pc, prev_op, entry.pc);
+        }
+
+        self.mark(entry.pc, CdlElement::OPCODE);
+        for i in 1..op.len().get() {
+            self.mark(entry.pc.wrapping_add_unsigned(i), CdlElement::OPERAND);
+        }
+
+        if op.opcode().is_read() {
+            if let Some(ea) = entry.effective_addr(op) {
+                let value_ok = entry
+                    .value
+                    .is_none_or(|value| self.memory.get_byte(ea).map(|x| x.0) == Some(value));
+                if value_ok {
+                    let flags = if matches!(op.operand(), Operand::IndX(_) | Operand::IndY(_)) {
+                        CdlElement::DATA.union(CdlElement::INDIRECT_DATA)
+                    } else {
+                        CdlElement::DATA
+                    };
+                    self.mark(ea, flags);
+                }
+            }
+        }
+
+        self.prev = Some((entry.pc, op));
+    }
+
+    /// 直前の命令 `prev_op` から `pc` へ実行が移ったことを記録する。
+    fn follow(&mut self, prev_pc: Address, prev_op: Op, pc: Address) {
+        let entry = CdlElement::JUMP_TARGET.union(CdlElement::ENTRYPOINT);
+
+        // 予想される行き先でなければ割り込みとみなし、戻り先として `ret` を積む。
+        let interrupted = |this: &mut Self, ret: Address| {
+            this.mark(pc, CdlElement::ENTRYPOINT);
+            this.push(ret);
+        };
+
+        match self.memory.resolve_op_succ(prev_pc, prev_op.succ()) {
+            OpSuccResolved::Normal(next) => {
+                if pc != next {
+                    interrupted(self, next);
+                }
+            }
+            OpSuccResolved::Brk(_) => {
+                self.mark(pc, CdlElement::ENTRYPOINT);
+                self.push(prev_pc.wrapping_add_unsigned(2_usize));
+            }
+            OpSuccResolved::Kil => {}
+            OpSuccResolved::Branch { taken, not_taken } => {
+                if pc == taken {
+                    self.mark(pc, CdlElement::JUMP_TARGET);
+                } else if pc != not_taken {
+                    interrupted(self, not_taken);
+                }
+            }
+            OpSuccResolved::Jsr(dst) => {
+                let ret = prev_pc.wrapping_add_unsigned(3_usize);
+                if pc == dst {
+                    self.mark(pc, entry);
+                    self.push(ret);
+                } else {
+                    interrupted(self, dst);
+                }
+            }
+            OpSuccResolved::Rti => {
+                self.pop_until(pc);
+            }
+            OpSuccResolved::Rts => {
+                // 戻り先でなければ RTS トリックによるディスパッチとみなす。
+                if !self.pop_until(pc) {
+                    self.mark(pc, entry);
+                }
+            }
+            OpSuccResolved::JmpAbs(dst) => {
+                if pc == dst {
+                    self.mark(pc, CdlElement::JUMP_TARGET);
+                } else {
+                    interrupted(self, dst);
+                }
+            }
+            OpSuccResolved::JmpInd(_) => {
+                self.mark(pc, entry);
+            }
+        }
+    }
+
+    /// 行のバンク番号が、バンク `bank_id` のバンク番号と矛盾しないかどうかを返す。
+    /// どちらかが不明なら `true` を返す。
+    fn bank_matches(&self, entry: &TraceEntry, bank_id: usize) -> bool {
+        let number = self.bank_numbers.get(bank_id).copied().flatten();
+        match (entry.bank, number) {
+            (Some(bank), Some(number)) => usize::from(bank) == number,
+            _ => true,
+        }
+    }
+
+    fn push(&mut self, ret: Address) {
+        if self.stack.len() == STACK_DEPTH_MAX {
+            self.stack.remove(0);
+        }
+        self.stack.push(ret);
+    }
+
+    /// スタック上に `ret` があれば、それより上を含めて取り除き、`true` を返す。
+    fn pop_until(&mut self, ret: Address) -> bool {
+        match self.stack.iter().rposition(|&x| x == ret) {
+            Some(idx) => {
+                self.stack.truncate(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// ロードされたバンク内のアドレスなら記録する。
+    fn mark(&mut self, addr: Address, flags: CdlElement) {
+        if self.memory.find_bank_id(addr).is_some() {
+            self.cdl.mark(addr, flags);
+        }
+    }
+}
+
+/// トレースログの 1 行から読み取った情報。
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct TraceEntry {
+    /// 行の先頭にあるバンク番号。
+    bank: Option<u8>,
+    pc: Address,
+    /// 機械語のバイト列 (空の場合もある)。
+    bytes: ArrayVec<u8, 3>,
+    x: Option<u8>,
+    y: Option<u8>,
+    /// 注釈された実効アドレス。
+    ea: Option<Address>,
+    /// 注釈された、実効アドレスから読み取った値。
+    value: Option<u8>,
+}
+
+impl TraceEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let mut tokens = line.split_whitespace();
+
+        // 先頭のトークンは "[$][バンク:]アドレス[:バイト]" の形。
+        let head = tokens.next()?;
+        let mut parts = head.strip_prefix('$').unwrap_or(head).split(':');
+        let mut bank = None;
+        let pc = loop {
+            let part = parts.next()?;
+            if let Some(pc) = parse_hex(part, 4) {
+                break Address::new(pc);
+            }
+            bank = Some(parse_hex(part, 2)? as u8);
+        };
+
+        let mut bytes = ArrayVec::<u8, 3>::new();
+        let byte_tokens = parts.chain(tokens.map(|token| token.strip_prefix('$').unwrap_or(token)));
+        for token in byte_tokens {
+            let Some(byte) = parse_hex(token, 2) else {
+                break;
+            };
+            if bytes.try_push(byte as u8).is_err() {
+                break;
+            }
+        }
+
+        let reg = |name: &str| {
+            line.split_whitespace()
+                .find_map(|token| token.strip_prefix(name))
+                .and_then(|s| parse_hex(s, 2))
+                .map(|x| x as u8)
+        };
+        let ea = hex_after(line, "[$", 4)
+            .or_else(|| hex_after(line, "@ $", 4))
+            .or_else(|| hex_after(line, "@$", 4))
+            .map(Address::new);
+        let value = hex_after(line, "= #$", 2)
+            .or_else(|| hex_after(line, "= $", 2))
+            .map(|x| x as u8);
+
+        Some(Self {
+            bank,
+            pc,
+            bytes,
+            x: reg("X:"),
+            y: reg("Y:"),
+            ea,
+            value,
+        })
+    }
+
+    /// 命令 `op` の実効アドレスを返す。求められなければ `None` を返す。
+    fn effective_addr(&self, op: Op) -> Option<Address> {
+        if let Some(ea) = self.ea {
+            return Some(ea);
+        }
+
+        match op.operand() {
+            Operand::Zp(zp) => Some(zp.into()),
+            Operand::Abs(abs) => Some(abs),
+            Operand::AbsX(abs) => self.x.map(|x| abs.wrapping_add_unsigned(x)),
+            Operand::AbsY(abs) => self.y.map(|y| abs.wrapping_add_unsigned(y)),
+            _ => None,
+        }
+    }
+}
+
+/// ちょうど `digits` 桁の 16 進数をパースする。
+fn parse_hex(s: &str, digits: usize) -> Option<u16> {
+    (s.len() == digits && s.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| u16::from_str_radix(s, 16).unwrap())
+}
+
+/// `line` 中の `pat` の直後にあるちょうど `digits` 桁の 16 進数をパースする。
+fn hex_after(line: &str, pat: &str, digits: usize) -> Option<u16> {
+    let (_, rest) = line.split_once(pat)?;
+    let len = rest.bytes().take_while(u8::is_ascii_hexdigit).count();
+    parse_hex(&rest[..len], digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bank::Bank;
+
+    use super::*;
+
+    #[test]
+    fn test_trace_entry() {
+        let entry = TraceEntry::parse("$8000:BD 05 80  LDA $8005,X @ $8007 = #$12  A:00 X:02 Y:00")
+            .unwrap();
+        assert_eq!(entry.pc, Address::new(0x8000));
+        assert_eq!(entry.bytes.as_slice(), [0xBD, 0x05, 0x80]);
+        assert_eq!(entry.x, Some(2));
+        assert_eq!(entry.ea, Some(Address::new(0x8007)));
+        assert_eq!(entry.value, Some(0x12));
+
+        assert_eq!(entry.bank, None);
+
+        let entry = TraceEntry::parse("$07:C000:78        SEI  A:00 X:00 Y:00").unwrap();
+        assert_eq!(entry.bank, Some(7));
+        assert_eq!(entry.pc, Address::new(0xC000));
+        assert_eq!(entry.bytes.as_slice(), [0x78]);
+
+        let entry = TraceEntry::parse("8000 $B1 $10   LDA ($10),Y [$8123] = $FF").unwrap();
+        assert_eq!(entry.bytes.as_slice(), [0xB1, 0x10]);
+        assert_eq!(entry.ea, Some(Address::new(0x8123)));
+        assert_eq!(entry.value, Some(0xFF));
+
+        let entry = TraceEntry::parse("8000  ADC #$01").unwrap();
+        assert!(entry.bytes.is_empty());
+
+        assert_eq!(TraceEntry::parse("NMI"), None);
+        assert_eq!(TraceEntry::parse(""), None);
+    }
+
+    #[test]
+    fn test_read_trace_log() {
+        let mut body = vec![0; 0x100];
+        let put = |body: &mut Vec<u8>, offset: usize, buf: &[u8]| {
+            body[offset..][..buf.len()].copy_from_slice(buf);
+        };
+        put(&mut body, 0x00, &[0x20, 0x08, 0xC0]); // $C000: jsr $C008
+        put(&mut body, 0x03, &[0x6C, 0x00, 0x03]); // $C003: jmp ($0300)
+        put(&mut body, 0x08, &[0xA9, 0xC0, 0x48, 0x48, 0x60]); // $C008: lda #$C0 / pha / pha / rts
+        put(&mut body, 0x50, &[0xBD, 0x80, 0xC0]); // $C050: lda $C080,x
+        put(&mut body, 0x53, &[0xAD, 0x90, 0xC0]); // $C053: lda $C090
+        put(&mut body, 0x60, &[0x40]); // $C060: rti
+        put(&mut body, 0xC1, &[0x60]); // $C0C1: rts
+        let memory = Memory::new(vec![Bank::new(Address::new(0xC000), body, true)]);
+
+        let log = "\
+$C000:20 08 C0  JSR $C008                A:00 X:00 Y:00
+$C008:A9 C0     LDA #$C0                 A:00 X:00 Y:00
+$C00A:48        PHA                      A:C0 X:00 Y:00
+$C00B:48        PHA                      A:C0 X:00 Y:00
+$C00C:60        RTS                      A:C0 X:00 Y:00
+$C0C1:60        RTS                      A:C0 X:00 Y:00
+$C003:6C 00 03  JMP ($0300) = $C050      A:C0 X:00 Y:00
+$C050:BD 80 C0  LDA $C080,X @ $C082 = #$00  A:C0 X:02 Y:00
+NMI
+$C060:40        RTI                      A:00 X:02 Y:00
+$C053:AD 90 C0  LDA $C090 = #$11         A:00 X:02 Y:00
+$C070:A9 00     LDA #$00                 A:11 X:02 Y:00
+";
+        let cdl = read_trace_log(&memory, &[None], log.as_bytes()).unwrap();
+        let at = |addr: u16| cdl[Address::new(addr)];
+
+        assert!(at(0xC000).is_opcode() && at(0xC001).is_operand());
+        assert!(at(0xC008).is_entrypoint());
+        // RTS トリックの行き先。
+        assert!(at(0xC0C1).is_entrypoint());
+        // jsr の戻り先。
+        assert!(at(0xC003).is_opcode() && !at(0xC003).is_entrypoint());
+        // 間接 jmp の行き先。
+        assert!(at(0xC050).is_entrypoint());
+        assert!(at(0xC082).is_data() && !at(0xC080).is_data());
+        // 割り込みハンドラ。
+        assert!(at(0xC060).is_entrypoint());
+        // 読み取った値が一致しない。
+        assert!(at(0xC053).is_opcode() && !at(0xC090).is_data());
+        // 機械語が一致しない。
+        assert!(!at(0xC070).is_opcode());
+    }
+
+    #[test]
+    fn test_read_trace_log_bank() {
+        // $8000 のバンク 2 をロードする。
+        let mut body = vec![0; 0x100];
+        body[..3].copy_from_slice(&[0x4C, 0x00, 0x80]); // $8000: jmp $8000
+        body[0x10] = 0xEA; // $8010: nop
+        let memory = Memory::new(vec![Bank::new(Address::new(0x8000), body, false)]);
+
+        let log = "\
+$02:8000:4C 00 80  JMP $8000  A:00 X:00 Y:00
+$03:8010:EA        NOP        A:00 X:00 Y:00
+";
+        let cdl = read_trace_log(&memory, &[Some(2)], log.as_bytes()).unwrap();
+        assert!(cdl[Address::new(0x8000)].is_opcode());
+        // 機械語は一致するが、別のバンクの行なので無視する。
+        assert!(!cdl[Address::new(0x8010)].is_opcode());
+
+        // バンク番号が不明ならバンク番号は照合しない。
+        let cdl = read_trace_log(&memory, &[None], log.as_bytes()).unwrap();
+        assert!(cdl[Address::new(0x8010)].is_opcode());
+    }
+}